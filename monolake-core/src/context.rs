@@ -8,3 +8,14 @@ pub struct PeerAddr(pub AcceptedAddr);
 
 #[derive(From, Into, Debug, Clone)]
 pub struct RemoteAddr(pub AcceptedAddr);
+
+/// Identity of a client certificate verified during a mutual TLS handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCertificate {
+    /// Distinguished name of the certificate subject, e.g. `CN=client,O=example`.
+    pub subject: String,
+    /// Subject alternative names, prefixed by their kind (`DNS:`, `URI:`, `IP:`, `email:`).
+    pub sans: Vec<String>,
+    /// Hex encoded SHA-256 digest of the DER encoded certificate.
+    pub fingerprint: String,
+}
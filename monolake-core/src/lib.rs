@@ -123,6 +123,8 @@
 //! - [`config`]: Configuration structures and utilities for the system.
 //! - [`context`]: Context management for request processing.
 //! - [`listener`]: Network listener implementations and abstractions.
+//! - `quic`: QUIC endpoint on the monoio runtime (requires the `quic` feature).
+//! - [`udp`]: UDP listener demultiplexing datagrams into sessions.
 //! - [`util`]: Various utility functions and helpers.
 //!
 //! ## Error Handling
@@ -139,7 +141,10 @@ pub mod context;
 pub mod http;
 pub mod listener;
 pub mod orchestrator;
+#[cfg(feature = "quic")]
+pub mod quic;
 pub mod thrift;
+pub mod udp;
 pub mod util;
 
 pub(crate) mod sealed {
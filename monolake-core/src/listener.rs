@@ -2,6 +2,9 @@
 //!
 //! This module provides unified abstractions for TCP and Unix domain socket listeners and streams,
 //! allowing for consistent handling of different network protocols in asynchronous environments.
+//! With the `quic` feature, QUIC endpoints are supported as well: each accepted QUIC connection is
+//! yielded as a single stream carrying the [`QuicConnection`](crate::quic::QuicConnection).
+//! UDP listeners similarly yield a stream carrying the [`UdpSession`] of each client address.
 //!
 //! # Key Components
 //!
@@ -13,6 +16,8 @@
 //! # Features
 //!
 //! - Support for both TCP and Unix domain sockets (Unix-only).
+//! - Optional QUIC listeners, bound with `SO_REUSEPORT` on every worker.
+//! - UDP listeners tracking sessions by client address, bound the same way.
 //! - Asynchronous I/O operations using the `monoio` runtime.
 //! - Optional pool based I/O for compatibility with Hyper
 //!
@@ -42,6 +47,8 @@ use monoio::{
 };
 use service_async::{AsyncMakeService, MakeService};
 
+use crate::udp::{UdpListener, UdpSession};
+
 /// A builder for creating network listeners.
 ///
 /// This enum provides a unified interface for building TCP and Unix domain socket listeners.
@@ -49,6 +56,9 @@ pub enum ListenerBuilder {
     Tcp(SocketAddr, ListenerOpts),
     #[cfg(unix)]
     Unix(std::os::unix::net::UnixListener),
+    #[cfg(feature = "quic")]
+    Quic(SocketAddr, std::sync::Arc<crate::quic::ServerConfig>),
+    Udp(SocketAddr, usize),
 }
 
 impl ListenerBuilder {
@@ -69,6 +79,19 @@ impl ListenerBuilder {
         Ok(Self::Tcp(addr, opts))
     }
 
+    #[cfg(feature = "quic")]
+    pub fn bind_quic(
+        addr: SocketAddr,
+        config: std::sync::Arc<crate::quic::ServerConfig>,
+    ) -> io::Result<ListenerBuilder> {
+        Ok(Self::Quic(addr, config))
+    }
+
+    /// Bind a UDP listener tracking at most `max_sessions` sessions, see [`UdpListener::bind`].
+    pub fn bind_udp(addr: SocketAddr, max_sessions: usize) -> io::Result<ListenerBuilder> {
+        Ok(Self::Udp(addr, max_sessions))
+    }
+
     pub fn build(&self) -> io::Result<Listener> {
         match self {
             ListenerBuilder::Tcp(addr, opts) => {
@@ -79,6 +102,13 @@ impl ListenerBuilder {
                 let sys_listener = listener.try_clone()?;
                 monoio::net::UnixListener::from_std(sys_listener).map(Listener::Unix)
             }
+            #[cfg(feature = "quic")]
+            ListenerBuilder::Quic(addr, config) => {
+                crate::quic::QuicListener::bind(*addr, config.clone()).map(Listener::Quic)
+            }
+            ListenerBuilder::Udp(addr, max_sessions) => {
+                UdpListener::bind(*addr, *max_sessions).map(Listener::Udp)
+            }
         }
     }
 }
@@ -111,6 +141,9 @@ pub enum Listener {
     Tcp(TcpListener),
     #[cfg(unix)]
     Unix(monoio::net::UnixListener),
+    #[cfg(feature = "quic")]
+    Quic(crate::quic::QuicListener),
+    Udp(UdpListener),
 }
 
 impl Stream for Listener {
@@ -135,6 +168,15 @@ impl Stream for Listener {
                 Some(Err(e)) => Some(Err(e)),
                 None => None,
             },
+            #[cfg(feature = "quic")]
+            Listener::Quic(l) => l.accept().await.map(|conn| {
+                let remote = conn.remote_address();
+                Ok((AcceptedStream::Quic(conn), AcceptedAddr::Udp(remote)))
+            }),
+            Listener::Udp(l) => l.accept().await.map(|session| {
+                let remote = session.peer_addr();
+                Ok((AcceptedStream::Udp(session), AcceptedAddr::Udp(remote)))
+            }),
         }
     }
 }
@@ -147,6 +189,26 @@ pub enum AcceptedStream {
     Tcp(TcpStream),
     #[cfg(unix)]
     Unix(monoio::net::UnixStream),
+    /// A QUIC connection. It carries multiplexed streams and does not support byte stream I/O.
+    #[cfg(feature = "quic")]
+    Quic(crate::quic::QuicConnection),
+    /// A UDP session. It carries datagrams and does not support byte stream I/O.
+    Udp(UdpSession),
+}
+
+#[cfg(feature = "quic")]
+fn quic_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "byte stream I/O on a QUIC connection",
+    )
+}
+
+fn udp_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "byte stream I/O on a UDP session",
+    )
 }
 
 unsafe impl Split for AcceptedStream {}
@@ -156,6 +218,8 @@ pub enum AcceptedAddr {
     Tcp(SocketAddr),
     #[cfg(unix)]
     Unix(monoio::net::unix::SocketAddr),
+    /// Client address of a UDP session or QUIC connection.
+    Udp(SocketAddr),
 }
 
 impl From<SocketAddr> for AcceptedAddr {
@@ -176,6 +240,9 @@ impl AsyncReadRent for AcceptedStream {
         match self {
             AcceptedStream::Tcp(inner) => inner.read(buf).await,
             AcceptedStream::Unix(inner) => inner.read(buf).await,
+            #[cfg(feature = "quic")]
+            AcceptedStream::Quic(_) => (Err(quic_unsupported()), buf),
+            AcceptedStream::Udp(_) => (Err(udp_unsupported()), buf),
         }
     }
 
@@ -183,6 +250,9 @@ impl AsyncReadRent for AcceptedStream {
         match self {
             AcceptedStream::Tcp(inner) => inner.readv(buf).await,
             AcceptedStream::Unix(inner) => inner.readv(buf).await,
+            #[cfg(feature = "quic")]
+            AcceptedStream::Quic(_) => (Err(quic_unsupported()), buf),
+            AcceptedStream::Udp(_) => (Err(udp_unsupported()), buf),
         }
     }
 }
@@ -193,6 +263,9 @@ impl AsyncWriteRent for AcceptedStream {
         match self {
             AcceptedStream::Tcp(inner) => inner.write(buf).await,
             AcceptedStream::Unix(inner) => inner.write(buf).await,
+            #[cfg(feature = "quic")]
+            AcceptedStream::Quic(_) => (Err(quic_unsupported()), buf),
+            AcceptedStream::Udp(_) => (Err(udp_unsupported()), buf),
         }
     }
 
@@ -201,6 +274,9 @@ impl AsyncWriteRent for AcceptedStream {
         match self {
             AcceptedStream::Tcp(inner) => inner.writev(buf_vec).await,
             AcceptedStream::Unix(inner) => inner.writev(buf_vec).await,
+            #[cfg(feature = "quic")]
+            AcceptedStream::Quic(_) => (Err(quic_unsupported()), buf_vec),
+            AcceptedStream::Udp(_) => (Err(udp_unsupported()), buf_vec),
         }
     }
 
@@ -209,6 +285,9 @@ impl AsyncWriteRent for AcceptedStream {
         match self {
             AcceptedStream::Tcp(inner) => inner.flush().await,
             AcceptedStream::Unix(inner) => inner.flush().await,
+            #[cfg(feature = "quic")]
+            AcceptedStream::Quic(_) => Err(quic_unsupported()),
+            AcceptedStream::Udp(_) => Err(udp_unsupported()),
         }
     }
 
@@ -217,6 +296,9 @@ impl AsyncWriteRent for AcceptedStream {
         match self {
             AcceptedStream::Tcp(inner) => inner.shutdown().await,
             AcceptedStream::Unix(inner) => inner.shutdown().await,
+            #[cfg(feature = "quic")]
+            AcceptedStream::Quic(_) => Err(quic_unsupported()),
+            AcceptedStream::Udp(_) => Err(udp_unsupported()),
         }
     }
 }
@@ -247,6 +329,9 @@ impl monoio::io::IntoPollIo for AcceptedStream {
                 .try_into_poll_io()
                 .map(AcceptedStreamPoll::Unix)
                 .map_err(|(e, io)| (e, AcceptedStream::Unix(io))),
+            #[cfg(feature = "quic")]
+            stream @ AcceptedStream::Quic(_) => Err((quic_unsupported(), stream)),
+            stream @ AcceptedStream::Udp(_) => Err((udp_unsupported(), stream)),
         }
     }
 }
@@ -0,0 +1,338 @@
+//! Connection limits of listeners.
+//!
+//! [`serve`](super::serve) asks its listener, through [`ConnectionGate`], when it may accept the
+//! next connection and whether an accepted connection is admitted. Admitted connections hold a
+//! [`ConnectionPermit`] until their task completes, rejected connections are closed at once.
+//!
+//! # Key Components
+//!
+//! - [`ConnectionLimitConfig`]: Limits of the concurrent connections of a listener.
+//! - [`LimitedListenerBuilder`]: Listener factory sharing the limits of a listener across workers.
+//! - [`LimitedListener`]: Listener enforcing the limits on a worker.
+//!
+//! # Limits
+//!
+//! - `max_connections` caps the connections of a listener across all the workers.
+//! - `max_connections_per_worker` caps the connections of a listener on each worker.
+//! - `max_connections_per_ip` caps the connections of each client IP address across all the
+//!   workers. Connections over Unix sockets have no IP address and are not counted.
+//!
+//! When the listener or worker limit is reached, [`LimitAction::Pause`] stops accepting until a
+//! connection completes, leaving new connections in the kernel backlog, while
+//! [`LimitAction::Close`] accepts and closes them. The client address is only known once a
+//! connection is accepted, so connections over the limit of their IP address are always closed.
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    future::{poll_fn, Future},
+    net::IpAddr,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Poll, Waker},
+    time::Duration,
+};
+
+use monoio::io::stream::Stream;
+use serde::{Deserialize, Serialize};
+use service_async::{AsyncMakeService, MakeService};
+
+use crate::listener::{AcceptedAddr, Listener};
+
+// Connections completing on other workers do not wake a paused listener, it rechecks the
+// listener limit at this interval.
+const RECHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Limits of the concurrent connections of a listener.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionLimitConfig {
+    /// Connections of the listener across all the workers.
+    pub max_connections: Option<usize>,
+    /// Connections of the listener on each worker.
+    pub max_connections_per_worker: Option<usize>,
+    /// Connections of each client IP address across all the workers.
+    pub max_connections_per_ip: Option<usize>,
+    /// Behaviour when the listener or worker limit is reached.
+    #[serde(default)]
+    pub on_limit: LimitAction,
+}
+
+/// Behaviour of a listener whose connection limit is reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// Stop accepting connections until one completes.
+    #[default]
+    Pause,
+    /// Accept connections and close them.
+    Close,
+}
+
+/// Admission control of the connections accepted by a listener.
+pub trait ConnectionGate<A> {
+    /// Wait until the listener may accept a connection.
+    fn ready(&self) -> impl Future<Output = ()> {
+        async {}
+    }
+
+    /// Admit an accepted connection, `None` rejects it.
+    fn admit(&self, _accepted: &A) -> Option<ConnectionPermit> {
+        Some(ConnectionPermit { _slot: None })
+    }
+}
+
+impl<A> ConnectionGate<A> for Listener {}
+
+/// Slot of an admitted connection, released when dropped.
+pub struct ConnectionPermit {
+    _slot: Option<Permit>,
+}
+
+struct Permit {
+    worker: Rc<WorkerConnections>,
+    shared: Arc<SharedConnections>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.worker.release();
+        self.shared.release(self.ip);
+    }
+}
+
+/// Connections of a listener across the workers.
+struct SharedConnections {
+    config: ConnectionLimitConfig,
+    active: AtomicUsize,
+    per_ip: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl SharedConnections {
+    fn has_slot(&self) -> bool {
+        self.config
+            .max_connections
+            .map_or(true, |max| self.active.load(Ordering::Relaxed) < max)
+    }
+
+    fn acquire(&self, ip: Option<IpAddr>) -> bool {
+        if let Some(max) = self.config.max_connections {
+            let acquired =
+                self.active
+                    .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |active| {
+                        (active < max).then_some(active + 1)
+                    });
+            if acquired.is_err() {
+                return false;
+            }
+        }
+        if let (Some(max), Some(ip)) = (self.config.max_connections_per_ip, ip) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            let count = per_ip.entry(ip).or_default();
+            if *count >= max {
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+                drop(per_ip);
+                self.release(None);
+                return false;
+            }
+            *count += 1;
+        }
+        true
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        if self.config.max_connections.is_some() {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+        }
+        if let (Some(_), Some(ip)) = (self.config.max_connections_per_ip, ip) {
+            let mut per_ip = self.per_ip.lock().unwrap();
+            if let Some(count) = per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Connections of a listener on a worker.
+#[derive(Default)]
+struct WorkerConnections {
+    max: Option<usize>,
+    active: Cell<usize>,
+    waiter: Cell<Option<Waker>>,
+}
+
+impl WorkerConnections {
+    fn has_slot(&self) -> bool {
+        self.max.map_or(true, |max| self.active.get() < max)
+    }
+
+    fn release(&self) {
+        self.active.set(self.active.get() - 1);
+        if let Some(waker) = self.waiter.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Listener factory sharing the connection limits of a listener across workers.
+pub struct LimitedListenerBuilder<T> {
+    inner: T,
+    shared: Option<Arc<SharedConnections>>,
+}
+
+impl<T> LimitedListenerBuilder<T> {
+    pub fn new(inner: T, config: Option<ConnectionLimitConfig>) -> Self {
+        Self {
+            inner,
+            shared: config.map(|config| {
+                Arc::new(SharedConnections {
+                    config,
+                    active: AtomicUsize::new(0),
+                    per_ip: Default::default(),
+                })
+            }),
+        }
+    }
+
+    fn limit<L>(&self, inner: L) -> LimitedListener<L> {
+        LimitedListener {
+            inner,
+            limits: self.shared.as_ref().map(|shared| {
+                let worker = WorkerConnections {
+                    max: shared.config.max_connections_per_worker,
+                    ..Default::default()
+                };
+                (Rc::new(worker), shared.clone())
+            }),
+        }
+    }
+}
+
+impl<T: MakeService> MakeService for LimitedListenerBuilder<T> {
+    type Service = LimitedListener<T::Service>;
+    type Error = T::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(self.limit(self.inner.make_via_ref(old.map(|o| &o.inner))?))
+    }
+}
+
+impl<T: AsyncMakeService> AsyncMakeService for LimitedListenerBuilder<T> {
+    type Service = LimitedListener<T::Service>;
+    type Error = T::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(self.limit(self.inner.make_via_ref(old.map(|o| &o.inner)).await?))
+    }
+}
+
+/// Listener enforcing the connection limits of a listener on a worker.
+pub struct LimitedListener<L> {
+    inner: L,
+    limits: Option<(Rc<WorkerConnections>, Arc<SharedConnections>)>,
+}
+
+impl<L: Stream> Stream for LimitedListener<L> {
+    type Item = L::Item;
+
+    fn next(&mut self) -> impl Future<Output = Option<Self::Item>> {
+        self.inner.next()
+    }
+}
+
+impl<L, S> ConnectionGate<(S, AcceptedAddr)> for LimitedListener<L> {
+    async fn ready(&self) {
+        let Some((worker, shared)) = &self.limits else {
+            return;
+        };
+        if shared.config.on_limit == LimitAction::Close {
+            return;
+        }
+        while !worker.has_slot() || !shared.has_slot() {
+            let released = poll_fn(|cx| {
+                if worker.has_slot() && shared.has_slot() {
+                    return Poll::Ready(());
+                }
+                worker.waiter.set(Some(cx.waker().clone()));
+                Poll::Pending
+            });
+            if shared.config.max_connections.is_some() {
+                monoio::select! {
+                    _ = released => {}
+                    _ = monoio::time::sleep(RECHECK_INTERVAL) => {}
+                }
+            } else {
+                released.await;
+            }
+        }
+    }
+
+    fn admit(&self, (_, addr): &(S, AcceptedAddr)) -> Option<ConnectionPermit> {
+        let Some((worker, shared)) = &self.limits else {
+            return Some(ConnectionPermit { _slot: None });
+        };
+        if !worker.has_slot() {
+            return None;
+        }
+        let ip = match addr {
+            AcceptedAddr::Tcp(addr) | AcceptedAddr::Udp(addr) => Some(addr.ip()),
+            #[cfg(unix)]
+            AcceptedAddr::Unix(_) => None,
+        };
+        if !shared.acquire(ip) {
+            return None;
+        }
+        worker.active.set(worker.active.get() + 1);
+        Some(ConnectionPermit {
+            _slot: Some(Permit {
+                worker: worker.clone(),
+                shared: shared.clone(),
+                ip,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn accepted(addr: &str) -> ((), AcceptedAddr) {
+        ((), AcceptedAddr::from(addr.parse::<SocketAddr>().unwrap()))
+    }
+
+    #[test]
+    fn permits_released_on_drop() {
+        let config = ConnectionLimitConfig {
+            max_connections: Some(3),
+            max_connections_per_worker: Some(2),
+            max_connections_per_ip: Some(1),
+            on_limit: LimitAction::Close,
+        };
+        let builder = LimitedListenerBuilder::new((), Some(config));
+        let (worker1, worker2) = (builder.limit(()), builder.limit(()));
+
+        let a = worker1.admit(&accepted("1.1.1.1:1000")).unwrap();
+        assert!(worker2.admit(&accepted("1.1.1.1:1001")).is_none());
+        let _b = worker1.admit(&accepted("2.2.2.2:1000")).unwrap();
+        // Worker limit.
+        assert!(worker1.admit(&accepted("3.3.3.3:1000")).is_none());
+        let _c = worker2.admit(&accepted("3.3.3.3:1000")).unwrap();
+        // Listener limit.
+        assert!(worker2.admit(&accepted("4.4.4.4:1000")).is_none());
+        drop(a);
+        let _d = worker2.admit(&accepted("1.1.1.1:1002")).unwrap();
+    }
+}
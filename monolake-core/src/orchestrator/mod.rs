@@ -42,10 +42,15 @@ use tracing::{debug, error, info, warn};
 
 use self::runtime::RuntimeWrapper;
 
+mod limit;
 mod runtime;
 mod service_executor;
 mod worker_manager;
 
+pub use limit::{
+    ConnectionGate, ConnectionLimitConfig, ConnectionPermit, LimitAction, LimitedListener,
+    LimitedListenerBuilder,
+};
 pub use service_executor::{
     Execute, ServiceCommand, ServiceCommandTask, ServiceDeploymentContainer, ServiceExecutor,
     ServiceSlot,
@@ -92,9 +97,11 @@ impl<E> ResultGroup<(), E> {
 /// - The listener closes, indicating no more incoming connections.
 ///
 /// For each accepted connection, a new task is spawned to handle it using the provided service.
+/// The listener is waited on through [`ConnectionGate`] before accepting a connection, and
+/// connections it does not admit are closed without being served.
 pub async fn serve<S, Svc, A, E>(mut listener: S, handler: ServiceSlot<Svc>, mut stop: OSender<()>)
 where
-    S: Stream<Item = Result<A, E>> + 'static,
+    S: Stream<Item = Result<A, E>> + ConnectionGate<A> + 'static,
     E: Debug,
     Svc: Service<A> + 'static,
     Svc::Error: Debug,
@@ -107,7 +114,10 @@ where
                 info!("server is notified to stop");
                 break;
             }
-            accept_opt = listener.next() => {
+            accept_opt = async {
+                listener.ready().await;
+                listener.next().await
+            } => {
                 let accept = match accept_opt {
                     Some(accept) => accept,
                     None => {
@@ -117,8 +127,13 @@ where
                 };
                 match accept {
                     Ok(accept) => {
+                        let Some(permit) = listener.admit(&accept) else {
+                            debug!("Connection rejected by connection limits");
+                            continue;
+                        };
                         let svc = handler.get_svc();
                         monoio::spawn(async move {
+                            let _permit = permit;
                             match svc.call(accept).await {
                                 Ok(_) => {
                                     debug!("Connection complete");
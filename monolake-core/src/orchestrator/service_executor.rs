@@ -37,7 +37,7 @@ use monoio::io::stream::Stream;
 use service_async::{AsyncMakeService, Service};
 use tracing::error;
 
-use super::serve;
+use super::{serve, ConnectionGate};
 use crate::AnyError;
 
 /// Manages multiple service deployments across different sites within a worker thread.
@@ -433,7 +433,7 @@ where
     F: AsyncMakeService<Service = S>,
     F::Error: Debug + Send + Sync + 'static,
     LF: AsyncMakeService,
-    LF::Service: Stream<Item = Result<A, E>> + 'static,
+    LF::Service: Stream<Item = Result<A, E>> + ConnectionGate<A> + 'static,
     E: Debug + Send + Sync + 'static,
     LF::Error: Debug + Send + Sync + 'static,
     S: Service<A> + 'static,
@@ -0,0 +1,643 @@
+//! QUIC endpoint running on the monoio runtime.
+//!
+//! `quinn_proto` implements the QUIC protocol without performing any I/O. [`QuicListener`] binds
+//! a UDP socket and drives a `quinn_proto` endpoint over it with two tasks spawned on the current
+//! worker:
+//!
+//! - the receiver reads datagrams from the socket and feeds them to the endpoint;
+//! - the driver handles timers, dispatches connection events to the tasks waiting for them and
+//!   sends the datagrams produced by the endpoint and its connections.
+//!
+//! Established connections are yielded by the listener as [`QuicConnection`] handles. A handle
+//! exposes the stream operations of its connection in poll form, which is what protocol
+//! implementations like HTTP/3 build upon.
+//!
+//! Connections keep being served after the listener is dropped: the tasks exit once the last
+//! connection is drained. Connection attempts arriving in the meantime are refused.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::{poll_fn, Future},
+    io, mem,
+    net::SocketAddr,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+use bytes::{Bytes, BytesMut};
+use futures_channel::mpsc;
+use futures_util::StreamExt;
+use monoio::net::udp::UdpSocket;
+pub use quinn_proto::{ConnectionError, Dir, ServerConfig, StreamId, VarInt};
+use quinn_proto::{
+    ConnectionHandle, DatagramEvent, Endpoint, EndpointConfig, Event, FinishError, ReadError,
+    ReadableError, StreamEvent, Transmit, WriteError,
+};
+use tracing::debug;
+
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// Errors of stream operations on a [`QuicConnection`].
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum StreamError {
+    #[error("connection lost: {0}")]
+    ConnectionLost(ConnectionError),
+    #[error("stream reset by peer with code {0}")]
+    Reset(VarInt),
+    #[error("stream stopped by peer with code {0}")]
+    Stopped(VarInt),
+    #[error("stream closed")]
+    Closed,
+}
+
+impl From<ConnectionError> for StreamError {
+    fn from(e: ConnectionError) -> Self {
+        Self::ConnectionLost(e)
+    }
+}
+
+/// A QUIC endpoint accepting connections on a UDP socket.
+pub struct QuicListener {
+    shared: Rc<Shared>,
+    incoming: mpsc::UnboundedReceiver<QuicConnection>,
+}
+
+impl QuicListener {
+    /// Bind a UDP socket to `addr` and serve QUIC connections on it with the given crypto and
+    /// transport configuration.
+    ///
+    /// The socket is bound with `SO_REUSEPORT` so that every worker can run its own endpoint on
+    /// the same address. Must be called within a monoio runtime.
+    pub fn bind(addr: SocketAddr, config: Arc<ServerConfig>) -> io::Result<Self> {
+        let socket = crate::udp::bind_reuse_port(addr)?;
+
+        let (tx, rx) = mpsc::unbounded();
+        let endpoint = Endpoint::new(
+            Arc::new(EndpointConfig::default()),
+            Some(config),
+            false,
+            None,
+        );
+        let shared = Rc::new(Shared {
+            socket,
+            state: RefCell::new(State {
+                endpoint,
+                connections: HashMap::new(),
+                lost: HashMap::new(),
+                incoming: tx,
+                outgoing: Vec::new(),
+                next_id: 0,
+            }),
+            released: RefCell::new(Vec::new()),
+            woken: Cell::new(false),
+            driver: Cell::new(None),
+            closed: Cell::new(false),
+            receiver: Cell::new(None),
+        });
+        monoio::spawn(receive(shared.clone()));
+        monoio::spawn(drive(shared.clone()));
+        Ok(Self {
+            shared,
+            incoming: rx,
+        })
+    }
+
+    /// Wait for the next established connection.
+    pub async fn accept(&mut self) -> Option<QuicConnection> {
+        self.incoming.next().await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        // Dropping the receiver closes the channel, which makes the endpoint refuse new
+        // connections and lets the driver exit once the existing ones are drained.
+        self.incoming.close();
+        self.shared.wake();
+    }
+}
+
+/// A handle to an established QUIC connection.
+///
+/// Handles are reference counted: the connection is closed with code 0 when the last handle to it
+/// is dropped.
+pub struct QuicConnection {
+    shared: Rc<Shared>,
+    handle: ConnectionHandle,
+    id: u64,
+    remote: SocketAddr,
+}
+
+impl QuicConnection {
+    fn new(shared: Rc<Shared>, handle: ConnectionHandle, id: u64, remote: SocketAddr) -> Self {
+        if let Some(conn) = shared.state.borrow_mut().conn(handle, id) {
+            conn.refs += 1;
+        }
+        Self {
+            shared,
+            handle,
+            id,
+            remote,
+        }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.remote
+    }
+
+    /// Accept the next stream of the given directionality opened by the peer.
+    pub fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+        dir: Dir,
+    ) -> Poll<Result<StreamId, StreamError>> {
+        self.poll_with(|conn| match conn.inner.streams().accept(dir) {
+            Some(id) => Poll::Ready(Ok(id)),
+            None => {
+                conn.accept[dir as usize] = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Open a new stream of the given directionality once the peer allows it.
+    pub fn poll_open(&self, cx: &mut Context<'_>, dir: Dir) -> Poll<Result<StreamId, StreamError>> {
+        self.poll_with(|conn| match conn.inner.streams().open(dir) {
+            Some(id) => Poll::Ready(Ok(id)),
+            None => {
+                conn.open[dir as usize] = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Read the next chunk of at most `max` bytes from a stream, or `None` once the peer has
+    /// finished it.
+    pub fn poll_read(
+        &self,
+        cx: &mut Context<'_>,
+        id: StreamId,
+        max: usize,
+    ) -> Poll<Result<Option<Bytes>, StreamError>> {
+        self.poll_with(|conn| {
+            let mut stream = conn.inner.recv_stream(id);
+            let mut chunks = match stream.read(true) {
+                Ok(chunks) => chunks,
+                Err(ReadableError::ClosedStream | ReadableError::IllegalOrderedRead) => {
+                    return Poll::Ready(Err(StreamError::Closed));
+                }
+            };
+            let read = chunks.next(max);
+            let _ = chunks.finalize();
+            match read {
+                Ok(chunk) => Poll::Ready(Ok(chunk.map(|chunk| chunk.bytes))),
+                Err(ReadError::Blocked) => {
+                    conn.readers.insert(id, cx.waker().clone());
+                    Poll::Pending
+                }
+                Err(ReadError::Reset(code)) => Poll::Ready(Err(StreamError::Reset(code))),
+            }
+        })
+    }
+
+    /// Write a prefix of `data` to a stream, returning the number of bytes written.
+    pub fn poll_write(
+        &self,
+        cx: &mut Context<'_>,
+        id: StreamId,
+        data: &[u8],
+    ) -> Poll<Result<usize, StreamError>> {
+        self.poll_with(|conn| match conn.inner.send_stream(id).write(data) {
+            Ok(written) => Poll::Ready(Ok(written)),
+            Err(WriteError::Blocked) => {
+                conn.writers.insert(id, cx.waker().clone());
+                Poll::Pending
+            }
+            Err(WriteError::Stopped(code)) => Poll::Ready(Err(StreamError::Stopped(code))),
+            Err(WriteError::ClosedStream) => Poll::Ready(Err(StreamError::Closed)),
+        })
+    }
+
+    /// Mark the end of the data written to a stream.
+    pub fn finish(&self, id: StreamId) -> Result<(), StreamError> {
+        self.with(|conn| {
+            conn.writers.remove(&id);
+            conn.inner.send_stream(id).finish().map_err(|e| match e {
+                FinishError::Stopped(code) => StreamError::Stopped(code),
+                FinishError::ClosedStream => StreamError::Closed,
+            })
+        })?
+    }
+
+    /// Abandon sending on a stream.
+    pub fn reset(&self, id: StreamId, code: VarInt) {
+        let _ = self.with(|conn| {
+            conn.writers.remove(&id);
+            let _ = conn.inner.send_stream(id).reset(code);
+        });
+    }
+
+    /// Ask the peer to stop sending on a stream.
+    pub fn stop(&self, id: StreamId, code: VarInt) {
+        let _ = self.with(|conn| {
+            conn.readers.remove(&id);
+            let _ = conn.inner.recv_stream(id).stop(code);
+        });
+    }
+
+    /// Close the connection immediately.
+    pub fn close(&self, code: VarInt, reason: &[u8]) {
+        let _ = self.with(|conn| {
+            conn.inner
+                .close(Instant::now(), code, Bytes::copy_from_slice(reason))
+        });
+    }
+
+    fn with<T>(&self, f: impl FnOnce(&mut Conn) -> T) -> Result<T, StreamError> {
+        let result = {
+            let mut state = self.shared.state.borrow_mut();
+            state.live_conn(self.handle, self.id).map(f)
+        };
+        self.shared.wake();
+        result.map_err(StreamError::ConnectionLost)
+    }
+
+    fn poll_with<T>(
+        &self,
+        f: impl FnOnce(&mut Conn) -> Poll<Result<T, StreamError>>,
+    ) -> Poll<Result<T, StreamError>> {
+        let result = {
+            let mut state = self.shared.state.borrow_mut();
+            match state.live_conn(self.handle, self.id) {
+                Ok(conn) => f(conn),
+                Err(e) => Poll::Ready(Err(StreamError::ConnectionLost(e))),
+            }
+        };
+        // Only progress may have produced frames to transmit.
+        if result.is_ready() {
+            self.shared.wake();
+        }
+        result
+    }
+}
+
+impl Clone for QuicConnection {
+    fn clone(&self) -> Self {
+        Self::new(self.shared.clone(), self.handle, self.id, self.remote)
+    }
+}
+
+impl Drop for QuicConnection {
+    fn drop(&mut self) {
+        match self.shared.state.try_borrow_mut() {
+            Ok(mut state) => state.release(self.handle, self.id),
+            // Handles dropped while the state is in use are released by the next round of the
+            // driver.
+            Err(_) => self
+                .shared
+                .released
+                .borrow_mut()
+                .push((self.handle, self.id)),
+        }
+        self.shared.wake();
+    }
+}
+
+struct Shared {
+    socket: UdpSocket,
+    state: RefCell<State>,
+    // Handles dropped while the state was borrowed.
+    released: RefCell<Vec<(ConnectionHandle, u64)>>,
+    // Set when the driver has to run another round.
+    woken: Cell<bool>,
+    driver: Cell<Option<Waker>>,
+    // Set by the driver on exit to stop the receiver.
+    closed: Cell<bool>,
+    receiver: Cell<Option<Waker>>,
+}
+
+impl Shared {
+    fn wake(&self) {
+        self.woken.set(true);
+        if let Some(waker) = self.driver.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct State {
+    endpoint: Endpoint,
+    connections: HashMap<ConnectionHandle, Conn>,
+    // Errors of drained connections still referenced by handles, keyed by connection id.
+    lost: HashMap<u64, ConnectionError>,
+    incoming: mpsc::UnboundedSender<QuicConnection>,
+    outgoing: Vec<(SocketAddr, Vec<u8>)>,
+    next_id: u64,
+}
+
+struct Conn {
+    inner: quinn_proto::Connection,
+    // Connection handles are reused by the endpoint once drained, the id tells generations apart.
+    id: u64,
+    refs: usize,
+    error: Option<ConnectionError>,
+    accept: [Option<Waker>; 2],
+    open: [Option<Waker>; 2],
+    readers: HashMap<StreamId, Waker>,
+    writers: HashMap<StreamId, Waker>,
+}
+
+impl Conn {
+    fn new(inner: quinn_proto::Connection, id: u64) -> Self {
+        Self {
+            inner,
+            id,
+            refs: 0,
+            error: None,
+            accept: [None, None],
+            open: [None, None],
+            readers: HashMap::new(),
+            writers: HashMap::new(),
+        }
+    }
+
+    fn dispatch(&mut self, event: Event) -> bool {
+        match event {
+            Event::Connected => return true,
+            Event::ConnectionLost { reason } => {
+                self.error = Some(reason);
+                self.wake_all();
+            }
+            Event::Stream(StreamEvent::Opened { dir }) => wake(&mut self.accept[dir as usize]),
+            Event::Stream(StreamEvent::Available { dir }) => wake(&mut self.open[dir as usize]),
+            Event::Stream(StreamEvent::Readable { id }) => {
+                if let Some(waker) = self.readers.remove(&id) {
+                    waker.wake();
+                }
+            }
+            Event::Stream(
+                StreamEvent::Writable { id }
+                | StreamEvent::Finished { id }
+                | StreamEvent::Stopped { id, .. },
+            ) => {
+                if let Some(waker) = self.writers.remove(&id) {
+                    waker.wake();
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn wake_all(&mut self) {
+        self.accept
+            .iter_mut()
+            .chain(self.open.iter_mut())
+            .for_each(wake);
+        self.readers
+            .drain()
+            .chain(self.writers.drain())
+            .for_each(|(_, waker)| waker.wake());
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+impl State {
+    fn conn(&mut self, handle: ConnectionHandle, id: u64) -> Option<&mut Conn> {
+        self.connections
+            .get_mut(&handle)
+            .filter(|conn| conn.id == id)
+    }
+
+    /// Release a handle of a connection, closing the connection with its last handle.
+    fn release(&mut self, handle: ConnectionHandle, id: u64) {
+        match self.conn(handle, id) {
+            Some(conn) => {
+                conn.refs -= 1;
+                if conn.refs == 0 {
+                    conn.inner
+                        .close(Instant::now(), VarInt::from_u32(0), Bytes::new());
+                }
+            }
+            None => {
+                self.lost.remove(&id);
+            }
+        }
+    }
+
+    fn live_conn(
+        &mut self,
+        handle: ConnectionHandle,
+        id: u64,
+    ) -> Result<&mut Conn, ConnectionError> {
+        let lost = self
+            .lost
+            .get(&id)
+            .cloned()
+            .unwrap_or(ConnectionError::LocallyClosed);
+        match self.connections.get_mut(&handle) {
+            Some(conn) if conn.id == id => match &conn.error {
+                Some(e) => Err(e.clone()),
+                None => Ok(conn),
+            },
+            _ => Err(lost),
+        }
+    }
+
+    fn handle_datagram(&mut self, now: Instant, remote: SocketAddr, data: BytesMut) {
+        let mut buf = Vec::new();
+        match self
+            .endpoint
+            .handle(now, remote, None, None, data, &mut buf)
+        {
+            Some(DatagramEvent::ConnectionEvent(handle, event)) => {
+                if let Some(conn) = self.connections.get_mut(&handle) {
+                    conn.inner.handle_event(event);
+                }
+            }
+            Some(DatagramEvent::NewConnection(incoming)) => {
+                if self.incoming.is_closed() {
+                    let transmit = self.endpoint.refuse(incoming, &mut buf);
+                    self.respond(transmit, buf);
+                    return;
+                }
+                match self.endpoint.accept(incoming, now, &mut buf, None) {
+                    Ok((handle, inner)) => {
+                        let id = self.next_id;
+                        self.next_id += 1;
+                        self.connections.insert(handle, Conn::new(inner, id));
+                    }
+                    Err(e) => {
+                        debug!("quic connection from {remote} rejected: {}", e.cause);
+                        if let Some(transmit) = e.response {
+                            self.respond(transmit, buf);
+                        }
+                    }
+                }
+            }
+            Some(DatagramEvent::Response(transmit)) => self.respond(transmit, buf),
+            None => {}
+        }
+    }
+
+    fn respond(&mut self, transmit: Transmit, mut buf: Vec<u8>) {
+        buf.truncate(transmit.size);
+        self.outgoing.push((transmit.destination, buf));
+    }
+
+    /// Run one round of all connections: fire expired timers, exchange events with the endpoint,
+    /// collect datagrams to send and dispatch events to the waiting tasks. Returns the earliest
+    /// timer deadline.
+    fn drive(
+        &mut self,
+        now: Instant,
+        connected: &mut Vec<(ConnectionHandle, u64, SocketAddr)>,
+    ) -> Option<Instant> {
+        let State {
+            endpoint,
+            connections,
+            lost,
+            outgoing,
+            ..
+        } = self;
+        let mut drained = Vec::new();
+        let mut deadline: Option<Instant> = None;
+        for (&handle, conn) in connections.iter_mut() {
+            if conn.inner.poll_timeout().is_some_and(|t| t <= now) {
+                conn.inner.handle_timeout(now);
+            }
+            loop {
+                while let Some(event) = conn.inner.poll_endpoint_events() {
+                    if event.is_drained() {
+                        drained.push(handle);
+                    }
+                    if let Some(event) = endpoint.handle_event(handle, event) {
+                        conn.inner.handle_event(event);
+                    }
+                }
+                let mut buf = Vec::new();
+                let Some(transmit) = conn.inner.poll_transmit(now, 1, &mut buf) else {
+                    break;
+                };
+                buf.truncate(transmit.size);
+                outgoing.push((transmit.destination, buf));
+            }
+            while let Some(event) = conn.inner.poll() {
+                if conn.dispatch(event) {
+                    connected.push((handle, conn.id, conn.inner.remote_address()));
+                }
+            }
+            if let Some(t) = conn.inner.poll_timeout() {
+                deadline = Some(deadline.map_or(t, |d| d.min(t)));
+            }
+        }
+        for handle in drained {
+            if let Some(mut conn) = connections.remove(&handle) {
+                conn.wake_all();
+                if conn.refs > 0 {
+                    lost.insert(
+                        conn.id,
+                        conn.error.unwrap_or(ConnectionError::LocallyClosed),
+                    );
+                }
+            }
+        }
+        deadline
+    }
+}
+
+async fn receive(shared: Rc<Shared>) {
+    let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    loop {
+        let closed = poll_fn(|cx| {
+            if shared.closed.get() {
+                return Poll::Ready(());
+            }
+            shared.receiver.set(Some(cx.waker().clone()));
+            Poll::Pending
+        });
+        let (result, b) = monoio::select! {
+            received = shared.socket.recv_from(buf) => received,
+            _ = closed => return,
+        };
+        buf = b;
+        match result {
+            Ok((n, remote)) => {
+                let data = BytesMut::from(&buf[..n]);
+                shared
+                    .state
+                    .borrow_mut()
+                    .handle_datagram(Instant::now(), remote, data);
+                shared.wake();
+            }
+            // Errors like ICMP unreachable reports concern a single peer, keep serving the others.
+            Err(e) => debug!("quic socket receive failed: {e}"),
+        }
+    }
+}
+
+async fn drive(shared: Rc<Shared>) {
+    loop {
+        shared.woken.set(false);
+        let mut connected = Vec::new();
+        let (outgoing, deadline, done, incoming) = {
+            let mut state = shared.state.borrow_mut();
+            for (handle, id) in mem::take(&mut *shared.released.borrow_mut()) {
+                state.release(handle, id);
+            }
+            let deadline = state.drive(Instant::now(), &mut connected);
+            let done = state.incoming.is_closed() && state.connections.is_empty();
+            (
+                mem::take(&mut state.outgoing),
+                deadline,
+                done,
+                state.incoming.clone(),
+            )
+        };
+        for (handle, id, remote) in connected {
+            // Connections established after the listener is gone are closed on drop.
+            let _ =
+                incoming.unbounded_send(QuicConnection::new(shared.clone(), handle, id, remote));
+        }
+        for (destination, data) in outgoing {
+            if let Err(e) = shared.socket.send_to(data, destination).await.0 {
+                debug!("quic socket send to {destination} failed: {e}");
+            }
+        }
+        if done {
+            shared.closed.set(true);
+            if let Some(waker) = shared.receiver.take() {
+                waker.wake();
+            }
+            return;
+        }
+
+        let mut sleep = deadline.map(|deadline| {
+            Box::pin(monoio::time::sleep_until(monoio::time::Instant::from_std(
+                deadline,
+            )))
+        });
+        poll_fn(|cx| {
+            if shared.woken.get() {
+                return Poll::Ready(());
+            }
+            shared.driver.set(Some(cx.waker().clone()));
+            match sleep.as_mut() {
+                Some(sleep) => sleep.as_mut().poll(cx),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+    }
+}
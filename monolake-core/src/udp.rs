@@ -0,0 +1,269 @@
+//! UDP listener demultiplexing datagrams into sessions.
+//!
+//! UDP has no connections: [`UdpListener`] binds a socket and tracks a session per client address
+//! instead. The first datagram of an unknown client opens a [`UdpSession`] which is yielded by the
+//! listener, and the following datagrams of the client are delivered to it. Replies are sent from
+//! the listening socket so that clients see them coming from the address they sent to.
+//!
+//! A session lasts until its handle is dropped, usually when the service serving it expires it
+//! after some idle time. Datagrams of the client arriving afterwards open a new session. At most
+//! `max_sessions` sessions are tracked at once, datagrams of new clients are dropped beyond.
+//!
+//! Sessions keep being served after the listener is dropped: the receiving task exits once the
+//! last session is dropped. Datagrams of new clients arriving in the meantime are discarded.
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    rc::Rc,
+    task::{Poll, Waker},
+};
+
+use bytes::Bytes;
+use futures_channel::mpsc;
+use futures_util::StreamExt;
+use monoio::{buf::IoBuf, net::udp::UdpSocket, BufResult};
+use tracing::debug;
+
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+// Datagrams queued for a session before further ones are dropped
+const SESSION_QUEUE_SIZE: usize = 64;
+/// Sessions tracked by a listener unless configured otherwise.
+pub const DEFAULT_MAX_SESSIONS: usize = 10_000;
+
+/// Bind a UDP socket with `SO_REUSEPORT` so that every worker can bind the same address.
+///
+/// Must be called within a monoio runtime.
+pub(crate) fn bind_reuse_port(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    let socket: std::net::UdpSocket = socket.into();
+    // Because we build the async UdpSocket from a std one, we have to make sure it is
+    // non_blocking.
+    if monoio::utils::is_legacy() {
+        socket.set_nonblocking(true)?;
+    }
+    UdpSocket::from_std(socket)
+}
+
+/// A UDP socket yielding a session per client address.
+pub struct UdpListener {
+    shared: Rc<Shared>,
+    incoming: mpsc::UnboundedReceiver<UdpSession>,
+}
+
+struct Shared {
+    socket: UdpSocket,
+    sessions: RefCell<HashMap<SocketAddr, mpsc::Sender<Bytes>>>,
+    max_sessions: usize,
+    listening: Cell<bool>,
+    receiver: Cell<Option<Waker>>,
+}
+
+impl UdpListener {
+    /// Bind a UDP socket to `addr`, tracking at most `max_sessions` sessions.
+    ///
+    /// The socket is bound with `SO_REUSEPORT` so that every worker receives datagrams on the
+    /// same address. The kernel delivers the datagrams of a client to the same worker, and each
+    /// worker has its own listener and limit of sessions. Must be called within a monoio runtime.
+    pub fn bind(addr: SocketAddr, max_sessions: usize) -> io::Result<Self> {
+        let socket = bind_reuse_port(addr)?;
+        let (tx, rx) = mpsc::unbounded();
+        let shared = Rc::new(Shared {
+            socket,
+            sessions: RefCell::new(HashMap::new()),
+            max_sessions,
+            listening: Cell::new(true),
+            receiver: Cell::new(None),
+        });
+        monoio::spawn(receive(shared.clone(), tx));
+        Ok(Self {
+            shared,
+            incoming: rx,
+        })
+    }
+
+    /// Wait for the first datagram of a new client.
+    pub async fn accept(&mut self) -> Option<UdpSession> {
+        self.incoming.next().await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+}
+
+impl Drop for UdpListener {
+    fn drop(&mut self) {
+        self.shared.listening.set(false);
+        self.shared.wake_if_done();
+    }
+}
+
+/// The datagrams exchanged with a client of a [`UdpListener`].
+pub struct UdpSession {
+    shared: Rc<Shared>,
+    peer: SocketAddr,
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl UdpSession {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    /// Wait for the next datagram sent by the client.
+    pub async fn recv(&mut self) -> Option<Bytes> {
+        self.rx.next().await
+    }
+
+    /// Send a datagram to the client from the listening socket.
+    pub async fn send<T: IoBuf>(&self, data: T) -> BufResult<usize, T> {
+        self.shared.socket.send_to(data, self.peer).await
+    }
+
+    /// A handle sending datagrams to the client while the session is receiving.
+    pub fn sender(&self) -> UdpSender {
+        UdpSender {
+            shared: self.shared.clone(),
+            peer: self.peer,
+        }
+    }
+}
+
+/// Sends datagrams to the client of a [`UdpSession`].
+pub struct UdpSender {
+    shared: Rc<Shared>,
+    peer: SocketAddr,
+}
+
+impl UdpSender {
+    /// Send a datagram to the client from the listening socket.
+    pub async fn send<T: IoBuf>(&self, data: T) -> BufResult<usize, T> {
+        self.shared.socket.send_to(data, self.peer).await
+    }
+}
+
+impl Drop for UdpSession {
+    fn drop(&mut self) {
+        self.shared.sessions.borrow_mut().remove(&self.peer);
+        self.shared.wake_if_done();
+    }
+}
+
+impl Shared {
+    fn done(&self) -> bool {
+        !self.listening.get() && self.sessions.borrow().is_empty()
+    }
+
+    fn wake_if_done(&self) {
+        if self.done() {
+            if let Some(waker) = self.receiver.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn dispatch(
+        self: &Rc<Self>,
+        peer: SocketAddr,
+        data: Bytes,
+        incoming: &mpsc::UnboundedSender<UdpSession>,
+    ) {
+        let mut sessions = self.sessions.borrow_mut();
+        if let Some(tx) = sessions.get_mut(&peer) {
+            if tx.try_send(data).is_err() {
+                debug!("udp session of {peer} is full, datagram dropped");
+            }
+            return;
+        }
+        if !self.listening.get() {
+            return;
+        }
+        if sessions.len() >= self.max_sessions {
+            debug!("udp session limit reached, datagram of {peer} dropped");
+            return;
+        }
+        let (mut tx, rx) = mpsc::channel(SESSION_QUEUE_SIZE);
+        let _ = tx.try_send(data);
+        sessions.insert(peer, tx);
+        drop(sessions);
+        // The session unregisters itself when dropped if the listener is gone.
+        let _ = incoming.unbounded_send(UdpSession {
+            shared: self.clone(),
+            peer,
+            rx,
+        });
+    }
+}
+
+async fn receive(shared: Rc<Shared>, incoming: mpsc::UnboundedSender<UdpSession>) {
+    let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    loop {
+        let done = poll_fn(|cx| {
+            if shared.done() {
+                return Poll::Ready(());
+            }
+            shared.receiver.set(Some(cx.waker().clone()));
+            Poll::Pending
+        });
+        let (result, b) = monoio::select! {
+            received = shared.socket.recv_from(buf) => received,
+            _ = done => return,
+        };
+        buf = b;
+        match result {
+            Ok((n, peer)) => shared.dispatch(peer, Bytes::copy_from_slice(&buf[..n]), &incoming),
+            // Errors like ICMP unreachable reports concern a single peer, keep serving the others.
+            Err(e) => debug!("udp socket receive failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    async fn client(listener: SocketAddr, data: &'static [u8]) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.send_to(data, listener).await.0.unwrap();
+        socket
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn max_sessions() {
+        let mut listener = UdpListener::bind("127.0.0.1:0".parse().unwrap(), 1).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let first = client(addr, b"one").await;
+        let mut session = listener.accept().await.unwrap();
+        assert_eq!(session.peer_addr(), first.local_addr().unwrap());
+        assert_eq!(&session.recv().await.unwrap()[..], b"one");
+
+        // The datagram of a second client is dropped while the first session lasts.
+        let second = client(addr, b"two").await;
+        let accept = monoio::time::timeout(Duration::from_millis(100), listener.accept());
+        assert!(accept.await.is_err());
+        drop(session);
+
+        second.send_to(&b"three"[..], addr).await.0.unwrap();
+        let mut session = listener.accept().await.unwrap();
+        assert_eq!(session.peer_addr(), second.local_addr().unwrap());
+        assert_eq!(&session.recv().await.unwrap()[..], b"three");
+    }
+}
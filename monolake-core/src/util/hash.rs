@@ -1,8 +1,8 @@
 use sha2::{Digest, Sha256};
 
-pub fn sha256(token: &str) -> String {
+pub fn sha256(data: impl AsRef<[u8]>) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(token.as_bytes());
+    hasher.update(data.as_ref());
     let result = hasher.finalize();
     hex::encode(result)
 }
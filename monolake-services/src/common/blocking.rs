@@ -0,0 +1,77 @@
+//! A small process wide thread pool for blocking calls of services.
+//!
+//! Workers run a single threaded runtime each, so calls which block the thread, such as name
+//! resolution through the system resolver or password hashing, are sent to this pool with
+//! [`run`] and their results awaited instead. The queue is bounded: when it is full, [`run`] fails
+//! fast rather than queueing unbounded work.
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+};
+
+use futures::channel::oneshot;
+
+const THREADS: usize = 4;
+const QUEUE_SIZE: usize = 1024;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+fn pool() -> &'static SyncSender<Job> {
+    static POOL: OnceLock<SyncSender<Job>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        let (tx, rx) = sync_channel::<Job>(QUEUE_SIZE);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..THREADS {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("monolake-blocking-{i}"))
+                .spawn(move || work(&rx))
+                .expect("spawn blocking thread");
+        }
+        tx
+    })
+}
+
+fn work(rx: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match rx.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // A panicking job drops its result sender, which its caller sees as a failure.
+        let _ = catch_unwind(AssertUnwindSafe(job));
+    }
+}
+
+/// Run `f` on the blocking pool and wait for its result, `None` when the queue is full or `f`
+/// panicked.
+pub(crate) async fn run<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool()
+        .try_send(Box::new(move || {
+            let _ = tx.send(f());
+        }))
+        .ok()?;
+    rx.await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[monoio::test]
+    async fn results() {
+        assert_eq!(run(|| 1 + 1).await, Some(2));
+        assert_eq!(run(|| -> u8 { panic!("job") }).await, None);
+        // The pool survives panicking jobs.
+        assert_eq!(run(|| "done").await, Some("done"));
+    }
+}
@@ -0,0 +1,401 @@
+//! IP address allow and deny lists.
+//!
+//! # Key Components
+//!
+//! - [`IpNet`]: An IPv4 or IPv6 network in CIDR notation, such as `10.0.0.0/8`. A bare address is a
+//!   network of this single address.
+//! - [`IpSet`]: A set of networks matched with a binary prefix trie, in time proportional to the
+//!   address length rather than to the number of networks.
+//! - [`IpFilterConfig`] and [`IpFilter`]: Allow and deny lists.
+//! - [`IpFilterService`]: Filters connections by their peer address, right after they are accepted
+//!   and before any byte is read from them, in particular before PROXY protocol headers and TLS
+//!   handshakes.
+//!
+//! # Behavior
+//!
+//! - Deny lists take precedence over allow lists.
+//! - An empty allow list allows all the addresses which are not denied.
+//! - IPv4-mapped IPv6 addresses are matched as IPv4 addresses.
+//! - Connections of Unix sockets have no address and are not filtered.
+//! - Filters are built from the server configuration, configuration reloads replace them.
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::Arc,
+};
+
+use monolake_core::{context::PeerAddr, listener::AcceptedAddr, AnyError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, ParamRef, Service,
+};
+use tracing::debug;
+
+/// An IP network in CIDR notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid ip network {0:?}")]
+pub struct IpNetParseError(String);
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let addr = addr.to_canonical();
+        if prefix_len > max_prefix_len(addr) {
+            return None;
+        }
+        // Clear the host bits.
+        let addr = match addr {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+            }
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+            }
+        };
+        Some(Self { addr, prefix_len })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        IpNet::new(ip, self.prefix_len).is_some_and(|net| net == *self)
+    }
+}
+
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = IpNetParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || IpNetParseError(s.to_string());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => {
+                let addr: IpAddr = addr.parse().map_err(|_| err())?;
+                (addr, len.parse().map_err(|_| err())?)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| err())?;
+                (addr, max_prefix_len(addr.to_canonical()))
+            }
+        };
+        // Mapped IPv6 prefixes cover the IPv4 network they map.
+        let prefix_len = match addr {
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_some() => {
+                prefix_len.checked_sub(96).ok_or_else(err)?
+            }
+            _ => prefix_len,
+        };
+        IpNet::new(addr, prefix_len).ok_or_else(err)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Serialize for IpNet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Binary trie of the prefixes of a set of networks of one address family.
+#[derive(Debug, Clone, Default)]
+struct PrefixTrie {
+    // The root is the first node when the trie is not empty.
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TrieNode {
+    // Indexes of the children, 0 when absent as the root is no child.
+    children: [u32; 2],
+    // A network ends at this node.
+    terminal: bool,
+}
+
+impl PrefixTrie {
+    /// Insert the network of the `len` most significant bits of `bits`.
+    fn insert(&mut self, bits: u128, len: u8) {
+        if self.nodes.is_empty() {
+            self.nodes.push(TrieNode::default());
+        }
+        let mut node = 0;
+        for i in 0..len {
+            if self.nodes[node].terminal {
+                // A shorter prefix already covers the network.
+                return;
+            }
+            let bit = (bits >> (127 - i)) as usize & 1;
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    self.nodes.push(TrieNode::default());
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        self.nodes[node].terminal = true;
+        // Longer prefixes are now covered.
+        self.nodes[node].children = [0, 0];
+    }
+
+    fn contains(&self, bits: u128, len: u8) -> bool {
+        let Some(mut node) = self.nodes.first() else {
+            return false;
+        };
+        for i in 0..len {
+            if node.terminal {
+                return true;
+            }
+            let bit = (bits >> (127 - i)) as usize & 1;
+            match node.children[bit] {
+                0 => return false,
+                child => node = &self.nodes[child as usize],
+            }
+        }
+        node.terminal
+    }
+}
+
+/// A set of IP networks.
+#[derive(Debug, Clone, Default)]
+pub struct IpSet {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+}
+
+impl IpSet {
+    pub fn insert(&mut self, net: IpNet) {
+        match net.addr {
+            IpAddr::V4(v4) => self
+                .v4
+                .insert((u32::from(v4) as u128) << 96, net.prefix_len),
+            IpAddr::V6(v6) => self.v6.insert(u128::from(v6), net.prefix_len),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => self.v4.contains((u32::from(v4) as u128) << 96, 32),
+            IpAddr::V6(v6) => self.v6.contains(u128::from(v6), 128),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.nodes.is_empty() && self.v6.nodes.is_empty()
+    }
+}
+
+impl<'a> FromIterator<&'a IpNet> for IpSet {
+    fn from_iter<I: IntoIterator<Item = &'a IpNet>>(iter: I) -> Self {
+        let mut set = IpSet::default();
+        for net in iter {
+            set.insert(*net);
+        }
+        set
+    }
+}
+
+/// Allow and deny lists of IP networks.
+///
+/// ```toml
+/// ip_filter = { allow = ["10.0.0.0/8", "2001:db8::/32"], deny = ["10.0.0.1"] }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpFilterConfig {
+    /// Networks allowed, all the networks when empty.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Networks denied, even when allowed.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+/// Matcher of the allow and deny lists of an [`IpFilterConfig`].
+#[derive(Debug, Clone)]
+pub struct IpFilter {
+    allow: IpSet,
+    deny: IpSet,
+}
+
+impl IpFilter {
+    pub fn new(config: &IpFilterConfig) -> Self {
+        Self {
+            allow: config.allow.iter().collect(),
+            deny: config.deny.iter().collect(),
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.contains(ip) && (self.allow.is_empty() || self.allow.contains(ip))
+    }
+
+    /// Whether addresses which are unknown are allowed, which they are unless only some
+    /// networks are allowed.
+    pub fn allows_unknown(&self) -> bool {
+        self.allow.is_empty()
+    }
+}
+
+/// Service closing the connections of peers denied by an [`IpFilter`].
+///
+/// Denied connections are closed without a response and complete with `None`.
+pub struct IpFilterService<T> {
+    inner: T,
+    filter: Option<Arc<IpFilter>>,
+}
+
+impl<S, CX, T> Service<(S, CX)> for IpFilterService<T>
+where
+    T: Service<(S, CX)>,
+    T::Error: Into<AnyError>,
+    CX: ParamRef<PeerAddr>,
+{
+    type Response = Option<T::Response>;
+    type Error = AnyError;
+
+    async fn call(&self, (stream, ctx): (S, CX)) -> Result<Self::Response, Self::Error> {
+        if let Some(filter) = &self.filter {
+            if let AcceptedAddr::Tcp(addr) | AcceptedAddr::Udp(addr) =
+                &ParamRef::<PeerAddr>::param_ref(&ctx).0
+            {
+                if !filter.allows(addr.ip()) {
+                    debug!("connection from {addr} denied");
+                    return Ok(None);
+                }
+            }
+        }
+        self.inner
+            .call((stream, ctx))
+            .await
+            .map(Some)
+            .map_err(Into::into)
+    }
+}
+
+pub struct IpFilterServiceFactory<F> {
+    inner: F,
+    filter: Option<Arc<IpFilter>>,
+}
+
+impl<F> IpFilterService<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = IpFilterServiceFactory<F>>
+    where
+        C: Param<Option<IpFilterConfig>>,
+    {
+        layer_fn(|c: &C, inner| IpFilterServiceFactory {
+            inner,
+            filter: c.param().map(|config| Arc::new(IpFilter::new(&config))),
+        })
+    }
+}
+
+impl<F: MakeService> MakeService for IpFilterServiceFactory<F> {
+    type Service = IpFilterService<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(IpFilterService {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            filter: self.filter.clone(),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for IpFilterServiceFactory<F> {
+    type Service = IpFilterService<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(IpFilterService {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            filter: self.filter.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_networks() {
+        let net: IpNet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert_eq!("::ffff:10.0.0.0/104".parse::<IpNet>().unwrap(), net);
+        assert_eq!("192.168.0.1".parse::<IpNet>().unwrap().prefix_len(), 32);
+        assert_eq!("2001:db8::/32".parse::<IpNet>().unwrap().prefix_len(), 32);
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn filter_addresses() {
+        let config = IpFilterConfig {
+            allow: ["10.0.0.0/8", "192.168.1.0/24", "2001:db8::/32"]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect(),
+            deny: ["10.1.0.0/16", "10.2.3.4"]
+                .iter()
+                .map(|s| s.parse().unwrap())
+                .collect(),
+        };
+        let filter = IpFilter::new(&config);
+        let allows = |s: &str| filter.allows(s.parse().unwrap());
+        assert!(allows("10.0.0.1"));
+        assert!(allows("::ffff:10.0.0.1"));
+        assert!(allows("192.168.1.200"));
+        assert!(allows("2001:db8::1"));
+        assert!(!allows("10.1.2.3"));
+        assert!(!allows("10.2.3.4"));
+        assert!(allows("10.2.3.5"));
+        assert!(!allows("192.168.2.1"));
+        assert!(!allows("2001:db9::1"));
+        assert!(!filter.allows_unknown());
+
+        let all = IpFilter::new(&IpFilterConfig {
+            allow: Vec::new(),
+            deny: vec!["0.0.0.0/0".parse().unwrap()],
+        });
+        assert!(!all.allows("1.2.3.4".parse().unwrap()));
+        assert!(all.allows("::1".parse().unwrap()));
+    }
+}
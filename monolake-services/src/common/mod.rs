@@ -1,11 +1,15 @@
-//! Generic services for panic catching, context management, and timeouts.
+//! Generic services for panic catching, context management, timeouts, rate limiting and IP
+//! filtering.
+pub(crate) mod blocking;
 pub mod cancel;
 pub mod context;
 pub mod delay;
 pub mod detect;
 pub mod erase;
+pub mod ip_filter;
 pub mod map;
 pub mod panic;
+pub mod rate_limit;
 pub mod selector;
 pub mod timeout;
 
@@ -0,0 +1,363 @@
+//! Token bucket rate limiting shared by the HTTP and Thrift rate limit handlers.
+//!
+//! # Key Components
+//!
+//! - [`RateLimitConfig`]: Rate, burst, key and scope of a limit.
+//! - [`RateLimit`]: A configured limit, creating the [`RateLimiter`] of each worker.
+//! - [`RateLimiter`]: Decides whether a request fits in the budget of its key.
+//!
+//! # Features
+//!
+//! - Budgets follow the generic cell rate algorithm, an exact token bucket which keeps a single
+//!   timestamp per key: `burst` requests are allowed at once, then `rate` requests per second
+//! - Requests are keyed by route, client IP address or header value
+//! - Budgets of a [`Worker`](RateLimitScope::Worker) scope are enforced by every worker on its own,
+//!   without synchronization, while workers share the budgets of a
+//!   [`Global`](RateLimitScope::Global) scope through atomics
+//! - Budgets are kept when a service is rebuilt with the same limit
+//! - The budgets of idle keys are full, they are dropped once many keys are tracked
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    net::IpAddr,
+    num::NonZeroU32,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    listener::AcceptedAddr,
+};
+use serde::{Deserialize, Serialize};
+use service_async::{ParamMaybeRef, ParamRef};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+// Idle keys are dropped when the number of tracked keys reaches this threshold.
+const SWEEP_THRESHOLD: usize = 16384;
+
+/// Configuration of a rate limit.
+///
+/// ```toml
+/// rate_limit = { rate = 100, burst = 200, key = { type = "client_ip" }, scope = "global" }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Requests per second once the burst is spent.
+    pub rate: NonZeroU32,
+    /// Requests allowed at once, defaults to `rate`.
+    #[serde(default)]
+    pub burst: Option<NonZeroU32>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+    #[serde(default)]
+    pub scope: RateLimitScope,
+}
+
+impl RateLimitConfig {
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.rate).get()
+    }
+}
+
+/// What requests share a budget.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum RateLimitKey {
+    /// A single budget for all the requests of the route, or of the server.
+    #[default]
+    Route,
+    /// A budget per client IP address, the source address of the PROXY protocol header when
+    /// present. Clients of Unix sockets share a budget.
+    ClientIp,
+    /// A budget per value of a header, requests without the header are not limited.
+    Header(String),
+}
+
+/// Where budgets are kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitScope {
+    /// Every worker has its own budgets, the rate of a server is the rate of the limit times its
+    /// number of workers.
+    #[default]
+    Worker,
+    /// Workers share the budgets.
+    Global,
+}
+
+/// Identifies the budget of a request.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BucketKey {
+    All,
+    Ip(IpAddr),
+    Value(Box<[u8]>),
+}
+
+impl BucketKey {
+    /// Key of the client of a request, identified by its address given by the PROXY protocol or
+    /// else by its peer address.
+    pub fn client<CX>(ctx: &CX) -> Self
+    where
+        CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+    {
+        let peer_addr = ParamRef::<PeerAddr>::param_ref(ctx);
+        let remote_addr = ParamMaybeRef::<Option<RemoteAddr>>::param_maybe_ref(ctx);
+        let addr = remote_addr
+            .and_then(|addr| addr.as_ref().map(|x| &x.0))
+            .unwrap_or(&peer_addr.0);
+        match addr {
+            AcceptedAddr::Tcp(addr) | AcceptedAddr::Udp(addr) => Self::Ip(addr.ip()),
+            #[cfg(unix)]
+            AcceptedAddr::Unix(_) => Self::All,
+        }
+    }
+}
+
+/// Outcome of checking a request against a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    /// Requests allowed at once.
+    pub limit: u32,
+    /// Requests left in the budget.
+    pub remaining: u32,
+    /// Time until the budget is full again.
+    pub reset: Duration,
+    /// Time to wait before retrying a rejected request.
+    pub retry_after: Option<Duration>,
+}
+
+impl Decision {
+    pub fn allowed(&self) -> bool {
+        self.retry_after.is_none()
+    }
+}
+
+/// Generic cell rate algorithm: every request moves the theoretical arrival time of the next
+/// request one emission interval ahead, and a request is allowed while this time is at most
+/// `burst` intervals ahead of now.
+#[derive(Debug, Clone, Copy)]
+struct Gcra {
+    interval: u64,
+    burst: u32,
+}
+
+impl Gcra {
+    fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            interval: NANOS_PER_SEC / config.rate.get() as u64,
+            burst: config.burst(),
+        }
+    }
+
+    /// Returns the decision and the new theoretical arrival time.
+    fn check(&self, tat: u64, now: u64) -> (Decision, u64) {
+        let tat = tat.max(now);
+        let new_tat = tat + self.interval;
+        let capacity = self.interval * self.burst as u64;
+        if new_tat - now > capacity {
+            let decision = Decision {
+                limit: self.burst,
+                remaining: 0,
+                reset: Duration::from_nanos(tat - now),
+                retry_after: Some(Duration::from_nanos(new_tat - now - capacity)),
+            };
+            return (decision, tat);
+        }
+        let decision = Decision {
+            limit: self.burst,
+            remaining: ((capacity - (new_tat - now)) / self.interval) as u32,
+            reset: Duration::from_nanos(new_tat - now),
+            retry_after: None,
+        };
+        (decision, new_tat)
+    }
+}
+
+/// Theoretical arrival times of the keys of a limit, in nanoseconds since its epoch.
+#[derive(Debug, Default)]
+struct Arrivals {
+    tats: HashMap<BucketKey, u64>,
+    sweep_at: usize,
+}
+
+impl Arrivals {
+    fn check(&mut self, gcra: &Gcra, key: &BucketKey, now: u64) -> Decision {
+        let tat = self.tats.get(key).copied().unwrap_or(0);
+        let (decision, tat) = gcra.check(tat, now);
+        match self.tats.get_mut(key) {
+            Some(entry) => *entry = tat,
+            None => {
+                if self.tats.len() >= self.sweep_at.max(SWEEP_THRESHOLD) {
+                    // Keys whose budget is full are the same as untracked keys.
+                    self.tats.retain(|_, tat| *tat > now);
+                    self.sweep_at = self.tats.len() * 2;
+                }
+                self.tats.insert(key.clone(), tat);
+            }
+        }
+        decision
+    }
+}
+
+#[derive(Debug)]
+struct WorkerBudget {
+    epoch: Instant,
+    arrivals: RefCell<Arrivals>,
+}
+
+#[derive(Debug)]
+struct SharedBudget {
+    epoch: Instant,
+    all: AtomicU64,
+    arrivals: Mutex<Arrivals>,
+}
+
+impl SharedBudget {
+    fn check(&self, gcra: &Gcra, key: &BucketKey) -> Decision {
+        let now = self.epoch.elapsed().as_nanos() as u64;
+        if *key != BucketKey::All {
+            let mut arrivals = self.arrivals.lock().unwrap_or_else(|e| e.into_inner());
+            return arrivals.check(gcra, key, now);
+        }
+        let mut tat = self.all.load(Ordering::Relaxed);
+        loop {
+            let (decision, new_tat) = gcra.check(tat, now);
+            if new_tat == tat {
+                return decision;
+            }
+            match self
+                .all
+                .compare_exchange_weak(tat, new_tat, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return decision,
+                Err(current) => tat = current,
+            }
+        }
+    }
+}
+
+/// A configured rate limit, shared by the workers of a server.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    config: RateLimitConfig,
+    shared: Option<Arc<SharedBudget>>,
+}
+
+impl RateLimit {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let shared = match config.scope {
+            RateLimitScope::Worker => None,
+            RateLimitScope::Global => Some(Arc::new(SharedBudget {
+                epoch: Instant::now(),
+                all: AtomicU64::new(0),
+                arrivals: Default::default(),
+            })),
+        };
+        Self { config, shared }
+    }
+
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Create the limiter of a worker, keeping the budgets of `old` when it enforces the same
+    /// limit.
+    pub fn limiter(&self, old: Option<&RateLimiter>) -> RateLimiter {
+        if let Some(old) = old.filter(|old| old.config == self.config) {
+            return old.clone();
+        }
+        let budget = match &self.shared {
+            Some(shared) => Budget::Global(shared.clone()),
+            None => Budget::Worker(Rc::new(WorkerBudget {
+                epoch: Instant::now(),
+                arrivals: Default::default(),
+            })),
+        };
+        RateLimiter {
+            config: self.config.clone(),
+            gcra: Gcra::new(&self.config),
+            budget,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Budget {
+    Worker(Rc<WorkerBudget>),
+    Global(Arc<SharedBudget>),
+}
+
+/// The rate limit of a worker.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    gcra: Gcra,
+    budget: Budget,
+}
+
+impl RateLimiter {
+    pub fn config(&self) -> &RateLimitConfig {
+        &self.config
+    }
+
+    /// Take a request from the budget of `key`.
+    pub fn check(&self, key: &BucketKey) -> Decision {
+        match &self.budget {
+            Budget::Worker(budget) => {
+                let now = budget.epoch.elapsed().as_nanos() as u64;
+                budget.arrivals.borrow_mut().check(&self.gcra, key, now)
+            }
+            Budget::Global(budget) => budget.check(&self.gcra, key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gcra(rate: u32, burst: u32) -> Gcra {
+        Gcra::new(&RateLimitConfig {
+            rate: NonZeroU32::new(rate).unwrap(),
+            burst: NonZeroU32::new(burst),
+            key: RateLimitKey::Route,
+            scope: RateLimitScope::Worker,
+        })
+    }
+
+    #[test]
+    fn allows_burst_then_rate() {
+        let gcra = gcra(10, 3);
+        let mut arrivals = Arrivals::default();
+        let key = BucketKey::All;
+        let remaining: Vec<_> = (0..3)
+            .map(|_| arrivals.check(&gcra, &key, 0).remaining)
+            .collect();
+        assert_eq!(remaining, [2, 1, 0]);
+
+        let rejected = arrivals.check(&gcra, &key, 0);
+        assert_eq!(rejected.retry_after, Some(Duration::from_millis(100)));
+        assert_eq!(rejected.reset, Duration::from_millis(300));
+
+        // A token is back after an interval, the budget is full after the burst.
+        assert!(arrivals.check(&gcra, &key, 100_000_000).allowed());
+        assert!(!arrivals.check(&gcra, &key, 100_000_000).allowed());
+        assert_eq!(arrivals.check(&gcra, &key, 1_000_000_000).remaining, 2);
+    }
+
+    #[test]
+    fn keys_have_their_own_budget() {
+        let gcra = gcra(1, 1);
+        let mut arrivals = Arrivals::default();
+        let a = BucketKey::Ip([127, 0, 0, 1].into());
+        let b = BucketKey::Value(b"key".to_vec().into());
+        assert!(arrivals.check(&gcra, &a, 0).allowed());
+        assert!(!arrivals.check(&gcra, &a, 0).allowed());
+        assert!(arrivals.check(&gcra, &b, 0).allowed());
+    }
+}
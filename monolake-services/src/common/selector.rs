@@ -293,6 +293,18 @@ impl<T> LoadBalancer<T> {
     }
 }
 
+impl<T> LoadBalancer<T> {
+    /// All endpoints which may be selected, in configuration order.
+    pub fn endpoints(&self) -> &[T] {
+        match self {
+            LoadBalancer::Random(random_selector) => &random_selector.0,
+            LoadBalancer::WeightedRandom(wr_selector) => &wr_selector.collection,
+            LoadBalancer::RoundRobin(round_robin_selector) => &round_robin_selector.collection,
+            LoadBalancer::Identity(identity_selector) => std::slice::from_ref(&identity_selector.0),
+        }
+    }
+}
+
 impl<T, A: ?Sized> Select<A> for LoadBalancer<T> {
     type Output<'a>
         = &'a T
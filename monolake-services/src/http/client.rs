@@ -0,0 +1,158 @@
+//! HTTP client of the handlers calling external services.
+//!
+//! Key sets, identity providers, authorization and rate limit services and health checks are
+//! reached through [`HttpClient`], which picks the plain or TLS connector from the scheme of the
+//! request uri, bounds the whole exchange with a timeout and collects the response body up to a
+//! size limit.
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use http::{header, HeaderValue, Request, Response, Version};
+use monoio_http::common::{
+    body::{Body, HttpBody},
+    error::HttpError,
+};
+#[cfg(feature = "tls")]
+use monoio_transports::connectors::TcpTlsAddr;
+#[cfg(feature = "acme")]
+use monoio_transports::connectors::{TcpConnector, TlsConnector};
+use monoio_transports::{connectors::Connector, http::HttpConnection, TransportError};
+
+#[cfg(feature = "tls")]
+use super::handlers::upstream::PooledHttpsConnector;
+use super::handlers::upstream::{resolve, PooledHttpConnector};
+
+/// Response bodies larger than this are rejected.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Failures of the calls to external services.
+#[derive(thiserror::Error, Debug)]
+pub enum ClientError {
+    #[error("invalid uri")]
+    Uri,
+    #[error("connect error: {0:?}")]
+    Connect(#[from] TransportError),
+    #[error("request error: {0:?}")]
+    Request(#[from] HttpError),
+    #[error("response body larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("timeout")]
+    Timeout,
+}
+
+/// Send a request on a pooled connection, adapting it to the negotiated version.
+macro_rules! send_request {
+    ($conn:expr, $request:expr) => {{
+        let mut conn = $conn;
+        let mut request = $request;
+        match conn {
+            HttpConnection::Http2(_) => {
+                *request.version_mut() = Version::HTTP_2;
+                request.headers_mut().remove(header::HOST);
+            }
+            HttpConnection::Http1(_) => {
+                *request.version_mut() = Version::HTTP_11;
+                if !request.headers().contains_key(header::HOST) {
+                    let host = request
+                        .uri()
+                        .authority()
+                        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+                        .ok_or(ClientError::Uri)?;
+                    request.headers_mut().insert(header::HOST, host);
+                }
+            }
+        }
+        conn.send_request(request).await.0?
+    }};
+}
+
+/// Pooled HTTP client of a worker.
+pub(crate) struct HttpClient {
+    connector: PooledHttpConnector,
+    #[cfg(feature = "tls")]
+    tls_connector: PooledHttpsConnector,
+    timeout: Duration,
+}
+
+impl HttpClient {
+    /// Client speaking HTTP/1.1, or HTTP/2 when a TLS server selects it with ALPN.
+    pub(crate) fn new(timeout: Duration) -> Self {
+        Self {
+            connector: PooledHttpConnector::default(),
+            #[cfg(feature = "tls")]
+            tls_connector: PooledHttpsConnector::default(),
+            timeout,
+        }
+    }
+
+    /// Client always speaking HTTP/2, as gRPC requires.
+    pub(crate) fn http2(timeout: Duration) -> Self {
+        Self {
+            connector: PooledHttpConnector::build_tcp_http2_only(),
+            #[cfg(feature = "tls")]
+            tls_connector: PooledHttpsConnector::build_tls_http2_only(),
+            timeout,
+        }
+    }
+
+    /// Client verifying TLS servers with `tls` instead of the default settings, e.g. to trust a
+    /// private CA.
+    #[cfg(feature = "acme")]
+    pub(crate) fn with_tls(timeout: Duration, tls: rustls_client::ClientConfig) -> Self {
+        Self {
+            connector: PooledHttpConnector::default(),
+            tls_connector: PooledHttpsConnector::new(TlsConnector::new(
+                TcpConnector::default(),
+                tls.into(),
+            )),
+            timeout,
+        }
+    }
+
+    /// Send `request` to the authority of its uri and collect the response.
+    ///
+    /// The `Host` header is set from the uri on HTTP/1.1 connections and dropped on HTTP/2 ones.
+    pub(crate) async fn send(
+        &self,
+        request: Request<HttpBody>,
+    ) -> Result<Response<Bytes>, ClientError> {
+        monoio::time::timeout(self.timeout, self.exchange(request))
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    async fn exchange(&self, request: Request<HttpBody>) -> Result<Response<Bytes>, ClientError> {
+        let uri = request.uri().clone();
+        #[cfg(feature = "tls")]
+        let response = if uri.scheme() == Some(&http::uri::Scheme::HTTPS) {
+            let key = TcpTlsAddr::try_from(&uri).map_err(|_| ClientError::Uri)?;
+            send_request!(self.tls_connector.connect(key).await?, request)
+        } else {
+            let key = resolve(&uri).ok_or(ClientError::Uri)?;
+            send_request!(self.connector.connect(key).await?, request)
+        };
+        #[cfg(not(feature = "tls"))]
+        let response = {
+            let key = resolve(&uri).ok_or(ClientError::Uri)?;
+            send_request!(self.connector.connect(key).await?, request)
+        };
+
+        let (parts, mut body) = response.into_parts();
+        let content_length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+        if content_length.is_some_and(|len| len > MAX_RESPONSE_SIZE) {
+            return Err(ClientError::TooLarge(MAX_RESPONSE_SIZE));
+        }
+        let mut data = BytesMut::with_capacity(content_length.unwrap_or_default());
+        while let Some(chunk) = body.next_data().await {
+            let chunk = chunk?;
+            if data.len() + chunk.len() > MAX_RESPONSE_SIZE {
+                return Err(ClientError::TooLarge(MAX_RESPONSE_SIZE));
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Response::from_parts(parts, data.freeze()))
+    }
+}
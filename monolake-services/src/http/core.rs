@@ -15,10 +15,23 @@
 //! # Features
 //!
 //! - Support for HTTP/1, HTTP/1.1, and HTTP/2 protocols
+//! - Protocol upgrades (e.g. WebSocket), CONNECT tunnels and WebSockets over HTTP/2 extended
+//!   CONNECT, see [`upgrade`](crate::http::upgrade)
+//! - Response trailers, such as the `grpc-status` of gRPC calls, are forwarded from HTTP/2
+//!   upstreams to HTTP/2 clients, and to HTTP/1.1 clients at the end of chunked bodies. Chunked
+//!   requests are read with their trailers, see [`h1`](crate::http::h1)
 //! - Composable design allowing a stack of `HttpHandler` implementations
 //! - Automatic protocol detection when combined with `H2Detect`
 //! - Efficient handling of concurrent requests using asynchronous I/O
 //! - Configurable timeout settings for different stages of request processing
+//! - Configurable HTTP/2 flow control windows and limits, keepalive PINGs and GOAWAY on idle
+//!   connections, see [`Http2Settings`]
+//! - HTTP/2 connections flooding the server with resets, pending streams, CONTINUATION, SETTINGS or
+//!   PING frames are terminated, see [`flood`](crate::http::flood). Streams reset by the client are
+//!   no longer handled.
+//! - Requests exceeding the URI, header or body limits of the server or route are answered with
+//!   `414`, `431` or `413`, see [`limits`](crate::http::limits). Request bodies reach the handler
+//!   chain as a [`LimitedBody`]
 //! - Integration with `service_async` for easy composition in service stacks
 //! - Automatic response encoding and error handling
 //!
@@ -63,23 +76,31 @@
 //! - Implements connection keep-alive for HTTP/1.1 to reduce connection overhead
 //! - Supports HTTP/2 multiplexing for efficient handling of concurrent requests
 //! - Automatic protocol detection allows for optimized handling based on the client's capabilities
-use std::{convert::Infallible, fmt::Debug, pin::Pin, time::Duration};
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    fmt::Debug,
+    future::poll_fn,
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use certain_map::{Attach, Fork};
 use futures::{stream::FuturesUnordered, StreamExt};
 use http::StatusCode;
-use monoio::io::{sink::SinkExt, stream::Stream, AsyncReadRent, AsyncWriteRent, Split, Splitable};
+use monoio::io::{
+    stream::Stream, AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Split, Splitable,
+};
 use monoio_http::{
     common::{
         body::{Body, HttpBody, StreamHint},
         response::Response,
     },
-    h1::codec::{
-        decoder::{FillPayload, RequestDecoder},
-        encoder::GenericEncoder,
-    },
-    h2::server::SendResponse,
+    h1::codec::decoder::FillPayload,
+    h2::{self, server::SendResponse, RecvStream},
 };
 use monolake_core::{
     context::PeerAddr,
@@ -92,7 +113,18 @@ use service_async::{
 };
 use tracing::{error, info, warn};
 
-use super::{generate_response, util::AccompanyPair};
+use super::{
+    flood::{FloodGuard, Http2Flood, Http2FloodStats},
+    generate_response,
+    h1::ResponseEncoder,
+    limits::{
+        BodyLimit, LimitExceeded, LimitedBody, LimitedRequestDecoder, RequestDecodeError,
+        RequestLimitConfig, RequestLimiter,
+    },
+    upgrade::{self, OnUpgrade},
+    util::{self, AccompanyPair},
+    Http2Settings,
+};
 
 /// Core HTTP service handler supporting both HTTP/1.1 and HTTP/2 protocols.
 ///
@@ -105,16 +137,32 @@ use super::{generate_response, util::AccompanyPair};
 pub struct HttpCoreService<H> {
     handler_chain: H,
     http_timeout: HttpServerTimeout,
+    h2_settings: Http2Settings,
+    flood_stats: Arc<Http2FloodStats>,
+    limiter: Arc<RequestLimiter>,
 }
 
 impl<H> HttpCoreService<H> {
-    pub fn new(handler_chain: H, http_timeout: HttpServerTimeout) -> Self {
+    pub fn new(
+        handler_chain: H,
+        http_timeout: HttpServerTimeout,
+        h2_settings: Http2Settings,
+        request_limits: RequestLimitConfig,
+    ) -> Self {
         HttpCoreService {
             handler_chain,
             http_timeout,
+            h2_settings,
+            flood_stats: Default::default(),
+            limiter: RequestLimiter::new(&request_limits),
         }
     }
 
+    /// Counters of the HTTP/2 connections terminated for flooding the server.
+    pub fn flood_stats(&self) -> &Http2FloodStats {
+        &self.flood_stats
+    }
+
     async fn h1_svc<S, CXIn, CXStore, CXState, Err>(&self, stream: S, ctx: CXIn)
     where
         CXIn: ParamRef<PeerAddr> + Fork<Store = CXStore, State = CXState>,
@@ -122,19 +170,19 @@ impl<H> HttpCoreService<H> {
         for<'a> CXState: Attach<CXStore>,
         for<'a> H: HttpHandler<
             <CXState as Attach<CXStore>>::Hdr<'a>,
-            HttpBody,
+            LimitedBody,
             Body = HttpBody,
             Error = Err,
         >,
         Err: Into<AnyError> + Debug,
         S: Split + AsyncReadRent + AsyncWriteRent,
     {
-        let (reader, writer) = stream.into_split();
-        let mut decoder = RequestDecoder::new(reader);
-        let mut encoder = GenericEncoder::new(writer);
+        let (mut reader, mut writer) = stream.into_split();
+        let mut decoder = LimitedRequestDecoder::new(&mut reader, *self.limiter.limits());
+        let mut encoder = ResponseEncoder::new(&mut writer);
         decoder.set_timeout(self.http_timeout.keepalive_timeout);
 
-        loop {
+        let upgraded = loop {
             // decode request with header timeout
             let decoded = match self.http_timeout.read_header_timeout {
                 Some(header_timeout) => {
@@ -145,7 +193,7 @@ impl<H> HttpCoreService<H> {
                                 "Connection {:?} decode http header timed out",
                                 ParamRef::<PeerAddr>::param_ref(&ctx),
                             );
-                            break;
+                            break None;
                         }
                     }
                 }
@@ -153,11 +201,15 @@ impl<H> HttpCoreService<H> {
             };
 
             let req = match decoded {
-                Some(Ok(req)) => HttpBody::request(req),
+                Some(Ok(req)) => HttpBody::request(req).map(LimitedBody::from),
+                Some(Err(RequestDecodeError::Limit(limit))) => {
+                    Self::h1_reject(&mut encoder, limit, &ctx).await;
+                    break None;
+                }
                 Some(Err(err)) => {
                     // decode error
                     warn!("decode request header failed: {err}");
-                    break;
+                    break None;
                 }
                 None => {
                     // EOF
@@ -165,10 +217,17 @@ impl<H> HttpCoreService<H> {
                         "Connection {:?} closed",
                         ParamRef::<PeerAddr>::param_ref(&ctx),
                     );
-                    break;
+                    break None;
                 }
             };
 
+            let body_limit = self.limiter.body_limit(req.uri().path());
+            if let Err(limit) = RequestLimiter::check_content_length(req.headers(), body_limit) {
+                Self::h1_reject(&mut encoder, limit, &ctx).await;
+                break None;
+            }
+            decoder.set_body_limit(body_limit);
+
             // fork ctx
             let (mut store, state) = ctx.fork();
             let forked_ctx = unsafe { state.attach(&mut store) };
@@ -180,15 +239,35 @@ impl<H> HttpCoreService<H> {
                 decoder.fill_payload(),
             );
             let res = unsafe { Pin::new_unchecked(&mut acc_fut) }.await;
+            // The body exceeded its limit while the handler chain was reading it.
+            if let Some(Err(RequestDecodeError::Limit(limit))) = acc_fut.accompany_output() {
+                let limit = *limit;
+                drop(acc_fut);
+                Self::h1_reject(&mut encoder, limit, &ctx).await;
+                break None;
+            }
             match res {
                 Ok((resp, should_cont)) => {
+                    // Switching protocols, or a successful CONNECT tunnel.
+                    let upgraded = (resp.status() == StatusCode::SWITCHING_PROTOCOLS
+                        || resp.status().is_success())
+                    .then(|| resp.extensions().get::<OnUpgrade>()?.take())
+                    .flatten();
+                    if let Some(upgraded) = upgraded {
+                        // The head is written after the request is read, see below.
+                        if let Err(e) = acc_fut.into_accompany().await {
+                            warn!("error when decode request body: {e}");
+                            break None;
+                        }
+                        break Some((upgraded, resp.into_parts().0));
+                    }
                     // 2. do these things simultaneously: read body and send + handle response
                     let mut f = acc_fut.replace(encoder.send_and_flush(resp));
                     match self.http_timeout.read_body_timeout {
                         None => {
                             if let Err(e) = unsafe { Pin::new_unchecked(&mut f) }.await {
                                 warn!("error when encode and write response: {e}");
-                                break;
+                                break None;
                             }
                         }
                         Some(body_timeout) => {
@@ -202,11 +281,11 @@ impl<H> HttpCoreService<H> {
                                         "Connection {:?} write timed out",
                                         ParamRef::<PeerAddr>::param_ref(&ctx),
                                     );
-                                    break;
+                                    break None;
                                 }
                                 Ok(Err(e)) => {
                                     warn!("error when encode and write response: {e}");
-                                    break;
+                                    break None;
                                 }
                                 _ => (),
                             }
@@ -214,11 +293,11 @@ impl<H> HttpCoreService<H> {
                     }
 
                     if !should_cont {
-                        break;
+                        break None;
                     }
                     if let Err(e) = f.into_accompany().await {
                         warn!("error when decode request body: {e}");
-                        break;
+                        break None;
                     }
                 }
                 Err(e) => {
@@ -233,20 +312,76 @@ impl<H> HttpCoreService<H> {
                     {
                         warn!("error when reply client: {e}");
                     }
-                    break;
+                    break None;
                 }
             }
+        };
+
+        drop((decoder, encoder));
+        // The connection switched protocols or became a tunnel, splice it with the upgraded
+        // upstream. The encoder is bypassed for the head since it always adds a content length.
+        if let Some((upgraded, head)) = upgraded {
+            if let Err(e) = writer.write_all(upgrade::encode_head(&head)).await.0 {
+                warn!("error when write switching protocols response: {e}");
+                return;
+            }
+            if let Err(e) = upgraded.splice(reader, writer).await {
+                info!(
+                    "Upgraded connection {:?} closed with error: {e}",
+                    ParamRef::<PeerAddr>::param_ref(&ctx),
+                );
+            }
         }
     }
 
+    /// Answer a request exceeding a limit, the connection is closed afterwards.
+    async fn h1_reject<W, CX>(encoder: &mut ResponseEncoder<W>, limit: LimitExceeded, ctx: &CX)
+    where
+        W: AsyncWriteRent,
+        CX: ParamRef<PeerAddr>,
+    {
+        info!(
+            "Connection {:?} request rejected: {limit}",
+            ParamRef::<PeerAddr>::param_ref(ctx),
+        );
+        if let Err(e) = encoder
+            .send_and_flush(generate_response::<HttpBody>(limit.status(), true))
+            .await
+        {
+            warn!("error when reply client: {e}");
+        }
+    }
+
+    fn h2_reject(response_handle: &mut SendResponse<Bytes>, limit: LimitExceeded) {
+        let (parts, _) = generate_response::<HttpBody>(limit.status(), false).into_parts();
+        let response = http::Response::from_parts(parts, ());
+        let _ = response_handle.send_response(response, true);
+    }
+
     async fn h2_process_response(
         response: Response<HttpBody>,
         mut response_handle: SendResponse<Bytes>,
     ) {
         let (mut parts, mut body) = response.into_parts();
         parts.headers.remove("connection");
+        // Tunnels and WebSockets are carried by the data of the stream.
+        let upgraded = parts
+            .extensions
+            .get::<OnUpgrade>()
+            .and_then(OnUpgrade::take);
+        if upgraded.is_some() {
+            parts.headers.remove(http::header::CONTENT_LENGTH);
+        }
         let response = http::Response::from_parts(parts, ());
 
+        if let Some(upgraded) = upgraded {
+            match response_handle.send_response(response, false) {
+                Ok(send_stream) => upgraded.send_h2(send_stream).await,
+                Err(e) => error!("H2 frontend response send fail {:?}", e),
+            }
+            return;
+        }
+
         match body.stream_hint() {
             StreamHint::None => {
                 if let Err(e) = response_handle.send_response(response, true) {
@@ -276,10 +411,21 @@ impl<H> HttpCoreService<H> {
                 };
 
                 while let Some(Ok(data)) = body.next_data().await {
-                    let _ = send_stream.send_data(data, false);
+                    // Stop reading the upstream once the client reset the stream.
+                    if !util::h2_send_data(&mut send_stream, data).await {
+                        return;
+                    }
                 }
 
-                let _ = send_stream.send_data(Bytes::new(), true);
+                // Trailers end the stream on their own, e.g. the grpc-status of gRPC calls.
+                match util::trailers(&mut body).await {
+                    Some(trailers) => {
+                        let _ = send_stream.send_trailers(trailers);
+                    }
+                    None => {
+                        let _ = send_stream.send_data(Bytes::new(), true);
+                    }
+                }
             }
         }
     }
@@ -291,17 +437,26 @@ impl<H> HttpCoreService<H> {
         for<'a> CXState: Attach<CXStore>,
         for<'a> H: HttpHandler<
             <CXState as Attach<CXStore>>::Hdr<'a>,
-            HttpBody,
+            LimitedBody,
             Body = HttpBody,
             Error = Err,
         >,
         Err: Into<AnyError> + Debug,
         S: Split + AsyncReadRent + AsyncWriteRent + Unpin + 'static,
     {
-        let mut connection = match monoio_http::h2::server::Builder::new()
-            .initial_window_size(1_000_000)
-            .max_concurrent_streams(1000)
-            .handshake::<S, Bytes>(stream)
+        let mut builder = h2::server::Builder::new();
+        self.h2_settings.apply_server(&mut builder);
+        let max_header_size =
+            u32::try_from(self.limiter.limits().max_header_size).unwrap_or(u32::MAX);
+        builder.max_header_list_size(
+            self.h2_settings
+                .max_header_list_size
+                .map_or(max_header_size, |size| size.min(max_header_size)),
+        );
+        let stream = FloodGuard::new(stream, self.h2_settings.flood, self.flood_stats.clone());
+        let connection = match builder
+            .enable_connect_protocol()
+            .handshake::<_, Bytes>(stream)
             .await
         {
             Ok(c) => {
@@ -320,45 +475,81 @@ impl<H> HttpCoreService<H> {
         let (tx, mut rx) = local_sync::mpsc::unbounded::channel();
         let mut backend_resp_stream = FuturesUnordered::new();
         let mut frontend_resp_stream = FuturesUnordered::new();
+        let activity = Rc::new(StreamActivity::new());
 
-        monoio::spawn(async move {
-            let tx = tx.clone();
-            while let Some(result) = connection.accept().await {
-                match tx.send(result) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Frontend Req send failed {e:?}");
-                        break;
-                    }
-                }
-            }
-        });
+        monoio::spawn(h2_accept(
+            connection,
+            tx,
+            self.h2_settings,
+            activity.clone(),
+            self.flood_stats.clone(),
+        ));
 
         loop {
             monoio::select! {
                  Some(Ok((request, response_handle))) = rx.recv() => {
+                        let mut response_handle = response_handle;
+                        let body_limit = self.limiter.body_limit(request.uri().path());
+                        let checked = self
+                            .limiter
+                            .check_head(request.uri(), request.headers())
+                            .and_then(|_| {
+                                RequestLimiter::check_content_length(request.headers(), body_limit)
+                            });
+                        if let Err(limit) = checked {
+                            info!("H2 request rejected: {limit}");
+                            Self::h2_reject(&mut response_handle, limit);
+                            activity.close();
+                            continue;
+                        }
                         let request = HttpBody::request(request);
+                        // Bodies with a limit are counted as the handler chain reads them.
+                        let (limit, exceeded) = match body_limit {
+                            Some(limit) if request.body().stream_hint() != StreamHint::None => {
+                                let (limit, exceeded) = BodyLimit::new(limit);
+                                (Some(limit), Some(exceeded))
+                            }
+                            _ => (None, None),
+                        };
+                        let request = request.map(|body| LimitedBody::new(body, limit));
                         // fork ctx
                         let (mut store, state) = ctx.fork();
                         backend_resp_stream.push(async move {
                             let forked_ctx = unsafe { state.attach(&mut store) };
-                            (self.handler_chain.handle(request, forked_ctx).await, response_handle)
+                            // Handling is cancelled when the client resets the stream.
+                            let handle = self.handler_chain.handle(request, forked_ctx);
+                            let mut result = monoio::select! {
+                                result = handle => Some(result),
+                                _ = poll_fn(|cx| response_handle.poll_reset(cx)) => None,
+                            };
+                            // Handlers fail or answer once the body failed.
+                            if result.is_some() && exceeded.is_some_and(|exceeded| exceeded.get()) {
+                                info!("H2 request rejected: {}", LimitExceeded::BodyTooLarge);
+                                Self::h2_reject(&mut response_handle, LimitExceeded::BodyTooLarge);
+                                result = None;
+                            }
+                            (result, response_handle)
                         });
                  }
                  Some(result) = backend_resp_stream.next() => {
                      match result {
-                         (Ok((response, _)), response_handle) => {
+                         (Some(Ok((response, _))), response_handle) => {
                              frontend_resp_stream.push(Self::h2_process_response(response, response_handle));
                          }
-                         (Err(e), mut response_handle) => {
+                         (None, _) => {
+                             activity.close();
+                         }
+                         (Some(Err(e)), mut response_handle) => {
                              error!("Handler chain returned error : {e:?}");
                              let (parts, _) = generate_response::<HttpBody>(StatusCode::INTERNAL_SERVER_ERROR, false).into_parts();
                              let response = http::Response::from_parts(parts, ());
                              let _ = response_handle.send_response(response, true);
+                             activity.close();
                          }
                      }
                  }
                  Some(_) = frontend_resp_stream.next() => {
+                     activity.close();
                  }
                   else => {
                      // No more futures to drive, break the loop
@@ -381,8 +572,12 @@ where
     CXIn: ParamRef<PeerAddr> + Fork<Store = CXStore, State = CXState>,
     CXStore: 'static,
     for<'a> CXState: Attach<CXStore>,
-    for<'a> H:
-        HttpHandler<<CXState as Attach<CXStore>>::Hdr<'a>, HttpBody, Body = HttpBody, Error = Err>,
+    for<'a> H: HttpHandler<
+        <CXState as Attach<CXStore>>::Hdr<'a>,
+        LimitedBody,
+        Body = HttpBody,
+        Error = Err,
+    >,
     Stream: Split + AsyncReadRent + AsyncWriteRent + Unpin + 'static,
     Err: Into<AnyError> + Debug,
 {
@@ -414,6 +609,9 @@ impl<F: MakeService> MakeService for HttpCoreService<F> {
                 .handler_chain
                 .make_via_ref(old.map(|o| &o.handler_chain))?,
             http_timeout: self.http_timeout,
+            h2_settings: self.h2_settings,
+            flood_stats: old.map_or_else(|| self.flood_stats.clone(), |o| o.flood_stats.clone()),
+            limiter: self.limiter.clone(),
         })
     }
 }
@@ -432,6 +630,9 @@ impl<F: AsyncMakeService> AsyncMakeService for HttpCoreService<F> {
                 .make_via_ref(old.map(|o| &o.handler_chain))
                 .await?,
             http_timeout: self.http_timeout,
+            h2_settings: self.h2_settings,
+            flood_stats: old.map_or_else(|| self.flood_stats.clone(), |o| o.flood_stats.clone()),
+            limiter: self.limiter.clone(),
         })
     }
 }
@@ -466,8 +667,139 @@ impl Default for HttpServerTimeout {
 impl<F> HttpCoreService<F> {
     pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
     where
-        C: Param<HttpServerTimeout>,
+        C: Param<HttpServerTimeout> + Param<Http2Settings> + Param<RequestLimitConfig>,
     {
-        layer_fn(|c: &C, inner| Self::new(inner, c.param()))
+        layer_fn(|c: &C, inner| Self::new(inner, c.param(), c.param(), c.param()))
+    }
+}
+
+type Accepted = Result<(http::Request<RecvStream>, SendResponse<Bytes>), h2::Error>;
+
+/// Accept the streams of an HTTP/2 connection, and keep the connection alive with PINGs or close
+/// it when idle according to `settings`. The connection is terminated with `ENHANCE_YOUR_CALM`
+/// when the client opens streams faster than they are handled.
+async fn h2_accept<S>(
+    mut connection: h2::server::Connection<S, Bytes>,
+    tx: local_sync::mpsc::unbounded::Tx<Accepted>,
+    settings: Http2Settings,
+    activity: Rc<StreamActivity>,
+    flood_stats: Arc<Http2FloodStats>,
+) where
+    S: AsyncReadRent + AsyncWriteRent + Unpin + 'static,
+{
+    let ping_pong = match settings.keepalive_interval() {
+        Some(_) => connection.ping_pong(),
+        None => None,
+    };
+    let mut keepalive = std::pin::pin!(h2_keepalive(ping_pong, settings));
+    let idle_timeout = settings.idle_timeout();
+    let max_pending_streams = settings.max_pending_streams();
+    let mut closing = false;
+    let mut flooded = false;
+
+    loop {
+        let idle = async {
+            match idle_timeout {
+                Some(timeout) if !closing => {
+                    monoio::time::sleep(timeout.saturating_sub(activity.idle_for())).await
+                }
+                _ => std::future::pending().await,
+            }
+        };
+        monoio::select! {
+            accepted = connection.accept() => {
+                let Some(accepted) = accepted else {
+                    break;
+                };
+                if accepted.is_ok() {
+                    if activity.in_flight() >= max_pending_streams {
+                        // The stream is reset when dropped.
+                        if !flooded {
+                            let flood = Http2Flood::PendingStreams;
+                            warn!("HTTP/2 client flood: {flood}, closing connection");
+                            flood_stats.record(flood);
+                            connection.abrupt_shutdown(h2::Reason::ENHANCE_YOUR_CALM);
+                            flooded = true;
+                            closing = true;
+                        }
+                        continue;
+                    }
+                    activity.open();
+                }
+                if let Err(e) = tx.send(accepted) {
+                    error!("Frontend Req send failed {e:?}");
+                    break;
+                }
+            }
+            _ = &mut keepalive => {
+                info!("H2 keepalive ping timed out, closing connection");
+                break;
+            }
+            _ = idle => {
+                if idle_timeout.is_some_and(|timeout| activity.is_idle(timeout)) {
+                    info!("H2 connection idle, sending GOAWAY");
+                    connection.graceful_shutdown();
+                    closing = true;
+                }
+            }
+        }
+    }
+}
+
+/// Ping the peer every keepalive interval, returns when a PING is not acknowledged in time.
+async fn h2_keepalive(ping_pong: Option<h2::PingPong>, settings: Http2Settings) {
+    let (Some(mut ping_pong), Some(interval)) = (ping_pong, settings.keepalive_interval()) else {
+        return std::future::pending().await;
+    };
+    loop {
+        monoio::time::sleep(interval).await;
+        match monoio::time::timeout(
+            settings.keepalive_timeout(),
+            ping_pong.ping(h2::Ping::opaque()),
+        )
+        .await
+        {
+            Ok(Ok(_)) => {}
+            // The connection failed, which ends accepting streams as well.
+            Ok(Err(_)) => return std::future::pending().await,
+            Err(_) => return,
+        }
+    }
+}
+
+/// Streams in flight on an HTTP/2 connection, which is idle when there is none.
+struct StreamActivity {
+    in_flight: Cell<usize>,
+    last_active: Cell<Instant>,
+}
+
+impl StreamActivity {
+    fn new() -> Self {
+        Self {
+            in_flight: Cell::new(0),
+            last_active: Cell::new(Instant::now()),
+        }
+    }
+
+    fn open(&self) {
+        self.in_flight.set(self.in_flight.get() + 1);
+        self.last_active.set(Instant::now());
+    }
+
+    fn close(&self) {
+        self.in_flight.set(self.in_flight.get().saturating_sub(1));
+        self.last_active.set(Instant::now());
+    }
+
+    fn in_flight(&self) -> usize {
+        self.in_flight.get()
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_active.get().elapsed()
+    }
+
+    fn is_idle(&self, timeout: Duration) -> bool {
+        self.in_flight.get() == 0 && self.idle_for() >= timeout
     }
 }
@@ -0,0 +1,406 @@
+//! Protection of HTTP/2 connections against frame floods.
+//!
+//! HTTP/2 lets a client make the server work much harder than itself with cheap frames. This
+//! module bounds the frames of each client connection, and terminates connections exceeding the
+//! bounds:
+//!
+//! - Rapid reset (CVE-2023-44487): streams opened and reset right away never count against the
+//!   concurrent streams limit, while the server keeps processing them.
+//! - CONTINUATION flood: header blocks split into endless CONTINUATION frames are buffered until
+//!   they are complete.
+//! - SETTINGS and PING floods: every such frame must be acknowledged, and the acknowledgments are
+//!   queued when the client does not read them.
+//!
+//! # Key Components
+//!
+//! - [`Http2FloodLimits`]: Limits of the frames and pending streams of a connection.
+//! - [`Http2FloodStats`]: Counters of the connections terminated for exceeding a limit.
+//!
+//! # Features
+//!
+//! - Frame headers are inspected as the connection is read, before frames reach the HTTP/2
+//!   implementation, and the connection fails with an error when a limit is exceeded
+//! - RST_STREAM, SETTINGS and PING frames are counted in fixed windows of `window_sec`
+//! - The streams waiting for the handler chain or sending their response are bounded by
+//!   [`HttpCoreService`](crate::http::HttpCoreService), which sends a GOAWAY with
+//!   `ENHANCE_YOUR_CALM` beyond the limit and stops handling the streams reset by the client
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use monoio::{
+    buf::{IoBuf, IoBufMut, IoVecBuf, IoVecBufMut, RawBuf},
+    io::{AsyncReadRent, AsyncWriteRent},
+    BufResult,
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+const PREFACE_LEN: usize = 24;
+const FRAME_HEADER_LEN: usize = 9;
+
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const HEADERS: u8 = 0x1;
+const CONTINUATION: u8 = 0x9;
+
+const ACK: u8 = 0x1;
+
+/// Limits of the frames and pending streams of an HTTP/2 client connection.
+///
+/// Frame counts are limits per window of `window_sec` seconds. Legitimate clients do reset streams,
+/// e.g. when a user navigates away or a gRPC call is cancelled, so the defaults leave room for
+/// them: 200 resets, 100 SETTINGS and 100 PINGs per 10 seconds, 32 CONTINUATION frames per header
+/// block and twice the concurrent streams pending.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Http2FloodLimits {
+    /// Streams waiting for the handler chain or sending their response, defaults to twice the
+    /// `max_concurrent_streams` of the connection.
+    pub max_pending_streams: Option<u32>,
+    /// RST_STREAM frames sent by the client.
+    pub max_resets: u32,
+    /// SETTINGS frames sent by the client, acknowledgments excluded.
+    pub max_settings: u32,
+    /// PING frames sent by the client, acknowledgments excluded.
+    pub max_pings: u32,
+    /// CONTINUATION frames following a HEADERS frame.
+    pub max_continuation_frames: u32,
+    pub window_sec: u64,
+}
+
+impl Default for Http2FloodLimits {
+    fn default() -> Self {
+        Self {
+            max_pending_streams: None,
+            max_resets: 200,
+            max_settings: 100,
+            max_pings: 100,
+            max_continuation_frames: 32,
+            window_sec: 10,
+        }
+    }
+}
+
+impl Http2FloodLimits {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_sec)
+    }
+}
+
+/// Limit of [`Http2FloodLimits`] exceeded by a connection.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Http2Flood {
+    #[error("too many pending streams")]
+    PendingStreams,
+    #[error("too many RST_STREAM frames")]
+    Reset,
+    #[error("too many SETTINGS frames")]
+    Settings,
+    #[error("too many PING frames")]
+    Ping,
+    #[error("too many CONTINUATION frames")]
+    Continuation,
+}
+
+/// Counters of the HTTP/2 connections terminated for exceeding an [`Http2FloodLimits`] limit.
+///
+/// Counters are shared by the workers of a server.
+#[derive(Debug, Default)]
+pub struct Http2FloodStats {
+    pending_streams: AtomicU64,
+    resets: AtomicU64,
+    settings: AtomicU64,
+    pings: AtomicU64,
+    continuations: AtomicU64,
+}
+
+impl Http2FloodStats {
+    /// Connections with too many pending streams.
+    pub fn pending_streams(&self) -> u64 {
+        self.pending_streams.load(Ordering::Relaxed)
+    }
+
+    /// Connections resetting too many streams.
+    pub fn resets(&self) -> u64 {
+        self.resets.load(Ordering::Relaxed)
+    }
+
+    /// Connections sending too many SETTINGS frames.
+    pub fn settings(&self) -> u64 {
+        self.settings.load(Ordering::Relaxed)
+    }
+
+    /// Connections sending too many PING frames.
+    pub fn pings(&self) -> u64 {
+        self.pings.load(Ordering::Relaxed)
+    }
+
+    /// Connections sending too many CONTINUATION frames.
+    pub fn continuations(&self) -> u64 {
+        self.continuations.load(Ordering::Relaxed)
+    }
+
+    /// Number of terminated connections.
+    pub fn total(&self) -> u64 {
+        self.pending_streams()
+            + self.resets()
+            + self.settings()
+            + self.pings()
+            + self.continuations()
+    }
+
+    pub(crate) fn record(&self, flood: Http2Flood) {
+        let counter = match flood {
+            Http2Flood::PendingStreams => &self.pending_streams,
+            Http2Flood::Reset => &self.resets,
+            Http2Flood::Settings => &self.settings,
+            Http2Flood::Ping => &self.pings,
+            Http2Flood::Continuation => &self.continuations,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Parses the frame headers of the bytes read from a client connection and counts its frames.
+#[derive(Debug)]
+struct FrameCounter {
+    limits: Http2FloodLimits,
+    preface_left: usize,
+    header: [u8; FRAME_HEADER_LEN],
+    header_len: usize,
+    payload_left: usize,
+    continuations: u32,
+    window_start: Instant,
+    resets: u32,
+    settings: u32,
+    pings: u32,
+}
+
+impl FrameCounter {
+    fn new(limits: Http2FloodLimits) -> Self {
+        Self {
+            limits,
+            preface_left: PREFACE_LEN,
+            header: [0; FRAME_HEADER_LEN],
+            header_len: 0,
+            payload_left: 0,
+            continuations: 0,
+            window_start: Instant::now(),
+            resets: 0,
+            settings: 0,
+            pings: 0,
+        }
+    }
+
+    fn feed(&mut self, mut data: &[u8]) -> Result<(), Http2Flood> {
+        while !data.is_empty() {
+            let skip = if self.preface_left > 0 {
+                &mut self.preface_left
+            } else if self.payload_left > 0 {
+                &mut self.payload_left
+            } else {
+                let n = (FRAME_HEADER_LEN - self.header_len).min(data.len());
+                self.header[self.header_len..self.header_len + n].copy_from_slice(&data[..n]);
+                self.header_len += n;
+                data = &data[n..];
+                if self.header_len == FRAME_HEADER_LEN {
+                    self.header_len = 0;
+                    let [l0, l1, l2, kind, flags, ..] = self.header;
+                    self.payload_left = u32::from_be_bytes([0, l0, l1, l2]) as usize;
+                    self.frame(kind, flags)?;
+                }
+                continue;
+            };
+            let n = (*skip).min(data.len());
+            *skip -= n;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8) -> Result<(), Http2Flood> {
+        match kind {
+            HEADERS => self.continuations = 0,
+            CONTINUATION => {
+                self.continuations += 1;
+                if self.continuations > self.limits.max_continuation_frames {
+                    return Err(Http2Flood::Continuation);
+                }
+            }
+            RST_STREAM => {
+                self.roll_window();
+                self.resets += 1;
+                if self.resets > self.limits.max_resets {
+                    return Err(Http2Flood::Reset);
+                }
+            }
+            SETTINGS if flags & ACK == 0 => {
+                self.roll_window();
+                self.settings += 1;
+                if self.settings > self.limits.max_settings {
+                    return Err(Http2Flood::Settings);
+                }
+            }
+            PING if flags & ACK == 0 => {
+                self.roll_window();
+                self.pings += 1;
+                if self.pings > self.limits.max_pings {
+                    return Err(Http2Flood::Ping);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn roll_window(&mut self) {
+        if self.window_start.elapsed() >= self.limits.window() {
+            self.window_start = Instant::now();
+            self.resets = 0;
+            self.settings = 0;
+            self.pings = 0;
+        }
+    }
+}
+
+/// IO of an HTTP/2 client connection failing reads once the client exceeds a limit.
+pub(crate) struct FloodGuard<S> {
+    io: S,
+    counter: FrameCounter,
+    flood: Option<Http2Flood>,
+    stats: Arc<Http2FloodStats>,
+}
+
+impl<S> FloodGuard<S> {
+    pub(crate) fn new(io: S, limits: Http2FloodLimits, stats: Arc<Http2FloodStats>) -> Self {
+        Self {
+            io,
+            counter: FrameCounter::new(limits),
+            flood: None,
+            stats,
+        }
+    }
+
+    fn inspect(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(flood) = self.flood {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, flood));
+        }
+        self.counter.feed(data).map_err(|flood| {
+            warn!("HTTP/2 client flood: {flood}, closing connection");
+            self.flood = Some(flood);
+            self.stats.record(flood);
+            io::Error::new(io::ErrorKind::InvalidData, flood)
+        })
+    }
+}
+
+impl<S: AsyncReadRent> AsyncReadRent for FloodGuard<S> {
+    async fn read<T: IoBufMut>(&mut self, buf: T) -> BufResult<usize, T> {
+        let (result, mut buf) = match self.flood {
+            Some(_) => (Ok(0), buf),
+            None => self.io.read(buf).await,
+        };
+        let n = match result {
+            Ok(n) => n,
+            Err(e) => return (Err(e), buf),
+        };
+        // The read bytes start at the write pointer of the buffer.
+        let data = unsafe { std::slice::from_raw_parts(buf.write_ptr() as *const u8, n) };
+        match self.inspect(data) {
+            Ok(()) => (Ok(n), buf),
+            Err(e) => (Err(e), buf),
+        }
+    }
+
+    async fn readv<T: IoVecBufMut>(&mut self, mut buf: T) -> BufResult<usize, T> {
+        // Frames are inspected in contiguous memory, so only the first buffer is filled.
+        let Some(raw) = (unsafe { RawBuf::new_from_iovec_mut(&mut buf) }) else {
+            return (Ok(0), buf);
+        };
+        let (result, _) = self.read(raw).await;
+        if let Ok(n) = result {
+            unsafe { buf.set_init(n) };
+        }
+        (result, buf)
+    }
+}
+
+impl<S: AsyncWriteRent> AsyncWriteRent for FloodGuard<S> {
+    #[inline]
+    async fn write<T: IoBuf>(&mut self, buf: T) -> BufResult<usize, T> {
+        self.io.write(buf).await
+    }
+
+    #[inline]
+    async fn writev<T: IoVecBuf>(&mut self, buf_vec: T) -> BufResult<usize, T> {
+        self.io.writev(buf_vec).await
+    }
+
+    #[inline]
+    async fn flush(&mut self) -> io::Result<()> {
+        self.io.flush().await
+    }
+
+    #[inline]
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.io.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut frame = vec![len[1], len[2], len[3], kind, flags, 0, 0, 0, 1];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn counts_frames_split_across_reads() {
+        let limits = Http2FloodLimits {
+            max_resets: 2,
+            ..Default::default()
+        };
+        let mut counter = FrameCounter::new(limits);
+        let mut bytes = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        bytes.extend(frame(SETTINGS, 0, &[0; 6]));
+        bytes.extend(frame(SETTINGS, ACK, &[]));
+        for _ in 0..3 {
+            bytes.extend(frame(RST_STREAM, 0, &[0, 0, 0, 8]));
+        }
+        let (head, tail) = bytes.split_at(bytes.len() - 10);
+        for chunk in head.chunks(5) {
+            assert_eq!(counter.feed(chunk), Ok(()));
+        }
+        assert_eq!(counter.settings, 1);
+        assert_eq!(counter.resets, 2);
+        assert_eq!(counter.feed(tail), Err(Http2Flood::Reset));
+    }
+
+    #[test]
+    fn limits_continuation_frames_per_header_block() {
+        let limits = Http2FloodLimits {
+            max_continuation_frames: 2,
+            ..Default::default()
+        };
+        let mut counter = FrameCounter::new(limits);
+        counter.preface_left = 0;
+        let mut block = frame(HEADERS, 0, &[0; 4]);
+        block.extend(frame(CONTINUATION, 0, &[0; 4]));
+        block.extend(frame(CONTINUATION, 0x4, &[0; 4]));
+        assert_eq!(counter.feed(&block), Ok(()));
+        assert_eq!(counter.feed(&block), Ok(()));
+        block.extend(frame(CONTINUATION, 0, &[0; 4]));
+        assert_eq!(counter.feed(&block), Err(Http2Flood::Continuation));
+    }
+}
@@ -0,0 +1,290 @@
+//! gRPC protocol helpers.
+//!
+//! gRPC runs over HTTP/2: methods are called with `POST /package.Service/Method` requests carrying
+//! an `application/grpc` content type, and the outcome of a call is reported with the
+//! `grpc-status` and `grpc-message` trailers rather than with the HTTP status, which is 200 for
+//! all calls reaching a gRPC server.
+//!
+//! This module provides what the proxy needs to understand gRPC calls:
+//!
+//! - [`is_grpc`]: Detects gRPC requests and responses by their content type.
+//! - [`parse_timeout`]: Parses the `grpc-timeout` header which carries the deadline of a call.
+//! - [`GrpcCode`] and [`status_response`]: Build "Trailers-Only" responses, which report the status
+//!   of a failed call in the response headers, for failures of the proxy itself.
+//! - [`encode_message`] and [`decode_message`]: Length-prefixed message framing.
+//! - [`encode_varint`], [`encode_varint_field`], [`encode_bytes_field`] and [`decode_fields`]: The
+//!   parts of the protobuf encoding needed for the few messages the proxy builds and reads itself.
+//! - [`decode_header`]: Decodes the `HeaderValue` message of the Envoy services the proxy calls.
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{
+    header::{HeaderName, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Response, StatusCode,
+};
+use monoio_http::common::body::FixedBody;
+
+pub const GRPC_TIMEOUT: &str = "grpc-timeout";
+pub const GRPC_STATUS: &str = "grpc-status";
+pub const GRPC_MESSAGE: &str = "grpc-message";
+pub const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+/// Status codes of gRPC calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcCode {
+    Ok = 0,
+    Cancelled = 1,
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    NotFound = 5,
+    AlreadyExists = 6,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    FailedPrecondition = 9,
+    Aborted = 10,
+    OutOfRange = 11,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    DataLoss = 15,
+    Unauthenticated = 16,
+}
+
+impl GrpcCode {
+    /// Map the status of an HTTP response which is not a gRPC response, following the gRPC
+    /// HTTP to gRPC status code mapping.
+    pub fn from_http_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => GrpcCode::Internal,
+            StatusCode::UNAUTHORIZED => GrpcCode::Unauthenticated,
+            StatusCode::FORBIDDEN => GrpcCode::PermissionDenied,
+            StatusCode::NOT_FOUND => GrpcCode::Unimplemented,
+            StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => GrpcCode::Unavailable,
+            _ => GrpcCode::Unknown,
+        }
+    }
+}
+
+/// Whether the message with these headers is a gRPC message, including `application/grpc+proto`
+/// and other codecs.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(GRPC_CONTENT_TYPE))
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', ';']))
+}
+
+/// Parse a `grpc-timeout` header value: up to 8 digits followed by a unit among `H`, `M`, `S`,
+/// `m`, `u` and `n`.
+pub fn parse_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(n * 3600),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    })
+}
+
+/// Build a "Trailers-Only" response reporting `code` and `message` without a body.
+pub fn status_response<B: FixedBody>(code: GrpcCode, message: &str) -> Response<B> {
+    let mut resp = Response::builder().status(StatusCode::OK);
+    let headers = resp.headers_mut().unwrap();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(GRPC_CONTENT_TYPE));
+    headers.insert(GRPC_STATUS, HeaderValue::from(code as u16));
+    if !message.is_empty() {
+        if let Ok(message) = HeaderValue::from_str(&percent_encode(message)) {
+            headers.insert(GRPC_MESSAGE, message);
+        }
+    }
+    resp.body(B::fixed_body(None)).unwrap()
+}
+
+// grpc-message is percent encoded, except for printable ascii characters other than '%'.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (b' '..=b'~').contains(&b) && b != b'%' {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Frame an uncompressed message with its length prefix.
+pub fn encode_message(message: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(5 + message.len());
+    buf.put_u8(0);
+    buf.put_u32(message.len() as u32);
+    buf.put_slice(message);
+    buf.freeze()
+}
+
+/// Extract the first uncompressed message of a body, if complete.
+pub fn decode_message(body: &[u8]) -> Option<&[u8]> {
+    let (&[0, a, b, c, d], rest) = body.split_first_chunk::<5>()? else {
+        return None;
+    };
+    let len = u32::from_be_bytes([a, b, c, d]) as usize;
+    rest.get(..len)
+}
+
+/// A field of a protobuf message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtoField<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+pub fn encode_varint(mut n: u64, buf: &mut Vec<u8>) {
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+/// Decode a varint, returning it with the number of bytes it takes.
+pub fn decode_varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut n = 0;
+    for (i, b) in buf.iter().take(10).enumerate() {
+        n |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((n, i + 1));
+        }
+    }
+    None
+}
+
+/// Encode a varint field: integers, booleans and enums.
+pub fn encode_varint_field(field: u32, value: u64, buf: &mut Vec<u8>) {
+    encode_varint(u64::from(field) << 3, buf);
+    encode_varint(value, buf);
+}
+
+/// Encode a length-delimited field: strings, bytes and embedded messages.
+pub fn encode_bytes_field(field: u32, value: &[u8], buf: &mut Vec<u8>) {
+    encode_varint(u64::from(field) << 3 | 2, buf);
+    encode_varint(value.len() as u64, buf);
+    buf.extend_from_slice(value);
+}
+
+/// Decode the varint and length-delimited fields of a message, skipping fixed size fields.
+/// Returns `None` when the message is malformed.
+pub fn decode_fields(mut message: &[u8]) -> Option<Vec<(u32, ProtoField<'_>)>> {
+    let mut fields = Vec::new();
+    while !message.is_empty() {
+        let (key, n) = decode_varint(message)?;
+        message = &message[n..];
+        let field = u32::try_from(key >> 3).ok()?;
+        match key & 7 {
+            0 => {
+                let (value, n) = decode_varint(message)?;
+                message = &message[n..];
+                fields.push((field, ProtoField::Varint(value)));
+            }
+            1 => message = message.get(8..)?,
+            2 => {
+                let (len, n) = decode_varint(message)?;
+                let end = n.checked_add(usize::try_from(len).ok()?)?;
+                fields.push((field, ProtoField::Bytes(message.get(n..end)?)));
+                message = &message[end..];
+            }
+            5 => message = message.get(4..)?,
+            _ => return None,
+        }
+    }
+    Some(fields)
+}
+
+/// Decode an `envoy.config.core.v3.HeaderValue { string key = 1; string value = 2; }`.
+pub fn decode_header(message: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+    let (mut name, mut value) = (None, None);
+    for (field, field_value) in decode_fields(message)? {
+        match (field, field_value) {
+            (1, ProtoField::Bytes(bytes)) => name = HeaderName::from_bytes(bytes).ok(),
+            (2, ProtoField::Bytes(bytes)) => value = HeaderValue::from_bytes(bytes).ok(),
+            _ => {}
+        }
+    }
+    Some((name?, value?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grpc_timeout() {
+        let parse = |s: &'static str| parse_timeout(&HeaderValue::from_static(s));
+        assert_eq!(parse("1S"), Some(Duration::from_secs(1)));
+        assert_eq!(parse("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse("99999999n"), Some(Duration::from_nanos(99999999)));
+        assert_eq!(parse("100000000n"), None);
+        assert_eq!(parse("S"), None);
+        assert_eq!(parse("-1S"), None);
+        assert_eq!(parse("10s"), None);
+    }
+
+    #[test]
+    fn grpc_content_type() {
+        let headers = |s: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(s));
+            headers
+        };
+        assert!(is_grpc(&headers("application/grpc")));
+        assert!(is_grpc(&headers("application/grpc+proto")));
+        assert!(!is_grpc(&headers("application/grpc-web")));
+        assert!(!is_grpc(&headers("application/json")));
+        assert!(!is_grpc(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn message_framing() {
+        let framed = encode_message(b"hello");
+        assert_eq!(&framed[..5], &[0, 0, 0, 0, 5]);
+        assert_eq!(decode_message(&framed), Some(&b"hello"[..]));
+        assert_eq!(decode_message(&framed[..6]), None);
+        assert_eq!(percent_encode("50% off\n"), "50%25 off%0A");
+    }
+
+    #[test]
+    fn protobuf_fields() {
+        assert_eq!(decode_varint(&[0x01]), Some((1, 1)));
+        assert_eq!(decode_varint(&[0xac, 0x02]), Some((300, 2)));
+        let mut buf = Vec::new();
+        encode_varint(300, &mut buf);
+        assert_eq!(buf, [0xac, 0x02]);
+
+        let mut message = vec![0x08, 0x02, 0x11, 0, 0, 0, 0, 0, 0, 0, 0];
+        encode_bytes_field(3, b"key", &mut message);
+        assert_eq!(
+            decode_fields(&message),
+            Some(vec![
+                (1, ProtoField::Varint(2)),
+                (3, ProtoField::Bytes(b"key"))
+            ])
+        );
+        assert_eq!(decode_fields(&message[..message.len() - 1]), None);
+    }
+}
@@ -0,0 +1,482 @@
+//! Trailers of HTTP/1.1 chunked bodies.
+//!
+//! The HTTP/1.1 codec of `monoio_http` supports neither side of the trailer section ending chunked
+//! bodies: chunked bodies followed by trailer fields fail to decode, and chunked bodies are always
+//! written without any. This module takes over these parts of the codec:
+//!
+//! - Chunked request bodies are read with their trailers, which handlers find in the
+//!   [`RequestTrailers`] extension of the request once they read the body to its end. The size and
+//!   number of trailer fields are bounded by the header limits of the server.
+//! - Responses are written to HTTP/1.1 clients with the trailers of their body, e.g. the
+//!   `grpc-status` of gRPC calls relayed from HTTP/2 upstreams.
+//!
+//! Trailers of HTTP/2 requests are read from their body with [`trailers`](util::trailers) once
+//! its data has been read, and those of HTTP/3 requests with `Http3Body::trailers`.
+//!
+//! The HTTP/1.1 and HTTP/2 connections to upstreams are provided by `monoio_transports`, which
+//! does not forward request trailers and fails on HTTP/1.1 responses carrying trailers, hence
+//! request trailers are not forwarded to upstreams and only the response trailers of HTTP/2
+//! upstreams reach clients.
+use std::{
+    fmt::Write,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Version};
+use monoio::io::{AsyncWriteRent, AsyncWriteRentExt};
+use monoio_codec::{Decoded, Decoder};
+use monoio_http::{
+    common::{
+        body::{Body, HttpBody, StreamHint},
+        error::HttpError,
+        ext::Reason,
+        response::Response,
+    },
+    h1::codec::decoder::DecodeError,
+};
+
+use super::{
+    limits::{LimitExceeded, RequestDecodeError},
+    util,
+};
+
+const CRLF: &[u8] = b"\r\n";
+const SECTION_END: &[u8] = b"\r\n\r\n";
+// Chunk size lines longer than this are refused, whatever their extensions.
+const MAX_CHUNK_LINE: usize = 4096;
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// Trailers of an HTTP/1.1 chunked request, set once its body has been read.
+///
+/// Chunked requests carry it in their extensions.
+#[derive(Debug, Clone, Default)]
+pub struct RequestTrailers(Arc<Mutex<Option<HeaderMap>>>);
+
+impl RequestTrailers {
+    /// Take the trailers of the request, `None` when the body has not been read to its end or
+    /// ended without trailers.
+    pub fn take(&self) -> Option<HeaderMap> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    fn set(&self, trailers: HeaderMap) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(trailers);
+    }
+}
+
+enum Chunk {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+/// Decoder of chunked HTTP/1.1 request bodies, reading the trailer section ending them.
+///
+/// Chunks are yielded as they arrive rather than once complete.
+pub(crate) struct ChunkedDecoder {
+    state: Chunk,
+    max_trailer_size: usize,
+    max_trailer_count: usize,
+    trailers: RequestTrailers,
+}
+
+impl ChunkedDecoder {
+    pub(crate) fn new(
+        max_trailer_size: usize,
+        max_trailer_count: usize,
+        trailers: RequestTrailers,
+    ) -> Self {
+        Self {
+            state: Chunk::Size,
+            max_trailer_size,
+            max_trailer_count,
+            trailers,
+        }
+    }
+
+    /// Decode the trailer fields of `section`, each line ending with a CRLF.
+    fn decode_trailers(&self, section: &[u8]) -> Result<HeaderMap, RequestDecodeError> {
+        let mut trailers = HeaderMap::new();
+        let section = section.strip_suffix(b"\n").unwrap_or(section);
+        for line in section.split(|&b| b == b'\n') {
+            let line = line.strip_suffix(b"\r").ok_or_else(invalid)?;
+            let colon = memchr::memchr(b':', line).ok_or_else(invalid)?;
+            let name = HeaderName::from_bytes(&line[..colon]).map_err(|_| invalid())?;
+            let value =
+                HeaderValue::from_bytes(line[colon + 1..].trim_ascii()).map_err(|_| invalid())?;
+            trailers.append(name, value);
+        }
+        if trailers.len() > self.max_trailer_count {
+            return Err(RequestDecodeError::Limit(LimitExceeded::TooManyHeaders));
+        }
+        Ok(trailers)
+    }
+}
+
+fn invalid() -> RequestDecodeError {
+    HttpError::from(DecodeError::Chunked).into()
+}
+
+fn chunk_size(line: &[u8]) -> Option<u64> {
+    let digits = line
+        .iter()
+        .position(|b| !b.is_ascii_hexdigit())
+        .unwrap_or(line.len());
+    // Chunk extensions following the size are ignored.
+    if digits == 0 || !matches!(line.get(digits), None | Some(b';' | b' ' | b'\t')) {
+        return None;
+    }
+    line[..digits].iter().try_fold(0u64, |size, &b| {
+        let digit = (b as char).to_digit(16)? as u64;
+        size.checked_mul(16)?.checked_add(digit)
+    })
+}
+
+impl Decoder for ChunkedDecoder {
+    type Item = Option<Bytes>;
+    type Error = RequestDecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Decoded<Self::Item>, Self::Error> {
+        loop {
+            match self.state {
+                Chunk::Size => {
+                    let Some(end) = memchr::memmem::find(src, CRLF) else {
+                        if src.len() > MAX_CHUNK_LINE {
+                            return Err(invalid());
+                        }
+                        return Ok(Decoded::Insufficient);
+                    };
+                    let size = chunk_size(&src[..end]).ok_or_else(invalid)?;
+                    src.advance(end + CRLF.len());
+                    self.state = match size {
+                        0 => Chunk::Trailers,
+                        size => Chunk::Data(size),
+                    };
+                }
+                Chunk::Data(left) => {
+                    if src.is_empty() {
+                        return Ok(Decoded::Insufficient);
+                    }
+                    let len = left.min(src.len() as u64);
+                    let data = src.split_to(len as usize).freeze();
+                    self.state = match left - len {
+                        0 => Chunk::DataEnd,
+                        left => Chunk::Data(left),
+                    };
+                    return Ok(Decoded::Some(Some(data)));
+                }
+                Chunk::DataEnd => {
+                    if src.len() < CRLF.len() {
+                        return Ok(Decoded::Insufficient);
+                    }
+                    if !src.starts_with(CRLF) {
+                        return Err(invalid());
+                    }
+                    src.advance(CRLF.len());
+                    self.state = Chunk::Size;
+                }
+                Chunk::Trailers => {
+                    if src.starts_with(CRLF) {
+                        src.advance(CRLF.len());
+                        self.state = Chunk::Done;
+                        return Ok(Decoded::Some(None));
+                    }
+                    let limit = src.len().min(self.max_trailer_size + SECTION_END.len());
+                    let Some(end) = memchr::memmem::find(&src[..limit], SECTION_END) else {
+                        if src.len() > self.max_trailer_size {
+                            return Err(RequestDecodeError::Limit(LimitExceeded::HeaderTooLarge));
+                        }
+                        return Ok(Decoded::Insufficient);
+                    };
+                    let trailers = self.decode_trailers(&src[..end + CRLF.len()])?;
+                    src.advance(end + SECTION_END.len());
+                    self.trailers.set(trailers);
+                    self.state = Chunk::Done;
+                    return Ok(Decoded::Some(None));
+                }
+                Chunk::Done => return Ok(Decoded::Some(None)),
+            }
+        }
+    }
+}
+
+/// Writer of HTTP/1.1 responses.
+///
+/// Responses are written like the `GenericEncoder` of `monoio_http` does, except that chunked
+/// bodies end with the trailers of the body.
+pub(crate) struct ResponseEncoder<W> {
+    io: W,
+    buf: BytesMut,
+}
+
+impl<W: AsyncWriteRent> ResponseEncoder<W> {
+    pub(crate) fn new(io: W) -> Self {
+        Self {
+            io,
+            buf: BytesMut::with_capacity(BUFFER_SIZE),
+        }
+    }
+
+    /// Write a response and flush the connection.
+    pub(crate) async fn send_and_flush(
+        &mut self,
+        response: Response<HttpBody>,
+    ) -> Result<(), HttpError> {
+        let (head, mut body) = response.into_parts();
+        match body.stream_hint() {
+            StreamHint::None => encode_head(&head, Some(0), &mut self.buf)?,
+            StreamHint::Fixed => {
+                let data = match body.next_data().await {
+                    Some(data) => data?,
+                    None => Bytes::new(),
+                };
+                encode_head(&head, Some(data.len()), &mut self.buf)?;
+                self.write(data).await?;
+            }
+            StreamHint::Stream => {
+                encode_head(&head, None, &mut self.buf)?;
+                while let Some(data) = body.next_data().await {
+                    let data = data?;
+                    // An empty chunk would end the body.
+                    if data.is_empty() {
+                        continue;
+                    }
+                    let _ = write!(self.buf, "{:X}\r\n", data.len());
+                    self.write(data).await?;
+                    self.buf.extend_from_slice(CRLF);
+                }
+                self.buf.extend_from_slice(b"0\r\n");
+                if let Some(trailers) = util::trailers(&mut body).await {
+                    encode_fields(&trailers, &mut self.buf);
+                }
+                self.buf.extend_from_slice(CRLF);
+            }
+        }
+        self.flush().await?;
+        Ok(())
+    }
+
+    async fn write(&mut self, data: Bytes) -> io::Result<()> {
+        if self.buf.len() + data.len() <= BUFFER_SIZE {
+            self.buf.extend_from_slice(&data);
+            return Ok(());
+        }
+        // Large data is written as is rather than copied to the buffer.
+        self.flush().await?;
+        self.io.write_all(data).await.0?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let (result, mut buf) = self.io.write_all(std::mem::take(&mut self.buf)).await;
+            buf.clear();
+            self.buf = buf;
+            result?;
+        }
+        self.io.flush().await
+    }
+}
+
+/// Encode the status line and headers of a response with a content length, or as chunked when
+/// the length is unknown.
+fn encode_head(
+    head: &http::response::Parts,
+    length: Option<usize>,
+    dst: &mut BytesMut,
+) -> io::Result<()> {
+    let version = match head.version {
+        Version::HTTP_11 => "HTTP/1.1",
+        Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_09 => "HTTP/0.9",
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "unexpected http version",
+            ))
+        }
+    };
+    let reason: &str = match head.extensions.get::<Reason>() {
+        Some(reason) => reason,
+        None => head.status.canonical_reason().unwrap_or("<none>"),
+    };
+    let _ = write!(dst, "{version} {} {reason}\r\n", head.status.as_str());
+    let _ = match length {
+        Some(length) => write!(dst, "content-length: {length}\r\n"),
+        None => write!(dst, "transfer-encoding: chunked\r\n"),
+    };
+    encode_fields(&head.headers, dst);
+    dst.extend_from_slice(CRLF);
+    Ok(())
+}
+
+fn encode_fields(fields: &HeaderMap, dst: &mut BytesMut) {
+    for (name, value) in fields
+        .iter()
+        .filter(|(name, _)| *name != header::CONTENT_LENGTH && *name != header::TRANSFER_ENCODING)
+    {
+        dst.extend_from_slice(name.as_ref());
+        dst.extend_from_slice(b": ");
+        dst.extend_from_slice(value.as_bytes());
+        dst.extend_from_slice(CRLF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio::{
+        io::{stream::Stream, AsyncReadRent},
+        net::{TcpListener, TcpStream},
+    };
+    use monoio_http::{common::body::FixedBody, h1::codec::decoder::FillPayload, h2};
+
+    use super::*;
+    use crate::{
+        http::limits::{LimitedRequestDecoder, RequestLimits},
+        testing::body_bytes,
+    };
+
+    /// Decode `src` fed one byte at a time, returning the data and the trailers.
+    fn decode(
+        src: &[u8],
+        max_size: usize,
+        max_count: usize,
+    ) -> Result<(Vec<u8>, Option<HeaderMap>), RequestDecodeError> {
+        let trailers = RequestTrailers::default();
+        let mut decoder = ChunkedDecoder::new(max_size, max_count, trailers.clone());
+        let (mut buf, mut data) = (BytesMut::new(), Vec::new());
+        for byte in src {
+            buf.extend_from_slice(&[*byte]);
+            while let Decoded::Some(item) = decoder.decode(&mut buf)? {
+                match item {
+                    Some(chunk) => data.extend_from_slice(&chunk),
+                    None => return Ok((data, trailers.take())),
+                }
+            }
+        }
+        panic!("incomplete body");
+    }
+
+    #[test]
+    fn chunked_trailers() {
+        let (data, trailers) = decode(
+            b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\ngrpc-status: 0\r\nx-a:  b \r\n\r\n",
+            64,
+            2,
+        )
+        .unwrap();
+        assert_eq!(data, b"hello world");
+        let trailers = trailers.unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["x-a"], "b");
+
+        let (data, trailers) = decode(b"3\r\nabc\r\n0\r\n\r\n", 64, 2).unwrap();
+        assert_eq!((data.as_slice(), trailers), (&b"abc"[..], None));
+
+        assert!(matches!(
+            decode(b"0\r\nx-long: aaaaaaaaaaaaaaaaaaaa\r\n\r\n", 16, 2),
+            Err(RequestDecodeError::Limit(LimitExceeded::HeaderTooLarge))
+        ));
+        assert!(matches!(
+            decode(b"0\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n", 64, 2),
+            Err(RequestDecodeError::Limit(LimitExceeded::TooManyHeaders))
+        ));
+        for invalid in [
+            &b"zz\r\n"[..],
+            b"5\r\nhelloXX",
+            b"0\r\nno colon\r\n\r\n",
+            b"0\r\nbad name: 1\r\n\r\n",
+        ] {
+            assert!(matches!(
+                decode(invalid, 64, 2),
+                Err(RequestDecodeError::Http(_))
+            ));
+        }
+    }
+
+    #[monoio::test]
+    async fn request_trailers() {
+        let src: &[u8] = b"POST / HTTP/1.1\r\nhost: a\r\ntransfer-encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n";
+        let mut decoder = LimitedRequestDecoder::new(src, RequestLimits::default());
+        let request = decoder.next().await.unwrap().unwrap();
+        let trailers = request
+            .extensions()
+            .get::<RequestTrailers>()
+            .unwrap()
+            .clone();
+        decoder.fill_payload().await.unwrap();
+        assert_eq!(
+            body_bytes(HttpBody::request(request).into_body()).await,
+            b"hello"
+        );
+        assert_eq!(trailers.take().unwrap()["grpc-status"], "0");
+    }
+
+    /// The body of a response received over HTTP/2, ending with `grpc-status: 0`.
+    async fn h2_body() -> HttpBody {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        // The server runs on a thread of its own, as both ends of HTTP/2 connections do not make
+        // progress on the same runtime.
+        std::thread::spawn(move || {
+            monoio::start::<monoio::LegacyDriver, _>(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut conn = h2::server::handshake(stream).await.unwrap();
+                let (_, mut respond) = conn.accept().await.unwrap().unwrap();
+                let mut send = respond
+                    .send_response(http::Response::new(()), false)
+                    .unwrap();
+                send.send_data(Bytes::from_static(b"hello"), false).unwrap();
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                send.send_trailers(trailers).unwrap();
+                while conn.accept().await.is_some() {}
+            })
+        });
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, conn) = h2::client::handshake(stream).await.unwrap();
+        monoio::spawn(conn);
+        let (response, _) = client.send_request(http::Request::new(()), true).unwrap();
+        HttpBody::from(response.await.unwrap().into_body())
+    }
+
+    #[monoio::test]
+    async fn response_trailers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let received = monoio::spawn(async move {
+            let mut received = Vec::new();
+            loop {
+                let (n, buf) = client.read(Vec::with_capacity(1024)).await;
+                match n.unwrap() {
+                    0 => return received,
+                    _ => received.extend_from_slice(&buf),
+                }
+            }
+        });
+
+        // Trailers of HTTP/2 upstreams end the chunked bodies of HTTP/1.1 clients.
+        let mut encoder = ResponseEncoder::new(stream);
+        let response = http::Response::new(h2_body().await);
+        encoder.send_and_flush(response).await.unwrap();
+        let response = http::Response::new(HttpBody::fixed_body(Some("fixed".into())));
+        encoder.send_and_flush(response).await.unwrap();
+        drop(encoder);
+
+        let received = received.await;
+        assert_eq!(
+            String::from_utf8(received).unwrap(),
+            "HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: \
+             0\r\n\r\nHTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nfixed"
+        );
+    }
+}
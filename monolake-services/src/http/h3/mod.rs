@@ -0,0 +1,735 @@
+//! HTTP/3 service for connections accepted by a QUIC listener.
+//!
+//! [`Http3CoreService`] is the HTTP/3 counterpart of
+//! [`HttpCoreService`](crate::http::HttpCoreService): it takes the QUIC connections yielded by a
+//! [`Listener::Quic`](monolake_core::listener::Listener::Quic), runs the HTTP/3 protocol over them
+//! and feeds every request to the same `HttpHandler` chain. Requests and responses are converted
+//! to an [`Http3Body`], which handlers treat as any other request body, and from the `HttpBody`
+//! type used by the rest of the stack, so handlers do not need to know which protocol a request
+//! arrived on besides its version being `HTTP/3`. Response trailers of HTTP/2 upstreams are
+//! forwarded as well, and request trailers are read with [`Http3Body::trailers`].
+//!
+//! TLS is part of QUIC and is configured on the listener, hence this service is not preceded by a
+//! TLS layer or `H2Detect`.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! use service_async::{layer::FactoryLayer, stack::FactoryStack};
+//!
+//! let stack = FactoryStack::new(config)
+//!     // ... handlers implementing HttpHandler ...
+//!     .push(ConnectionReuseHandler::layer())
+//!     .push(Http3CoreService::layer());
+//! ```
+use std::{
+    cell::Cell, convert::Infallible, fmt::Debug, future::Future, io, rc::Rc, sync::Arc,
+    time::Duration,
+};
+
+use bytes::{Buf, Bytes};
+use certain_map::{Attach, Fork};
+use futures::{stream::FuturesUnordered, StreamExt};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use monoio_http::common::{
+    body::{Body, FixedBody, HttpBody, StreamHint},
+    error::HttpError,
+};
+use monolake_core::{
+    context::PeerAddr, http::HttpHandler, listener::AcceptedStream, quic::QuicConnection, AnyError,
+};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, ParamRef, Service,
+};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    generate_response,
+    limits::{BodyLimit, LimitExceeded, RequestLimitConfig, RequestLimiter},
+    util::trailers,
+    HttpServerTimeout,
+};
+
+mod quic;
+
+type RequestStream<S> = h3::server::RequestStream<S, Bytes>;
+type SendStream = RequestStream<quic::SendStream<Bytes>>;
+type RecvStream = RequestStream<quic::RecvStream>;
+
+/// Hop-by-hop headers which are not allowed in HTTP/3 responses.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Core HTTP/3 service handling QUIC connections.
+///
+/// For implementation details and example usage, see the
+/// [module level documentation](crate::http::h3).
+#[derive(Clone)]
+pub struct Http3CoreService<H> {
+    handler_chain: H,
+    http_timeout: HttpServerTimeout,
+    limiter: Arc<RequestLimiter>,
+}
+
+impl<H> Http3CoreService<H> {
+    pub fn new(
+        handler_chain: H,
+        http_timeout: HttpServerTimeout,
+        request_limits: RequestLimitConfig,
+    ) -> Self {
+        Http3CoreService {
+            handler_chain,
+            http_timeout,
+            limiter: RequestLimiter::new(&request_limits),
+        }
+    }
+
+    /// Read the head of a request, then turn its stream into a request with an [`Http3Body`] and
+    /// the stream to send the response on, along with the flag raised once the body exceeds its
+    /// limit. Requests exceeding a limit with their head are answered here.
+    async fn read_request(
+        &self,
+        resolver: h3::server::RequestResolver<quic::Connection<Bytes>, Bytes>,
+    ) -> Option<(Request<Http3Body>, SendStream, Option<Rc<Cell<bool>>>)> {
+        let resolved = match self.http_timeout.read_header_timeout {
+            Some(timeout) => match monoio::time::timeout(timeout, resolver.resolve_request()).await
+            {
+                Ok(resolved) => resolved,
+                Err(_) => {
+                    info!("h3 read request header timed out");
+                    return None;
+                }
+            },
+            None => resolver.resolve_request().await,
+        };
+        let (request, stream) = match resolved {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                warn!("h3 read request header failed: {e}");
+                return None;
+            }
+        };
+        let (send, recv) = stream.split();
+
+        let (parts, _) = request.into_parts();
+        let body_limit = self.limiter.body_limit(parts.uri.path());
+        let checked = self
+            .limiter
+            .check_head(&parts.uri, &parts.headers)
+            .and_then(|_| RequestLimiter::check_content_length(&parts.headers, body_limit));
+        if let Err(limit) = checked {
+            info!("H3 request rejected: {limit}");
+            Self::send_response(generate_response(limit.status(), false), send).await;
+            return None;
+        }
+        let content_length = parts
+            .headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+        // The end of the stream is only known once read, so the request is taken as carrying a
+        // body unless its head says otherwise: the body is then read as the handlers pull it.
+        let bodiless = match content_length {
+            Some(length) => length == 0,
+            None => matches!(parts.method, Method::GET | Method::HEAD),
+        };
+        let (body, exceeded) = match bodiless {
+            true => (Http3Body::fixed_body(None), None),
+            false => {
+                let (limit, exceeded) = body_limit.map(BodyLimit::new).unzip();
+                let body = Http3Body(Inner::Stream {
+                    recv,
+                    read_timeout: self.http_timeout.read_body_timeout,
+                    limit,
+                });
+                (body, exceeded)
+            }
+        };
+        Some((Request::from_parts(parts, body), send, exceeded))
+    }
+
+    async fn send_response(response: Response<HttpBody>, mut send: SendStream) {
+        let (mut parts, mut body) = response.into_parts();
+        for name in CONNECTION_HEADERS {
+            parts.headers.remove(name);
+        }
+        parts.version = http::Version::HTTP_3;
+        if let Err(e) = send.send_response(Response::from_parts(parts, ())).await {
+            debug!("h3 send response failed: {e}");
+            return;
+        }
+
+        match body.stream_hint() {
+            StreamHint::None => {}
+            StreamHint::Fixed => {
+                if let Some(Ok(data)) = body.next_data().await {
+                    if let Err(e) = send.send_data(data).await {
+                        debug!("h3 send response body failed: {e}");
+                        return;
+                    }
+                }
+            }
+            StreamHint::Stream => {
+                while let Some(data) = body.next_data().await {
+                    let result = match data {
+                        Ok(data) => send.send_data(data).await,
+                        Err(e) => {
+                            warn!("h3 response body failed: {e:?}");
+                            send.stop_stream(h3::error::Code::H3_INTERNAL_ERROR);
+                            return;
+                        }
+                    };
+                    if let Err(e) = result {
+                        debug!("h3 send response body failed: {e}");
+                        return;
+                    }
+                }
+                if let Some(trailers) = trailers(&mut body).await {
+                    if let Err(e) = send.send_trailers(trailers).await {
+                        debug!("h3 send response trailers failed: {e}");
+                        return;
+                    }
+                }
+            }
+        }
+        if let Err(e) = send.finish().await {
+            debug!("h3 finish response failed: {e}");
+        }
+    }
+}
+
+/// Streams which may carry a QUIC connection.
+///
+/// Only the streams accepted by a QUIC listener do. The TLS streams implement it so that
+/// [`Http3CoreService`] can be placed under the server-wide TLS layer, which passes QUIC
+/// connections through as the TLS config of QUIC servers is `None`.
+pub trait QuicStream {
+    fn into_quic(self) -> Option<QuicConnection>;
+}
+
+impl QuicStream for AcceptedStream {
+    #[inline]
+    fn into_quic(self) -> Option<QuicConnection> {
+        match self {
+            AcceptedStream::Quic(conn) => Some(conn),
+            _ => None,
+        }
+    }
+}
+
+impl<S> QuicStream for crate::tls::RustlsStream<S> {
+    #[inline]
+    fn into_quic(self) -> Option<QuicConnection> {
+        None
+    }
+}
+
+impl<S> QuicStream for monoio_native_tls::TlsStream<S> {
+    #[inline]
+    fn into_quic(self) -> Option<QuicConnection> {
+        None
+    }
+}
+
+/// Body of a request received over HTTP/3.
+///
+/// The request stream is read as the body is pulled, so the flow control of the stream holds the
+/// client back while handlers do not consume it.
+pub struct Http3Body(Inner);
+
+enum Inner {
+    Ready(Option<Bytes>),
+    Stream {
+        recv: RecvStream,
+        read_timeout: Option<Duration>,
+        limit: Option<BodyLimit>,
+    },
+    // The data has been read, the trailers may follow.
+    Ended {
+        recv: RecvStream,
+        read_timeout: Option<Duration>,
+    },
+}
+
+impl Http3Body {
+    /// Read the trailers ending the request, once its data has been read.
+    pub async fn trailers(&mut self) -> Option<HeaderMap> {
+        let Inner::Ended { recv, read_timeout } = &mut self.0 else {
+            return None;
+        };
+        let read_timeout = *read_timeout;
+        let trailers = read(recv.recv_trailers(), read_timeout).await;
+        self.0 = Inner::Ready(None);
+        trailers.ok().flatten()
+    }
+}
+
+/// Read from a request stream within `read_timeout`.
+async fn read<T>(
+    read: impl Future<Output = Result<T, h3::error::StreamError>>,
+    read_timeout: Option<Duration>,
+) -> io::Result<T> {
+    let result = match read_timeout {
+        Some(timeout) => monoio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "read body timed out"))?,
+        None => read.await,
+    };
+    result.map_err(|e| io::Error::new(io::ErrorKind::ConnectionReset, e))
+}
+
+impl Body for Http3Body {
+    type Data = Bytes;
+    type Error = HttpError;
+
+    async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        let (recv, read_timeout, limit) = match &mut self.0 {
+            Inner::Ready(data) => return data.take().map(Ok),
+            Inner::Ended { .. } => return None,
+            Inner::Stream {
+                recv,
+                read_timeout,
+                limit,
+            } => (recv, *read_timeout, limit),
+        };
+        let data = read(recv.recv_data(), read_timeout).await;
+        match data {
+            Ok(Some(mut data)) => {
+                if let Some(Err(e)) = limit.as_mut().map(|limit| limit.count(data.remaining())) {
+                    self.0 = Inner::Ready(None);
+                    return Some(Err(e));
+                }
+                Some(Ok(data.copy_to_bytes(data.remaining())))
+            }
+            Ok(None) => {
+                // The stream is kept for the trailers.
+                let Inner::Stream {
+                    recv, read_timeout, ..
+                } = std::mem::replace(&mut self.0, Inner::Ready(None))
+                else {
+                    unreachable!()
+                };
+                self.0 = Inner::Ended { recv, read_timeout };
+                None
+            }
+            Err(e) => {
+                self.0 = Inner::Ready(None);
+                Some(Err(HttpError::IOError(e)))
+            }
+        }
+    }
+
+    fn stream_hint(&self) -> StreamHint {
+        match &self.0 {
+            Inner::Ready(None) | Inner::Ended { .. } => StreamHint::None,
+            Inner::Ready(Some(_)) => StreamHint::Fixed,
+            Inner::Stream { .. } => StreamHint::Stream,
+        }
+    }
+}
+
+impl FixedBody for Http3Body {
+    fn fixed_body(data: Option<Bytes>) -> Self {
+        Self(Inner::Ready(data))
+    }
+}
+
+impl<H, S, CXIn, CXStore, CXState, Err> Service<(S, CXIn)> for Http3CoreService<H>
+where
+    S: QuicStream,
+    CXIn: ParamRef<PeerAddr> + Fork<Store = CXStore, State = CXState>,
+    CXStore: 'static,
+    for<'a> CXState: Attach<CXStore>,
+    for<'a> H:
+        HttpHandler<<CXState as Attach<CXStore>>::Hdr<'a>, Http3Body, Body = HttpBody, Error = Err>,
+    Err: Into<AnyError> + Debug,
+{
+    type Response = ();
+    type Error = Infallible;
+
+    async fn call(&self, (stream, ctx): (S, CXIn)) -> Result<(), Infallible> {
+        let conn = match stream.into_quic() {
+            Some(conn) => conn,
+            None => {
+                error!("h3 requires a quic connection");
+                return Ok(());
+            }
+        };
+        let max_field_section_size = self.limiter.limits().max_header_size as u64;
+        let mut connection = match h3::server::builder()
+            .max_field_section_size(max_field_section_size)
+            .build(quic::Connection::new(conn))
+            .await
+        {
+            Ok(c) => {
+                info!(
+                    "H3 handshake complete for {:?}",
+                    ParamRef::<PeerAddr>::param_ref(&ctx),
+                );
+                c
+            }
+            Err(e) => {
+                error!("h3 server build failed: {e}");
+                return Ok(());
+            }
+        };
+
+        let mut requests = FuturesUnordered::new();
+        let mut accepting = true;
+        loop {
+            monoio::select! {
+                accepted = connection.accept(), if accepting => {
+                    match accepted {
+                        Ok(Some(resolver)) => {
+                            // fork ctx
+                            let (mut store, state) = ctx.fork();
+                            requests.push(async move {
+                                let Some((request, send, exceeded)) = self.read_request(resolver).await else {
+                                    return;
+                                };
+                                let forked_ctx = unsafe { state.attach(&mut store) };
+                                let mut response = match self.handler_chain.handle(request, forked_ctx).await {
+                                    Ok((response, _)) => response,
+                                    Err(e) => {
+                                        error!("Handler chain returned error : {e:?}");
+                                        generate_response(StatusCode::INTERNAL_SERVER_ERROR, false)
+                                    }
+                                };
+                                // Handlers fail or answer once the body failed.
+                                if exceeded.is_some_and(|exceeded| exceeded.get()) {
+                                    info!("H3 request rejected: {}", LimitExceeded::BodyTooLarge);
+                                    response = generate_response(LimitExceeded::BodyTooLarge.status(), false);
+                                }
+                                Self::send_response(response, send).await;
+                            });
+                        }
+                        Ok(None) => accepting = false,
+                        Err(e) => {
+                            if !e.is_h3_no_error() {
+                                info!("h3 connection closed with error: {e}");
+                            }
+                            accepting = false;
+                        }
+                    }
+                }
+                Some(()) = requests.next() => {}
+                else => {
+                    // No more requests to serve, break the loop
+                    // and drop the connection.
+                    break;
+                }
+            }
+        }
+
+        info!(
+            "H3 connection processing complete for {:?}",
+            ParamRef::<PeerAddr>::param_ref(&ctx)
+        );
+        Ok(())
+    }
+}
+
+// Http3CoreService is a Service and a MakeService.
+impl<F: MakeService> MakeService for Http3CoreService<F> {
+    type Service = Http3CoreService<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(Http3CoreService {
+            handler_chain: self
+                .handler_chain
+                .make_via_ref(old.map(|o| &o.handler_chain))?,
+            http_timeout: self.http_timeout,
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for Http3CoreService<F> {
+    type Service = Http3CoreService<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(Http3CoreService {
+            handler_chain: self
+                .handler_chain
+                .make_via_ref(old.map(|o| &o.handler_chain))
+                .await?,
+            http_timeout: self.http_timeout,
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+impl<F> Http3CoreService<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
+    where
+        C: Param<HttpServerTimeout> + Param<RequestLimitConfig>,
+    {
+        layer_fn(|c: &C, inner| Self::new(inner, c.param(), c.param()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible, future::Future, net::SocketAddr, rc::Rc, sync::Arc, time::Duration,
+    };
+
+    use monolake_core::{http::ResponseWithContinue, quic::QuicListener};
+    use rustls_client::{crypto::aws_lc_rs, pki_types::CertificateDer, version::TLS13};
+    use service_async::Service;
+
+    use super::*;
+    use crate::{
+        http::RequestLimits,
+        testing::{TestCa, TestCtx},
+        tls::{PemServerConfig, H3_ALPN},
+    };
+
+    /// Answers `/early` right away, echoes the body of other requests followed by the values of
+    /// their trailers.
+    struct Echo;
+
+    impl Service<(Request<Http3Body>, TestCtx)> for Echo {
+        type Response = ResponseWithContinue<HttpBody>;
+        type Error = Infallible;
+
+        async fn call(
+            &self,
+            (request, _): (Request<Http3Body>, TestCtx),
+        ) -> Result<Self::Response, Self::Error> {
+            let early = request.uri().path() == "/early";
+            let mut body = request.into_body();
+            let mut data = Vec::new();
+            if early {
+                data.extend_from_slice(b"early");
+            } else {
+                while let Some(Ok(chunk)) = body.next_data().await {
+                    data.extend_from_slice(&chunk);
+                }
+                for value in body.trailers().await.iter().flat_map(HeaderMap::values) {
+                    data.push(b' ');
+                    data.extend_from_slice(value.as_bytes());
+                }
+            }
+            let response = Response::new(HttpBody::fixed_body(Some(data.into())));
+            Ok((response, true))
+        }
+    }
+
+    /// Serve HTTP/3 with [`Echo`] on a loopback port, returning the port and the CA to trust.
+    fn serve(limits: RequestLimitConfig) -> (SocketAddr, TestCa) {
+        let ca = TestCa::new();
+        let (chain, key) = ca.issue(&["localhost"]);
+        let config = PemServerConfig::from((chain.into_bytes(), key.into_bytes()));
+        let mut listener = QuicListener::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            config.quic_server_config().unwrap(),
+        )
+        .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = Rc::new(Http3CoreService::new(
+            Echo,
+            HttpServerTimeout::default(),
+            limits,
+        ));
+        monoio::spawn(async move {
+            while let Some(conn) = listener.accept().await {
+                let service = service.clone();
+                monoio::spawn(async move {
+                    let ctx = TestCtx::new("127.0.0.1:1");
+                    let _ = service.call((AcceptedStream::Quic(conn), ctx)).await;
+                });
+            }
+        });
+        (addr, ca)
+    }
+
+    /// Run `client` with an HTTP/3 client connected to `addr` on a thread of its own, while the
+    /// runtime of the test keeps serving.
+    async fn with_client<F, Fut>(addr: SocketAddr, ca: &TestCa, client: F)
+    where
+        F: FnOnce(h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()>,
+    {
+        let mut roots = rustls_client::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut ca.pem().as_bytes()).unwrap() {
+            roots.add(CertificateDer::from(cert)).unwrap();
+        }
+        let thread = std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let mut tls = rustls_client::ClientConfig::builder_with_provider(Arc::new(
+                    aws_lc_rs::default_provider(),
+                ))
+                .with_protocol_versions(&[&TLS13])
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+                tls.alpn_protocols = vec![H3_ALPN.to_vec()];
+                let tls = quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap();
+                let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+                endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+                let conn = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+                let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(conn))
+                    .await
+                    .unwrap();
+                tokio::spawn(async move {
+                    let _ = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+                });
+                tokio::time::timeout(Duration::from_secs(10), client(send_request))
+                    .await
+                    .expect("h3 exchange timed out");
+                endpoint.close(0u32.into(), b"");
+            });
+        });
+        while !thread.is_finished() {
+            monoio::time::sleep(Duration::from_millis(10)).await;
+        }
+        thread.join().unwrap();
+    }
+
+    async fn read_body<S: h3::quic::RecvStream>(
+        stream: &mut h3::client::RequestStream<S, Bytes>,
+    ) -> Vec<u8> {
+        let mut body = Vec::new();
+        while let Some(mut data) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+        }
+        body
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn requests() {
+        let (addr, ca) = serve(Default::default());
+        with_client(addr, &ca, |mut send_request| async move {
+            // Handlers get requests before their body, and may answer without reading it.
+            let request = Request::post("https://localhost/early").body(()).unwrap();
+            let mut stream = send_request.send_request(request).await.unwrap();
+            let response = stream.recv_response().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(read_body(&mut stream).await, b"early");
+
+            // Bodies are read as the handler pulls them.
+            let request = Request::post("https://localhost/echo").body(()).unwrap();
+            let mut stream = send_request.send_request(request).await.unwrap();
+            stream
+                .send_data(Bytes::from_static(b"hello "))
+                .await
+                .unwrap();
+            stream
+                .send_data(Bytes::from_static(b"world"))
+                .await
+                .unwrap();
+            stream.finish().await.unwrap();
+            let response = stream.recv_response().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(read_body(&mut stream).await, b"hello world");
+
+            // Trailers are read once the body has been.
+            let request = Request::post("https://localhost/echo").body(()).unwrap();
+            let mut stream = send_request.send_request(request).await.unwrap();
+            stream.send_data(Bytes::from_static(b"call")).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            stream.send_trailers(trailers).await.unwrap();
+            stream.finish().await.unwrap();
+            let response = stream.recv_response().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(read_body(&mut stream).await, b"call 0");
+
+            let request = Request::get("https://localhost/").body(()).unwrap();
+            let mut stream = send_request.send_request(request).await.unwrap();
+            stream.finish().await.unwrap();
+            let response = stream.recv_response().await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(read_body(&mut stream).await.is_empty());
+        })
+        .await;
+    }
+
+    /// Send `request` with `body`, returning the status of the response.
+    async fn exchange(
+        send_request: &mut h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+        request: Request<()>,
+        body: &'static [u8],
+    ) -> StatusCode {
+        let mut stream = send_request.send_request(request).await.unwrap();
+        if !body.is_empty() {
+            // The stream may be stopped once the body exceeds its limit.
+            let _ = stream.send_data(Bytes::from_static(body)).await;
+        }
+        let _ = stream.finish().await;
+        stream.recv_response().await.unwrap().status()
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn limits() {
+        let limits = RequestLimitConfig {
+            limits: RequestLimits {
+                max_uri_length: Some(16),
+                max_header_count: 4,
+                max_body_size: Some(8),
+                ..Default::default()
+            },
+            route_body_sizes: vec![("/big".to_string(), Some(64))],
+        };
+        let (addr, ca) = serve(limits);
+        with_client(addr, &ca, |mut send_request| async move {
+            let request = Request::get("https://localhost/a/very/long/path")
+                .body(())
+                .unwrap();
+            assert_eq!(
+                exchange(&mut send_request, request, b"").await,
+                StatusCode::URI_TOO_LONG
+            );
+
+            let mut request = Request::get("https://localhost/").body(()).unwrap();
+            for i in 0..5 {
+                request.headers_mut().insert(
+                    format!("x-{i}").parse::<http::HeaderName>().unwrap(),
+                    "1".parse().unwrap(),
+                );
+            }
+            assert_eq!(
+                exchange(&mut send_request, request, b"").await,
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            );
+
+            // Bodies are refused by their length, or once they exceed the limit while read.
+            let request = Request::post("https://localhost/")
+                .header("content-length", "9")
+                .body(())
+                .unwrap();
+            assert_eq!(
+                exchange(&mut send_request, request, b"").await,
+                StatusCode::PAYLOAD_TOO_LARGE
+            );
+            let request = Request::post("https://localhost/").body(()).unwrap();
+            assert_eq!(
+                exchange(&mut send_request, request, b"0123456789").await,
+                StatusCode::PAYLOAD_TOO_LARGE
+            );
+
+            // Routes may allow larger bodies.
+            let request = Request::post("https://localhost/big").body(()).unwrap();
+            assert_eq!(
+                exchange(&mut send_request, request, b"0123456789").await,
+                StatusCode::OK
+            );
+        })
+        .await;
+    }
+}
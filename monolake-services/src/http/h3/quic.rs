@@ -0,0 +1,301 @@
+//! Implementation of the `h3` QUIC transport traits over [`QuicConnection`].
+use std::{
+    marker::PhantomData,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, Bytes};
+use h3::{
+    error::Code,
+    quic::{self, ConnectionErrorIncoming, StreamErrorIncoming, WriteBuf},
+};
+use monolake_core::quic::{ConnectionError, Dir, QuicConnection, StreamError, StreamId, VarInt};
+
+/// A QUIC connection carrying HTTP/3.
+pub(crate) struct Connection<B> {
+    conn: QuicConnection,
+    _marker: PhantomData<B>,
+}
+
+impl<B> Connection<B> {
+    pub(crate) fn new(conn: QuicConnection) -> Self {
+        Self {
+            conn,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<B> Clone for Connection<B> {
+    fn clone(&self) -> Self {
+        Self::new(self.conn.clone())
+    }
+}
+
+impl<B: Buf> quic::Connection<B> for Connection<B> {
+    type RecvStream = RecvStream;
+    type OpenStreams = Self;
+
+    fn poll_accept_recv(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::RecvStream, ConnectionErrorIncoming>> {
+        let id = ready!(self.conn.poll_accept(cx, Dir::Uni)).map_err(connection_error)?;
+        Poll::Ready(Ok(RecvStream::new(self.conn.clone(), id)))
+    }
+
+    fn poll_accept_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, ConnectionErrorIncoming>> {
+        let id = ready!(self.conn.poll_accept(cx, Dir::Bi)).map_err(connection_error)?;
+        Poll::Ready(Ok(BidiStream::new(&self.conn, id)))
+    }
+
+    fn opener(&self) -> Self::OpenStreams {
+        self.clone()
+    }
+}
+
+impl<B: Buf> quic::OpenStreams<B> for Connection<B> {
+    type BidiStream = BidiStream<B>;
+    type SendStream = SendStream<B>;
+
+    fn poll_open_bidi(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::BidiStream, StreamErrorIncoming>> {
+        let id = ready!(self.conn.poll_open(cx, Dir::Bi)).map_err(stream_error)?;
+        Poll::Ready(Ok(BidiStream::new(&self.conn, id)))
+    }
+
+    fn poll_open_send(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::SendStream, StreamErrorIncoming>> {
+        let id = ready!(self.conn.poll_open(cx, Dir::Uni)).map_err(stream_error)?;
+        Poll::Ready(Ok(SendStream::new(self.conn.clone(), id)))
+    }
+
+    fn close(&mut self, code: Code, reason: &[u8]) {
+        self.conn.close(var_int(code.value()), reason);
+    }
+}
+
+/// A bidirectional QUIC stream, split into its send and receive halves by `h3`.
+pub(crate) struct BidiStream<B> {
+    send: SendStream<B>,
+    recv: RecvStream,
+}
+
+impl<B> BidiStream<B> {
+    fn new(conn: &QuicConnection, id: StreamId) -> Self {
+        Self {
+            send: SendStream::new(conn.clone(), id),
+            recv: RecvStream::new(conn.clone(), id),
+        }
+    }
+}
+
+impl<B: Buf> quic::BidiStream<B> for BidiStream<B> {
+    type SendStream = SendStream<B>;
+    type RecvStream = RecvStream;
+
+    fn split(self) -> (Self::SendStream, Self::RecvStream) {
+        (self.send, self.recv)
+    }
+}
+
+impl<B> quic::RecvStream for BidiStream<B> {
+    type Buf = Bytes;
+
+    fn poll_data(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::Buf>, StreamErrorIncoming>> {
+        self.recv.poll_data(cx)
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.recv.stop_sending(error_code)
+    }
+
+    fn recv_id(&self) -> quic::StreamId {
+        self.recv.recv_id()
+    }
+}
+
+impl<B: Buf> quic::SendStream<B> for BidiStream<B> {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        self.send.poll_ready(cx)
+    }
+
+    fn send_data<T: Into<WriteBuf<B>>>(&mut self, data: T) -> Result<(), StreamErrorIncoming> {
+        self.send.send_data(data)
+    }
+
+    fn poll_finish(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        self.send.poll_finish(cx)
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        self.send.reset(reset_code)
+    }
+
+    fn send_id(&self) -> quic::StreamId {
+        self.send.send_id()
+    }
+}
+
+/// The receiving half of a QUIC stream. The peer is asked to stop sending if the stream is dropped
+/// before it is finished.
+pub(crate) struct RecvStream {
+    conn: QuicConnection,
+    id: StreamId,
+    done: bool,
+}
+
+impl RecvStream {
+    fn new(conn: QuicConnection, id: StreamId) -> Self {
+        Self {
+            conn,
+            id,
+            done: false,
+        }
+    }
+}
+
+impl quic::RecvStream for RecvStream {
+    type Buf = Bytes;
+
+    fn poll_data(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Self::Buf>, StreamErrorIncoming>> {
+        let read = ready!(self.conn.poll_read(cx, self.id, usize::MAX));
+        self.done = !matches!(read, Ok(Some(_)));
+        Poll::Ready(read.map_err(stream_error))
+    }
+
+    fn stop_sending(&mut self, error_code: u64) {
+        self.done = true;
+        self.conn.stop(self.id, var_int(error_code));
+    }
+
+    fn recv_id(&self) -> quic::StreamId {
+        h3_stream_id(self.id)
+    }
+}
+
+impl Drop for RecvStream {
+    fn drop(&mut self) {
+        if !self.done {
+            self.conn.stop(self.id, var_int(Code::H3_NO_ERROR.value()));
+        }
+    }
+}
+
+/// The sending half of a QUIC stream. The stream is finished if it is dropped before being
+/// finished or reset.
+pub(crate) struct SendStream<B> {
+    conn: QuicConnection,
+    id: StreamId,
+    writing: Option<WriteBuf<B>>,
+    done: bool,
+}
+
+impl<B> SendStream<B> {
+    fn new(conn: QuicConnection, id: StreamId) -> Self {
+        Self {
+            conn,
+            id,
+            writing: None,
+            done: false,
+        }
+    }
+}
+
+impl<B: Buf> quic::SendStream<B> for SendStream<B> {
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        if let Some(data) = self.writing.as_mut() {
+            while data.has_remaining() {
+                let written = ready!(self.conn.poll_write(cx, self.id, data.chunk()))
+                    .map_err(stream_error)?;
+                data.advance(written);
+            }
+        }
+        self.writing = None;
+        Poll::Ready(Ok(()))
+    }
+
+    fn send_data<T: Into<WriteBuf<B>>>(&mut self, data: T) -> Result<(), StreamErrorIncoming> {
+        if self.writing.is_some() {
+            return Err(StreamErrorIncoming::ConnectionErrorIncoming {
+                connection_error: ConnectionErrorIncoming::InternalError(
+                    "send_data called while send stream is not ready".to_string(),
+                ),
+            });
+        }
+        self.writing = Some(data.into());
+        Ok(())
+    }
+
+    fn poll_finish(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), StreamErrorIncoming>> {
+        self.done = true;
+        Poll::Ready(self.conn.finish(self.id).map_err(stream_error))
+    }
+
+    fn reset(&mut self, reset_code: u64) {
+        self.done = true;
+        self.conn.reset(self.id, var_int(reset_code));
+    }
+
+    fn send_id(&self) -> quic::StreamId {
+        h3_stream_id(self.id)
+    }
+}
+
+impl<B> Drop for SendStream<B> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.conn.finish(self.id);
+        }
+    }
+}
+
+fn h3_stream_id(id: StreamId) -> quic::StreamId {
+    u64::from(id)
+        .try_into()
+        .expect("quic stream ids are valid varints")
+}
+
+fn var_int(code: u64) -> VarInt {
+    VarInt::from_u64(code).unwrap_or(VarInt::MAX)
+}
+
+fn connection_error(e: StreamError) -> ConnectionErrorIncoming {
+    match e {
+        StreamError::ConnectionLost(ConnectionError::ApplicationClosed(close)) => {
+            ConnectionErrorIncoming::ApplicationClose {
+                error_code: close.error_code.into_inner(),
+            }
+        }
+        StreamError::ConnectionLost(ConnectionError::TimedOut) => ConnectionErrorIncoming::Timeout,
+        e => ConnectionErrorIncoming::Undefined(Arc::new(e)),
+    }
+}
+
+fn stream_error(e: StreamError) -> StreamErrorIncoming {
+    match e {
+        StreamError::ConnectionLost(_) => StreamErrorIncoming::ConnectionErrorIncoming {
+            connection_error: connection_error(e),
+        },
+        StreamError::Reset(code) | StreamError::Stopped(code) => {
+            StreamErrorIncoming::StreamTerminated {
+                error_code: code.into_inner(),
+            }
+        }
+        StreamError::Closed => StreamErrorIncoming::Unknown(Box::new(e)),
+    }
+}
@@ -0,0 +1,90 @@
+//! ACME HTTP-01 challenge responder.
+//!
+//! Requests for `/.well-known/acme-challenge/{token}` are answered with the key authorization of
+//! a pending challenge started by an ACME managed TLS listener (see
+//! [`AcmeConfig`](crate::tls::AcmeConfig)). All other requests, and tokens without a pending
+//! challenge, are passed to the inner handler.
+//!
+//! Since the ACME server validates over plain HTTP on port 80, this handler is meant to run on
+//! every HTTP listener, independently of which listener requested the certificate.
+use bytes::Bytes;
+use http::{HeaderValue, Request, Response, StatusCode};
+use monoio_http::common::body::FixedBody;
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Service,
+};
+
+use crate::tls::acme::challenges;
+
+const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Handler answering ACME HTTP-01 challenges before the inner handler.
+#[derive(Clone)]
+pub struct AcmeChallengeHandler<H> {
+    inner: H,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for AcmeChallengeHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let key_authorization = request
+            .uri()
+            .path()
+            .strip_prefix(CHALLENGE_PREFIX)
+            .and_then(|token| challenges().http01(token));
+        match key_authorization {
+            Some(key_authorization) => {
+                let response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header(
+                        http::header::CONTENT_TYPE,
+                        HeaderValue::from_static("application/octet-stream"),
+                    )
+                    .header(http::header::CONTENT_LENGTH, key_authorization.len())
+                    .body(H::Body::fixed_body(Some(Bytes::from(key_authorization))))
+                    .unwrap();
+                Ok((response, true))
+            }
+            None => self.inner.handle(request, ctx).await,
+        }
+    }
+}
+
+impl<H> AcmeChallengeHandler<H> {
+    pub fn layer<C>() -> impl FactoryLayer<C, H, Factory = Self> {
+        layer_fn(|_: &C, inner| AcmeChallengeHandler { inner })
+    }
+}
+
+impl<F: MakeService> MakeService for AcmeChallengeHandler<F> {
+    type Service = AcmeChallengeHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(AcmeChallengeHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for AcmeChallengeHandler<F> {
+    type Service = AcmeChallengeHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(AcmeChallengeHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+}
@@ -0,0 +1,82 @@
+//! `Alt-Svc` advertisement of alternative services.
+//!
+//! [`AltSvcHandler`] adds an `Alt-Svc` header (RFC 7838) to the HTTP/1.x and HTTP/2 responses of
+//! the inner handler, telling clients that the same origin is also served elsewhere, typically over
+//! HTTP/3 on a QUIC listener: `h3=":443"; ma=86400`.
+//!
+//! Responses which already carry an `Alt-Svc` header, e.g. set by the upstream, are left untouched,
+//! as are responses switching protocols and responses to HTTP/3 requests.
+use http::{header::ALT_SVC, HeaderValue, Request, StatusCode, Version};
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Service,
+};
+
+/// Handler adding an `Alt-Svc` header to the responses of the inner handler.
+#[derive(Clone)]
+pub struct AltSvcHandler<H> {
+    inner: H,
+    value: HeaderValue,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for AltSvcHandler<H>
+where
+    H: HttpHandler<CX, B>,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let advertise = request.version() < Version::HTTP_3;
+        let (mut response, cont) = self.inner.handle(request, ctx).await?;
+        if advertise && response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            response
+                .headers_mut()
+                .entry(ALT_SVC)
+                .or_insert_with(|| self.value.clone());
+        }
+        Ok((response, cont))
+    }
+}
+
+impl<F> AltSvcHandler<F> {
+    /// Returns a layer advertising `value` when one is given.
+    pub fn opt_layer<C>(
+        value: Option<HeaderValue>,
+    ) -> Option<impl FactoryLayer<C, F, Factory = Self>> {
+        value.map(|value| {
+            layer_fn(move |_: &C, inner| Self {
+                inner,
+                value: value.clone(),
+            })
+        })
+    }
+}
+
+impl<F: MakeService> MakeService for AltSvcHandler<F> {
+    type Service = AltSvcHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(AltSvcHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            value: self.value.clone(),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for AltSvcHandler<F> {
+    type Service = AltSvcHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(AltSvcHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            value: self.value.clone(),
+        })
+    }
+}
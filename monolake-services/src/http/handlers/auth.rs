@@ -0,0 +1,624 @@
+//! HTTP Basic and API key authentication of routes.
+//!
+//! [`AuthHandler`] authenticates the requests of the routes with an [`AuthRouteConfig`], with the
+//! users of an htpasswd file, or the API keys of a key file, or both. It suits internal tools
+//! which do not warrant an identity provider.
+//!
+//! # Key Components
+//!
+//! - [`AuthRouteConfig`]: The realm, credential files and user header of a route.
+//! - [`ApiKeyConfig`]: The key file of a route, and where requests carry their key.
+//!
+//! # Credential Files
+//!
+//! - htpasswd files have a `user:hash` line per user, with bcrypt (`$2y$`, `$2b$`, `$2a$`) or
+//!   argon2 (`$argon2id$`, `$argon2i$`, `$argon2d$`) password hashes, as written by `htpasswd -B`
+//!   or `argon2 -e`. Users with other hashes are ignored.
+//! - Key files have a `name:hash` line per key, the hash being the hex encoded SHA-256 of the key.
+//!   The name identifies the client of the key, it is the first 8 characters of the hash on lines
+//!   without one.
+//!
+//! Empty lines and lines starting with `#` are ignored.
+//!
+//! # Features
+//!
+//! - Credential files are read by each worker and checked for changes every `reload_secs`, changed
+//!   files are reloaded without restarting or reloading the proxy. Files which can not be read keep
+//!   their last loaded credentials
+//! - Passwords are verified off the worker threads, and valid passwords are cached per worker until
+//!   their file changes, so that their slow hashes are only verified once
+//! - Requests without valid credentials are answered with `401 Unauthorized`, with a `Basic`
+//!   challenge when the route has an htpasswd file, and with `503 Service Unavailable` when no
+//!   credential file of the route could ever be read
+//! - The name of the authenticated user or key is set in the `user_header` of the route, replacing
+//!   the header sent by the client
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(AuthHandler::layer())
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http::{header, HeaderName, HeaderValue, Request, StatusCode};
+use monoio_http::common::body::FixedBody;
+use monolake_core::{
+    http::{HttpHandler, ResponseWithContinue},
+    util::hash::sha256,
+};
+use serde::{Deserialize, Serialize};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, Service,
+};
+use tracing::{debug, info, warn};
+
+use super::route::{RouteConfig, RouteMatcher};
+use crate::{common::blocking, http::generate_response};
+
+// Valid passwords cached per route and worker.
+const MAX_VERIFIED: usize = 1024;
+
+/// Basic and API key authentication of a route.
+///
+/// ```toml
+/// [[servers.demo.routes]]
+/// path = "/admin"
+/// upstreams = [{ endpoint = { type = "uri", value = "http://127.0.0.1:9080" } }]
+/// auth = { htpasswd = "/etc/monolake/htpasswd", api_keys = { file = "/etc/monolake/keys" }, user_header = "x-user" }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthRouteConfig {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    /// Users allowed with HTTP Basic authentication.
+    #[serde(default)]
+    pub htpasswd: Option<PathBuf>,
+    #[serde(default)]
+    pub api_keys: Option<ApiKeyConfig>,
+    /// Header set to the name of the authenticated user or key.
+    #[serde(default)]
+    pub user_header: Option<String>,
+    /// Seconds between checks for changes of the credential files.
+    #[serde(default = "default_reload_secs")]
+    pub reload_secs: u64,
+}
+
+/// API keys allowed on a route.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// The key file.
+    pub file: PathBuf,
+    /// Header carrying the key.
+    #[serde(default = "default_key_header")]
+    pub header: String,
+    /// Query parameter carrying the key, keys are only read from the header without one.
+    #[serde(default)]
+    pub query: Option<String>,
+}
+
+fn default_realm() -> String {
+    "monolake".to_string()
+}
+
+fn default_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+const fn default_reload_secs() -> u64 {
+    5
+}
+
+/// Credentials parsed from a file.
+trait Credentials: Sized {
+    fn parse(content: &str, path: &Path) -> Self;
+}
+
+/// Users of an htpasswd file, with the passwords verified so far.
+struct Htpasswd {
+    users: HashMap<String, String>,
+    // SHA-256 of the `user:password` of valid passwords.
+    verified: RefCell<HashSet<String>>,
+}
+
+impl Credentials for Htpasswd {
+    fn parse(content: &str, path: &Path) -> Self {
+        let mut users = HashMap::new();
+        for line in credential_lines(content) {
+            let Some((user, hash)) = line.split_once(':') else {
+                continue;
+            };
+            if hash.starts_with("$2") || hash.starts_with("$argon2") {
+                users.insert(user.to_string(), hash.to_string());
+            } else {
+                warn!(
+                    "user {user} of {} ignored: unsupported hash",
+                    path.display()
+                );
+            }
+        }
+        Self {
+            users,
+            verified: Default::default(),
+        }
+    }
+}
+
+impl Htpasswd {
+    /// Verifies the password of a user on the blocking pool, as its hash is slow on purpose.
+    /// Passwords are refused when the pool is full.
+    async fn verify(&self, user: &str, password: &str) -> bool {
+        let Some(hash) = self.users.get(user) else {
+            return false;
+        };
+        let key = sha256(format!("{user}:{password}"));
+        if self.verified.borrow().contains(&key) {
+            return true;
+        }
+        let (hash, password) = (hash.clone(), password.to_string());
+        let valid = blocking::run(move || check_hash(&hash, &password))
+            .await
+            .unwrap_or_else(|| {
+                warn!("password of {user} not verified: blocking pool full");
+                false
+            });
+        if valid {
+            let mut verified = self.verified.borrow_mut();
+            if verified.len() >= MAX_VERIFIED {
+                verified.clear();
+            }
+            verified.insert(key);
+        }
+        valid
+    }
+}
+
+fn check_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        PasswordHash::new(hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    }
+}
+
+/// Names of the keys of a key file, by the hex encoded SHA-256 of the key.
+struct ApiKeys(HashMap<String, String>);
+
+impl Credentials for ApiKeys {
+    fn parse(content: &str, _: &Path) -> Self {
+        let keys = credential_lines(content)
+            .map(|line| match line.split_once(':') {
+                Some((name, hash)) => (hash.trim().to_ascii_lowercase(), name.to_string()),
+                None => (
+                    line.to_ascii_lowercase(),
+                    line.chars().take(8).collect::<String>(),
+                ),
+            })
+            .collect();
+        Self(keys)
+    }
+}
+
+fn credential_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Credentials loaded from a file, reloaded when the file changes.
+struct CredentialFile<T> {
+    path: PathBuf,
+    reload_interval: Duration,
+    loaded: RefCell<Option<Loaded<T>>>,
+    last_check: Cell<Option<Instant>>,
+}
+
+/// Credentials with the modification time and length of their file.
+struct Loaded<T> {
+    credentials: Rc<T>,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl<T: Credentials> CredentialFile<T> {
+    fn new(path: PathBuf, reload_interval: Duration) -> Self {
+        Self {
+            path,
+            reload_interval,
+            loaded: RefCell::new(None),
+            last_check: Cell::new(None),
+        }
+    }
+
+    /// The credentials of the file, `None` until it could be read.
+    async fn get(&self) -> Option<Rc<T>> {
+        let now = Instant::now();
+        let due = self
+            .last_check
+            .get()
+            .map_or(true, |last| now >= last + self.reload_interval);
+        if due {
+            self.last_check.set(Some(now));
+            if let Err(e) = self.reload().await {
+                warn!("credential file {} not loaded: {e}", self.path.display());
+            }
+        }
+        self.loaded
+            .borrow()
+            .as_ref()
+            .map(|loaded| loaded.credentials.clone())
+    }
+
+    async fn reload(&self) -> std::io::Result<()> {
+        // since monoio has not support statx, we have to use std
+        let metadata = std::fs::metadata(&self.path)?;
+        let modified = metadata.modified().ok();
+        let unchanged = self.loaded.borrow().as_ref().is_some_and(|loaded| {
+            modified.is_some() && loaded.modified == modified && loaded.len == metadata.len()
+        });
+        if unchanged {
+            return Ok(());
+        }
+        let content = monolake_core::util::file_read(&self.path).await?;
+        let credentials = T::parse(&String::from_utf8_lossy(&content), &self.path);
+        if self.loaded.borrow().is_some() {
+            info!("credential file {} reloaded", self.path.display());
+        }
+        *self.loaded.borrow_mut() = Some(Loaded {
+            credentials: Rc::new(credentials),
+            modified,
+            len: metadata.len(),
+        });
+        Ok(())
+    }
+}
+
+/// Authentication of a route in a worker.
+struct RouteAuth {
+    challenge: Option<HeaderValue>,
+    htpasswd: Option<CredentialFile<Htpasswd>>,
+    api_keys: Option<(ApiKeyConfig, CredentialFile<ApiKeys>)>,
+    user_header: Option<HeaderName>,
+}
+
+/// Outcome of the authentication of a request.
+enum Authenticated {
+    User(String),
+    Denied,
+    Unavailable,
+}
+
+impl RouteAuth {
+    fn new(config: &AuthRouteConfig) -> Self {
+        let reload_interval = Duration::from_secs(config.reload_secs);
+        let htpasswd = config
+            .htpasswd
+            .clone()
+            .map(|path| CredentialFile::new(path, reload_interval));
+        let challenge = htpasswd
+            .as_ref()
+            .and_then(|_| HeaderValue::from_str(&format!("Basic realm=\"{}\"", config.realm)).ok());
+        let user_header = config.user_header.as_ref().and_then(|name| {
+            HeaderName::from_bytes(name.as_bytes())
+                .inspect_err(|_| warn!("invalid user header {name} ignored"))
+                .ok()
+        });
+        Self {
+            challenge,
+            htpasswd,
+            api_keys: config.api_keys.clone().map(|api_keys| {
+                let file = CredentialFile::new(api_keys.file.clone(), reload_interval);
+                (api_keys, file)
+            }),
+            user_header,
+        }
+    }
+
+    async fn authenticate<B>(&self, request: &Request<B>) -> Authenticated {
+        let mut available = false;
+        if let Some((config, file)) = &self.api_keys {
+            if let Some(keys) = file.get().await {
+                available = true;
+                let key = api_key(request, config);
+                if let Some(name) = key.and_then(|key| keys.0.get(&sha256(key))) {
+                    return Authenticated::User(name.clone());
+                }
+            }
+        }
+        if let Some(file) = &self.htpasswd {
+            if let Some(htpasswd) = file.get().await {
+                available = true;
+                if let Some((user, password)) = basic_credentials(request) {
+                    if htpasswd.verify(&user, &password).await {
+                        return Authenticated::User(user);
+                    }
+                }
+            }
+        }
+        if available {
+            Authenticated::Denied
+        } else {
+            Authenticated::Unavailable
+        }
+    }
+}
+
+/// The API key of a request, from its header or else its query.
+fn api_key<'a, B>(request: &'a Request<B>, config: &ApiKeyConfig) -> Option<&'a str> {
+    if let Some(key) = request.headers().get(config.header.as_str()) {
+        return key.to_str().ok();
+    }
+    let name = config.query.as_deref()?;
+    request.uri().query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then_some(value)
+    })
+}
+
+/// The user and password of the `Authorization` header of a request.
+fn basic_credentials<B>(request: &Request<B>) -> Option<(String, String)> {
+    let value = request
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(STANDARD.decode(token.trim()).ok()?).ok()?;
+    let (user, password) = credentials.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Authentication settings of the routes of a server.
+type Routes = RouteMatcher<AuthRouteConfig>;
+
+/// Handler authenticating requests with HTTP Basic credentials or API keys.
+///
+/// See the [module level documentation](crate::http::handlers::auth) for details.
+pub struct AuthHandler<H> {
+    inner: H,
+    routes: Arc<Routes>,
+    auths: Rc<RouteMatcher<RouteAuth>>,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for AuthHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (mut request, ctx): (Request<B>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let Some(auth) = self.auths.at(request.uri().path()) else {
+            return self.inner.handle(request, ctx).await;
+        };
+        match auth.authenticate(&request).await {
+            Authenticated::User(user) => {
+                if let Some(name) = &auth.user_header {
+                    request.headers_mut().remove(name);
+                    if let Ok(value) = HeaderValue::from_str(&user) {
+                        request.headers_mut().insert(name, value);
+                    }
+                }
+                self.inner.handle(request, ctx).await
+            }
+            Authenticated::Denied => {
+                debug!("request to {} not authenticated", request.uri().path());
+                let mut response = generate_response(StatusCode::UNAUTHORIZED, false);
+                if let Some(challenge) = &auth.challenge {
+                    response
+                        .headers_mut()
+                        .insert(header::WWW_AUTHENTICATE, challenge.clone());
+                }
+                Ok((response, true))
+            }
+            Authenticated::Unavailable => Ok((
+                generate_response(StatusCode::SERVICE_UNAVAILABLE, false),
+                true,
+            )),
+        }
+    }
+}
+
+impl<F> AuthHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = AuthHandlerFactory<F>>
+    where
+        C: Param<Vec<RouteConfig>>,
+    {
+        layer_fn(|c: &C, inner| {
+            let routes: Vec<RouteConfig> = c.param();
+            let routes = routes.into_iter().map(|route| (route.path, route.auth));
+            AuthHandlerFactory {
+                inner,
+                routes: Arc::new(RouteMatcher::new("authentication", routes)),
+            }
+        })
+    }
+}
+
+/// Factory for [`AuthHandler`].
+pub struct AuthHandlerFactory<F> {
+    inner: F,
+    routes: Arc<Routes>,
+}
+
+impl<F> AuthHandlerFactory<F> {
+    // Keep the loaded credentials when the routes did not change.
+    fn make_auths<H>(&self, old: Option<&AuthHandler<H>>) -> Rc<RouteMatcher<RouteAuth>> {
+        match old {
+            Some(old) if old.routes == self.routes => old.auths.clone(),
+            _ => Rc::new(self.routes.map(|_, route| RouteAuth::new(route))),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for AuthHandlerFactory<F> {
+    type Service = AuthHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(AuthHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            routes: self.routes.clone(),
+            auths: self.make_auths(old),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for AuthHandlerFactory<F> {
+    type Service = AuthHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(AuthHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            routes: self.routes.clone(),
+            auths: self.make_auths(old),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use monoio_http::common::body::HttpBody;
+
+    use super::*;
+    use crate::testing::{write_file, Recorder, TestCtx};
+
+    #[monoio::test]
+    async fn credentials() {
+        let argon2 = Argon2::default()
+            .hash_password(b"hunter2", &SaltString::from_b64("c2FsdHNhbHQ").unwrap())
+            .unwrap()
+            .to_string();
+        let content = format!(
+            "# users\nalice:{}\nbob:{argon2}\ncarol:{{SHA}}x\n",
+            bcrypt::hash("secret", 4).unwrap()
+        );
+        let htpasswd = Htpasswd::parse(&content, Path::new("htpasswd"));
+        assert_eq!(htpasswd.users.len(), 2);
+        assert!(htpasswd.verify("alice", "secret").await);
+        assert!(htpasswd.verified.borrow().len() == 1);
+        assert!(htpasswd.verify("alice", "secret").await);
+        assert!(!htpasswd.verify("alice", "wrong").await);
+        assert!(htpasswd.verify("bob", "hunter2").await);
+        assert!(!htpasswd.verify("carol", "x").await);
+
+        let keys = ApiKeys::parse(
+            &format!("ci:{}\n{}\n", sha256("k1"), sha256("k2")),
+            Path::new("keys"),
+        );
+        assert_eq!(keys.0[&sha256("k1")], "ci");
+        assert_eq!(keys.0[&sha256("k2")], sha256("k2")[..8]);
+
+        let config = ApiKeyConfig {
+            file: PathBuf::new(),
+            header: default_key_header(),
+            query: Some("key".to_string()),
+        };
+        let request = Request::get("/?a=1&key=k1").body(()).unwrap();
+        assert_eq!(api_key(&request, &config), Some("k1"));
+        let request = Request::get("/")
+            .header(header::AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0")
+            .body(())
+            .unwrap();
+        assert_eq!(api_key(&request, &config), None);
+        assert_eq!(
+            basic_credentials(&request),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+    }
+
+    fn handler(htpasswd: String) -> AuthHandler<Recorder> {
+        let config: AuthRouteConfig = serde_json::from_value(serde_json::json!({
+            "htpasswd": htpasswd,
+            "user_header": "x-user",
+        }))
+        .unwrap();
+        let routes = Arc::new(RouteMatcher::new(
+            "authentication",
+            [("/admin".to_string(), Some(config))],
+        ));
+        AuthHandler {
+            inner: Recorder::default(),
+            auths: Rc::new(routes.map(|_, route| RouteAuth::new(route))),
+            routes,
+        }
+    }
+
+    fn request(authorization: Option<&str>) -> Request<HttpBody> {
+        let mut request = Request::get("/admin").header("x-user", "mallory");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+        request.body(HttpBody::fixed_body(None)).unwrap()
+    }
+
+    #[monoio::test]
+    async fn failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let content = format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap());
+        let alice = handler(write_file(dir.path(), "htpasswd", content));
+        let ctx = || TestCtx::new("10.0.0.1:1234");
+
+        // alice:wrong, bob:secret, and a malformed header.
+        for authorization in [
+            None,
+            Some("Basic YWxpY2U6d3Jvbmc="),
+            Some("Basic Ym9iOnNlY3JldA=="),
+            Some("Basic !"),
+        ] {
+            let (response, _) = alice.call((request(authorization), ctx())).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                response.headers()[header::WWW_AUTHENTICATE],
+                "Basic realm=\"monolake\""
+            );
+        }
+        assert!(alice.inner.take().is_empty());
+
+        let (response, _) = alice
+            .call((request(Some("Basic YWxpY2U6c2VjcmV0")), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(alice.inner.take()[0].headers()["x-user"], "alice");
+
+        // Routes whose credentials were never read are unavailable rather than open.
+        let missing = handler(dir.path().join("missing").to_str().unwrap().to_string());
+        let (response, _) = missing
+            .call((request(Some("Basic YWxpY2U6c2VjcmV0")), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(missing.inner.take().is_empty());
+    }
+}
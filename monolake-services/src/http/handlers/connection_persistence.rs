@@ -11,10 +11,14 @@
 //! # Features
 //!
 //! - Automatic detection and handling of keep-alive support for incoming requests
-//! - Version-specific handling for HTTP/1.0, HTTP/1.1, and HTTP/2
+//! - Version-specific handling for HTTP/1.0, HTTP/1.1, HTTP/2 and HTTP/3
 //! - Modification of request and response headers to ensure proper keep-alive behavior
 //! - Seamless integration with `service_async` for easy composition in service stacks
 //! - Support for upgrading HTTP/1.0 connections to HTTP/1.1-like behavior
+//! - `Connection: Upgrade` is preserved on upgrade requests, and connections switching protocols or
+//!   turned into CONNECT tunnels are not reused for further requests
+//! - Responses to HTTP/1.x requests carry the request version, whichever version the upstream
+//!   answered with
 //!
 //! # Usage
 //!
@@ -30,7 +34,7 @@
 //!             route::RouteConfig, ConnectionReuseHandler, ContentHandler, RewriteAndRouteHandler,
 //!             UpstreamHandler,
 //!         },
-//!         HttpServerTimeout,
+//!         Http2Settings, HttpServerTimeout, RequestLimitConfig,
 //!     },
 //! };
 //! use service_async::{layer::FactoryLayer, stack::FactoryStack, Param};
@@ -49,6 +53,16 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<Http2Settings> for DummyConfig {
+//!     fn param(&self) -> Http2Settings {
+//!         Http2Settings::default()
+//!     }
+//! }
+//! impl Param<RequestLimitConfig> for DummyConfig {
+//!     fn param(&self) -> RequestLimitConfig {
+//!         RequestLimitConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
@@ -69,7 +83,7 @@
 //!
 //! - Efficient header manipulation to minimize overhead
 //! - Optimized handling for HTTP/2, which has built-in connection persistence
-use http::{Request, Version};
+use http::{Request, StatusCode, Version};
 use monolake_core::http::{HttpHandler, ResponseWithContinue};
 use service_async::{
     layer::{layer_fn, FactoryLayer},
@@ -77,7 +91,10 @@ use service_async::{
 };
 use tracing::debug;
 
-use crate::http::{CLOSE, CLOSE_VALUE, KEEPALIVE, KEEPALIVE_VALUE};
+use crate::http::{
+    upgrade::{is_upgrade_request, OnUpgrade},
+    CLOSE, CLOSE_VALUE, KEEPALIVE, KEEPALIVE_VALUE,
+};
 
 /// Handler for managing HTTP connection persistence and keep-alive behavior.
 ///
@@ -118,6 +135,9 @@ where
                 // send
                 let (mut response, mut cont) = self.inner.handle(request, ctx).await?;
                 cont &= keepalive;
+                // the upstream may speak another version, e.g. HTTP/2, which the HTTP/1.1
+                // encoder refuses to write
+                *response.version_mut() = version;
 
                 // modify back and make sure reply keepalive if client want it and server
                 // support it.
@@ -133,12 +153,23 @@ where
                 Ok((response, cont))
             }
             Version::HTTP_11 => {
-                // remove connection header
-                let _ = request.headers_mut().remove(http::header::CONNECTION);
+                // remove connection header, unless the upstream needs it to switch protocols
+                if !is_upgrade_request(&request) {
+                    let _ = request.headers_mut().remove(http::header::CONNECTION);
+                }
 
                 // send
                 let (mut response, mut cont) = self.inner.handle(request, ctx).await?;
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS
+                    || response.extensions().get::<OnUpgrade>().is_some()
+                {
+                    // the connection no longer carries http exchanges
+                    return Ok((response, false));
+                }
                 cont &= keepalive;
+                // the upstream may speak another version, e.g. HTTP/2, which the HTTP/1.1
+                // encoder refuses to write
+                *response.version_mut() = version;
 
                 // modify back and make sure reply keepalive if client want it and server
                 // support it.
@@ -151,7 +182,8 @@ where
                 }
                 Ok((response, cont))
             }
-            Version::HTTP_2 => {
+            // multiplexed connections are kept regardless of the request
+            Version::HTTP_2 | Version::HTTP_3 => {
                 let (response, _) = self.inner.handle(request, ctx).await?;
                 Ok((response, true))
             }
@@ -212,3 +244,40 @@ fn is_conn_keepalive(headers: &http::HeaderMap<http::HeaderValue>, version: Vers
         _ => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use http::Response;
+
+    use super::*;
+
+    struct Upstream(Version);
+
+    impl Service<(Request<()>, ())> for Upstream {
+        type Response = ResponseWithContinue<()>;
+        type Error = Infallible;
+
+        async fn call(&self, _: (Request<()>, ())) -> Result<Self::Response, Self::Error> {
+            let mut response = Response::new(());
+            *response.version_mut() = self.0;
+            Ok((response, true))
+        }
+    }
+
+    #[monoio::test]
+    async fn response_version() {
+        for version in [Version::HTTP_10, Version::HTTP_11] {
+            for upstream in [Version::HTTP_11, Version::HTTP_2] {
+                let handler = ConnectionReuseHandler {
+                    inner: Upstream(upstream),
+                };
+                let mut request = Request::new(());
+                *request.version_mut() = version;
+                let (response, _) = handler.call((request, ())).await.unwrap();
+                assert_eq!(response.version(), version);
+            }
+        }
+    }
+}
@@ -30,7 +30,7 @@
 //!             route::RouteConfig, ConnectionReuseHandler, ContentHandler, RewriteAndRouteHandler,
 //!             UpstreamHandler,
 //!         },
-//!         HttpServerTimeout,
+//!         Http2Settings, HttpServerTimeout, RequestLimitConfig,
 //!     },
 //! };
 //! use service_async::{layer::FactoryLayer, stack::FactoryStack, Param};
@@ -49,6 +49,16 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<Http2Settings> for DummyConfig {
+//!     fn param(&self) -> Http2Settings {
+//!         Http2Settings::default()
+//!     }
+//! }
+//! impl Param<RequestLimitConfig> for DummyConfig {
+//!     fn param(&self) -> RequestLimitConfig {
+//!         RequestLimitConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
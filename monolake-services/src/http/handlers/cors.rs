@@ -0,0 +1,504 @@
+//! Cross-Origin Resource Sharing of HTTP routes.
+//!
+//! [`CorsHandler`] implements CORS for the routes with a [`CorsConfig`], so that backends do not
+//! have to. Preflight requests are answered by the proxy, and the responses to cross-origin
+//! requests are given the `Access-Control-*` headers allowing the origin of the request to read
+//! them.
+//!
+//! # Behavior
+//!
+//! - Requests without an `Origin` header, and requests to routes without CORS settings, are passed
+//!   through unchanged.
+//! - Preflight requests, `OPTIONS` requests with an `Access-Control-Request-Method` header, are
+//!   answered with `204 No Content` and the allowed methods, headers and max age when their origin,
+//!   method and headers are allowed, and with `403 Forbidden` otherwise.
+//! - Other requests are passed to the inner handler. Their responses are given the
+//!   `Access-Control-Allow-Origin`, `Access-Control-Allow-Credentials` and
+//!   `Access-Control-Expose-Headers` headers when their origin is allowed. The `Access-Control-*`
+//!   headers of the backend are replaced.
+//! - Origins are allowed by exact match, or by a regular expression matching the whole origin. `*`
+//!   allows any origin, but only without credentials: browsers refuse the wildcard with
+//!   credentials, and echoing any origin would let any site read the responses of its users.
+//! - Responses which depend on the origin get `Vary: Origin`.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(CorsHandler::layer())
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::sync::Arc;
+
+use http::{
+    header::{self, HeaderName},
+    HeaderMap, HeaderValue, Method, Request, StatusCode,
+};
+use monoio_http::common::body::FixedBody;
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, Service,
+};
+use tracing::{debug, warn};
+
+use super::route::{RouteConfig, RouteMatcher};
+use crate::http::generate_response;
+
+/// CORS settings of a route.
+///
+/// ```toml
+/// [[servers.demo.routes]]
+/// path = "/api/{*p}"
+/// upstreams = [{ endpoint = { type = "uri", value = "http://127.0.0.1:9080" } }]
+/// cors = { allow_origins = ["https://app.example.com"], allow_origin_regexes = ['^https://[a-z0-9-]+\.example\.com$'], allow_methods = ["GET", "POST"], allow_headers = ["content-type"], allow_credentials = true, max_age_secs = 600 }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// Origins allowed by exact match, `*` allows any origin when credentials are not allowed.
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    /// Regular expressions of the origins allowed, matching whole origins.
+    #[serde(default)]
+    pub allow_origin_regexes: Vec<String>,
+    /// Methods allowed by preflight requests.
+    #[serde(default = "default_allow_methods")]
+    pub allow_methods: Vec<String>,
+    /// Request headers allowed by preflight requests, `*` allows any header.
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// Response headers exposed to the origin.
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// Allow requests with credentials: cookies, TLS client certificates and `Authorization`.
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds preflight responses may be cached for by clients.
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+/// Invalid value of [`CorsConfig`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidCorsConfig {
+    #[error("allow_origins `*` can not be combined with allow_credentials, list the origins")]
+    CredentialedAnyOrigin,
+}
+
+impl CorsConfig {
+    /// Check that any origin is only allowed without credentials.
+    pub fn validate(&self) -> Result<(), InvalidCorsConfig> {
+        if self.allow_credentials && self.allow_origins.iter().any(|origin| origin == "*") {
+            return Err(InvalidCorsConfig::CredentialedAnyOrigin);
+        }
+        Ok(())
+    }
+}
+
+fn default_allow_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST"].map(String::from).to_vec()
+}
+
+/// CORS settings of a route, ready to be checked.
+struct Cors {
+    any_origin: bool,
+    origins: Vec<String>,
+    regexes: Vec<Regex>,
+    methods: Vec<Method>,
+    any_header: bool,
+    // Lowercase names of the allowed request headers.
+    headers: Vec<String>,
+    allow_methods: Option<HeaderValue>,
+    allow_headers: Option<HeaderValue>,
+    expose_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age: Option<HeaderValue>,
+}
+
+fn join(values: &[String]) -> Option<HeaderValue> {
+    if values.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&values.join(", ")).ok()
+}
+
+impl Cors {
+    fn new(config: &CorsConfig, path: &str) -> Self {
+        let regexes = config
+            .allow_origin_regexes
+            .iter()
+            .filter_map(|pattern| match Regex::new(&format!("^(?:{pattern})$")) {
+                Ok(regex) => Some(regex),
+                Err(e) => {
+                    warn!("cors origin regex {pattern} of route {path} ignored: {e}");
+                    None
+                }
+            })
+            .collect();
+        let methods = config
+            .allow_methods
+            .iter()
+            .filter_map(|method| Method::from_bytes(method.as_bytes()).ok())
+            .collect();
+        let any_header = config.allow_headers.iter().any(|name| name == "*");
+        let headers: Vec<_> = config
+            .allow_headers
+            .iter()
+            .filter(|name| *name != "*")
+            .map(|name| name.to_ascii_lowercase())
+            .collect();
+        // Credentials are never shared with any origin, whatever the config validation.
+        let any_origin = config.allow_origins.iter().any(|origin| origin == "*");
+        if any_origin && config.allow_credentials {
+            warn!("cors any origin of route {path} ignored: credentials are allowed");
+        }
+        Self {
+            any_origin: any_origin && !config.allow_credentials,
+            origins: config.allow_origins.clone(),
+            regexes,
+            methods,
+            any_header,
+            allow_methods: join(&config.allow_methods),
+            allow_headers: join(&headers),
+            headers,
+            expose_headers: join(&config.expose_headers),
+            allow_credentials: config.allow_credentials,
+            max_age: config.max_age_secs.map(HeaderValue::from),
+        }
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        self.any_origin
+            || self.origins.iter().any(|allowed| allowed == origin)
+            || self.regexes.iter().any(|regex| regex.is_match(origin))
+    }
+
+    /// The `Access-Control-Allow-Origin` of an allowed origin.
+    fn allow_origin(&self, origin: &HeaderValue) -> HeaderValue {
+        if self.any_origin {
+            HeaderValue::from_static("*")
+        } else {
+            origin.clone()
+        }
+    }
+
+    /// Whether responses depend on the origin of requests.
+    fn varies(&self) -> bool {
+        !self.any_origin
+    }
+
+    /// The headers of the response to a preflight request, `None` when the request is not allowed.
+    fn preflight(&self, origin: &HeaderValue, headers: &HeaderMap) -> Option<HeaderMap> {
+        let method = headers.get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+        let method = Method::from_bytes(method.as_bytes()).ok()?;
+        if !self.methods.contains(&method) {
+            debug!("cors preflight of method {method} denied");
+            return None;
+        }
+        let requested = headers
+            .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty());
+        let mut requested_headers = Vec::new();
+        for name in requested {
+            let name = name.to_ascii_lowercase();
+            if !self.any_header && !self.headers.contains(&name) {
+                debug!("cors preflight of header {name} denied");
+                return None;
+            }
+            requested_headers.push(name);
+        }
+
+        let mut response = HeaderMap::new();
+        self.add_origin(origin, &mut response);
+        if let Some(methods) = &self.allow_methods {
+            response.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods.clone());
+        }
+        let allow_headers = if self.any_header {
+            // Requested headers are echoed as the wildcard is not honored with credentials.
+            HeaderValue::from_str(&requested_headers.join(", ")).ok()
+        } else {
+            self.allow_headers.clone()
+        };
+        if let Some(allow_headers) = allow_headers.filter(|value| !value.is_empty()) {
+            response.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = &self.max_age {
+            response.insert(header::ACCESS_CONTROL_MAX_AGE, max_age.clone());
+        }
+        if self.any_header {
+            response.append(
+                header::VARY,
+                HeaderValue::from_static("Access-Control-Request-Headers"),
+            );
+        }
+        Some(response)
+    }
+
+    fn add_origin(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            self.allow_origin(origin),
+        );
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if self.varies() {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+
+    /// Replaces the CORS headers of a response to a request from `origin`.
+    fn decorate(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        const CORS_HEADERS: [HeaderName; 6] = [
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            header::ACCESS_CONTROL_MAX_AGE,
+        ];
+        for name in CORS_HEADERS {
+            headers.remove(name);
+        }
+        match origin {
+            Some(origin) => {
+                self.add_origin(origin, headers);
+                if let Some(expose_headers) = &self.expose_headers {
+                    headers.insert(
+                        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                        expose_headers.clone(),
+                    );
+                }
+            }
+            None if self.varies() => {
+                headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            }
+            None => {}
+        }
+    }
+}
+
+/// CORS settings of the routes of a server.
+type Routes = RouteMatcher<Cors>;
+
+/// Handler implementing CORS for the routes of a server.
+///
+/// See the [module level documentation](crate::http::handlers::cors) for details.
+pub struct CorsHandler<H> {
+    inner: H,
+    routes: Arc<Routes>,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for CorsHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let Some(cors) = self.routes.at(request.uri().path()) else {
+            return self.inner.handle(request, ctx).await;
+        };
+        // Only the origins allowed are given CORS headers.
+        let origin = request
+            .headers()
+            .get(header::ORIGIN)
+            .filter(|origin| {
+                origin
+                    .to_str()
+                    .is_ok_and(|origin| cors.allows_origin(origin))
+            })
+            .cloned();
+
+        if request.method() == Method::OPTIONS
+            && request
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+            && request.headers().contains_key(header::ORIGIN)
+        {
+            let preflight = origin
+                .as_ref()
+                .and_then(|origin| cors.preflight(origin, request.headers()));
+            let Some(headers) = preflight else {
+                debug!("cors preflight to {} denied", request.uri().path());
+                return Ok((generate_response(StatusCode::FORBIDDEN, false), true));
+            };
+            let mut response = generate_response(StatusCode::NO_CONTENT, false);
+            response.headers_mut().extend(headers);
+            return Ok((response, true));
+        }
+
+        let (mut response, cont) = self.inner.handle(request, ctx).await?;
+        cors.decorate(origin.as_ref(), response.headers_mut());
+        Ok((response, cont))
+    }
+}
+
+impl<F> CorsHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = CorsHandlerFactory<F>>
+    where
+        C: Param<Vec<RouteConfig>>,
+    {
+        layer_fn(|c: &C, inner| {
+            let routes: Vec<RouteConfig> = c.param();
+            let routes = routes.into_iter().map(|route| {
+                let cors = route.cors.as_ref().map(|cors| Cors::new(cors, &route.path));
+                (route.path, cors)
+            });
+            CorsHandlerFactory {
+                inner,
+                routes: Arc::new(RouteMatcher::new("cors settings", routes)),
+            }
+        })
+    }
+}
+
+/// Factory for [`CorsHandler`].
+pub struct CorsHandlerFactory<F> {
+    inner: F,
+    routes: Arc<Routes>,
+}
+
+impl<F: MakeService> MakeService for CorsHandlerFactory<F> {
+    type Service = CorsHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(CorsHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for CorsHandlerFactory<F> {
+    type Service = CorsHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(CorsHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preflight(method: &'static str, headers: &'static str) -> HeaderMap {
+        let mut request = HeaderMap::new();
+        request.insert(
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            HeaderValue::from_static(method),
+        );
+        request.insert(
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            HeaderValue::from_static(headers),
+        );
+        request
+    }
+
+    #[test]
+    fn cors_checks() {
+        let config: CorsConfig = serde_json::from_value(serde_json::json!({
+            "allow_origins": ["https://app.example.com"],
+            "allow_origin_regexes": [r"https://[a-z]+\.example\.org", "("],
+            "allow_headers": ["Content-Type", "X-Request-Id"],
+            "allow_credentials": true,
+            "max_age_secs": 600,
+        }))
+        .unwrap();
+        let cors = Cors::new(&config, "/api");
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(cors.allows_origin("https://docs.example.org"));
+        assert!(!cors.allows_origin("https://docs.example.org.evil.com"));
+        assert!(!cors.allows_origin("https://example.com"));
+
+        let origin = HeaderValue::from_static("https://app.example.com");
+        let headers = cors
+            .preflight(&origin, &preflight("POST", "content-type, x-request-id"))
+            .unwrap();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, HEAD, POST"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-request-id"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(headers[header::VARY], "Origin");
+        assert!(cors.preflight(&origin, &preflight("DELETE", "")).is_none());
+        assert!(cors
+            .preflight(&origin, &preflight("GET", "authorization"))
+            .is_none());
+
+        // Any origin without credentials is not echoed.
+        let config: CorsConfig = serde_json::from_value(serde_json::json!({
+            "allow_origins": ["*"],
+            "allow_headers": ["*"],
+            "expose_headers": ["x-total"],
+        }))
+        .unwrap();
+        let cors = Cors::new(&config, "/api");
+        let headers = cors
+            .preflight(&origin, &preflight("GET", "authorization"))
+            .unwrap();
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization"
+        );
+        let mut response = HeaderMap::new();
+        response.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("https://backend.example.com"),
+        );
+        cors.decorate(Some(&origin), &mut response);
+        assert_eq!(response[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(response[header::ACCESS_CONTROL_EXPOSE_HEADERS], "x-total");
+        assert!(!response.contains_key(header::VARY));
+    }
+
+    #[test]
+    fn credentialed_any_origin() {
+        let config: CorsConfig = serde_json::from_value(serde_json::json!({
+            "allow_origins": ["*", "https://app.example.com"],
+            "allow_credentials": true,
+        }))
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(InvalidCorsConfig::CredentialedAnyOrigin)
+        );
+        // Only the origins listed are allowed, and never echoed at large.
+        let cors = Cors::new(&config, "/api");
+        assert!(cors.allows_origin("https://app.example.com"));
+        assert!(!cors.allows_origin("https://evil.example.com"));
+        let origin = HeaderValue::from_static("https://app.example.com");
+        let mut response = HeaderMap::new();
+        cors.decorate(Some(&origin), &mut response);
+        assert_eq!(response[header::ACCESS_CONTROL_ALLOW_ORIGIN], origin);
+        assert_eq!(response[header::VARY], "Origin");
+    }
+}
@@ -0,0 +1,1014 @@
+//! External authorization of HTTP requests.
+//!
+//! [`ExtAuthzHandler`] delegates the authorization of requests to an external policy service
+//! before they are routed. The service is sent the metadata of each request, and optionally a
+//! bounded prefix of its body, and either allows the request, with headers to add to it, or denies
+//! it with the response to answer.
+//!
+//! # Key Components
+//!
+//! - [`ExtAuthzConfig`]: Service, protocol, headers, body, timeout and failure mode of a server.
+//! - [`AuthzProtocol`]: The protocol of the authorization service:
+//!   - `grpc`: `Check` of the `envoy.service.auth.v3.Authorization` gRPC service. The request
+//!     method, path, host, scheme, headers, body and client address are sent in the
+//!     `AttributeContext` of the `CheckRequest`, and the `CheckResponse` tells the headers to set,
+//!     append or remove on allowed requests, or the status, headers and body of the denial.
+//!   - `http`: The request is sent to the service with the same method, its path appended to the
+//!     path of the service URI. A `2xx` status allows the request, with the headers of the answer
+//!     listed in `upstream_headers` set on it, any other status denies it and the answer of the
+//!     service is returned to the client.
+//! - [`AuthzBodyConfig`]: The body prefix sent to the service.
+//!
+//! # Features
+//!
+//! - Only the request headers listed in `request_headers` are sent when the list is not empty
+//! - Only the headers listed in `upstream_headers` are taken from allowing answers of the HTTP
+//!   protocol, none of them by default
+//! - Request bodies are sent up to `max_bytes`. Larger bodies are answered with `413 Payload Too
+//!   Large`, or sent truncated when partial bodies are allowed. At most `max_bytes` are read ahead
+//!   of the check, the rest of larger bodies is streamed once the request is allowed
+//! - When the service fails or does not answer in time, requests are answered with `403 Forbidden`
+//!   with the `closed` failure mode, the default, and allowed with the `open` failure mode
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(ExtAuthzHandler::opt_layer(ext_authz))
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::{rc::Rc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use http::{
+    header::{self, HeaderName},
+    request::Parts,
+    HeaderValue, Request, Response, StatusCode, Uri,
+};
+use monoio_http::common::body::{Body, FixedBody, HttpBody, StreamHint};
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    http::{HttpHandler, ResponseWithContinue},
+    util::uri_serde,
+};
+use serde::{Deserialize, Serialize};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, ParamMaybeRef, ParamRef, Service,
+};
+use tracing::{debug, warn};
+
+pub use super::global_rate_limit::FailureMode;
+use crate::{
+    common::rate_limit::BucketKey,
+    http::{
+        client::{ClientError, HttpClient},
+        generate_response,
+        grpc::{self, ProtoField},
+    },
+};
+
+const CHECK_PATH: &str = "/envoy.service.auth.v3.Authorization/Check";
+// Headers describing a message or a connection rather than a request.
+const HOP_HEADERS: [HeaderName; 6] = [
+    header::CONNECTION,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::TE,
+    header::UPGRADE,
+    header::HOST,
+];
+
+/// External authorization settings of a server.
+///
+/// ```toml
+/// [servers.demo.ext_authz]
+/// uri = "http://127.0.0.1:9191"
+/// protocol = "grpc"
+/// with_body = { max_bytes = 8192, allow_partial = true }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtAuthzConfig {
+    /// Address of the authorization service, and path prefix of the HTTP protocol.
+    #[serde(with = "uri_serde")]
+    pub uri: Uri,
+    #[serde(default)]
+    pub protocol: AuthzProtocol,
+    /// Request headers sent to the service, all of them when empty.
+    #[serde(default)]
+    pub request_headers: Vec<String>,
+    /// Headers of allowing answers of the HTTP protocol set on requests, none of them when empty.
+    #[serde(default)]
+    pub upstream_headers: Vec<String>,
+    #[serde(default)]
+    pub with_body: Option<AuthzBodyConfig>,
+    /// Milliseconds after which a call to the service fails.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_failure_mode")]
+    pub failure_mode: FailureMode,
+}
+
+/// Protocol of an authorization service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthzProtocol {
+    /// The Envoy external authorization gRPC protocol.
+    #[default]
+    Grpc,
+    /// Requests forwarded to the service over HTTP.
+    Http,
+}
+
+/// Body sent to an authorization service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthzBodyConfig {
+    /// Bytes of the body sent.
+    pub max_bytes: usize,
+    /// Send the first `max_bytes` of larger bodies rather than rejecting them.
+    #[serde(default)]
+    pub allow_partial: bool,
+}
+
+const fn default_timeout_ms() -> u64 {
+    200
+}
+
+const fn default_failure_mode() -> FailureMode {
+    FailureMode::Closed
+}
+
+#[derive(thiserror::Error, Debug)]
+enum AuthzError {
+    #[error("invalid authorization service uri")]
+    Uri,
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("unexpected status {0}")]
+    Status(StatusCode),
+    #[error("invalid response")]
+    Response,
+}
+
+/// Answer of an authorization service.
+#[derive(Debug)]
+enum Decision {
+    Allow {
+        /// Headers set on the request, or appended when flagged.
+        request_headers: Vec<(HeaderName, HeaderValue, bool)>,
+        remove_headers: Vec<HeaderName>,
+        response_headers: Vec<(HeaderName, HeaderValue)>,
+    },
+    Deny {
+        status: StatusCode,
+        headers: Vec<(HeaderName, HeaderValue)>,
+        body: Bytes,
+    },
+}
+
+/// Request attributes sent to an authorization service.
+struct CheckRequest<'a> {
+    parts: &'a Parts,
+    client: Option<String>,
+    body: &'a [u8],
+    /// Size of the whole body, `None` when unknown.
+    size: Option<u64>,
+}
+
+/// Client of the authorization service of a worker.
+struct AuthzClient {
+    config: ExtAuthzConfig,
+    request_headers: Vec<HeaderName>,
+    upstream_headers: Vec<HeaderName>,
+    client: HttpClient,
+}
+
+fn header_names(names: &[String]) -> Vec<HeaderName> {
+    names
+        .iter()
+        .filter_map(|name| match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => Some(name),
+            Err(_) => {
+                warn!("invalid header name {name} ignored");
+                None
+            }
+        })
+        .collect()
+}
+
+impl AuthzClient {
+    fn new(config: ExtAuthzConfig) -> Self {
+        let request_headers = header_names(&config.request_headers);
+        let upstream_headers = header_names(&config.upstream_headers);
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let client = match config.protocol {
+            AuthzProtocol::Grpc => HttpClient::http2(timeout),
+            AuthzProtocol::Http => HttpClient::new(timeout),
+        };
+        Self {
+            config,
+            request_headers,
+            upstream_headers,
+            client,
+        }
+    }
+
+    async fn check(&self, check: &CheckRequest<'_>) -> Result<Decision, AuthzError> {
+        let request = self.request(check).ok_or(AuthzError::Uri)?;
+        let response = self.client.send(request).await?;
+        self.decision(response)
+    }
+
+    /// Whether a request header is sent to the service.
+    fn sends_header(&self, name: &HeaderName) -> bool {
+        !HOP_HEADERS.contains(name)
+            && (self.request_headers.is_empty() || self.request_headers.contains(name))
+    }
+
+    fn request(&self, check: &CheckRequest<'_>) -> Option<Request<HttpBody>> {
+        let parts = check.parts;
+        let authority = self.config.uri.authority()?;
+        let (method, path, body) = match self.config.protocol {
+            AuthzProtocol::Grpc => (
+                http::Method::POST,
+                CHECK_PATH.to_string(),
+                grpc::encode_message(&self.encode_request(check)),
+            ),
+            AuthzProtocol::Http => (
+                parts.method.clone(),
+                format!(
+                    "{}{}",
+                    self.config.uri.path().trim_end_matches('/'),
+                    parts.uri.path_and_query().map_or("/", |p| p.as_str())
+                ),
+                Bytes::copy_from_slice(check.body),
+            ),
+        };
+        let uri = Uri::builder()
+            .scheme(
+                self.config
+                    .uri
+                    .scheme()
+                    .cloned()
+                    .unwrap_or(http::uri::Scheme::HTTP),
+            )
+            .authority(authority.clone())
+            .path_and_query(path)
+            .build()
+            .ok()?;
+        let mut request = Request::builder().method(method).uri(uri);
+        let headers = request.headers_mut()?;
+        match self.config.protocol {
+            AuthzProtocol::Grpc => {
+                headers.insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(grpc::GRPC_CONTENT_TYPE),
+                );
+                headers.insert(header::TE, HeaderValue::from_static("trailers"));
+            }
+            AuthzProtocol::Http => {
+                for (name, value) in &parts.headers {
+                    if self.sends_header(name) {
+                        headers.append(name, value.clone());
+                    }
+                }
+                if let Some(host) = request_host(parts).and_then(|h| h.parse().ok()) {
+                    headers.insert(HeaderName::from_static("x-forwarded-host"), host);
+                }
+            }
+        }
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+        request.body(HttpBody::fixed_body(Some(body))).ok()
+    }
+
+    /// Encode a `CheckRequest { AttributeContext attributes = 1; }` with an `AttributeContext {
+    /// Peer source = 1; Request request = 4; }`. The client address is the `SocketAddress {
+    /// string address = 2; }` of the `Address { SocketAddress socket_address = 1; }` of the `Peer
+    /// { Address address = 1; }` source, and the request the `HttpRequest http = 2` of the
+    /// `Request`.
+    fn encode_request(&self, check: &CheckRequest<'_>) -> Vec<u8> {
+        let mut attributes = Vec::new();
+        if let Some(client) = &check.client {
+            let mut socket_address = Vec::new();
+            grpc::encode_bytes_field(2, client.as_bytes(), &mut socket_address);
+            let mut address = Vec::new();
+            grpc::encode_bytes_field(1, &socket_address, &mut address);
+            let mut peer = Vec::new();
+            grpc::encode_bytes_field(1, &address, &mut peer);
+            grpc::encode_bytes_field(1, &peer, &mut attributes);
+        }
+        let mut request = Vec::new();
+        grpc::encode_bytes_field(2, &self.encode_http_request(check), &mut request);
+        grpc::encode_bytes_field(4, &request, &mut attributes);
+        let mut message = Vec::new();
+        grpc::encode_bytes_field(1, &attributes, &mut message);
+        message
+    }
+
+    /// Encode an `HttpRequest { string method = 2; map<string, string> headers = 3; string path =
+    /// 4; string host = 5; string scheme = 6; int64 size = 9; string protocol = 10; string body =
+    /// 11; bytes raw_body = 12; }`, bodies which are not UTF-8 being sent as `raw_body`.
+    fn encode_http_request(&self, check: &CheckRequest<'_>) -> Vec<u8> {
+        let parts = check.parts;
+        let mut http = Vec::new();
+        grpc::encode_bytes_field(2, parts.method.as_str().as_bytes(), &mut http);
+        let mut headers: Vec<(&str, String)> = Vec::new();
+        for (name, value) in &parts.headers {
+            let (Ok(value), true) = (value.to_str(), self.sends_header(name)) else {
+                continue;
+            };
+            match headers.iter_mut().find(|(n, _)| *n == name.as_str()) {
+                Some((_, values)) => {
+                    values.push(',');
+                    values.push_str(value);
+                }
+                None => headers.push((name.as_str(), value.to_string())),
+            }
+        }
+        for (name, value) in headers {
+            let mut entry = Vec::new();
+            grpc::encode_bytes_field(1, name.as_bytes(), &mut entry);
+            grpc::encode_bytes_field(2, value.as_bytes(), &mut entry);
+            grpc::encode_bytes_field(3, &entry, &mut http);
+        }
+        let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+        grpc::encode_bytes_field(4, path.as_bytes(), &mut http);
+        if let Some(host) = request_host(parts) {
+            grpc::encode_bytes_field(5, host.as_bytes(), &mut http);
+        }
+        let scheme = parts.uri.scheme_str().unwrap_or("http");
+        grpc::encode_bytes_field(6, scheme.as_bytes(), &mut http);
+        // -1 when unknown
+        grpc::encode_varint_field(9, check.size.unwrap_or(u64::MAX), &mut http);
+        let protocol = format!("{:?}", parts.version);
+        grpc::encode_bytes_field(10, protocol.as_bytes(), &mut http);
+        if !check.body.is_empty() {
+            match std::str::from_utf8(check.body) {
+                Ok(body) => grpc::encode_bytes_field(11, body.as_bytes(), &mut http),
+                Err(_) => grpc::encode_bytes_field(12, check.body, &mut http),
+            }
+        }
+        http
+    }
+
+    fn decision(&self, response: Response<Bytes>) -> Result<Decision, AuthzError> {
+        let (parts, data) = response.into_parts();
+        match self.config.protocol {
+            AuthzProtocol::Http if parts.status.is_success() => {
+                let request_headers = parts
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| self.upstream_headers.contains(name))
+                    .map(|(name, value)| (name.clone(), value.clone(), false))
+                    .collect();
+                Ok(Decision::Allow {
+                    request_headers,
+                    remove_headers: Vec::new(),
+                    response_headers: Vec::new(),
+                })
+            }
+            AuthzProtocol::Http => Ok(Decision::Deny {
+                status: parts.status,
+                headers: parts
+                    .headers
+                    .iter()
+                    .filter(|(name, _)| !HOP_HEADERS.contains(name))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+                body: data,
+            }),
+            AuthzProtocol::Grpc => {
+                if parts.status != StatusCode::OK || !grpc::is_grpc(&parts.headers) {
+                    return Err(AuthzError::Status(parts.status));
+                }
+                grpc::decode_message(&data)
+                    .and_then(decode_response)
+                    .ok_or(AuthzError::Response)
+            }
+        }
+    }
+}
+
+/// The authority of the request uri, or else its `Host` header.
+fn request_host(parts: &Parts) -> Option<&str> {
+    match parts.uri.authority() {
+        Some(authority) => Some(authority.as_str()),
+        None => parts.headers.get(header::HOST)?.to_str().ok(),
+    }
+}
+
+/// Decode a `CheckResponse { google.rpc.Status status = 1; DeniedHttpResponse denied_response =
+/// 2; OkHttpResponse ok_response = 3; }`, requests being allowed when the `int32 code = 1` of the
+/// `Status` is 0.
+fn decode_response(message: &[u8]) -> Option<Decision> {
+    let (mut code, mut denied, mut ok) = (0, None, None);
+    for (field, value) in grpc::decode_fields(message)? {
+        match (field, value) {
+            (1, ProtoField::Bytes(status)) => {
+                for (field, value) in grpc::decode_fields(status)? {
+                    if let (1, ProtoField::Varint(value)) = (field, value) {
+                        code = value;
+                    }
+                }
+            }
+            (2, ProtoField::Bytes(response)) => denied = Some(response),
+            (3, ProtoField::Bytes(response)) => ok = Some(response),
+            _ => {}
+        }
+    }
+    if code != 0 {
+        return decode_denied(denied.unwrap_or_default());
+    }
+    let mut decision = Decision::Allow {
+        request_headers: Vec::new(),
+        remove_headers: Vec::new(),
+        response_headers: Vec::new(),
+    };
+    let Decision::Allow {
+        request_headers,
+        remove_headers,
+        response_headers,
+    } = &mut decision
+    else {
+        unreachable!()
+    };
+    // OkHttpResponse { repeated HeaderValueOption headers = 2; repeated string headers_to_remove
+    // = 5; repeated HeaderValueOption response_headers_to_add = 6; }
+    for (field, value) in grpc::decode_fields(ok.unwrap_or_default())? {
+        match (field, value) {
+            (2, ProtoField::Bytes(option)) => request_headers.push(decode_header_option(option)?),
+            (5, ProtoField::Bytes(name)) => remove_headers.push(HeaderName::from_bytes(name).ok()?),
+            (6, ProtoField::Bytes(option)) => {
+                let (name, value, _) = decode_header_option(option)?;
+                response_headers.push((name, value));
+            }
+            _ => {}
+        }
+    }
+    Some(decision)
+}
+
+/// Decode a `DeniedHttpResponse { HttpStatus status = 1; repeated HeaderValueOption headers = 2;
+/// string body = 3; }`, with an `HttpStatus { StatusCode code = 1; }` defaulting to 403.
+fn decode_denied(message: &[u8]) -> Option<Decision> {
+    let mut status = StatusCode::FORBIDDEN;
+    let mut headers = Vec::new();
+    let mut body = Bytes::new();
+    for (field, value) in grpc::decode_fields(message)? {
+        match (field, value) {
+            (1, ProtoField::Bytes(http_status)) => {
+                for (field, value) in grpc::decode_fields(http_status)? {
+                    if let (1, ProtoField::Varint(code)) = (field, value) {
+                        status = u16::try_from(code)
+                            .ok()
+                            .and_then(|code| StatusCode::from_u16(code).ok())
+                            .unwrap_or(StatusCode::FORBIDDEN);
+                    }
+                }
+            }
+            (2, ProtoField::Bytes(option)) => {
+                let (name, value, _) = decode_header_option(option)?;
+                headers.push((name, value));
+            }
+            (3, ProtoField::Bytes(bytes)) => body = Bytes::copy_from_slice(bytes),
+            _ => {}
+        }
+    }
+    Some(Decision::Deny {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Decode a `HeaderValueOption { HeaderValue header = 1; google.protobuf.BoolValue append = 2;
+/// }`, headers replacing existing ones unless appended.
+fn decode_header_option(message: &[u8]) -> Option<(HeaderName, HeaderValue, bool)> {
+    let (mut header, mut append) = (None, false);
+    for (field, value) in grpc::decode_fields(message)? {
+        match (field, value) {
+            (1, ProtoField::Bytes(bytes)) => header = grpc::decode_header(bytes),
+            (2, ProtoField::Bytes(bool_value)) => {
+                append = grpc::decode_fields(bool_value)?.contains(&(1, ProtoField::Varint(1)));
+            }
+            _ => {}
+        }
+    }
+    let (name, value) = header?;
+    Some((name, value, append))
+}
+
+/// Handler authorizing requests with an external authorization service.
+///
+/// See the [module level documentation](crate::http::handlers::ext_authz) for details.
+pub struct ExtAuthzHandler<H> {
+    inner: H,
+    client: Rc<AuthzClient>,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for ExtAuthzHandler<H>
+where
+    H: HttpHandler<CX, AuthzBody<B>>,
+    H::Body: FixedBody,
+    B: Body<Data = Bytes> + FixedBody,
+    B::Error: std::fmt::Debug,
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let (mut parts, mut body) = request.into_parts();
+        let content_length = parts
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse::<u64>().ok());
+
+        // The start of the body read to be sent to the service, and whether it is the whole body.
+        let mut prefix = BytesMut::new();
+        let mut complete = false;
+        if let Some(with_body) = &self.client.config.with_body {
+            let max_bytes = with_body.max_bytes;
+            let too_large = |len: u64| len > max_bytes as u64 && !with_body.allow_partial;
+            if content_length.is_some_and(too_large) {
+                return Ok((
+                    generate_response(StatusCode::PAYLOAD_TOO_LARGE, false),
+                    true,
+                ));
+            }
+            while prefix.len() < max_bytes
+                || (prefix.len() == max_bytes && !with_body.allow_partial)
+            {
+                match body.next_data().await {
+                    Some(Ok(chunk)) => prefix.extend_from_slice(&chunk),
+                    Some(Err(e)) => {
+                        debug!("request body to authorize failed: {e:?}");
+                        return Ok((generate_response(StatusCode::BAD_REQUEST, false), true));
+                    }
+                    None => {
+                        complete = true;
+                        break;
+                    }
+                }
+            }
+            if too_large(prefix.len() as u64) {
+                return Ok((
+                    generate_response(StatusCode::PAYLOAD_TOO_LARGE, false),
+                    true,
+                ));
+            }
+        }
+        let prefix = prefix.freeze();
+
+        let client = match BucketKey::client(&ctx) {
+            BucketKey::Ip(ip) => Some(ip.to_string()),
+            _ => None,
+        };
+        let max_bytes = self
+            .client
+            .config
+            .with_body
+            .map_or(0, |with_body| with_body.max_bytes);
+        let check = CheckRequest {
+            parts: &parts,
+            client,
+            body: &prefix[..prefix.len().min(max_bytes)],
+            size: if complete {
+                Some(prefix.len() as u64)
+            } else {
+                content_length
+            },
+        };
+        // Bodies left unread are forwarded as they are, the others after their prefix.
+        let body = match (self.client.config.with_body, complete) {
+            (None, _) => AuthzBody::new(None, Some(body)),
+            (Some(_), false) => AuthzBody::new(Some(prefix.clone()), Some(body)),
+            (Some(_), true) => AuthzBody::new(Some(prefix.clone()), None),
+        };
+        let decision = match self.client.check(&check).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                warn!(
+                    "authorization service {} failed: {e}",
+                    self.client.config.uri
+                );
+                return match self.client.config.failure_mode {
+                    FailureMode::Open => {
+                        self.inner
+                            .handle(Request::from_parts(parts, body), ctx)
+                            .await
+                    }
+                    FailureMode::Closed => {
+                        Ok((generate_response(StatusCode::FORBIDDEN, false), true))
+                    }
+                };
+            }
+        };
+        match decision {
+            Decision::Deny {
+                status,
+                headers,
+                body,
+            } => {
+                debug!("request to {} denied with {status}", parts.uri.path());
+                let mut response = Response::new(H::Body::fixed_body(
+                    (!body.is_empty()).then_some(body.clone()),
+                ));
+                *response.status_mut() = status;
+                for (name, value) in headers {
+                    response.headers_mut().append(name, value);
+                }
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+                Ok((response, true))
+            }
+            Decision::Allow {
+                request_headers,
+                remove_headers,
+                response_headers,
+            } => {
+                for name in remove_headers {
+                    parts.headers.remove(name);
+                }
+                for (name, value, append) in request_headers {
+                    if append {
+                        parts.headers.append(name, value);
+                    } else {
+                        parts.headers.insert(name, value);
+                    }
+                }
+                let (mut response, cont) = self
+                    .inner
+                    .handle(Request::from_parts(parts, body), ctx)
+                    .await?;
+                for (name, value) in response_headers {
+                    response.headers_mut().append(name, value);
+                }
+                Ok((response, cont))
+            }
+        }
+    }
+}
+
+/// Body of the requests checked by [`ExtAuthzHandler`]: the prefix read to be sent to the
+/// authorization service, followed by the rest of the original body.
+pub struct AuthzBody<B> {
+    prefix: Option<Bytes>,
+    rest: Option<B>,
+}
+
+impl<B> AuthzBody<B> {
+    fn new(prefix: Option<Bytes>, rest: Option<B>) -> Self {
+        Self {
+            prefix: prefix.filter(|prefix| !prefix.is_empty()),
+            rest,
+        }
+    }
+}
+
+impl<B: Body<Data = Bytes>> Body for AuthzBody<B> {
+    type Data = Bytes;
+    type Error = B::Error;
+
+    async fn next_data(&mut self) -> Option<Result<Self::Data, Self::Error>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Some(Ok(prefix));
+        }
+        self.rest.as_mut()?.next_data().await
+    }
+
+    fn stream_hint(&self) -> StreamHint {
+        match (&self.prefix, &self.rest) {
+            (None, None) => StreamHint::None,
+            (Some(_), None) => StreamHint::Fixed,
+            (None, Some(rest)) => rest.stream_hint(),
+            (Some(_), Some(_)) => StreamHint::Stream,
+        }
+    }
+}
+
+impl<B: Body<Data = Bytes>> FixedBody for AuthzBody<B> {
+    fn fixed_body(data: Option<Bytes>) -> Self {
+        Self::new(data, None)
+    }
+}
+
+impl<F> ExtAuthzHandler<F> {
+    /// Returns a layer authorizing requests with the service of `config` when one is given.
+    pub fn opt_layer<C>(
+        config: Option<ExtAuthzConfig>,
+    ) -> Option<impl FactoryLayer<C, F, Factory = ExtAuthzHandlerFactory<F>>> {
+        config.map(|config| {
+            layer_fn(move |_: &C, inner| ExtAuthzHandlerFactory {
+                inner,
+                config: config.clone(),
+            })
+        })
+    }
+}
+
+/// Factory for [`ExtAuthzHandler`].
+pub struct ExtAuthzHandlerFactory<F> {
+    inner: F,
+    config: ExtAuthzConfig,
+}
+
+impl<F> ExtAuthzHandlerFactory<F> {
+    // Keep the connections of the client when its config did not change.
+    fn make_client<H>(&self, old: Option<&ExtAuthzHandler<H>>) -> Rc<AuthzClient> {
+        match old {
+            Some(old) if old.client.config == self.config => old.client.clone(),
+            _ => Rc::new(AuthzClient::new(self.config.clone())),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for ExtAuthzHandlerFactory<F> {
+    type Service = ExtAuthzHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(ExtAuthzHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            client: self.make_client(old),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for ExtAuthzHandlerFactory<F> {
+    type Service = ExtAuthzHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(ExtAuthzHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            client: self.make_client(old),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use monoio_http::h1::payload::{stream_payload_pair, Payload};
+
+    use super::*;
+    use crate::testing::{body_bytes, stub_http, Recorder, TestCtx};
+
+    #[test]
+    fn authz_messages() {
+        let config: ExtAuthzConfig = serde_json::from_value(serde_json::json!({
+            "uri": "http://127.0.0.1:9191",
+            "request_headers": ["authorization", "x-tenant"],
+        }))
+        .unwrap();
+        assert_eq!(config.failure_mode, FailureMode::Closed);
+        let client = AuthzClient::new(config);
+        let (parts, _) = Request::post("http://example.com/api?q=1")
+            .header("authorization", "Bearer t")
+            .header("x-tenant", "a")
+            .header("x-tenant", "b")
+            .header("cookie", "c=1")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let check = CheckRequest {
+            parts: &parts,
+            client: Some("10.0.0.1".to_string()),
+            body: b"{}",
+            size: Some(2),
+        };
+        let http = client.encode_http_request(&check);
+        let fields = grpc::decode_fields(&http).unwrap();
+        assert!(fields.contains(&(2, ProtoField::Bytes(b"POST"))));
+        assert!(fields.contains(&(4, ProtoField::Bytes(b"/api?q=1"))));
+        assert!(fields.contains(&(5, ProtoField::Bytes(b"example.com"))));
+        assert!(fields.contains(&(11, ProtoField::Bytes(b"{}"))));
+        let headers: Vec<_> = fields
+            .iter()
+            .filter_map(|field| match field {
+                (3, ProtoField::Bytes(entry)) => Some(grpc::decode_fields(entry).unwrap()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1][1], (2, ProtoField::Bytes(b"a,b")));
+
+        // A denial with a 401 status, a header and a body.
+        let mut status = Vec::new();
+        grpc::encode_varint_field(1, 16, &mut status);
+        let mut http_status = Vec::new();
+        grpc::encode_varint_field(1, 401, &mut http_status);
+        let mut header = Vec::new();
+        grpc::encode_bytes_field(1, b"www-authenticate", &mut header);
+        grpc::encode_bytes_field(2, b"Bearer", &mut header);
+        let mut option = Vec::new();
+        grpc::encode_bytes_field(1, &header, &mut option);
+        let mut denied = Vec::new();
+        grpc::encode_bytes_field(1, &http_status, &mut denied);
+        grpc::encode_bytes_field(2, &option, &mut denied);
+        grpc::encode_bytes_field(3, b"denied", &mut denied);
+        let mut response = Vec::new();
+        grpc::encode_bytes_field(1, &status, &mut response);
+        grpc::encode_bytes_field(2, &denied, &mut response);
+        let Some(Decision::Deny {
+            status,
+            headers,
+            body,
+        }) = decode_response(&response)
+        else {
+            panic!("request not denied");
+        };
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(headers[0].0, header::WWW_AUTHENTICATE);
+        assert_eq!(body, "denied");
+
+        // An allowance appending a header.
+        let mut append = Vec::new();
+        grpc::encode_varint_field(1, 1, &mut append);
+        grpc::encode_bytes_field(2, &append, &mut option);
+        let mut ok = Vec::new();
+        grpc::encode_bytes_field(2, &option, &mut ok);
+        grpc::encode_bytes_field(5, b"cookie", &mut ok);
+        let mut response = Vec::new();
+        grpc::encode_bytes_field(3, &ok, &mut response);
+        let Some(Decision::Allow {
+            request_headers,
+            remove_headers,
+            ..
+        }) = decode_response(&response)
+        else {
+            panic!("request not allowed");
+        };
+        assert!(request_headers[0].2);
+        assert_eq!(remove_headers, [header::COOKIE]);
+    }
+
+    fn handler(addr: std::net::SocketAddr, extra: serde_json::Value) -> ExtAuthzHandler<Recorder> {
+        let mut config = serde_json::json!({
+            "uri": format!("http://{addr}/authz"),
+            "protocol": "http",
+            "upstream_headers": ["x-user-id"],
+            "timeout_ms": 100,
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        ExtAuthzHandler {
+            inner: Recorder::default(),
+            client: Rc::new(AuthzClient::new(serde_json::from_value(config).unwrap())),
+        }
+    }
+
+    fn request(path: &str, body: HttpBody) -> Request<HttpBody> {
+        Request::post(path)
+            .header("host", "example.com")
+            .body(body)
+            .unwrap()
+    }
+
+    /// Stub service allowing `/authz/allow`, denying `/authz/deny`, answering `/authz/slow` too
+    /// late, and keeping the bodies it was sent.
+    fn stub_authz(bodies: Rc<RefCell<Vec<Bytes>>>) -> std::net::SocketAddr {
+        stub_http(move |request: Request<Bytes>| {
+            let bodies = bodies.clone();
+            async move {
+                bodies.borrow_mut().push(request.body().clone());
+                match request.uri().path() {
+                    "/authz/allow" => Response::builder()
+                        .header("x-user-id", "42")
+                        .header("set-cookie", "session=1")
+                        .body(Vec::new())
+                        .unwrap(),
+                    "/authz/slow" => {
+                        monoio::time::sleep(Duration::from_millis(500)).await;
+                        Response::new(Vec::new())
+                    }
+                    _ => Response::builder()
+                        .status(StatusCode::UNAUTHORIZED)
+                        .header("www-authenticate", "Bearer")
+                        .body(b"denied".to_vec())
+                        .unwrap(),
+                }
+            }
+        })
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn http_service_decisions() {
+        let addr = stub_authz(Default::default());
+        let handler = handler(addr, serde_json::json!({}));
+        let ctx = || TestCtx::new("10.0.0.1:1234");
+
+        // Only the allow-listed headers of the answer are set on allowed requests.
+        let (response, _) = handler
+            .call((request("/allow", HttpBody::fixed_body(None)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let forwarded = handler.inner.take();
+        assert_eq!(forwarded[0].headers()["x-user-id"], "42");
+        assert!(!forwarded[0].headers().contains_key(header::SET_COOKIE));
+
+        // Denials are answered with the answer of the service.
+        let (response, _) = handler
+            .call((request("/deny", HttpBody::fixed_body(None)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+        assert_eq!(body_bytes(response.into_body()).await, b"denied");
+        assert!(handler.inner.take().is_empty());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn failure_modes() {
+        let addr = stub_authz(Default::default());
+        let ctx = || TestCtx::new("10.0.0.1:1234");
+
+        let closed = handler(addr, serde_json::json!({}));
+        let (response, _) = closed
+            .call((request("/slow", HttpBody::fixed_body(None)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(closed.inner.take().is_empty());
+
+        let open = handler(addr, serde_json::json!({ "failure_mode": "open" }));
+        let (response, _) = open
+            .call((
+                request("/slow", HttpBody::fixed_body(Some("body".into()))),
+                ctx(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(open.inner.take()[0].body(), b"body");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn body_prefix() {
+        let bodies = Rc::new(RefCell::new(Vec::new()));
+        let addr = stub_authz(bodies.clone());
+        let ctx = || TestCtx::new("10.0.0.1:1234");
+
+        // Only the prefix is read ahead of the check: the rest of the body is sent once the
+        // service got it, and would never be with the whole body read first.
+        let partial = handler(
+            addr,
+            serde_json::json!({ "with_body": { "max_bytes": 4, "allow_partial": true } }),
+        );
+        let (payload, mut sender) = stream_payload_pair();
+        sender.feed_data(Some(Bytes::from_static(b"abc")));
+        sender.feed_data(Some(Bytes::from_static(b"def")));
+        let checked = bodies.clone();
+        monoio::spawn(async move {
+            while checked.borrow().is_empty() {
+                monoio::time::sleep(Duration::from_millis(10)).await;
+            }
+            sender.feed_data(Some(Bytes::from_static(b"ghi")));
+            sender.feed_data(None);
+        });
+        let body = HttpBody::from(Payload::from(payload));
+        let (response, _) = monoio::time::timeout(
+            Duration::from_secs(2),
+            partial.call((request("/allow", body), ctx())),
+        )
+        .await
+        .expect("body read ahead of the check")
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(bodies.take(), [Bytes::from_static(b"abcd")]);
+        assert_eq!(partial.inner.take()[0].body(), b"abcdefghi");
+
+        // Larger bodies are rejected unless partial bodies are allowed.
+        let whole = handler(addr, serde_json::json!({ "with_body": { "max_bytes": 4 } }));
+        let (payload, mut sender) = stream_payload_pair();
+        sender.feed_data(Some(Bytes::from_static(b"abc")));
+        sender.feed_data(Some(Bytes::from_static(b"def")));
+        sender.feed_data(None);
+        let body = HttpBody::from(Payload::from(payload));
+        let (response, _) = whole.call((request("/allow", body), ctx())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(bodies.take().is_empty());
+
+        let (response, _) = whole
+            .call((
+                request("/allow", HttpBody::fixed_body(Some("abcd".into()))),
+                ctx(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(bodies.take(), [Bytes::from_static(b"abcd")]);
+        assert_eq!(whole.inner.take()[0].body(), b"abcd");
+    }
+}
@@ -0,0 +1,567 @@
+//! Forward proxy handling of CONNECT and absolute-form requests.
+//!
+//! [`ForwardProxyHandler`] turns a server into an egress proxy for clients configured to use it:
+//!
+//! - `CONNECT host:port` requests open a TCP connection to the destination and answer `200` once it
+//!   is established. The client connection, or the HTTP/2 stream of the request, is then spliced
+//!   with the destination, see [`upgrade`](crate::http::upgrade).
+//! - Absolute-form requests such as `GET http://example.com/` are passed to the inner handler,
+//!   typically an [`UpstreamHandler`](super::UpstreamHandler), which sends them to the host of
+//!   their uri. Requests without a scheme and authority are rejected with `400`.
+//!
+//! Destinations are checked against the allow and deny lists of hosts and ports of
+//! [`ForwardProxyConfig`] and refused with `403`. Deny lists take precedence, and empty allow lists
+//! allow everything. Hosts are compared without case, trailing dot or IPv6 brackets, and IP
+//! literals in their canonical form. Host patterns either match a host exactly or start with `*.`
+//! to match all subdomains of a domain.
+//!
+//! Allowed hosts are then resolved off the worker thread, and destinations resolving to loopback,
+//! private, link-local or other non-public addresses are refused with `403` as well, unless
+//! [`ForwardProxyConfig::allow_private`] is set. Tunnels and cleartext requests connect to the
+//! checked address, so the host can not be resolved again to another one. HTTPS requests connect
+//! by name, the certificate of the destination binding it to the host.
+//!
+//! With [`ProxyAuth`] configured, requests without valid `Proxy-Authorization` basic credentials
+//! are answered with `407` and a `Proxy-Authenticate` challenge. The `Proxy-Authorization` and
+//! `Proxy-Connection` headers are never forwarded.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! use service_async::stack::FactoryStack;
+//!
+//! let stack = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(Default::default(), Default::default()))
+//!     .push(ForwardProxyHandler::layer())
+//!     .push(ConnectionReuseHandler::layer())
+//!     .push(HttpCoreService::layer());
+//! ```
+use std::{
+    hint::black_box,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use http::{
+    header::{InvalidHeaderValue, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
+    uri::Scheme,
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Version,
+};
+use monoio::{io::Splitable, net::TcpStream};
+use monoio_http::common::body::{Body, FixedBody, HttpBody};
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, Service,
+};
+use tracing::{debug, info};
+
+use super::upstream::{HttpUpstreamTimeout, ResolvedAddr};
+use crate::{
+    common::blocking,
+    http::{
+        generate_response,
+        upgrade::{self, OnUpgrade, Upgraded},
+    },
+};
+
+const PROXY_CONNECTION: &str = "proxy-connection";
+
+/// Destination and credential policy of a forward proxy.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardProxyConfig {
+    /// Hosts which may be reached, all hosts when empty.
+    pub allow_hosts: Vec<String>,
+    /// Hosts which may not be reached.
+    pub deny_hosts: Vec<String>,
+    /// Ports which may be reached, all ports when empty.
+    pub allow_ports: Vec<u16>,
+    /// Ports which may not be reached.
+    pub deny_ports: Vec<u16>,
+    /// Whether hosts resolving to loopback, private, link-local and other non-public addresses
+    /// may be reached.
+    pub allow_private: bool,
+    /// Credentials required from clients, none when `None`.
+    pub auth: Option<ProxyAuth>,
+}
+
+impl ForwardProxyConfig {
+    /// Whether the destination `host:port` may be reached.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        let host = normalize_host(host);
+        let host_matches = |pattern: &String| {
+            let pattern = normalize_host(pattern);
+            match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .len()
+                    .checked_sub(domain.len() + 1)
+                    .is_some_and(|dot| host.as_bytes()[dot] == b'.' && host[dot + 1..] == *domain),
+                None => pattern == host,
+            }
+        };
+        !self.deny_hosts.iter().any(host_matches)
+            && !self.deny_ports.contains(&port)
+            && (self.allow_hosts.is_empty() || self.allow_hosts.iter().any(host_matches))
+            && (self.allow_ports.is_empty() || self.allow_ports.contains(&port))
+    }
+}
+
+/// Lowercase `host` without trailing dot and IPv6 brackets, with IP literals in canonical form.
+fn normalize_host(host: &str) -> String {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    let host = host.strip_suffix('.').unwrap_or(host);
+    match host.parse::<IpAddr>() {
+        Ok(ip) => ip.to_string(),
+        Err(_) => host.to_ascii_lowercase(),
+    }
+}
+
+/// Whether `ip` is a public unicast address.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // 0.0.0.0/8 "this network" and 100.64.0.0/10 shared address space
+                || a == 0
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Compare without returning early, so the time taken does not tell how much of `a` matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && black_box(a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y))) == 0
+}
+
+/// `Proxy-Authorization` basic credentials accepted by a forward proxy.
+#[derive(Debug, Clone)]
+pub struct ProxyAuth {
+    challenge: HeaderValue,
+    // `user:password` of every user
+    credentials: Vec<Vec<u8>>,
+}
+
+impl ProxyAuth {
+    /// Accept the given `(user, password)` pairs, challenging clients for the `realm`.
+    pub fn basic(
+        realm: &str,
+        users: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, InvalidHeaderValue> {
+        Ok(Self {
+            challenge: HeaderValue::from_str(&format!("Basic realm=\"{realm}\""))?,
+            credentials: users
+                .into_iter()
+                .map(|(user, password)| format!("{user}:{password}").into_bytes())
+                .collect(),
+        })
+    }
+
+    fn verify(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(PROXY_AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| {
+                let (scheme, token) = value.trim().split_once(' ')?;
+                scheme.eq_ignore_ascii_case("basic").then_some(token)
+            })
+            .and_then(|token| STANDARD.decode(token.trim()).ok())
+            .is_some_and(|credentials| {
+                // Every user is compared, the time taken not telling which one matched.
+                self.credentials.iter().fold(false, |found, user| {
+                    found | constant_time_eq(user, &credentials)
+                })
+            })
+    }
+}
+
+/// Handler serving CONNECT tunnels and passing absolute-form requests to the inner handler.
+///
+/// For implementation details and example usage, see the
+/// [module level documentation](crate::http::handlers::forward_proxy).
+#[derive(Clone)]
+pub struct ForwardProxyHandler<H> {
+    inner: H,
+    config: ForwardProxyConfig,
+    connect_timeout: Option<Duration>,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for ForwardProxyHandler<H>
+where
+    H: HttpHandler<CX, B, Body = HttpBody>,
+    B: Body<Data = Bytes> + 'static,
+{
+    type Response = ResponseWithContinue<HttpBody>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (mut request, ctx): (Request<B>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        if let Some(auth) = &self.config.auth {
+            if !auth.verify(request.headers()) {
+                let mut response =
+                    generate_response(StatusCode::PROXY_AUTHENTICATION_REQUIRED, false);
+                response
+                    .headers_mut()
+                    .insert(PROXY_AUTHENTICATE, auth.challenge.clone());
+                return Ok((response, true));
+            }
+        }
+        let headers = request.headers_mut();
+        headers.remove(PROXY_AUTHORIZATION);
+        headers.remove(PROXY_CONNECTION);
+
+        if request.method() == Method::CONNECT {
+            return Ok(self.tunnel(request).await);
+        }
+        let uri = request.uri();
+        let (Some(scheme), Some(host)) = (uri.scheme(), uri.host()) else {
+            info!("forward proxy request is not in absolute-form: {uri}");
+            return Ok((generate_response(StatusCode::BAD_REQUEST, false), true));
+        };
+        let https = *scheme == Scheme::HTTPS;
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let host = normalize_host(host);
+        if !self.config.allows(&host, port) {
+            info!("forward proxy destination {host}:{port} is not allowed");
+            return Ok((generate_response(StatusCode::FORBIDDEN, false), true));
+        }
+        match self.resolve(&host, port).await {
+            Ok(addr) if !https => {
+                request.extensions_mut().insert(ResolvedAddr(addr));
+            }
+            Ok(_) => {}
+            Err(status) => return Ok((generate_response(status, false), true)),
+        }
+        self.inner.handle(request, ctx).await
+    }
+}
+
+impl<H> ForwardProxyHandler<H> {
+    /// Open a tunnel to the authority of a CONNECT request.
+    async fn tunnel<B>(&self, request: Request<B>) -> ResponseWithContinue<HttpBody>
+    where
+        B: Body<Data = Bytes> + 'static,
+    {
+        if upgrade::extended_connect_protocol(&request).is_some() {
+            info!("extended connect is not supported by forward proxies");
+            return (generate_response(StatusCode::NOT_IMPLEMENTED, false), true);
+        }
+        let Some((host, port)) = request
+            .uri()
+            .authority()
+            .and_then(|authority| Some((authority.host(), authority.port_u16()?)))
+        else {
+            info!("connect request without host and port: {}", request.uri());
+            return (generate_response(StatusCode::BAD_REQUEST, false), true);
+        };
+        let host = normalize_host(host);
+        if !self.config.allows(&host, port) {
+            info!("forward proxy destination {host}:{port} is not allowed");
+            return (generate_response(StatusCode::FORBIDDEN, false), true);
+        }
+        let addr = match self.resolve(&host, port).await {
+            Ok(addr) => addr,
+            Err(status) => return (generate_response(status, false), true),
+        };
+        let Some(stream) = self.connect(addr).await else {
+            return (generate_response(StatusCode::BAD_GATEWAY, false), true);
+        };
+        let (reader, writer) = stream.into_split();
+
+        let response = Response::builder().status(StatusCode::OK);
+        match request.version() {
+            Version::HTTP_2 => {
+                // The tunnel is carried by the stream data of the request and response.
+                let (client, upstream) = Upgraded::pair();
+                let body = request.into_body();
+                monoio::spawn(async move {
+                    if let Err(e) = upgrade::splice_stream(body, upstream, reader, writer).await {
+                        debug!("tunnel closed with error: {e}");
+                    }
+                });
+                let response = response
+                    .extension(OnUpgrade::new(client))
+                    .body(HttpBody::fixed_body(None))
+                    .unwrap();
+                (response, true)
+            }
+            _ => {
+                let (client, upstream) = Upgraded::pair();
+                monoio::spawn(async move {
+                    if let Err(e) = upstream.splice(reader, writer).await {
+                        debug!("tunnel closed with error: {e}");
+                    }
+                });
+                let response = response
+                    .extension(OnUpgrade::new(client))
+                    .body(HttpBody::fixed_body(None))
+                    .unwrap();
+                (response, false)
+            }
+        }
+    }
+
+    /// Resolve the normalized `host` off the worker thread and check its addresses, returning the
+    /// address to connect to or the status refusing the destination.
+    async fn resolve(&self, host: &str, port: u16) -> Result<SocketAddr, StatusCode> {
+        let addrs = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => {
+                let name = host.to_string();
+                let resolved = blocking::run(move || {
+                    (name.as_str(), port).to_socket_addrs().map(Vec::from_iter)
+                })
+                .await;
+                match resolved {
+                    Some(Ok(addrs)) => addrs,
+                    Some(Err(e)) => {
+                        info!("resolve {host}:{port} error: {e}");
+                        return Err(StatusCode::BAD_GATEWAY);
+                    }
+                    None => {
+                        info!("resolve {host}:{port} failed");
+                        return Err(StatusCode::BAD_GATEWAY);
+                    }
+                }
+            }
+        };
+        if !self.config.allow_private {
+            if let Some(addr) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+                info!(
+                    "forward proxy destination {host}:{port} resolves to non-public {}",
+                    addr.ip()
+                );
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+        addrs.first().copied().ok_or_else(|| {
+            info!("unable to resolve host: {host}");
+            StatusCode::BAD_GATEWAY
+        })
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> Option<TcpStream> {
+        let connect = match self.connect_timeout {
+            Some(connect_timeout) => {
+                match monoio::time::timeout(connect_timeout, TcpStream::connect(addr)).await {
+                    Ok(connect) => connect,
+                    Err(_) => {
+                        info!("connect {addr} timeout");
+                        return None;
+                    }
+                }
+            }
+            None => TcpStream::connect(addr).await,
+        };
+        connect.map_err(|e| info!("connect {addr} error: {e}")).ok()
+    }
+}
+
+impl<F> ForwardProxyHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
+    where
+        C: Param<ForwardProxyConfig> + Param<HttpUpstreamTimeout>,
+    {
+        layer_fn(|c: &C, inner| {
+            let timeout: HttpUpstreamTimeout = c.param();
+            ForwardProxyHandler {
+                inner,
+                config: c.param(),
+                connect_timeout: timeout.connect_timeout,
+            }
+        })
+    }
+}
+
+impl<F: MakeService> MakeService for ForwardProxyHandler<F> {
+    type Service = ForwardProxyHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(ForwardProxyHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            config: self.config.clone(),
+            connect_timeout: self.connect_timeout,
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for ForwardProxyHandler<F> {
+    type Service = ForwardProxyHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(ForwardProxyHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            config: self.config.clone(),
+            connect_timeout: self.connect_timeout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Recorder, TestCtx};
+
+    #[test]
+    fn destination_lists() {
+        let config = ForwardProxyConfig {
+            allow_hosts: vec!["example.com".to_string(), "*.example.org".to_string()],
+            deny_hosts: vec!["private.example.org".to_string()],
+            allow_ports: vec![80, 443],
+            ..Default::default()
+        };
+        assert!(config.allows("Example.com", 443));
+        assert!(config.allows("www.example.org", 80));
+        assert!(!config.allows("example.org", 443));
+        assert!(!config.allows("wwwexample.org", 443));
+        assert!(!config.allows("private.example.org", 443));
+        assert!(!config.allows("example.com", 22));
+        assert!(ForwardProxyConfig::default().allows("example.net", 8080));
+        // Hosts are normalized before matching.
+        assert!(!config.allows("PRIVATE.example.org.", 443));
+        assert!(config.allows("www.example.org.", 80));
+        let config = ForwardProxyConfig {
+            deny_hosts: vec!["::1".to_string(), "localhost.".to_string()],
+            ..Default::default()
+        };
+        assert!(!config.allows("[0:0::1]", 443));
+        assert!(!config.allows("LocalHost", 443));
+    }
+
+    #[test]
+    fn public_addresses() {
+        for ip in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn credentials() {
+        let auth = ProxyAuth::basic("proxy", [("user".to_string(), "secret".to_string())]).unwrap();
+        let headers = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(PROXY_AUTHORIZATION, value.parse().unwrap());
+            headers
+        };
+        assert!(auth.verify(&headers(&format!(
+            "Basic {}",
+            STANDARD.encode("user:secret")
+        ))));
+        assert!(!auth.verify(&headers(&format!(
+            "Basic {}",
+            STANDARD.encode("user:secreT")
+        ))));
+        assert!(!auth.verify(&headers(&format!(
+            "Basic {}",
+            STANDARD.encode("user:secret2")
+        ))));
+        assert!(!auth.verify(&HeaderMap::new()));
+    }
+
+    fn handler(allow_private: bool) -> ForwardProxyHandler<Recorder> {
+        ForwardProxyHandler {
+            inner: Recorder::default(),
+            config: ForwardProxyConfig {
+                allow_private,
+                ..Default::default()
+            },
+            connect_timeout: Some(Duration::from_secs(1)),
+        }
+    }
+
+    fn connect(authority: &str) -> Request<HttpBody> {
+        Request::builder()
+            .method(Method::CONNECT)
+            .uri(authority)
+            .body(HttpBody::fixed_body(None))
+            .unwrap()
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn private_destinations() {
+        let listener = monoio::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let ctx = TestCtx::new("127.0.0.1:1");
+
+        let denied = handler(false);
+        for authority in [
+            format!("127.0.0.1:{port}"),
+            format!("[::ffff:127.0.0.1]:{port}"),
+            format!("localhost.:{port}"),
+        ] {
+            let (response, _) = denied
+                .call((connect(&authority), ctx.clone()))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "{authority}");
+        }
+        let request = Request::get(format!("http://localhost:{port}/"))
+            .body(HttpBody::fixed_body(None))
+            .unwrap();
+        let (response, _) = denied.call((request, ctx.clone())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(denied.inner.take().is_empty());
+
+        let allowed = handler(true);
+        let authority = format!("localhost:{port}");
+        let (response, _) = allowed
+            .call((connect(&authority), ctx.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(listener.accept().await.is_ok());
+        // Cleartext requests carry the checked address to the upstream handler.
+        let request = Request::get(format!("http://localhost:{port}/"))
+            .body(HttpBody::fixed_body(None))
+            .unwrap();
+        let (response, _) = allowed.call((request, ctx)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let requests = allowed.inner.take();
+        let addr = requests[0].extensions().get::<ResolvedAddr>().unwrap().0;
+        assert!(addr.ip().is_loopback() && addr.port() == port);
+    }
+}
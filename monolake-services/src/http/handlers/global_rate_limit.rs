@@ -0,0 +1,770 @@
+//! Global rate limiting of HTTP requests with an external rate limit service.
+//!
+//! [`GlobalRateLimitHandler`] asks a rate limit service shared by a fleet of proxies whether a
+//! request fits in its quotas. The request is described by a list of descriptors, each made of
+//! key/value entries taken from the request, and the service answers whether any of the matching
+//! quotas is exceeded.
+//!
+//! # Key Components
+//!
+//! - [`GlobalRateLimitConfig`]: Service, domain, descriptors, timeout, failure mode and cache of a
+//!   server.
+//! - [`DescriptorEntry`]: The request attributes making up descriptors.
+//! - [`RlsProtocol`]: The protocol of the rate limit service:
+//!   - `grpc`: `ShouldRateLimit` of the `envoy.service.ratelimit.v3.RateLimitService` gRPC service.
+//!   - `http`: A `POST` of the JSON mapping of the same request, `{"domain": "...", "descriptors":
+//!     [{"entries": [{"key": "...", "value": "..."}]}]}`, answered with a 200 status when the
+//!     request is allowed and a 429 status when it is over limit.
+//!
+//! # Features
+//!
+//! - Requests over limit are answered with `429 Too Many Requests`, with a `Retry-After` header
+//!   when the service tells when the quota resets
+//! - Headers the service asks to add are added to the request or to the response, `RateLimit-*` and
+//!   `Retry-After` headers of the HTTP protocol are added to the response
+//! - Descriptors with a missing header are not sent, requests without descriptors are not limited
+//! - When the service fails or does not answer in time, requests are allowed with the `open`
+//!   failure mode and answered with `503 Service Unavailable` with the `closed` failure mode
+//! - Answers are cached per worker: over limit answers until the quota resets, or for the cache ttl
+//!   when the reset is unknown, allowed answers for the cache ttl, trading accuracy for fewer calls
+//!   to the service
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(GlobalRateLimitHandler::opt_layer(global_rate_limit))
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::{
+    header::{self, HeaderName, RETRY_AFTER},
+    HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri,
+};
+use monoio_http::common::body::{FixedBody, HttpBody};
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    http::{HttpHandler, ResponseWithContinue},
+    util::uri_serde,
+};
+use serde::{Deserialize, Serialize};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, ParamMaybeRef, ParamRef, Service,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    common::rate_limit::BucketKey,
+    http::{
+        client::{ClientError, HttpClient},
+        generate_response,
+        grpc::{self, ProtoField},
+    },
+};
+
+const SHOULD_RATE_LIMIT_PATH: &str = "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+// RateLimitResponse.Code
+const CODE_OVER_LIMIT: u64 = 2;
+
+/// Global rate limit settings of a server.
+///
+/// ```toml
+/// [servers.demo.global_rate_limit]
+/// uri = "http://127.0.0.1:8081"
+/// domain = "edge"
+/// descriptors = [[{ type = "remote_address" }], [{ type = "header", name = "x-api-key", key = "api_key" }]]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GlobalRateLimitConfig {
+    /// Address of the rate limit service, and path of the HTTP protocol.
+    #[serde(with = "uri_serde")]
+    pub uri: Uri,
+    #[serde(default)]
+    pub protocol: RlsProtocol,
+    /// Domain of the quotas of the server.
+    pub domain: String,
+    /// Descriptors sent for each request, a list of entries each.
+    pub descriptors: Vec<Vec<DescriptorEntry>>,
+    /// Milliseconds after which a call to the service fails.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+    #[serde(default)]
+    pub cache: RlsCacheConfig,
+}
+
+/// Protocol of a rate limit service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RlsProtocol {
+    /// The Envoy rate limit service gRPC protocol.
+    #[default]
+    Grpc,
+    /// The JSON mapping of the gRPC protocol, over HTTP.
+    Http,
+}
+
+/// Outcome of requests whose rate limit can not be checked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureMode {
+    /// Allow the requests.
+    #[default]
+    Open,
+    /// Reject the requests.
+    Closed,
+}
+
+/// Cache of the answers of a rate limit service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RlsCacheConfig {
+    /// Milliseconds allowed answers are cached, and over limit answers which do not tell when the
+    /// quota resets. Zero disables caching them.
+    #[serde(default)]
+    pub ttl_ms: u64,
+    /// Answers cached per worker.
+    #[serde(default = "default_cache_size")]
+    pub max_entries: usize,
+}
+
+impl Default for RlsCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_ms: 0,
+            max_entries: default_cache_size(),
+        }
+    }
+}
+
+/// An entry of a descriptor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DescriptorEntry {
+    /// A fixed key and value.
+    Generic { key: String, value: String },
+    /// `remote_address` with the client IP address, the source address of the PROXY protocol
+    /// header when present.
+    RemoteAddress,
+    /// `key` with the value of a header. Descriptors are not sent when the header is missing.
+    Header { name: String, key: String },
+    /// `path` with the request path.
+    Path,
+    /// `method` with the request method.
+    Method,
+    /// `authority` with the authority of the request uri, or else its `Host` header.
+    Authority,
+}
+
+/// Longest time answers are cached, whatever the service or the config tells.
+const MAX_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+const fn default_timeout_ms() -> u64 {
+    100
+}
+
+const fn default_cache_size() -> usize {
+    10000
+}
+
+type Descriptors = Vec<Vec<(String, String)>>;
+
+#[derive(thiserror::Error, Debug)]
+enum RlsError {
+    #[error("invalid rate limit service uri")]
+    Uri,
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("unexpected status {0}")]
+    Status(StatusCode),
+    #[error("invalid response")]
+    Response,
+}
+
+/// Answer of a rate limit service.
+#[derive(Debug, Default)]
+struct Verdict {
+    over_limit: bool,
+    retry_after: Option<Duration>,
+    request_headers: Vec<(HeaderName, HeaderValue)>,
+    response_headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Client of the rate limit service of a worker, with its cache.
+struct RlsClient {
+    config: GlobalRateLimitConfig,
+    client: HttpClient,
+    cache: RefCell<HashMap<Descriptors, (Instant, Rc<Verdict>)>>,
+}
+
+impl RlsClient {
+    fn new(config: GlobalRateLimitConfig) -> Self {
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let client = match config.protocol {
+            RlsProtocol::Grpc => HttpClient::http2(timeout),
+            RlsProtocol::Http => HttpClient::new(timeout),
+        };
+        Self {
+            config,
+            client,
+            cache: Default::default(),
+        }
+    }
+
+    async fn check(&self, descriptors: Descriptors) -> Result<Rc<Verdict>, RlsError> {
+        let now = Instant::now();
+        if let Some((expiry, verdict)) = self.cache.borrow().get(&descriptors) {
+            if *expiry > now {
+                return Ok(verdict.clone());
+            }
+        }
+        let verdict = self.call(&descriptors).await?;
+        let verdict = Rc::new(verdict);
+        let ttl = match (verdict.over_limit, verdict.retry_after) {
+            (true, Some(retry_after)) => retry_after,
+            _ => Duration::from_millis(self.config.cache.ttl_ms),
+        }
+        .min(MAX_CACHE_TTL);
+        if !ttl.is_zero() {
+            let mut cache = self.cache.borrow_mut();
+            if cache.len() >= self.config.cache.max_entries {
+                cache.retain(|_, (expiry, _)| *expiry > now);
+                if cache.len() >= self.config.cache.max_entries {
+                    cache.clear();
+                }
+            }
+            cache.insert(descriptors, (now + ttl, verdict.clone()));
+        }
+        Ok(verdict)
+    }
+
+    async fn call(&self, descriptors: &Descriptors) -> Result<Verdict, RlsError> {
+        let request = self.request(descriptors).ok_or(RlsError::Uri)?;
+        let response = self.client.send(request).await?;
+        self.verdict(response)
+    }
+
+    fn request(&self, descriptors: &Descriptors) -> Option<Request<HttpBody>> {
+        let (path, content_type, body) = match self.config.protocol {
+            RlsProtocol::Grpc => (
+                SHOULD_RATE_LIMIT_PATH,
+                grpc::GRPC_CONTENT_TYPE,
+                grpc::encode_message(&encode_request(&self.config.domain, descriptors)),
+            ),
+            RlsProtocol::Http => (
+                self.config.uri.path(),
+                "application/json",
+                Bytes::from(json_request(&self.config.domain, descriptors)),
+            ),
+        };
+        let authority = self.config.uri.authority()?;
+        let uri = Uri::builder()
+            .scheme(
+                self.config
+                    .uri
+                    .scheme()
+                    .cloned()
+                    .unwrap_or(http::uri::Scheme::HTTP),
+            )
+            .authority(authority.clone())
+            .path_and_query(path)
+            .build()
+            .ok()?;
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len());
+        if self.config.protocol == RlsProtocol::Grpc {
+            request = request.header(header::TE, "trailers");
+        }
+        request.body(HttpBody::fixed_body(Some(body))).ok()
+    }
+
+    fn verdict(&self, response: Response<Bytes>) -> Result<Verdict, RlsError> {
+        match self.config.protocol {
+            RlsProtocol::Http => {
+                let over_limit = match response.status() {
+                    StatusCode::OK => false,
+                    StatusCode::TOO_MANY_REQUESTS => true,
+                    status => return Err(RlsError::Status(status)),
+                };
+                let headers = response.headers();
+                let retry_after = headers
+                    .get(RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok()?.parse().ok())
+                    .map(Duration::from_secs);
+                let response_headers = headers
+                    .iter()
+                    .filter(|(name, _)| {
+                        name.as_str().starts_with("ratelimit") || *name == RETRY_AFTER
+                    })
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect();
+                Ok(Verdict {
+                    over_limit,
+                    retry_after,
+                    request_headers: Vec::new(),
+                    response_headers,
+                })
+            }
+            RlsProtocol::Grpc => {
+                if response.status() != StatusCode::OK || !grpc::is_grpc(response.headers()) {
+                    return Err(RlsError::Status(response.status()));
+                }
+                grpc::decode_message(response.body())
+                    .and_then(decode_response)
+                    .ok_or(RlsError::Response)
+            }
+        }
+    }
+}
+
+/// Encode a `RateLimitRequest { string domain = 1; repeated RateLimitDescriptor descriptors = 2;
+/// }`, descriptors being `RateLimitDescriptor { repeated Entry entries = 1; }` and entries
+/// `Entry { string key = 1; string value = 2; }`.
+fn encode_request(domain: &str, descriptors: &Descriptors) -> Vec<u8> {
+    let mut message = Vec::new();
+    grpc::encode_bytes_field(1, domain.as_bytes(), &mut message);
+    for entries in descriptors {
+        let mut descriptor = Vec::new();
+        for (key, value) in entries {
+            let mut entry = Vec::new();
+            grpc::encode_bytes_field(1, key.as_bytes(), &mut entry);
+            grpc::encode_bytes_field(2, value.as_bytes(), &mut entry);
+            grpc::encode_bytes_field(1, &entry, &mut descriptor);
+        }
+        grpc::encode_bytes_field(2, &descriptor, &mut message);
+    }
+    message
+}
+
+fn json_request(domain: &str, descriptors: &Descriptors) -> Vec<u8> {
+    let descriptors: Vec<_> = descriptors
+        .iter()
+        .map(|entries| {
+            let entries: Vec<_> = entries
+                .iter()
+                .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                .collect();
+            serde_json::json!({ "entries": entries })
+        })
+        .collect();
+    serde_json::json!({ "domain": domain, "descriptors": descriptors })
+        .to_string()
+        .into_bytes()
+}
+
+/// Decode a `RateLimitResponse { Code overall_code = 1; repeated DescriptorStatus statuses = 2;
+/// repeated HeaderValue response_headers_to_add = 3; repeated HeaderValue request_headers_to_add
+/// = 4; }`. The quotas of descriptors over limit reset after the `Duration duration_until_reset
+/// = 4` of their `DescriptorStatus { Code code = 1; }`.
+fn decode_response(message: &[u8]) -> Option<Verdict> {
+    let mut verdict = Verdict::default();
+    for (field, value) in grpc::decode_fields(message)? {
+        match (field, value) {
+            (1, ProtoField::Varint(code)) => verdict.over_limit = code == CODE_OVER_LIMIT,
+            (2, ProtoField::Bytes(status)) => {
+                let fields = grpc::decode_fields(status)?;
+                if !fields.contains(&(1, ProtoField::Varint(CODE_OVER_LIMIT))) {
+                    continue;
+                }
+                let reset = fields.iter().find_map(|field| match field {
+                    (4, ProtoField::Bytes(duration)) => decode_duration(duration),
+                    _ => None,
+                });
+                verdict.retry_after = verdict.retry_after.max(reset);
+            }
+            (3, ProtoField::Bytes(header)) => {
+                verdict.response_headers.push(grpc::decode_header(header)?)
+            }
+            (4, ProtoField::Bytes(header)) => {
+                verdict.request_headers.push(grpc::decode_header(header)?)
+            }
+            _ => {}
+        }
+    }
+    Some(verdict)
+}
+
+/// Decode a `Duration { int64 seconds = 1; int32 nanos = 2; }`.
+fn decode_duration(message: &[u8]) -> Option<Duration> {
+    let mut duration = Duration::ZERO;
+    for (field, value) in grpc::decode_fields(message)? {
+        match (field, value) {
+            (1, ProtoField::Varint(secs)) => {
+                duration = duration.checked_add(Duration::from_secs(secs))?
+            }
+            (2, ProtoField::Varint(nanos)) => {
+                duration = duration.checked_add(Duration::from_nanos(nanos))?
+            }
+            _ => {}
+        }
+    }
+    Some(duration)
+}
+
+/// Handler checking requests against the quotas of an external rate limit service.
+///
+/// See the [module level documentation](crate::http::handlers::global_rate_limit) for details.
+pub struct GlobalRateLimitHandler<H> {
+    inner: H,
+    client: Rc<RlsClient>,
+}
+
+impl<H> GlobalRateLimitHandler<H> {
+    fn descriptors<B, CX>(&self, request: &Request<B>, ctx: &CX) -> Descriptors
+    where
+        CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+    {
+        self.client
+            .config
+            .descriptors
+            .iter()
+            .filter_map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| descriptor_entry(entry, request, ctx))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .collect()
+    }
+}
+
+fn descriptor_entry<B, CX>(
+    entry: &DescriptorEntry,
+    request: &Request<B>,
+    ctx: &CX,
+) -> Option<(String, String)>
+where
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    let entry = match entry {
+        DescriptorEntry::Generic { key, value } => (key.clone(), value.clone()),
+        DescriptorEntry::RemoteAddress => {
+            let value = match BucketKey::client(ctx) {
+                BucketKey::Ip(ip) => ip.to_string(),
+                _ => "unix".to_string(),
+            };
+            ("remote_address".to_string(), value)
+        }
+        DescriptorEntry::Header { name, key } => {
+            let value = request.headers().get(name)?.to_str().ok()?;
+            (key.clone(), value.to_string())
+        }
+        DescriptorEntry::Path => ("path".to_string(), request.uri().path().to_string()),
+        DescriptorEntry::Method => ("method".to_string(), request.method().to_string()),
+        DescriptorEntry::Authority => {
+            let authority = match request.uri().authority() {
+                Some(authority) => authority.as_str(),
+                None => request.headers().get(header::HOST)?.to_str().ok()?,
+            };
+            ("authority".to_string(), authority.to_string())
+        }
+    };
+    Some(entry)
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for GlobalRateLimitHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (mut request, ctx): (Request<B>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let descriptors = self.descriptors(&request, &ctx);
+        if descriptors.is_empty() {
+            return self.inner.handle(request, ctx).await;
+        }
+        let verdict = match self.client.check(descriptors).await {
+            Ok(verdict) => verdict,
+            Err(e) => {
+                warn!("rate limit service {} failed: {e}", self.client.config.uri);
+                return match self.client.config.failure_mode {
+                    FailureMode::Open => self.inner.handle(request, ctx).await,
+                    FailureMode::Closed => Ok((
+                        generate_response(StatusCode::SERVICE_UNAVAILABLE, false),
+                        true,
+                    )),
+                };
+            }
+        };
+        if verdict.over_limit {
+            debug!("request to {} over global rate limit", request.uri().path());
+            let mut response = generate_response(StatusCode::TOO_MANY_REQUESTS, false);
+            add_headers(response.headers_mut(), &verdict.response_headers);
+            if let Some(retry_after) = verdict.retry_after {
+                let secs = retry_after
+                    .as_secs()
+                    .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, HeaderValue::from(secs));
+            }
+            return Ok((response, true));
+        }
+        for (name, value) in &verdict.request_headers {
+            request.headers_mut().insert(name, value.clone());
+        }
+        let (mut response, cont) = self.inner.handle(request, ctx).await?;
+        add_headers(response.headers_mut(), &verdict.response_headers);
+        Ok((response, cont))
+    }
+}
+
+fn add_headers(headers: &mut HeaderMap, added: &[(HeaderName, HeaderValue)]) {
+    for (name, value) in added {
+        headers.insert(name, value.clone());
+    }
+}
+
+impl<F> GlobalRateLimitHandler<F> {
+    /// Returns a layer checking requests with the rate limit service of `config` when one is
+    /// given.
+    pub fn opt_layer<C>(
+        config: Option<GlobalRateLimitConfig>,
+    ) -> Option<impl FactoryLayer<C, F, Factory = GlobalRateLimitHandlerFactory<F>>> {
+        config.map(|config| {
+            layer_fn(move |_: &C, inner| GlobalRateLimitHandlerFactory {
+                inner,
+                config: config.clone(),
+            })
+        })
+    }
+}
+
+/// Factory for [`GlobalRateLimitHandler`].
+pub struct GlobalRateLimitHandlerFactory<F> {
+    inner: F,
+    config: GlobalRateLimitConfig,
+}
+
+impl<F> GlobalRateLimitHandlerFactory<F> {
+    // Keep the connections and the cache of the client when its config did not change.
+    fn make_client<H>(&self, old: Option<&GlobalRateLimitHandler<H>>) -> Rc<RlsClient> {
+        match old {
+            Some(old) if old.client.config == self.config => old.client.clone(),
+            _ => Rc::new(RlsClient::new(self.config.clone())),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for GlobalRateLimitHandlerFactory<F> {
+    type Service = GlobalRateLimitHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(GlobalRateLimitHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            client: self.make_client(old),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for GlobalRateLimitHandlerFactory<F> {
+    type Service = GlobalRateLimitHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(GlobalRateLimitHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            client: self.make_client(old),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{stub_http, Recorder, TestCtx};
+
+    #[test]
+    fn rls_messages() {
+        let descriptors = vec![vec![
+            ("remote_address".to_string(), "10.0.0.1".to_string()),
+            ("path".to_string(), "/api".to_string()),
+        ]];
+        let message = encode_request("edge", &descriptors);
+        let fields = grpc::decode_fields(&message).unwrap();
+        assert_eq!(fields[0], (1, ProtoField::Bytes(b"edge")));
+        let ProtoField::Bytes(descriptor) = fields[1].1 else {
+            panic!("descriptor is not a message");
+        };
+        assert_eq!(grpc::decode_fields(descriptor).unwrap().len(), 2);
+
+        // OVER_LIMIT, a status over limit resetting in 1.5s and a response header.
+        let mut duration = vec![0x08, 0x01, 0x10];
+        grpc::encode_varint(500_000_000, &mut duration);
+        let mut status = vec![0x08, 0x02];
+        grpc::encode_bytes_field(4, &duration, &mut status);
+        let mut header = Vec::new();
+        grpc::encode_bytes_field(1, b"x-ratelimit-limit", &mut header);
+        grpc::encode_bytes_field(2, b"10", &mut header);
+        let mut response = vec![0x08, 0x02];
+        grpc::encode_bytes_field(2, &status, &mut response);
+        grpc::encode_bytes_field(3, &header, &mut response);
+
+        let verdict = decode_response(&response).unwrap();
+        assert!(verdict.over_limit);
+        assert_eq!(verdict.retry_after, Some(Duration::from_millis(1500)));
+        assert_eq!(verdict.response_headers.len(), 1);
+        assert!(!decode_response(&[0x08, 0x01]).unwrap().over_limit);
+
+        // Durations past the largest one are invalid.
+        let mut duration = Vec::new();
+        grpc::encode_varint_field(1, u64::MAX, &mut duration);
+        grpc::encode_varint_field(2, 999_999_999, &mut duration);
+        grpc::encode_varint_field(2, 1, &mut duration);
+        assert_eq!(decode_duration(&duration), None);
+    }
+
+    fn handler(
+        addr: std::net::SocketAddr,
+        extra: serde_json::Value,
+    ) -> GlobalRateLimitHandler<Recorder> {
+        let mut config = serde_json::json!({
+            "uri": format!("http://{addr}/rls"),
+            "protocol": "http",
+            "domain": "edge",
+            "descriptors": [[{ "type": "header", "name": "x-api-key", "key": "api_key" }]],
+            "timeout_ms": 100,
+            "cache": { "ttl_ms": 60000 },
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        GlobalRateLimitHandler {
+            inner: Recorder::default(),
+            client: Rc::new(RlsClient::new(serde_json::from_value(config).unwrap())),
+        }
+    }
+
+    fn request(key: &str) -> (Request<HttpBody>, TestCtx) {
+        let request = Request::get("/api")
+            .header("x-api-key", key)
+            .body(HttpBody::fixed_body(None))
+            .unwrap();
+        (request, TestCtx::new("10.0.0.1:1234"))
+    }
+
+    /// Stub service answering with the api key of the descriptor: `ok` is allowed, `over` over
+    /// limit for 2s, `forever` over limit for longer than any clock, `slow` too late and `broken`
+    /// with an error. It keeps the keys it was asked about.
+    fn stub_rls(keys: Rc<RefCell<Vec<String>>>) -> std::net::SocketAddr {
+        stub_http(move |request: Request<Bytes>| {
+            let keys = keys.clone();
+            async move {
+                let body: serde_json::Value = serde_json::from_slice(request.body()).unwrap();
+                let key = body["descriptors"][0]["entries"][0]["value"]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                keys.borrow_mut().push(key.clone());
+                let response = Response::builder().header("ratelimit-limit", "10");
+                let response = match key.as_str() {
+                    "ok" => response,
+                    "over" => response
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header(RETRY_AFTER, "2"),
+                    "forever" => response
+                        .status(StatusCode::TOO_MANY_REQUESTS)
+                        .header(RETRY_AFTER, u64::MAX),
+                    "slow" => {
+                        monoio::time::sleep(Duration::from_millis(500)).await;
+                        response
+                    }
+                    _ => response.status(StatusCode::INTERNAL_SERVER_ERROR),
+                };
+                response.body(Vec::new()).unwrap()
+            }
+        })
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn http_service_verdicts() {
+        let keys = Rc::new(RefCell::new(Vec::new()));
+        let handler = handler(stub_rls(keys.clone()), serde_json::json!({}));
+
+        // Allowed requests get the headers of the answer, which is cached.
+        for _ in 0..2 {
+            let (response, _) = handler.call(request("ok")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()["ratelimit-limit"], "10");
+        }
+        assert_eq!(handler.inner.take().len(), 2);
+        assert_eq!(keys.take(), ["ok"]);
+
+        // Requests over limit are rejected until the quota resets.
+        for _ in 0..2 {
+            let (response, _) = handler.call(request("over")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(response.headers()[RETRY_AFTER], "2");
+        }
+        assert_eq!(keys.take(), ["over"]);
+
+        // Quotas resetting after any representable instant are cached for a bounded time.
+        for _ in 0..2 {
+            let (response, _) = handler.call(request("forever")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                response.headers()[RETRY_AFTER],
+                u64::MAX.to_string().as_str()
+            );
+        }
+        assert_eq!(keys.take(), ["forever"]);
+        assert!(handler.inner.take().is_empty());
+
+        // Requests without descriptors are not checked.
+        let (response, _) = handler
+            .call((
+                Request::new(HttpBody::fixed_body(None)),
+                TestCtx::new("10.0.0.1:1234"),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(keys.take().is_empty());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn failure_modes() {
+        let keys = Rc::new(RefCell::new(Vec::new()));
+        let addr = stub_rls(keys.clone());
+
+        let open = handler(addr, serde_json::json!({}));
+        for key in ["broken", "slow"] {
+            let (response, _) = open.call(request(key)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(open.inner.take().len(), 2);
+
+        // Failures are not cached.
+        let closed = handler(addr, serde_json::json!({ "failure_mode": "closed" }));
+        for key in ["broken", "broken", "slow"] {
+            let (response, _) = closed.call(request(key)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert!(closed.inner.take().is_empty());
+        assert_eq!(keys.take()[..4], ["broken", "slow", "broken", "broken"]);
+    }
+}
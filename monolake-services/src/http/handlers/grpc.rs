@@ -0,0 +1,129 @@
+//! gRPC call deadlines and status reporting.
+//!
+//! gRPC clients expect the failures of a call to be reported with a `grpc-status`, while the
+//! proxy reports its own failures, like a missing route or an unreachable upstream, with bare HTTP
+//! statuses such as 404 or 502. [`GrpcHandler`] sits in front of the routing handler and turns
+//! them into gRPC statuses for gRPC requests.
+//!
+//! # Features
+//!
+//! - The `grpc-timeout` of a call is the deadline of the inner handler, which includes routing and
+//!   waiting for the response headers of the upstream. Calls exceeding it end with
+//!   `DEADLINE_EXCEEDED`. The header is forwarded so that the upstream enforces it as well.
+//! - Responses which are not gRPC responses, whether generated by the proxy or by an upstream which
+//!   is not a gRPC server, are replaced by a "Trailers-Only" response whose `grpc-status` follows
+//!   the gRPC HTTP status mapping, e.g. `UNIMPLEMENTED` for 404 and `UNAVAILABLE` for 502.
+//! - Errors of the inner handler end the call with `INTERNAL` instead of an HTTP 500.
+//!
+//! Other requests are passed through untouched.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(GrpcHandler::layer())
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::fmt::Debug;
+
+use http::Request;
+use monoio_http::common::body::FixedBody;
+use monolake_core::http::{HttpHandler, ResponseWithContinue};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Service,
+};
+use tracing::info;
+
+use crate::http::grpc::{self, GrpcCode, GRPC_TIMEOUT};
+
+/// Handler reporting the failures of gRPC calls with gRPC statuses.
+///
+/// See the [module level documentation](crate::http::handlers::grpc) for details.
+#[derive(Clone)]
+pub struct GrpcHandler<H> {
+    inner: H,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for GrpcHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+    H::Error: Debug,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        if !grpc::is_grpc(request.headers()) {
+            return self.inner.handle(request, ctx).await;
+        }
+        let deadline = request
+            .headers()
+            .get(GRPC_TIMEOUT)
+            .and_then(grpc::parse_timeout);
+        let result = match deadline {
+            Some(deadline) => {
+                match monoio::time::timeout(deadline, self.inner.handle(request, ctx)).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        return Ok((
+                            grpc::status_response(GrpcCode::DeadlineExceeded, "deadline exceeded"),
+                            true,
+                        ))
+                    }
+                }
+            }
+            None => self.inner.handle(request, ctx).await,
+        };
+        match result {
+            Ok((response, cont)) if grpc::is_grpc(response.headers()) => Ok((response, cont)),
+            Ok((response, cont)) => {
+                let status = response.status();
+                let code = GrpcCode::from_http_status(status);
+                let message = format!("HTTP status {status}");
+                Ok((grpc::status_response(code, &message), cont))
+            }
+            Err(e) => {
+                info!("grpc call failed: {e:?}");
+                Ok((
+                    grpc::status_response(GrpcCode::Internal, "internal error"),
+                    true,
+                ))
+            }
+        }
+    }
+}
+
+impl<F> GrpcHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self> {
+        layer_fn(|_: &C, inner| GrpcHandler { inner })
+    }
+}
+
+impl<F: MakeService> MakeService for GrpcHandler<F> {
+    type Service = GrpcHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(GrpcHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for GrpcHandler<F> {
+    type Service = GrpcHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(GrpcHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+        })
+    }
+}
@@ -0,0 +1,247 @@
+//! Filtering of HTTP requests by client IP address.
+//!
+//! [`IpFilterHandler`] checks the client address of every request against the IP filter of its
+//! server and the IP filter of its route, see [`ip_filter`](crate::common::ip_filter) for the
+//! allow and deny lists. Denied requests are answered with `403 Forbidden`.
+//!
+//! # Client Address
+//!
+//! The client address is the source address of the PROXY protocol header when present, or else
+//! the peer address of the connection. When this address belongs to a trusted proxy, the
+//! `X-Forwarded-For` chain is walked from its end, the address appended last, skipping the
+//! addresses of trusted proxies: the first untrusted address is the client address. Clients
+//! whose address can not be read from the chain are unknown, and only allowed by filters without
+//! allow lists.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(IpFilterHandler::layer())
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::{net::IpAddr, sync::Arc};
+
+use http::{HeaderMap, Request, StatusCode};
+use monoio_http::common::body::FixedBody;
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    http::{HttpHandler, ResponseWithContinue},
+    listener::AcceptedAddr,
+};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, ParamMaybeRef, ParamRef, Service,
+};
+use tracing::debug;
+
+use super::route::{RouteConfig, RouteMatcher};
+use crate::{
+    common::ip_filter::{IpFilter, IpFilterConfig, IpNet, IpSet},
+    http::generate_response,
+};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// IP filter of the requests of a server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestIpFilterConfig {
+    /// Filter of all the requests of the server, routes may have their own.
+    pub filter: Option<IpFilterConfig>,
+    /// Proxies whose `X-Forwarded-For` headers are trusted.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Handler denying requests by their client IP address.
+///
+/// See the [module level documentation](crate::http::handlers::ip_filter) for details.
+pub struct IpFilterHandler<H> {
+    inner: H,
+    filters: Arc<Filters>,
+}
+
+struct Filters {
+    server: Option<IpFilter>,
+    trusted_proxies: IpSet,
+    routes: RouteMatcher<IpFilter>,
+}
+
+impl Filters {
+    fn client_ip<CX>(&self, headers: &HeaderMap, ctx: &CX) -> Option<IpAddr>
+    where
+        CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+    {
+        let peer_addr = ParamRef::<PeerAddr>::param_ref(ctx);
+        let remote_addr = ParamMaybeRef::<Option<RemoteAddr>>::param_maybe_ref(ctx);
+        let addr = remote_addr
+            .and_then(|addr| addr.as_ref().map(|x| &x.0))
+            .unwrap_or(&peer_addr.0);
+        let mut ip = match addr {
+            AcceptedAddr::Tcp(addr) | AcceptedAddr::Udp(addr) => addr.ip(),
+            #[cfg(unix)]
+            AcceptedAddr::Unix(_) => return None,
+        };
+        if self.trusted_proxies.is_empty() {
+            return Some(ip);
+        }
+        let forwarded: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+        for hop in forwarded.into_iter().rev() {
+            if !self.trusted_proxies.contains(ip) {
+                break;
+            }
+            ip = hop.trim().parse().ok()?;
+        }
+        Some(ip)
+    }
+
+    fn allows(filter: &IpFilter, client_ip: Option<IpAddr>) -> bool {
+        match client_ip {
+            Some(ip) => filter.allows(ip),
+            None => filter.allows_unknown(),
+        }
+    }
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for IpFilterHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let filters = &self.filters;
+        let route = filters.routes.at(request.uri().path());
+        if filters.server.is_none() && route.is_none() {
+            return self.inner.handle(request, ctx).await;
+        }
+        let client_ip = filters.client_ip(request.headers(), &ctx);
+        let allowed = [filters.server.as_ref(), route]
+            .into_iter()
+            .flatten()
+            .all(|filter| Filters::allows(filter, client_ip));
+        if !allowed {
+            debug!(
+                "request to {} from {client_ip:?} denied",
+                request.uri().path()
+            );
+            return Ok((generate_response(StatusCode::FORBIDDEN, false), true));
+        }
+        self.inner.handle(request, ctx).await
+    }
+}
+
+/// Factory for [`IpFilterHandler`].
+pub struct IpFilterHandlerFactory<F> {
+    inner: F,
+    filters: Arc<Filters>,
+}
+
+impl<F: MakeService> MakeService for IpFilterHandlerFactory<F> {
+    type Service = IpFilterHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(IpFilterHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            filters: self.filters.clone(),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for IpFilterHandlerFactory<F> {
+    type Service = IpFilterHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(IpFilterHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            filters: self.filters.clone(),
+        })
+    }
+}
+
+impl<F> IpFilterHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = IpFilterHandlerFactory<F>>
+    where
+        C: Param<RequestIpFilterConfig> + Param<Vec<RouteConfig>>,
+    {
+        layer_fn(|c: &C, inner| {
+            let config = Param::<RequestIpFilterConfig>::param(c);
+            let routes = Param::<Vec<RouteConfig>>::param(c)
+                .into_iter()
+                .map(|route| {
+                    let filter = route.ip_filter.as_ref().map(IpFilter::new);
+                    (route.path, filter)
+                });
+            IpFilterHandlerFactory {
+                inner,
+                filters: Arc::new(Filters {
+                    server: config.filter.as_ref().map(IpFilter::new),
+                    trusted_proxies: config.trusted_proxies.iter().collect(),
+                    routes: RouteMatcher::new("ip filter", routes),
+                }),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use monolake_core::context::PeerAddr;
+
+    use super::*;
+
+    struct Ctx(PeerAddr, Option<RemoteAddr>);
+
+    impl ParamRef<PeerAddr> for Ctx {
+        fn param_ref(&self) -> &PeerAddr {
+            &self.0
+        }
+    }
+
+    impl ParamMaybeRef<Option<RemoteAddr>> for Ctx {
+        fn param_maybe_ref(&self) -> Option<&Option<RemoteAddr>> {
+            Some(&self.1)
+        }
+    }
+
+    #[test]
+    fn client_ip_from_forwarded_chain() {
+        let filters = Filters {
+            server: None,
+            trusted_proxies: [&"10.0.0.0/8".parse().unwrap()].into_iter().collect(),
+            routes: RouteMatcher::new("ip filter", Vec::new()),
+        };
+        let ctx = |ip: &str| {
+            let addr = AcceptedAddr::from(std::net::SocketAddr::new(ip.parse().unwrap(), 1234));
+            Ctx(PeerAddr(addr), None)
+        };
+        let mut headers = HeaderMap::new();
+        headers.append(
+            X_FORWARDED_FOR,
+            HeaderValue::from_static("1.1.1.1, 2.2.2.2"),
+        );
+        headers.append(X_FORWARDED_FOR, HeaderValue::from_static("10.0.0.2"));
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+
+        assert_eq!(filters.client_ip(&headers, &ctx("10.0.0.1")), ip("2.2.2.2"));
+        // Headers of untrusted peers are ignored.
+        assert_eq!(filters.client_ip(&headers, &ctx("3.3.3.3")), ip("3.3.3.3"));
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("unknown"));
+        assert_eq!(filters.client_ip(&headers, &ctx("10.0.0.1")), None);
+    }
+}
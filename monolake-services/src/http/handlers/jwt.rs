@@ -0,0 +1,790 @@
+//! Bearer token authentication of HTTP requests with JSON Web Tokens.
+//!
+//! [`JwtHandler`] validates the JWT of the `Authorization: Bearer` header of every request
+//! against the keys of a JSON Web Key Set, fetched from a URL or read from a file, before the
+//! request is routed. Routes may require scopes and claim values, and claims of valid tokens are
+//! forwarded to upstreams as request headers.
+//!
+//! # Key Components
+//!
+//! - [`JwtConfig`]: Key set, issuer, audiences, algorithms and forwarded claims of a server.
+//! - [`JwtRouteConfig`]: Scopes and claim values required by a route, or the opt-out of a route.
+//! - [`JwksSource`]: The URL or file of the key set. Key sets are only fetched over HTTPS, unless
+//!   `allow_http_jwks` is set.
+//!
+//! # Features
+//!
+//! - `RS256`, `ES256`, `EdDSA` and `HS256` signatures, `HS256` keys being `oct` keys of the set
+//! - The `iss`, `aud`, `exp` and `nbf` claims are checked, `exp` is required
+//! - Keys are selected by the `kid` of the token, or else by its algorithm. Key sets are cached per
+//!   worker and reloaded after the refresh interval, or when a token names an unknown key so that
+//!   rotated keys are picked up. Reloads are at most once per second, and the cached keys are kept
+//!   when a reload fails
+//! - Required scopes are read from the `scope` claim, a space separated string, or the `scp` claim.
+//!   Required claims, looked up by name or by a dotted path into nested objects, match when they
+//!   equal the value or one of the values of the requirement, or when they are arrays containing it
+//! - Headers of forwarded claims are removed from incoming requests, so clients can not set them
+//!
+//! # Responses
+//!
+//! - `401 Unauthorized` with a `WWW-Authenticate: Bearer` header for missing or invalid tokens
+//! - `403 Forbidden` with an `insufficient_scope` error for tokens missing scopes or claims
+//! - `503 Service Unavailable` when no key set could be loaded
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(JwtHandler::opt_layer(jwt))
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    path::PathBuf,
+    rc::Rc,
+    slice,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use http::{
+    header::{self, HeaderName},
+    uri::Scheme,
+    HeaderMap, HeaderValue, Method, Request, StatusCode, Uri,
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use local_sync::semaphore::Semaphore;
+use monoio_http::common::body::{FixedBody, HttpBody};
+use monolake_core::{
+    http::{HttpHandler, ResponseWithContinue},
+    util::uri_serde,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, Service,
+};
+use tracing::{debug, warn};
+
+use super::route::{RouteConfig, RouteMatcher};
+use crate::http::{
+    client::{ClientError, HttpClient},
+    generate_response,
+};
+
+// Key sets are not reloaded more often, whatever the key ids of tokens.
+const MIN_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// JWT authentication settings of a server.
+///
+/// ```toml
+/// [servers.demo.jwt]
+/// jwks = { type = "uri", value = "https://idp.example.com/.well-known/jwks.json" }
+/// issuer = "https://idp.example.com/"
+/// audiences = ["api"]
+/// forward_claims = [{ claim = "sub", header = "x-user-id" }]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtConfig {
+    pub jwks: JwksSource,
+    /// Expected `iss` claim, any issuer is accepted when unset.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// Accepted `aud` claims, the audience is not checked when empty.
+    #[serde(default)]
+    pub audiences: Vec<String>,
+    /// Accepted signature algorithms.
+    #[serde(default = "default_algorithms")]
+    pub algorithms: Vec<Algorithm>,
+    /// Seconds of clock skew tolerated when checking `exp` and `nbf`.
+    #[serde(default = "default_leeway_secs")]
+    pub leeway_secs: u64,
+    /// Seconds after which the key set is reloaded.
+    #[serde(default = "default_refresh_secs")]
+    pub refresh_secs: u64,
+    /// Milliseconds after which fetching the key set fails.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Claims of valid tokens added to the request headers.
+    #[serde(default)]
+    pub forward_claims: Vec<ForwardClaim>,
+    /// Keep the `Authorization` header of requests with a valid token.
+    #[serde(default = "default_forward_token")]
+    pub forward_token: bool,
+    /// Allow fetching the key set over plain HTTP, where anyone on the path may substitute their
+    /// own keys.
+    #[serde(default)]
+    pub allow_http_jwks: bool,
+}
+
+/// Invalid value of [`JwtConfig`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum InvalidJwtConfig {
+    #[error("key set uri {0} is not https, set allow_http_jwks to fetch it anyway")]
+    InsecureJwks(Uri),
+}
+
+impl JwtConfig {
+    /// Check that the key set is read from a file or fetched over HTTPS, unless plain HTTP is
+    /// allowed.
+    pub fn validate(&self) -> Result<(), InvalidJwtConfig> {
+        match &self.jwks {
+            JwksSource::Uri(uri)
+                if uri.scheme() != Some(&Scheme::HTTPS) && !self.allow_http_jwks =>
+            {
+                Err(InvalidJwtConfig::InsecureJwks(uri.clone()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Location of a JSON Web Key Set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum JwksSource {
+    /// An HTTP or HTTPS URL.
+    #[serde(with = "uri_serde")]
+    Uri(Uri),
+    /// A local file, read again at each refresh.
+    File(PathBuf),
+}
+
+/// A claim forwarded as a request header. String claims are forwarded as they are, other claims
+/// as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForwardClaim {
+    /// Name or dotted path of the claim.
+    pub claim: String,
+    pub header: String,
+}
+
+/// JWT requirements of a route.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtRouteConfig {
+    /// Serve the route without checking tokens.
+    #[serde(default)]
+    pub disabled: bool,
+    /// Serve requests without token, tokens of other requests are still checked.
+    #[serde(default)]
+    pub allow_missing: bool,
+    /// Scopes the token must grant.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Claims the token must have, by name or dotted path, with their accepted value or values.
+    #[serde(default)]
+    pub claims: HashMap<String, Value>,
+}
+
+fn default_algorithms() -> Vec<Algorithm> {
+    vec![
+        Algorithm::RS256,
+        Algorithm::ES256,
+        Algorithm::EdDSA,
+        Algorithm::HS256,
+    ]
+}
+
+const fn default_leeway_secs() -> u64 {
+    60
+}
+
+const fn default_refresh_secs() -> u64 {
+    300
+}
+
+const fn default_timeout_ms() -> u64 {
+    1000
+}
+
+const fn default_forward_token() -> bool {
+    true
+}
+
+#[derive(thiserror::Error, Debug)]
+enum JwksError {
+    #[error("invalid key set uri")]
+    Uri,
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("unexpected status {0}")]
+    Status(StatusCode),
+    #[error("read error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid key set: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unavailable")]
+    Unavailable,
+    #[error(transparent)]
+    Config(#[from] InvalidJwtConfig),
+}
+
+#[derive(thiserror::Error, Debug)]
+enum JwtError {
+    #[error("algorithm {0:?} not accepted")]
+    Algorithm(Algorithm),
+    #[error("no key for the token")]
+    UnknownKey,
+    #[error("invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+    #[error("key set error: {0}")]
+    Jwks(#[from] JwksError),
+}
+
+/// Keys of a key set, with the time they were loaded.
+struct KeySet {
+    keys: Vec<(Jwk, DecodingKey)>,
+    loaded: Instant,
+}
+
+impl KeySet {
+    fn parse(data: &[u8]) -> Result<Self, JwksError> {
+        #[derive(Deserialize)]
+        struct RawKeySet {
+            keys: Vec<Value>,
+        }
+
+        let raw: RawKeySet = serde_json::from_slice(data)?;
+        // Skip the keys this handler can not use rather than failing the whole set.
+        let keys = raw
+            .keys
+            .into_iter()
+            .filter_map(|key| {
+                let jwk: Jwk = serde_json::from_value(key)
+                    .inspect_err(|e| debug!("jwk ignored: {e}"))
+                    .ok()?;
+                if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+                    return None;
+                }
+                let key = DecodingKey::from_jwk(&jwk)
+                    .inspect_err(|e| debug!("jwk ignored: {e}"))
+                    .ok()?;
+                Some((jwk, key))
+            })
+            .collect();
+        Ok(Self {
+            keys,
+            loaded: Instant::now(),
+        })
+    }
+
+    fn contains(&self, kid: &str) -> bool {
+        self.keys
+            .iter()
+            .any(|(jwk, _)| jwk.common.key_id.as_deref() == Some(kid))
+    }
+
+    fn matching<'a>(
+        &'a self,
+        kid: Option<&'a str>,
+        alg: Algorithm,
+    ) -> impl Iterator<Item = &'a DecodingKey> {
+        self.keys
+            .iter()
+            .filter(move |(jwk, _)| {
+                kid.map_or(true, |kid| jwk.common.key_id.as_deref() == Some(kid))
+                    && verifies(jwk, alg)
+            })
+            .map(|(_, key)| key)
+    }
+}
+
+/// Whether a key verifies signatures of an algorithm, the `alg` of the key when present or else
+/// its type.
+fn verifies(jwk: &Jwk, alg: Algorithm) -> bool {
+    if let Some(key_alg) = jwk.common.key_algorithm {
+        return key_alg.to_string().parse::<Algorithm>().ok() == Some(alg);
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => matches!(
+            alg,
+            Algorithm::RS256
+                | Algorithm::RS384
+                | Algorithm::RS512
+                | Algorithm::PS256
+                | Algorithm::PS384
+                | Algorithm::PS512
+        ),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => alg == Algorithm::ES256,
+            EllipticCurve::P384 => alg == Algorithm::ES384,
+            _ => false,
+        },
+        AlgorithmParameters::OctetKeyPair(_) => alg == Algorithm::EdDSA,
+        AlgorithmParameters::OctetKey(_) => {
+            matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+        }
+    }
+}
+
+/// Key set and token validation of a worker.
+struct JwtValidator {
+    config: JwtConfig,
+    validation: Validation,
+    forward_claims: Vec<(String, HeaderName)>,
+    client: HttpClient,
+    keys: RefCell<Option<Rc<KeySet>>>,
+    last_load: Cell<Option<Instant>>,
+    // Requests waiting for a reload do not start their own.
+    loading: Semaphore,
+}
+
+impl JwtValidator {
+    fn new(config: JwtConfig) -> Self {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = config.leeway_secs;
+        validation.validate_nbf = true;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if config.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&config.audiences);
+        }
+        let forward_claims = config
+            .forward_claims
+            .iter()
+            .filter_map(|forward| match HeaderName::try_from(&forward.header) {
+                Ok(header) => Some((forward.claim.clone(), header)),
+                Err(e) => {
+                    warn!("claim {} not forwarded: {e}", forward.claim);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            client: HttpClient::new(Duration::from_millis(config.timeout_ms)),
+            config,
+            validation,
+            forward_claims,
+            keys: RefCell::new(None),
+            last_load: Cell::new(None),
+            loading: Semaphore::new(1),
+        }
+    }
+
+    async fn validate(&self, token: &str) -> Result<Map<String, Value>, JwtError> {
+        let header = decode_header(token)?;
+        if !self.config.algorithms.contains(&header.alg) {
+            return Err(JwtError::Algorithm(header.alg));
+        }
+        let keys = self.keys(header.kid.as_deref()).await?;
+        let mut validation = self.validation.clone();
+        validation.algorithms = vec![header.alg];
+        let mut result = Err(JwtError::UnknownKey);
+        for key in keys.matching(header.kid.as_deref(), header.alg) {
+            match decode::<Map<String, Value>>(token, key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => result = Err(e.into()),
+            }
+        }
+        result
+    }
+
+    async fn keys(&self, kid: Option<&str>) -> Result<Rc<KeySet>, JwksError> {
+        if let Some(cached) = self.cached(kid) {
+            return cached;
+        }
+        let _permit = self.loading.acquire().await;
+        // The key set may have been reloaded while waiting.
+        if let Some(cached) = self.cached(kid) {
+            return cached;
+        }
+        self.last_load.set(Some(Instant::now()));
+        match self.load().await {
+            Ok(keys) => {
+                let keys = Rc::new(keys);
+                *self.keys.borrow_mut() = Some(keys.clone());
+                Ok(keys)
+            }
+            Err(e) => {
+                warn!("loading key set {:?} failed: {e}", self.config.jwks);
+                self.keys.borrow().clone().ok_or(e)
+            }
+        }
+    }
+
+    /// The cached keys when they are fresh and know `kid`, or were reloaded too recently.
+    fn cached(&self, kid: Option<&str>) -> Option<Result<Rc<KeySet>, JwksError>> {
+        let now = Instant::now();
+        let recently_loaded = self
+            .last_load
+            .get()
+            .is_some_and(|last| now < last + MIN_RELOAD_INTERVAL);
+        match &*self.keys.borrow() {
+            Some(keys) => {
+                let fresh = keys
+                    .loaded
+                    .checked_add(Duration::from_secs(self.config.refresh_secs))
+                    .map_or(true, |expiry| now < expiry);
+                let known = kid.map_or(true, |kid| keys.contains(kid));
+                (recently_loaded || fresh && known).then(|| Ok(keys.clone()))
+            }
+            None => recently_loaded.then_some(Err(JwksError::Unavailable)),
+        }
+    }
+
+    async fn load(&self) -> Result<KeySet, JwksError> {
+        let data = match &self.config.jwks {
+            JwksSource::File(path) => monolake_core::util::file_read(path).await?,
+            JwksSource::Uri(uri) => self.fetch(uri).await?,
+        };
+        KeySet::parse(&data)
+    }
+
+    async fn fetch(&self, uri: &Uri) -> Result<Vec<u8>, JwksError> {
+        self.config.validate()?;
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(uri.clone())
+            .header(header::ACCEPT, "application/json")
+            .body(HttpBody::fixed_body(None))
+            .map_err(|_| JwksError::Uri)?;
+        let response = self.client.send(request).await?;
+        if response.status() != StatusCode::OK {
+            return Err(JwksError::Status(response.status()));
+        }
+        Ok(response.into_body().to_vec())
+    }
+}
+
+/// A claim by name, or else by dotted path into nested objects.
+fn claim<'a>(claims: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+    if let Some(value) = claims.get(name) {
+        return Some(value);
+    }
+    let mut parts = name.split('.');
+    let mut value = claims.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_object()?.get(part)?;
+    }
+    Some(value)
+}
+
+fn claim_matches(claim: Option<&Value>, expected: &Value) -> bool {
+    let accepted = match expected {
+        Value::Array(values) => values.as_slice(),
+        value => slice::from_ref(value),
+    };
+    match claim {
+        Some(Value::Array(values)) => values.iter().any(|value| accepted.contains(value)),
+        Some(value) => accepted.contains(value),
+        None => false,
+    }
+}
+
+fn has_scopes(claims: &Map<String, Value>, scopes: &[String]) -> bool {
+    let mut granted = Vec::new();
+    if let Some(Value::String(scope)) = claims.get("scope") {
+        granted.extend(scope.split_whitespace());
+    }
+    match claims.get("scp") {
+        Some(Value::String(scp)) => granted.extend(scp.split_whitespace()),
+        Some(Value::Array(scp)) => granted.extend(scp.iter().filter_map(Value::as_str)),
+        _ => {}
+    }
+    scopes.iter().all(|scope| granted.contains(&scope.as_str()))
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+fn unauthorized<B: FixedBody>(challenge: &'static str) -> ResponseWithContinue<B> {
+    let mut response = generate_response(StatusCode::UNAUTHORIZED, false);
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(challenge),
+    );
+    (response, true)
+}
+
+/// JWT requirements of the routes of a server.
+type Routes = RouteMatcher<JwtRouteConfig>;
+
+/// Handler authenticating requests with the JWT of their `Authorization` header.
+///
+/// See the [module level documentation](crate::http::handlers::jwt) for details.
+pub struct JwtHandler<H> {
+    inner: H,
+    validator: Rc<JwtValidator>,
+    routes: Arc<Routes>,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for JwtHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (mut request, ctx): (Request<B>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let validator = &self.validator;
+        for (_, header) in &validator.forward_claims {
+            request.headers_mut().remove(header);
+        }
+        let route = self.routes.at(request.uri().path());
+        if route.is_some_and(|route| route.disabled) {
+            return self.inner.handle(request, ctx).await;
+        }
+        let Some(token) = bearer_token(request.headers()) else {
+            if route.is_some_and(|route| route.allow_missing) {
+                return self.inner.handle(request, ctx).await;
+            }
+            debug!("request to {} without token", request.uri().path());
+            return Ok(unauthorized("Bearer"));
+        };
+        let claims = match validator.validate(token).await {
+            Ok(claims) => claims,
+            Err(JwtError::Jwks(e)) => {
+                warn!(
+                    "token of request to {} not checked: {e}",
+                    request.uri().path()
+                );
+                return Ok((
+                    generate_response(StatusCode::SERVICE_UNAVAILABLE, false),
+                    true,
+                ));
+            }
+            Err(e) => {
+                debug!("request to {} denied: {e}", request.uri().path());
+                return Ok(unauthorized("Bearer error=\"invalid_token\""));
+            }
+        };
+        if let Some(route) = route {
+            let allowed = has_scopes(&claims, &route.scopes)
+                && route
+                    .claims
+                    .iter()
+                    .all(|(name, expected)| claim_matches(claim(&claims, name), expected));
+            if !allowed {
+                debug!(
+                    "request to {} denied: missing scopes or claims",
+                    request.uri().path()
+                );
+                let mut response = generate_response(StatusCode::FORBIDDEN, false);
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer error=\"insufficient_scope\""),
+                );
+                return Ok((response, true));
+            }
+        }
+        for (name, header) in &validator.forward_claims {
+            let value = match claim(&claims, name) {
+                Some(Value::String(value)) => HeaderValue::from_str(value),
+                Some(value) => HeaderValue::from_str(&value.to_string()),
+                None => continue,
+            };
+            if let Ok(value) = value {
+                request.headers_mut().insert(header, value);
+            }
+        }
+        if !validator.config.forward_token {
+            request.headers_mut().remove(header::AUTHORIZATION);
+        }
+        self.inner.handle(request, ctx).await
+    }
+}
+
+impl<F> JwtHandler<F> {
+    /// Returns a layer authenticating requests with the settings of `config` when one is given,
+    /// and the requirements of the routes.
+    pub fn opt_layer<C>(
+        config: Option<JwtConfig>,
+    ) -> Option<impl FactoryLayer<C, F, Factory = JwtHandlerFactory<F>>>
+    where
+        C: Param<Vec<RouteConfig>>,
+    {
+        config.map(|config| {
+            layer_fn(move |c: &C, inner| {
+                let routes: Vec<RouteConfig> = c.param();
+                let routes = routes.into_iter().map(|route| (route.path, route.jwt));
+                JwtHandlerFactory {
+                    inner,
+                    config: config.clone(),
+                    routes: Arc::new(RouteMatcher::new("jwt requirements", routes)),
+                }
+            })
+        })
+    }
+}
+
+/// Factory for [`JwtHandler`].
+pub struct JwtHandlerFactory<F> {
+    inner: F,
+    config: JwtConfig,
+    routes: Arc<Routes>,
+}
+
+impl<F> JwtHandlerFactory<F> {
+    // Keep the cached key set and the connections when the config did not change.
+    fn make_validator<H>(&self, old: Option<&JwtHandler<H>>) -> Rc<JwtValidator> {
+        match old {
+            Some(old) if old.validator.config == self.config => old.validator.clone(),
+            _ => Rc::new(JwtValidator::new(self.config.clone())),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for JwtHandlerFactory<F> {
+    type Service = JwtHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(JwtHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            validator: self.make_validator(old),
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for JwtHandlerFactory<F> {
+    type Service = JwtHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(JwtHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            validator: self.make_validator(old),
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    fn validator(keys: &str) -> JwtValidator {
+        let config: JwtConfig = serde_json::from_value(json!({
+            "jwks": { "type": "file", "value": "jwks.json" },
+            "issuer": "https://idp",
+            "audiences": ["api"],
+        }))
+        .unwrap();
+        let validator = JwtValidator::new(config);
+        *validator.keys.borrow_mut() = Some(Rc::new(KeySet::parse(keys.as_bytes()).unwrap()));
+        validator
+    }
+
+    fn token(kid: &str, secret: &[u8], claims: Value) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.to_string());
+        encode(&header, &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn validate_tokens() {
+        // "secret" and "other", base64url encoded, the RSA encryption key is skipped.
+        let validator = validator(
+            r#"{"keys": [
+                {"kty": "oct", "kid": "a", "k": "c2VjcmV0"},
+                {"kty": "oct", "kid": "b", "alg": "HS512", "k": "b3RoZXI"},
+                {"kty": "RSA", "kid": "c", "use": "enc", "alg": "RSA-OAEP", "n": "AQAB", "e": "AQAB"}
+            ]}"#,
+        );
+        let exp = jsonwebtoken::get_current_timestamp() + 600;
+        let claims = json!({ "iss": "https://idp", "aud": "api", "exp": exp, "sub": "alice" });
+        let valid = token("a", b"secret", claims.clone());
+        assert_eq!(validator.validate(&valid).await.unwrap()["sub"], "alice");
+
+        let forged = token("a", b"other", claims.clone());
+        assert!(matches!(
+            validator.validate(&forged).await,
+            Err(JwtError::Invalid(_))
+        ));
+        // The key of "b" is restricted to HS512.
+        let wrong_alg = token("b", b"other", claims.clone());
+        assert!(matches!(
+            validator.validate(&wrong_alg).await,
+            Err(JwtError::UnknownKey)
+        ));
+        let mut other_audience = claims.clone();
+        other_audience["aud"] = json!("web");
+        assert!(matches!(
+            validator
+                .validate(&token("a", b"secret", other_audience))
+                .await,
+            Err(JwtError::Invalid(_))
+        ));
+        let mut expired = claims;
+        expired["exp"] = json!(exp - 1200);
+        assert!(matches!(
+            validator.validate(&token("a", b"secret", expired)).await,
+            Err(JwtError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn jwks_sources() {
+        let config = |jwks: Value, extra: Value| {
+            let mut config = json!({ "jwks": jwks });
+            config
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            serde_json::from_value::<JwtConfig>(config).unwrap()
+        };
+        let file = json!({ "type": "file", "value": "jwks.json" });
+        let https = json!({ "type": "uri", "value": "https://idp/jwks.json" });
+        let http = json!({ "type": "uri", "value": "http://idp/jwks.json" });
+        assert!(config(file.clone(), json!({})).validate().is_ok());
+        assert!(config(https, json!({})).validate().is_ok());
+        assert!(matches!(
+            config(http.clone(), json!({})).validate(),
+            Err(InvalidJwtConfig::InsecureJwks(_))
+        ));
+        assert!(config(http, json!({ "allow_http_jwks": true }))
+            .validate()
+            .is_ok());
+
+        // Key sets refreshed after longer than the clock can tell never expire.
+        let validator = JwtValidator::new(config(file, json!({ "refresh_secs": u64::MAX })));
+        *validator.keys.borrow_mut() = Some(Rc::new(KeySet::parse(b"{\"keys\": []}").unwrap()));
+        assert!(matches!(validator.cached(None), Some(Ok(_))));
+    }
+
+    #[test]
+    fn route_requirements() {
+        let claims = json!({
+            "scope": "read write",
+            "roles": ["admin", "dev"],
+            "org": { "id": 7 },
+        });
+        let claims = claims.as_object().unwrap();
+        assert!(has_scopes(claims, &["read".to_string()]));
+        assert!(!has_scopes(
+            claims,
+            &["read".to_string(), "delete".to_string()]
+        ));
+        assert!(claim_matches(claim(claims, "roles"), &json!("admin")));
+        assert!(claim_matches(claim(claims, "org.id"), &json!([6, 7])));
+        assert!(!claim_matches(claim(claims, "org.name"), &json!("acme")));
+    }
+}
@@ -16,11 +16,32 @@
 //!   balancing and error handling.
 //! - [`RewriteAndRouteHandler`]: Handles request routing based on predefined rules, directing
 //!   requests to appropriate handlers or upstream servers.
+//! - [`AltSvcHandler`]: Advertises alternative services such as HTTP/3 with an `Alt-Svc` header.
+//! - [`ForwardProxyHandler`]: Serves CONNECT tunnels and absolute-form requests of a forward proxy,
+//!   with destination allow and deny lists and `Proxy-Authorization` basic credentials.
+//! - [`RateLimitHandler`]: Limits the rate of requests per server and per route, keyed by route,
+//!   client IP or header value.
+//! - [`GlobalRateLimitHandler`]: Checks requests against the quotas of an external rate limit
+//!   service shared by a fleet of proxies.
+//! - [`ExtAuthzHandler`]: Delegates the authorization of requests to an external policy service
+//!   over HTTP or gRPC.
+//! - [`IpFilterHandler`]: Denies requests by client IP address, read from the PROXY protocol or a
+//!   trusted `X-Forwarded-For` chain, per server and per route.
+//! - [`CorsHandler`]: Answers CORS preflight requests and gives responses the `Access-Control-*`
+//!   headers allowing the origins of their route.
+//! - [`GrpcHandler`]: Enforces the `grpc-timeout` of gRPC calls and reports failures of the proxy
+//!   with `grpc-status` instead of bare HTTP statuses.
 //!
 //! # Optional Components
 //!
-//! - [`OpenIdHandler`]: Provides OpenID Connect authentication functionality (available with the
-//!   "openid" feature).
+//! - [`OpenIdHandler`]: Signs users in with an OpenID Connect provider, keeping their sessions in
+//!   encrypted cookies (available with the "openid" feature).
+//! - [`AcmeChallengeHandler`]: Answers ACME HTTP-01 challenges (available with the "acme" feature).
+//! - [`AuthHandler`]: Authenticates the requests of routes with HTTP Basic credentials checked
+//!   against an htpasswd file, or with API keys checked against a key file (available with the
+//!   "auth" feature).
+//! - [`JwtHandler`]: Authenticates requests with the JWT of their bearer token, checking the scopes
+//!   and claims required by routes (available with the "jwt" feature).
 //!
 //! # HttpHandler Trait
 //!
@@ -66,7 +87,7 @@
 //!             route::RouteConfig, ConnectionReuseHandler, ContentHandler, RewriteAndRouteHandler,
 //!             UpstreamHandler,
 //!         },
-//!         HttpServerTimeout,
+//!         Http2Settings, HttpServerTimeout, RequestLimitConfig,
 //!     },
 //! };
 //! use service_async::{layer::FactoryLayer, stack::FactoryStack, Param};
@@ -85,6 +106,16 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<Http2Settings> for DummyConfig {
+//!     fn param(&self) -> Http2Settings {
+//!         Http2Settings::default()
+//!     }
+//! }
+//! impl Param<RequestLimitConfig> for DummyConfig {
+//!     fn param(&self) -> RequestLimitConfig {
+//!         RequestLimitConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
@@ -116,16 +147,47 @@
 //! # Feature Flags
 //!
 //! - `openid`: Enables the OpenID Connect authentication functionality
+//! - `acme`: Enables the ACME HTTP-01 challenge responder
+//! - `jwt`: Enables the JWT bearer token authentication
+//! - `auth`: Enables the HTTP Basic and API key authentication
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod alt_svc;
+#[cfg(feature = "auth")]
+pub mod auth;
 pub mod connection_persistence;
 pub mod content_handler;
+pub mod cors;
+pub mod ext_authz;
+pub mod forward_proxy;
+pub mod global_rate_limit;
+pub mod grpc;
+pub mod ip_filter;
+#[cfg(feature = "jwt")]
+pub mod jwt;
 #[cfg(feature = "openid")]
 pub mod openid;
+pub mod rate_limit;
 pub mod route;
 pub mod upstream;
 
+#[cfg(feature = "acme")]
+pub use acme::AcmeChallengeHandler;
+pub use alt_svc::AltSvcHandler;
+#[cfg(feature = "auth")]
+pub use auth::AuthHandler;
 pub use connection_persistence::ConnectionReuseHandler;
 pub use content_handler::ContentHandler;
+pub use cors::CorsHandler;
+pub use ext_authz::ExtAuthzHandler;
+pub use forward_proxy::ForwardProxyHandler;
+pub use global_rate_limit::GlobalRateLimitHandler;
+pub use grpc::GrpcHandler;
+pub use ip_filter::IpFilterHandler;
+#[cfg(feature = "jwt")]
+pub use jwt::JwtHandler;
 #[cfg(feature = "openid")]
 pub use openid::OpenIdHandler;
+pub use rate_limit::RateLimitHandler;
 pub use route::{RewriteAndRouteHandler, RoutingFactoryError};
 pub use upstream::UpstreamHandler;
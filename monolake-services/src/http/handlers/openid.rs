@@ -1,148 +1,629 @@
+//! OpenID Connect authentication of HTTP requests.
+//!
+//! [`OpenIdHandler`] signs users in with the authorization code flow of an OpenID Connect
+//! provider and keeps their sessions in encrypted cookies, so that no state is kept by the proxy
+//! and any worker or instance can serve any request.
+//!
+//! # Key Components
+//!
+//! - [`OpenIdConfig`]: Provider, client, cookie and session settings of a server.
+//! - [`OpenIdRouteConfig`]: The opt-out of a route.
+//! - [`Error`]: Failures of the calls to the provider.
+//!
+//! # Flow
+//!
+//! 1. Requests without a valid session are redirected to the authorization endpoint of the
+//!    provider, with a PKCE challenge. The state, nonce and PKCE verifier of the login, and the
+//!    requested URL, are kept in a short lived encrypted cookie. Requests other than `GET` and
+//!    `HEAD` are answered with `401 Unauthorized` instead.
+//! 2. The provider redirects the user to the path of the redirect URL, where the code is exchanged
+//!    for tokens and the ID token is verified against the nonce of the login. The session cookie is
+//!    set and the user is redirected to the requested URL.
+//! 3. Requests with a session cookie are passed to the inner handler until the access token
+//!    expires. Sessions are then refreshed with their refresh token, or end. Sessions also end
+//!    after the session lifetime, whatever their tokens.
+//! 4. Requests to the logout path clear the session cookie and are redirected to the post logout
+//!    URL, or to `/`.
+//!
+//! # Features
+//!
+//! - Cookies are encrypted and authenticated with AES-256-GCM, with a key derived from the cookie
+//!   secret of the server. Cookies set by other servers or tampered with are ignored
+//! - The provider metadata and keys are discovered once per worker and rediscovered every hour, or
+//!   after a failure
+//! - The cookies of the handler are removed from the requests passed to the inner handler
+//! - Failures of the provider are answered with `502 Bad Gateway` rather than failing the worker
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(OpenIdHandler::layer())
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    cell::RefCell,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use bytes::{Bytes, BytesMut};
-use cookie::Cookie;
-use http::{HeaderName, HeaderValue, Request, Response, StatusCode};
-use lazy_static::lazy_static;
-use monoio::net::TcpStream;
-use monoio_http::common::body::{Body, FixedBody, HttpBody, StreamHint};
-use monoio_transports::{
-    connectors::{Connector, TcpConnector, TcpTlsAddr, TlsConnector, TlsStream},
-    http::H1Connector,
-};
+use bytes::Bytes;
+use cookie::{Cookie, CookieJar, Key, SameSite};
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use monoio_http::common::body::{FixedBody, HttpBody};
 use monolake_core::http::{HttpHandler, ResponseWithContinue};
-#[allow(unused)]
-use openidconnect::core::{
-    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClient, CoreClientAuthMethod,
-    CoreGenderClaim, CoreGrantType, CoreIdTokenClaims, CoreIdTokenVerifier, CoreJsonWebKey,
-    CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm,
-    CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm, CoreProviderMetadata, CoreResponseMode,
-    CoreResponseType, CoreRevocableToken, CoreSubjectIdentifierType,
-};
-#[allow(unused)]
 use openidconnect::{
-    AccessToken, AdditionalClaims, AdditionalProviderMetadata, AuthenticationFlow,
-    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse,
-    ProviderMetadata, RedirectUrl, RevocationUrl, Scope, UserInfoClaims,
+    core::{CoreClient, CoreProviderMetadata, CoreResponseType},
+    AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, HttpRequest,
+    HttpResponse, IssuerUrl, Nonce, OAuth2TokenResponse, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, RefreshToken, Scope,
 };
-use openidconnect::{HttpRequest, HttpResponse};
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use service_async::{
     layer::{layer_fn, FactoryLayer},
     AsyncMakeService, MakeService, Param, Service,
 };
 use thiserror::Error;
-use tracing::debug;
+use tracing::{debug, warn};
 use url::Url;
 
-use crate::http::generate_response;
+use super::route::{RouteConfig, RouteMatcher};
+use crate::http::{
+    client::{ClientError, HttpClient},
+    generate_response,
+};
 
-type HttpsConnector = H1Connector<TlsConnector<TcpConnector>, TcpTlsAddr, TlsStream<TcpStream>>;
+const STATE_COOKIE: &str = "monolake_oidc_state";
+// Logins not completed by then must start again.
+const LOGIN_TTL: Duration = Duration::from_secs(600);
+const DISCOVERY_TTL: Duration = Duration::from_secs(3600);
 
+/// Failures of the calls to an OpenID Connect provider.
 #[derive(Debug, Error)]
-pub enum Error {}
+pub enum Error {
+    #[error("invalid url {0}")]
+    Url(String),
+    #[error("invalid request: {0}")]
+    Http(#[from] http::Error),
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error("discovery failed: {0}")]
+    Discovery(String),
+    #[error("token request failed: {0}")]
+    Token(String),
+    #[error("invalid id token: {0}")]
+    IdToken(String),
+}
+
+/// OpenID Connect settings of a server.
+///
+/// ```toml
+/// [servers.demo.openid]
+/// issuer_url = "https://idp.example.com"
+/// client_id = "monolake"
+/// client_secret = "..."
+/// redirect_url = "https://app.example.com/oauth2/callback"
+/// cookie_secret = "a random secret of at least 32 bytes"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct OpenIdConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub issuer_url: String,
+    /// Callback URL registered with the provider, its path is served by the handler.
+    pub redirect_url: String,
+    /// Scopes requested in addition to `openid`.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    /// Secret the cookies are encrypted with, at least 32 bytes.
+    pub cookie_secret: String,
+    #[serde(default = "default_cookie_name")]
+    pub cookie_name: String,
+    /// Seconds after which sessions end, whatever their tokens.
+    #[serde(default = "default_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// Path clearing the session.
+    #[serde(default = "default_logout_path")]
+    pub logout_path: String,
+    /// URL users are redirected to once logged out, such as the end session endpoint of the
+    /// provider.
+    #[serde(default)]
+    pub post_logout_redirect_url: Option<String>,
+    /// Milliseconds after which calls to the provider fail.
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// OpenID Connect settings of a route.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OpenIdRouteConfig {
+    /// Serve the route without session.
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+fn default_cookie_name() -> String {
+    "monolake_session".to_string()
+}
+
+const fn default_session_ttl_secs() -> u64 {
+    86400
+}
+
+fn default_logout_path() -> String {
+    "/logout".to_string()
+}
+
+const fn default_timeout_ms() -> u64 {
+    5000
+}
+
+/// Session of a signed in user, kept in the session cookie.
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    sub: String,
+    /// Unix time the access token expires at, the session must then be refreshed.
+    refresh_at: u64,
+    /// Unix time the session ends at.
+    expires_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+}
 
-#[derive(Debug, Deserialize, Serialize)]
-struct ExtraClaims {
-    // Deprecated and thus optional as it might be removed in the futre
-    // sub_legacy: Option<String>,
-    // groups: Vec<String>,
+/// Login in progress, kept in the state cookie.
+#[derive(Debug, Serialize, Deserialize)]
+struct LoginState {
+    csrf: String,
+    nonce: String,
+    pkce_verifier: String,
+    /// Path and query the user requested, on this site.
+    target: String,
 }
-impl AdditionalClaims for ExtraClaims {}
-
-fn handle_error<T: std::error::Error>(fail: &T, msg: &'static str) {
-    let mut err_msg = format!("ERROR: {}", msg);
-    let mut cur_fail: Option<&dyn std::error::Error> = Some(fail);
-    while let Some(cause) = cur_fail {
-        err_msg += &format!("\n    caused by: {}", cause);
-        cur_fail = cause.source();
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// The path and query a login returns to, or `/` when it would lead the user to another site,
+/// like the scheme relative `//evil.example`.
+fn local_target(target: &str) -> &str {
+    match target.as_bytes() {
+        [b'/', b'/' | b'\\', ..] => "/",
+        [b'/', ..] => target,
+        _ => "/",
     }
-    debug!("{}", err_msg);
-    // exit(1);
 }
 
-pub async fn async_http_client(request: HttpRequest) -> Result<HttpResponse, Error> {
-    let uri = request.url.as_str().parse::<http::uri::Uri>().unwrap();
-    let method = request.method.as_str().parse::<http::Method>().unwrap();
-    let mut req = Request::builder()
-        .version(http::Version::HTTP_11)
-        .method(method)
-        .uri(&uri);
-
-    let headers = req.headers_mut().unwrap();
-    for (key, value) in request.headers.iter() {
-        headers.insert(
-            HeaderName::from_bytes(key.as_str().as_bytes()).unwrap(),
-            HeaderValue::from_str(value.to_str().unwrap()).unwrap(),
-        );
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        message += &format!(": {cause}");
+        source = cause.source();
     }
-    let host = uri.host().unwrap();
-    headers.insert("Host", HeaderValue::from_bytes(host.as_bytes()).unwrap());
+    message
+}
 
-    let request_payload: Bytes = request.body.into();
-    let req: http::Request<HttpBody> = req
-        .body(HttpBody::fixed_body(Some(request_payload)))
-        .unwrap();
+/// Provider client and cookie keys of a worker.
+struct OpenIdClient {
+    config: OpenIdConfig,
+    key: Key,
+    // Cookies are only sent over HTTPS when the redirect URL is.
+    secure: bool,
+    callback_path: String,
+    client: HttpClient,
+    provider: RefCell<Option<(Instant, CoreClient)>>,
+}
 
-    let client = HttpsConnector::default().with_default_pool();
-    let key = req.uri().try_into().unwrap();
-    let mut client = client.connect(key).await.unwrap();
-    let (response, _) = client.send_request(req).await;
-    let response = response.unwrap();
-
-    let status = response.status().as_u16();
-    // let headers: HeaderMap = response.headers().clone();
-    let mut headers = openidconnect::http::HeaderMap::new();
-    for (key, value) in response.headers().iter() {
-        headers.insert(
-            openidconnect::http::HeaderName::from_bytes(key.as_str().as_bytes()).unwrap(),
-            openidconnect::http::HeaderValue::from_str(value.to_str().unwrap()).unwrap(),
-        );
+impl OpenIdClient {
+    /// # Panics
+    ///
+    /// Panics if the cookie secret is shorter than 32 bytes.
+    fn new(config: OpenIdConfig) -> Self {
+        let redirect_url = Url::parse(&config.redirect_url).ok();
+        Self {
+            key: Key::derive_from(config.cookie_secret.as_bytes()),
+            secure: redirect_url
+                .as_ref()
+                .map_or(true, |url| url.scheme() == "https"),
+            callback_path: redirect_url.map_or_else(String::new, |url| url.path().to_string()),
+            client: HttpClient::new(Duration::from_millis(config.timeout_ms)),
+            config,
+            provider: RefCell::new(None),
+        }
+    }
+
+    async fn http(&self, request: HttpRequest) -> Result<HttpResponse, Error> {
+        let uri: Uri = request
+            .url
+            .as_str()
+            .parse()
+            .map_err(|_| Error::Url(request.url.to_string()))?;
+        let mut builder = Request::builder()
+            .method(request.method.as_str())
+            .uri(uri.clone())
+            .header(header::CONTENT_LENGTH, request.body.len());
+        for (name, value) in request.headers.iter() {
+            builder = builder.header(name.as_str(), value.as_bytes());
+        }
+        let request = builder.body(HttpBody::fixed_body(Some(Bytes::from(request.body))))?;
+        let response = self.client.send(request).await?;
+
+        let status_code = openidconnect::http::StatusCode::from_u16(response.status().as_u16())
+            .map_err(|_| Error::Url(uri.to_string()))?;
+        let mut headers = openidconnect::http::HeaderMap::new();
+        for (name, value) in response.headers() {
+            if let (Ok(name), Ok(value)) = (
+                openidconnect::http::HeaderName::from_bytes(name.as_str().as_bytes()),
+                openidconnect::http::HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        Ok(HttpResponse {
+            status_code,
+            headers,
+            body: response.into_body().to_vec(),
+        })
     }
-    let mut body = response.into_body();
-
-    let payload = match body.stream_hint() {
-        StreamHint::None => Bytes::new(),
-        StreamHint::Fixed => body.next_data().await.unwrap().unwrap(),
-        StreamHint::Stream => {
-            let mut bytes = BytesMut::new();
-            while let Some(Ok(data)) = body.next_data().await {
-                bytes.extend_from_slice(&data[..]);
+
+    /// The client of the provider, discovered again once stale.
+    async fn provider(&self) -> Result<CoreClient, Error> {
+        if let Some((discovered, client)) = &*self.provider.borrow() {
+            if discovered.elapsed() < DISCOVERY_TTL {
+                return Ok(client.clone());
             }
-            bytes.freeze()
         }
-    };
-
-    let body: Vec<u8> = payload.to_vec();
-    Ok(HttpResponse {
-        status_code: status.try_into().unwrap(),
-        headers,
-        body,
-    })
+        let config = &self.config;
+        let issuer_url =
+            IssuerUrl::new(config.issuer_url.clone()).map_err(|e| Error::Url(e.to_string()))?;
+        let redirect_url =
+            RedirectUrl::new(config.redirect_url.clone()).map_err(|e| Error::Url(e.to_string()))?;
+        let metadata =
+            CoreProviderMetadata::discover_async(issuer_url, |request| self.http(request))
+                .await
+                .map_err(|e| Error::Discovery(error_chain(&e)))?;
+        let client = CoreClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id.clone()),
+            Some(ClientSecret::new(config.client_secret.clone())),
+        )
+        .set_redirect_uri(redirect_url);
+        *self.provider.borrow_mut() = Some((Instant::now(), client.clone()));
+        Ok(client)
+    }
+
+    /// Builds an encrypted cookie, or a cookie clearing `name` without value.
+    fn seal(&self, name: &str, value: Option<&impl Serialize>, max_age: u64) -> HeaderValue {
+        let value = value
+            .and_then(|value| serde_json::to_string(value).ok())
+            .unwrap_or_default();
+        let clear = value.is_empty();
+        let cookie = Cookie::build((name.to_string(), value))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            // Lax cookies are sent along the redirects of the provider.
+            .same_site(SameSite::Lax)
+            .max_age(cookie::time::Duration::seconds(max_age as i64));
+        let cookie = if clear {
+            cookie.build()
+        } else {
+            let mut jar = CookieJar::new();
+            jar.private_mut(&self.key).add(cookie);
+            jar.get(name)
+                .cloned()
+                .unwrap_or_else(|| Cookie::new("", ""))
+        };
+        HeaderValue::from_str(&cookie.to_string()).unwrap_or(HeaderValue::from_static(""))
+    }
+
+    /// Decrypts the cookie `name` of a request.
+    fn open<T: DeserializeOwned>(&self, headers: &HeaderMap, name: &str) -> Option<T> {
+        let jar = CookieJar::new();
+        let cookie = request_cookies(headers).find(|cookie| cookie.name() == name)?;
+        let cookie = jar.private(&self.key).decrypt(cookie.into_owned())?;
+        serde_json::from_str(cookie.value()).ok()
+    }
+
+    fn redirect<B: FixedBody>(&self, location: &str) -> Response<B> {
+        let mut response = generate_response(StatusCode::FOUND, false);
+        if let Ok(location) = HeaderValue::from_str(location) {
+            response.headers_mut().insert(header::LOCATION, location);
+        }
+        response
+    }
+
+    /// Redirects the user to the provider, or answers `401 Unauthorized` to other than page
+    /// loads.
+    async fn login<B: FixedBody, T>(&self, request: &Request<T>) -> Result<Response<B>, Error> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return Ok(generate_response(StatusCode::UNAUTHORIZED, false));
+        }
+        let client = self.provider().await?;
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut authorize = client
+            .authorize_url(
+                AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+                CsrfToken::new_random,
+                Nonce::new_random,
+            )
+            .set_pkce_challenge(pkce_challenge);
+        for scope in &self.config.scopes {
+            authorize = authorize.add_scope(Scope::new(scope.clone()));
+        }
+        let (authorize_url, csrf, nonce) = authorize.url();
+        let state = LoginState {
+            csrf: csrf.secret().clone(),
+            nonce: nonce.secret().clone(),
+            pkce_verifier: pkce_verifier.secret().clone(),
+            target: local_target(
+                request
+                    .uri()
+                    .path_and_query()
+                    .map_or("/", |target| target.as_str()),
+            )
+            .to_string(),
+        };
+        let mut response = self.redirect(authorize_url.as_str());
+        response.headers_mut().append(
+            header::SET_COOKIE,
+            self.seal(STATE_COOKIE, Some(&state), LOGIN_TTL.as_secs()),
+        );
+        Ok(response)
+    }
+
+    /// Completes a login with the code the provider redirected the user with.
+    async fn callback<B: FixedBody, T>(&self, request: &Request<T>) -> Result<Response<B>, Error> {
+        let query = request.uri().query().unwrap_or_default();
+        let param = |name: &str| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        let state: Option<LoginState> = self.open(request.headers(), STATE_COOKIE);
+        let (Some(state), Some(code)) = (state, param("code")) else {
+            debug!("openid callback without login: {:?}", param("error"));
+            return Ok(generate_response(StatusCode::BAD_REQUEST, false));
+        };
+        if param("state").as_deref() != Some(state.csrf.as_str()) {
+            debug!("openid callback with a mismatched state");
+            return Ok(generate_response(StatusCode::BAD_REQUEST, false));
+        }
+
+        let client = self.provider().await?;
+        let token_response = client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(state.pkce_verifier))
+            .request_async(|request| self.http(request))
+            .await
+            .map_err(|e| Error::Token(error_chain(&e)))?;
+        let id_token = token_response
+            .extra_fields()
+            .id_token()
+            .ok_or_else(|| Error::IdToken("missing".to_string()))?;
+        let claims = id_token
+            .claims(&client.id_token_verifier(), &Nonce::new(state.nonce))
+            .map_err(|e| Error::IdToken(error_chain(&e)))?;
+
+        let now = unix_now();
+        let refresh_at = match token_response.expires_in() {
+            Some(expires_in) => now + expires_in.as_secs(),
+            None => claims.expiration().timestamp().max(0) as u64,
+        };
+        let session = Session {
+            sub: claims.subject().to_string(),
+            refresh_at,
+            expires_at: now + self.config.session_ttl_secs,
+            refresh_token: token_response
+                .refresh_token()
+                .map(|token| token.secret().clone()),
+        };
+        debug!("openid session started for {}", session.sub);
+        let mut response = self.redirect(local_target(&state.target));
+        let headers = response.headers_mut();
+        headers.append(header::SET_COOKIE, self.session_cookie(&session, now));
+        headers.append(header::SET_COOKIE, self.seal(STATE_COOKIE, None::<&()>, 0));
+        Ok(response)
+    }
+
+    /// Refreshes the tokens of an expired session.
+    async fn refresh(&self, session: &mut Session) -> Result<(), Error> {
+        let Some(refresh_token) = &session.refresh_token else {
+            return Err(Error::Token("no refresh token".to_string()));
+        };
+        let client = self.provider().await?;
+        let token_response = client
+            .exchange_refresh_token(&RefreshToken::new(refresh_token.clone()))
+            .request_async(|request| self.http(request))
+            .await
+            .map_err(|e| Error::Token(error_chain(&e)))?;
+        let now = unix_now();
+        session.refresh_at = now
+            + token_response
+                .expires_in()
+                .map_or(self.config.session_ttl_secs, |expires_in| {
+                    expires_in.as_secs()
+                });
+        // Providers rotating refresh tokens send a new one.
+        if let Some(refresh_token) = token_response.refresh_token() {
+            session.refresh_token = Some(refresh_token.secret().clone());
+        }
+        Ok(())
+    }
+
+    fn session_cookie(&self, session: &Session, now: u64) -> HeaderValue {
+        let max_age = session.expires_at.saturating_sub(now);
+        self.seal(&self.config.cookie_name, Some(session), max_age)
+    }
+
+    /// Removes the cookies of the handler from a request.
+    fn strip_cookies(&self, headers: &mut HeaderMap) {
+        let kept: Vec<_> = request_cookies(headers)
+            .filter(|cookie| {
+                cookie.name() != self.config.cookie_name && cookie.name() != STATE_COOKIE
+            })
+            .map(|cookie| cookie.to_string())
+            .collect();
+        headers.remove(header::COOKIE);
+        if kept.is_empty() {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&kept.join("; ")) {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+}
+
+fn request_cookies(headers: &HeaderMap) -> impl Iterator<Item = Cookie<'_>> {
+    headers
+        .get_all(header::COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(Result::ok)
 }
 
-#[derive(Clone)]
+/// Routes opted out of the handler.
+type Routes = RouteMatcher<OpenIdRouteConfig>;
+
+/// Handler authenticating users with an OpenID Connect provider.
+///
+/// See the [module level documentation](crate::http::handlers::openid) for details.
 pub struct OpenIdHandler<H> {
     inner: H,
-    openid_config: Option<OpenIdConfig>,
+    client: Option<Rc<OpenIdClient>>,
+    routes: Arc<Routes>,
 }
 
-impl<F: MakeService> MakeService for OpenIdHandler<F> {
+impl<H, CX, B> Service<(Request<B>, CX)> for OpenIdHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (mut request, ctx): (Request<B>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let Some(client) = &self.client else {
+            return self.inner.handle(request, ctx).await;
+        };
+        let path = request.uri().path();
+        let disabled = self.routes.at(path).is_some_and(|route| route.disabled);
+        if disabled {
+            return self.inner.handle(request, ctx).await;
+        }
+
+        if path == client.config.logout_path {
+            let location = client
+                .config
+                .post_logout_redirect_url
+                .as_deref()
+                .unwrap_or("/");
+            let mut response = client.redirect(location);
+            response.headers_mut().append(
+                header::SET_COOKIE,
+                client.seal(&client.config.cookie_name, None::<&()>, 0),
+            );
+            return Ok((response, true));
+        }
+        let result = if path == client.callback_path {
+            client.callback(&request).await
+        } else {
+            let now = unix_now();
+            let session = client
+                .open::<Session>(request.headers(), &client.config.cookie_name)
+                .filter(|session| now < session.expires_at);
+            // The cookie to set along the response when authenticated.
+            let authenticated = match session {
+                Some(session) if now < session.refresh_at => Some(None),
+                Some(mut session) => match client.refresh(&mut session).await {
+                    Ok(()) => Some(Some(client.session_cookie(&session, now))),
+                    Err(e) => {
+                        debug!("openid session of {} not refreshed: {e}", session.sub);
+                        None
+                    }
+                },
+                None => None,
+            };
+            if let Some(refreshed) = authenticated {
+                client.strip_cookies(request.headers_mut());
+                let (mut response, cont) = self.inner.handle(request, ctx).await?;
+                if let Some(cookie) = refreshed {
+                    response.headers_mut().append(header::SET_COOKIE, cookie);
+                }
+                return Ok((response, cont));
+            }
+            client.login(&request).await
+        };
+        match result {
+            Ok(response) => Ok((response, true)),
+            Err(e) => {
+                warn!("openid provider {} failed: {e}", client.config.issuer_url);
+                Ok((generate_response(StatusCode::BAD_GATEWAY, false), true))
+            }
+        }
+    }
+}
+
+impl<F> OpenIdHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = OpenIdHandlerFactory<F>>
+    where
+        C: Param<Option<OpenIdConfig>> + Param<Vec<RouteConfig>>,
+    {
+        layer_fn(|c: &C, inner| {
+            let routes = Param::<Vec<RouteConfig>>::param(c)
+                .into_iter()
+                .map(|route| (route.path, route.openid));
+            OpenIdHandlerFactory {
+                inner,
+                config: Param::<Option<OpenIdConfig>>::param(c),
+                routes: Arc::new(RouteMatcher::new("openid settings", routes)),
+            }
+        })
+    }
+}
+
+/// Factory for [`OpenIdHandler`].
+pub struct OpenIdHandlerFactory<F> {
+    inner: F,
+    config: Option<OpenIdConfig>,
+    routes: Arc<Routes>,
+}
+
+impl<F> OpenIdHandlerFactory<F> {
+    // Keep the discovered provider and the connections when the config did not change.
+    fn make_client<H>(&self, old: Option<&OpenIdHandler<H>>) -> Option<Rc<OpenIdClient>> {
+        let config = self.config.as_ref()?;
+        match old.and_then(|old| old.client.as_ref()) {
+            Some(client) if client.config == *config => Some(client.clone()),
+            _ => Some(Rc::new(OpenIdClient::new(config.clone()))),
+        }
+    }
+}
+
+impl<F: MakeService> MakeService for OpenIdHandlerFactory<F> {
     type Service = OpenIdHandler<F::Service>;
     type Error = F::Error;
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
         Ok(OpenIdHandler {
             inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
-            openid_config: self.openid_config.clone(),
+            client: self.make_client(old),
+            routes: self.routes.clone(),
         })
     }
 }
 
-impl<F: AsyncMakeService> AsyncMakeService for OpenIdHandler<F> {
+impl<F: AsyncMakeService> AsyncMakeService for OpenIdHandlerFactory<F> {
     type Service = OpenIdHandler<F::Service>;
     type Error = F::Error;
 
@@ -152,251 +633,369 @@ impl<F: AsyncMakeService> AsyncMakeService for OpenIdHandler<F> {
     ) -> Result<Self::Service, Self::Error> {
         Ok(OpenIdHandler {
             inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
-            openid_config: self.openid_config.clone(),
+            client: self.make_client(old),
+            routes: self.routes.clone(),
         })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-pub struct OpenIdConfig {
-    // TODO: Need to add openid scopes etc.
-    pub client_id: String,
-    pub client_secret: String,
-    pub issuer_url: String,
-    pub redirect_url: String,
-}
-
-impl<F> OpenIdHandler<F> {
-    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
-    where
-        C: Param<Option<OpenIdConfig>>,
-    {
-        layer_fn(move |c: &C, inner| Self {
-            inner,
-            openid_config: c.param(),
-        })
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, net::SocketAddr};
+
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use ring::hmac;
+
+    use super::*;
+    use crate::testing::{stub_http, Recorder, TestCtx};
+
+    #[test]
+    fn session_cookies() {
+        let config: OpenIdConfig = serde_json::from_value(serde_json::json!({
+            "client_id": "monolake",
+            "client_secret": "secret",
+            "issuer_url": "http://127.0.0.1:8080",
+            "redirect_url": "http://127.0.0.1/callback",
+            "cookie_secret": "0123456789abcdef0123456789abcdef",
+        }))
+        .unwrap();
+        let client = OpenIdClient::new(config);
+        assert_eq!(client.callback_path, "/callback");
+        let session = Session {
+            sub: "alice".to_string(),
+            refresh_at: 1,
+            expires_at: 2,
+            refresh_token: None,
+        };
+        let cookie = client.seal("monolake_session", Some(&session), 60);
+        let cookie = Cookie::parse(cookie.to_str().unwrap()).unwrap();
+        assert!(cookie.http_only().unwrap());
+        assert!(!cookie.value().contains("alice"));
+
+        let mut headers = HeaderMap::new();
+        let value = format!("other=1; monolake_session={}", cookie.value());
+        headers.insert(header::COOKIE, HeaderValue::from_str(&value).unwrap());
+        let opened: Session = client.open(&headers, "monolake_session").unwrap();
+        assert_eq!(opened.sub, "alice");
+        // Cookies are bound to their name.
+        let value = format!("monolake_oidc_state={}", cookie.value());
+        headers.insert(header::COOKIE, HeaderValue::from_str(&value).unwrap());
+        assert!(client.open::<Session>(&headers, STATE_COOKIE).is_none());
+
+        let value = format!("other=1; monolake_session={}", cookie.value());
+        headers.insert(header::COOKIE, HeaderValue::from_str(&value).unwrap());
+        client.strip_cookies(&mut headers);
+        assert_eq!(headers[header::COOKIE], "other=1");
     }
-}
-
-#[derive(Clone)]
-struct SessionState {
-    // Plenty more to add, eg. expiration time
-    pub nonce: Nonce,
-    pub access_token: Option<AccessToken>,
-}
 
-// TODO: This is only a PoC, eventually need to replace this with a backend store like Redis for
-// example.
-lazy_static! {
-    static ref SESSION_STORE: Arc<RwLock<HashMap<String, SessionState>>> =
-        Arc::new(RwLock::new(HashMap::new()));
-}
-
-// impl<H> HttpHandler for OpenIdHandler<H>
-impl<H, CX, B> Service<(Request<B>, CX)> for OpenIdHandler<H>
-where
-    H: HttpHandler<CX, B>,
-    H::Body: FixedBody,
-{
-    type Response = ResponseWithContinue<H::Body>;
-    type Error = H::Error;
-
-    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
-        if self.openid_config.is_none() {
-            return self.inner.handle(request, ctx).await;
-        }
+    #[test]
+    fn login_targets() {
+        assert_eq!(local_target("/app?x=1"), "/app?x=1");
+        assert_eq!(local_target("//evil.example/app"), "/");
+        assert_eq!(local_target("/\\evil.example/app"), "/");
+        assert_eq!(local_target("https://evil.example/"), "/");
+        assert_eq!(local_target(""), "/");
+    }
 
-        let headers = request.headers();
-        let mut auth_cookie: Option<String> = None;
-        if headers.contains_key(http::header::COOKIE) {
-            let cookies = Cookie::split_parse(
-                (headers.get(http::header::COOKIE).unwrap())
-                    .to_str()
-                    .unwrap(),
-            );
-            for cookie in cookies {
-                let cookie = cookie.unwrap();
-                if cookie.name() == "session-id" {
-                    let session_store = SESSION_STORE.read().unwrap();
-                    if let Some(state) = session_store.get(cookie.value())
-                        && state.access_token.is_some()
-                    {
-                        auth_cookie = Some(cookie.value().to_string());
+    /// Stub provider serving its metadata, no keys as ID tokens are signed with the client
+    /// secret, and a token endpoint exchanging the code `code` and the refresh token `refresh-1`.
+    /// ID tokens carry the nonce of `nonce`.
+    fn stub_provider(nonce: Rc<RefCell<String>>, grants: Rc<RefCell<Vec<String>>>) -> SocketAddr {
+        let issuer = Rc::new(Cell::new(None::<SocketAddr>));
+        let issuer_of_stub = issuer.clone();
+        let addr = stub_http(move |request: Request<Bytes>| {
+            let issuer = format!("http://{}", issuer_of_stub.get().unwrap());
+            let nonce = nonce.borrow().clone();
+            let grants = grants.clone();
+            async move {
+                let json = |status: StatusCode, body: serde_json::Value| {
+                    Response::builder()
+                        .status(status)
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(body.to_string().into_bytes())
+                        .unwrap()
+                };
+                let form: Vec<(String, String)> = url::form_urlencoded::parse(request.body())
+                    .into_owned()
+                    .collect();
+                let param = |name: &str| {
+                    form.iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.as_str())
+                };
+                match request.uri().path() {
+                    "/.well-known/openid-configuration" => json(
+                        StatusCode::OK,
+                        serde_json::json!({
+                            "issuer": issuer,
+                            "authorization_endpoint": format!("{issuer}/authorize"),
+                            "token_endpoint": format!("{issuer}/token"),
+                            "jwks_uri": format!("{issuer}/jwks"),
+                            "response_types_supported": ["code"],
+                            "subject_types_supported": ["public"],
+                            "id_token_signing_alg_values_supported": ["HS256"],
+                        }),
+                    ),
+                    "/jwks" => json(StatusCode::OK, serde_json::json!({ "keys": [] })),
+                    "/token" => {
+                        let grant = param("grant_type").unwrap_or_default().to_string();
+                        grants.borrow_mut().push(grant.clone());
+                        match grant.as_str() {
+                            "authorization_code"
+                                if param("code") == Some("code")
+                                    && param("code_verifier").is_some() =>
+                            {
+                                json(
+                                    StatusCode::OK,
+                                    serde_json::json!({
+                                        "access_token": "access-0",
+                                        "token_type": "Bearer",
+                                        "expires_in": 60,
+                                        "refresh_token": "refresh-1",
+                                        "id_token": id_token(&issuer, &nonce),
+                                    }),
+                                )
+                            }
+                            "refresh_token" if param("refresh_token") == Some("refresh-1") => json(
+                                StatusCode::OK,
+                                serde_json::json!({
+                                    "access_token": "access-1",
+                                    "token_type": "Bearer",
+                                    "expires_in": 60,
+                                    "refresh_token": "refresh-2",
+                                }),
+                            ),
+                            _ => json(
+                                StatusCode::BAD_REQUEST,
+                                serde_json::json!({ "error": "invalid_grant" }),
+                            ),
+                        }
                     }
-                    break;
+                    _ => json(StatusCode::NOT_FOUND, serde_json::json!({})),
                 }
             }
-        }
+        });
+        issuer.set(Some(addr));
+        addr
+    }
 
-        let mut authed = false;
-        if let Some(auth) = auth_cookie {
-            // authorized
-            let session_store = SESSION_STORE.read().unwrap();
-            if let Some(access) = session_store.get(&auth) {
-                authed = access.access_token.is_some()
-            }
+    /// ID token of `alice` signed with the client secret `secret`.
+    fn id_token(issuer: &str, nonce: &str) -> String {
+        let now = unix_now();
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(
+            serde_json::json!({
+                "iss": issuer,
+                "sub": "alice",
+                "aud": "monolake",
+                "iat": now,
+                "exp": now + 60,
+                "nonce": nonce,
+            })
+            .to_string(),
+        );
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let signature = hmac::sign(&key, format!("{header}.{claims}").as_bytes());
+        format!(
+            "{header}.{claims}.{}",
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    fn handler(addr: SocketAddr) -> OpenIdHandler<Recorder> {
+        let config: OpenIdConfig = serde_json::from_value(serde_json::json!({
+            "client_id": "monolake",
+            "client_secret": "secret",
+            "issuer_url": format!("http://{addr}"),
+            "redirect_url": "http://app.example.com/callback",
+            "cookie_secret": "0123456789abcdef0123456789abcdef",
+            "timeout_ms": 1000,
+        }))
+        .unwrap();
+        OpenIdHandler {
+            inner: Recorder::default(),
+            client: Some(Rc::new(OpenIdClient::new(config))),
+            routes: Arc::new(RouteMatcher::new("openid settings", Vec::new())),
         }
-        if authed {
-            return self.inner.handle(request, ctx).await;
+    }
+
+    fn request(method: Method, target: &str, cookie: Option<&str>) -> Request<HttpBody> {
+        let mut request = Request::builder().method(method).uri(target);
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
         }
+        request.body(HttpBody::fixed_body(None)).unwrap()
+    }
+
+    /// The `name=value` pair of the cookie `name` set by a response.
+    fn set_cookie(response: &Response<HttpBody>, name: &str) -> Option<String> {
+        response
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .into_iter()
+            .filter_map(|value| Cookie::parse(value.to_str().ok()?.to_string()).ok())
+            .find(|cookie| cookie.name() == name)
+            .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+    }
 
-        let openid_config = self.openid_config.clone().unwrap();
-        let client_id = ClientId::new(openid_config.client_id);
-        let client_secret = ClientSecret::new(openid_config.client_secret);
-        let issuer_url = IssuerUrl::new(openid_config.issuer_url).expect("Invalid issuer URL");
+    fn session_cookie(handler: &OpenIdHandler<Recorder>, session: &Session) -> String {
+        let client = handler.client.as_ref().unwrap();
+        let cookie = client.seal("monolake_session", Some(session), 60);
+        let cookie = Cookie::parse(cookie.to_str().unwrap().to_string()).unwrap();
+        format!("monolake_session={}", cookie.value())
+    }
 
-        let provider_metadata = CoreProviderMetadata::discover_async(issuer_url, async_http_client)
+    #[monoio::test(timer_enabled = true)]
+    async fn login_flow() {
+        let nonce = Rc::new(RefCell::new(String::new()));
+        let grants = Rc::new(RefCell::new(Vec::new()));
+        let addr = stub_provider(nonce.clone(), grants.clone());
+        let handler = handler(addr);
+        let ctx = || TestCtx::new("10.0.0.1:1234");
+
+        // Page loads are redirected to the provider, other requests are refused.
+        let (response, _) = handler
+            .call((request(Method::POST, "/app", None), ctx()))
             .await
-            .unwrap_or_else(|err| {
-                handle_error(&err, "Failed to discover OpenID Provider");
-                unreachable!();
-            });
-
-        // Set up the config for the OAuth2 process.
-        let client =
-            CoreClient::from_provider_metadata(provider_metadata, client_id, Some(client_secret))
-                .set_redirect_uri(
-                    RedirectUrl::new(openid_config.redirect_url).expect("Invalid redirect URL"),
-                );
-
-        // Generate the authorization URL to which we'll redirect the user.
-        let (authorize_url, csrf_state, mut nonce) = client
-            .authorize_url(
-                AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-                CsrfToken::new_random,
-                Nonce::new_random,
-            )
-            // Should add scopes to the server config as well in order to set them up here.
-            //.add_scope(Scope::new("email".to_string()))
-            //.add_scope(Scope::new("profile".to_string()))
-            .url();
-
-        debug!("CSRF: {}", csrf_state.secret());
-
-        let uri = request.uri().to_string();
-        let url = Url::parse(&("http://localhost".to_string() + &uri)).unwrap();
-
-        let code_pair = url.query_pairs().find(|pair| pair.0 == "code");
-        let state_pair = url.query_pairs().find(|pair| pair.0 == "state");
-
-        let code;
-        let state;
-        {
-            if code_pair.is_none() || state_pair.is_none() {
-                let mut redirect_response = Response::builder()
-                    .status(StatusCode::from_u16(301).unwrap())
-                    .body(H::Body::fixed_body(None))
-                    .unwrap();
-                redirect_response
-                    .headers_mut()
-                    .insert(http::header::LOCATION, unsafe {
-                        HeaderValue::from_maybe_shared_unchecked(format!("{}", authorize_url))
-                    });
-                SESSION_STORE.write().unwrap().insert(
-                    csrf_state.secret().clone(),
-                    SessionState {
-                        nonce,
-                        access_token: None,
-                    },
-                );
-                return Ok((redirect_response, false));
-            }
-            let session_store = SESSION_STORE.read().unwrap();
-            let (_, code_val) = code_pair.clone().unwrap();
-            code = AuthorizationCode::new(code_val.into_owned());
-            let (_, state_val) = state_pair.clone().unwrap();
-            state = CsrfToken::new(state_val.clone().into_owned());
-            if !session_store.contains_key(&state_val.to_string()) {
-                let mut redirect_response: Response<H::Body> = Response::builder()
-                    .status(StatusCode::from_u16(301).unwrap())
-                    .body(H::Body::fixed_body(None))
-                    .unwrap();
-                redirect_response
-                    .headers_mut()
-                    .insert(http::header::LOCATION, unsafe {
-                        HeaderValue::from_maybe_shared_unchecked(format!("{}", authorize_url))
-                    });
-                let mut session_store = SESSION_STORE.write().unwrap();
-                session_store.insert(
-                    state_val.to_string(),
-                    SessionState {
-                        nonce,
-                        access_token: None,
-                    },
-                );
-                return Ok((redirect_response, false));
-            }
-            nonce = session_store
-                .get(&state_val.to_string())
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let (response, _) = handler
+            .call((request(Method::GET, "/app?x=1", None), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        let location = Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), "/authorize");
+        let param = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
                 .unwrap()
-                .nonce
-                .clone();
-        }
+        };
+        assert_eq!(param("code_challenge_method"), "S256");
+        *nonce.borrow_mut() = param("nonce");
+        let csrf = param("state");
+        let state_cookie = set_cookie(&response, STATE_COOKIE).unwrap();
+
+        // The callback must come with the state of the login.
+        let (response, _) = handler
+            .call((
+                request(
+                    Method::GET,
+                    "/callback?code=code&state=other",
+                    Some(&state_cookie),
+                ),
+                ctx(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(grants.borrow().is_empty());
 
-        debug!(
-            "OpenID provider returned the following code:\n{}\n",
-            code.secret()
-        );
-        debug!(
-            "OpenID provider returned the following state: {}",
-            state.secret()
+        let callback = format!("/callback?code=code&state={csrf}");
+        let (response, _) = handler
+            .call((request(Method::GET, &callback, Some(&state_cookie)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "/app?x=1");
+        assert_eq!(
+            set_cookie(&response, STATE_COOKIE).unwrap(),
+            format!("{STATE_COOKIE}=")
         );
+        let session = set_cookie(&response, "monolake_session").unwrap();
+        assert_eq!(grants.take(), ["authorization_code"]);
 
-        // Exchange the code with a token.
-        let token_response = client
-            .exchange_code(code)
-            .request_async(async_http_client)
+        // Sessions are passed without the cookies of the handler.
+        let cookies = format!("{session}; other=1");
+        let (response, _) = handler
+            .call((request(Method::GET, "/app", Some(&cookies)), ctx()))
             .await
-            .unwrap_or_else(|err| {
-                handle_error(&err, "Failed to contact token endpoint");
-                unreachable!();
-            });
-        debug!(
-            "OpenID provider returned access token:\n{}\n",
-            token_response.access_token().secret()
-        );
-        debug!(
-            "OpenID provider returned scopes: {:?}",
-            token_response.scopes()
-        );
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(set_cookie(&response, "monolake_session").is_none());
+        assert_eq!(handler.inner.take()[0].headers()[header::COOKIE], "other=1");
 
-        // Need to save this as well
-        let id_token_verifier: CoreIdTokenVerifier = client.id_token_verifier();
-        let id_token_claims: &CoreIdTokenClaims = token_response
-            .extra_fields()
-            .id_token()
-            .expect("Server did not return an ID token")
-            .claims(&id_token_verifier, &nonce)
-            .unwrap_or_else(|err| {
-                handle_error(&err, "Failed to verify ID token");
-                unreachable!();
-            });
-        debug!("OpenID provider returned ID token: {:?}\n", id_token_claims);
-
-        {
-            let mut session_store = SESSION_STORE.write().unwrap();
-            session_store.get_mut(state.secret()).unwrap().access_token =
-                Some(token_response.access_token().clone());
-        }
+        let (response, _) = handler
+            .call((request(Method::GET, "/logout", Some(&session)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(response.headers()[header::LOCATION], "/");
+        assert_eq!(
+            set_cookie(&response, "monolake_session").unwrap(),
+            "monolake_session="
+        );
+        assert!(handler.inner.take().is_empty());
+    }
 
-        match self.inner.handle(request, ctx).await {
-            Ok((mut response, cont)) => {
-                let headers = response.headers_mut();
-                // Use the state number (csrf) as the session-id for future auth. Need to add
-                // more cookies like expiration time.
-                headers.insert(http::header::SET_COOKIE, unsafe {
-                    HeaderValue::from_maybe_shared_unchecked(format!(
-                        "{}={}",
-                        "session-id",
-                        state.secret()
-                    ))
-                });
-                Ok((response, cont))
-            }
-            Err(_e) => Ok((
-                generate_response(StatusCode::INTERNAL_SERVER_ERROR, false),
-                false,
-            )),
-        }
+    #[monoio::test(timer_enabled = true)]
+    async fn session_lifetime() {
+        let grants = Rc::new(RefCell::new(Vec::new()));
+        let addr = stub_provider(Default::default(), grants.clone());
+        let handler = handler(addr);
+        let ctx = || TestCtx::new("10.0.0.1:1234");
+        let now = unix_now();
+        let session = |refresh_at, expires_at, refresh_token: &str| Session {
+            sub: "alice".to_string(),
+            refresh_at,
+            expires_at,
+            refresh_token: Some(refresh_token.to_string()),
+        };
+
+        // Sessions with an expired access token are refreshed.
+        let cookie = session_cookie(&handler, &session(now - 1, now + 60, "refresh-1"));
+        let (response, _) = handler
+            .call((request(Method::GET, "/app", Some(&cookie)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(grants.take(), ["refresh_token"]);
+        let refreshed = set_cookie(&response, "monolake_session").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(&refreshed).unwrap());
+        let client = handler.client.as_ref().unwrap();
+        let refreshed: Session = client.open(&headers, "monolake_session").unwrap();
+        assert!(refreshed.refresh_at >= now + 60);
+        assert_eq!(refreshed.refresh_token.as_deref(), Some("refresh-2"));
+        assert_eq!(handler.inner.take().len(), 1);
+
+        // Sessions not refreshed, or past their lifetime, must log in again.
+        let cookie = session_cookie(&handler, &session(now - 1, now + 60, "revoked"));
+        let (response, _) = handler
+            .call((request(Method::GET, "/app", Some(&cookie)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert_eq!(grants.take(), ["refresh_token"]);
+        let cookie = session_cookie(&handler, &session(now + 60, now - 1, "refresh-1"));
+        let (response, _) = handler
+            .call((request(Method::GET, "/app", Some(&cookie)), ctx()))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FOUND);
+        assert!(set_cookie(&response, STATE_COOKIE).is_some());
+        assert!(grants.borrow().is_empty());
+        assert!(handler.inner.take().is_empty());
+
+        // Logins never return the user to another site.
+        let state = LoginState {
+            csrf: "csrf".to_string(),
+            nonce: String::new(),
+            pkce_verifier: "verifier".to_string(),
+            target: "//evil.example/".to_string(),
+        };
+        let state = client.seal(STATE_COOKIE, Some(&state), 60);
+        let state = Cookie::parse(state.to_str().unwrap().to_string()).unwrap();
+        let state = format!("{STATE_COOKIE}={}", state.value());
+        let (response, _) = handler
+            .call((
+                request(Method::GET, "/callback?code=code&state=csrf", Some(&state)),
+                ctx(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::LOCATION], "/");
     }
 }
@@ -0,0 +1,205 @@
+//! Local rate limiting of HTTP requests.
+//!
+//! [`RateLimitHandler`] sits in front of the routing handler and checks every request against the
+//! rate limit of its server and the rate limit of its route, see
+//! [`rate_limit`](crate::common::rate_limit) for the budgets and their keys.
+//!
+//! # Features
+//!
+//! - Requests exceeding a limit are answered with `429 Too Many Requests` and a `Retry-After`
+//!   header, without reaching the upstream
+//! - Responses carry the `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers of
+//!   the most restrictive limit of their request, unless the upstream set them
+//! - Routes are matched like the routing handler does, so a request is limited by the route which
+//!   serves it
+//! - Budgets are kept on configuration reloads for the limits which did not change
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(UpstreamHandler::factory(timeout, version))
+//!     .push(RewriteAndRouteHandler::layer())
+//!     .push(RateLimitHandler::layer())
+//!     .push(ConnectionReuseHandler::layer());
+//! ```
+use http::{
+    header::{HeaderName, RETRY_AFTER},
+    HeaderMap, HeaderValue, Request, StatusCode,
+};
+use monoio_http::common::body::FixedBody;
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    http::{HttpHandler, ResponseWithContinue},
+};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, ParamMaybeRef, ParamRef, Service,
+};
+use tracing::debug;
+
+use super::route::{RouteConfig, RouteMatcher};
+use crate::{
+    common::rate_limit::{
+        BucketKey, Decision, RateLimit, RateLimitConfig, RateLimitKey, RateLimiter,
+    },
+    http::generate_response,
+};
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Handler enforcing the rate limits of a server and of its routes.
+///
+/// See the [module level documentation](crate::http::handlers::rate_limit) for details.
+#[derive(Clone)]
+pub struct RateLimitHandler<H> {
+    inner: H,
+    server: Option<RateLimiter>,
+    routes: RouteMatcher<RateLimiter>,
+}
+
+impl<H, CX, B> Service<(Request<B>, CX)> for RateLimitHandler<H>
+where
+    H: HttpHandler<CX, B>,
+    H::Body: FixedBody,
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    type Response = ResponseWithContinue<H::Body>;
+    type Error = H::Error;
+
+    async fn call(&self, (request, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let limiters = [self.server.as_ref(), self.routes.at(request.uri().path())];
+        let mut reported: Option<Decision> = None;
+        for limiter in limiters.into_iter().flatten() {
+            let Some(key) = bucket_key(limiter.config(), request.headers(), &ctx) else {
+                continue;
+            };
+            let decision = limiter.check(&key);
+            if !decision.allowed() {
+                debug!("request to {} rate limited", request.uri().path());
+                return Ok((too_many_requests(&decision), true));
+            }
+            if reported.map_or(true, |r| decision.remaining < r.remaining) {
+                reported = Some(decision);
+            }
+        }
+
+        let (mut response, cont) = self.inner.handle(request, ctx).await?;
+        if let Some(decision) = reported {
+            if !response.headers().contains_key(RATELIMIT_LIMIT) {
+                insert_headers(response.headers_mut(), &decision);
+            }
+        }
+        Ok((response, cont))
+    }
+}
+
+fn bucket_key<CX>(config: &RateLimitConfig, headers: &HeaderMap, ctx: &CX) -> Option<BucketKey>
+where
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    match &config.key {
+        RateLimitKey::Route => Some(BucketKey::All),
+        RateLimitKey::ClientIp => Some(BucketKey::client(ctx)),
+        RateLimitKey::Header(name) => headers
+            .get(name)
+            .map(|value| BucketKey::Value(value.as_bytes().into())),
+    }
+}
+
+fn too_many_requests<B: FixedBody>(decision: &Decision) -> http::Response<B> {
+    let mut response = generate_response(StatusCode::TOO_MANY_REQUESTS, false);
+    let headers = response.headers_mut();
+    if let Some(retry_after) = decision.retry_after {
+        headers.insert(RETRY_AFTER, seconds(retry_after));
+    }
+    insert_headers(headers, decision);
+    response
+}
+
+fn insert_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert(RATELIMIT_LIMIT, HeaderValue::from(decision.limit));
+    headers.insert(RATELIMIT_REMAINING, HeaderValue::from(decision.remaining));
+    headers.insert(RATELIMIT_RESET, seconds(decision.reset));
+}
+
+// Whole seconds, rounded up so that clients do not retry too early.
+fn seconds(duration: std::time::Duration) -> HeaderValue {
+    let secs = duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+    HeaderValue::from(secs)
+}
+
+/// Factory for [`RateLimitHandler`], holding the limits shared by the workers of a server.
+pub struct RateLimitHandlerFactory<F> {
+    inner: F,
+    server: Option<RateLimit>,
+    routes: RouteMatcher<RateLimit>,
+}
+
+impl<F> RateLimitHandlerFactory<F> {
+    fn make_limiters<H>(
+        &self,
+        old: Option<&RateLimitHandler<H>>,
+    ) -> (Option<RateLimiter>, RouteMatcher<RateLimiter>) {
+        let server = self
+            .server
+            .as_ref()
+            .map(|limit| limit.limiter(old.and_then(|o| o.server.as_ref())));
+        let routes = self
+            .routes
+            .map(|path, limit| limit.limiter(old.and_then(|o| o.routes.route(path))));
+        (server, routes)
+    }
+}
+
+impl<F: MakeService> MakeService for RateLimitHandlerFactory<F> {
+    type Service = RateLimitHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        let (server, routes) = self.make_limiters(old);
+        Ok(RateLimitHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            server,
+            routes,
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for RateLimitHandlerFactory<F> {
+    type Service = RateLimitHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        let (server, routes) = self.make_limiters(old);
+        Ok(RateLimitHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            server,
+            routes,
+        })
+    }
+}
+
+impl<F> RateLimitHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = RateLimitHandlerFactory<F>>
+    where
+        C: Param<Option<RateLimitConfig>> + Param<Vec<RouteConfig>>,
+    {
+        layer_fn(|c: &C, inner| {
+            let server = Param::<Option<RateLimitConfig>>::param(c).map(RateLimit::new);
+            let routes = Param::<Vec<RouteConfig>>::param(c)
+                .into_iter()
+                .map(|route| (route.path, route.rate_limit.map(RateLimit::new)));
+            RateLimitHandlerFactory {
+                inner,
+                server,
+                routes: RouteMatcher::new("rate limit", routes),
+            }
+        })
+    }
+}
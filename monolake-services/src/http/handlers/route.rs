@@ -19,8 +19,10 @@
 //!
 //! 1. A `RewriteAndRouteHandler` is created by its factory, initialized with a set of routes.
 //! 2. Incoming requests are matched against these routes using a [`matchit::Router`].
-//! 3. When a match is found, an upstream server is selected (with support for load balancing).
-//! 4. The request is rewritten as necessary for the selected upstream.
+//! 3. When a match is found, an upstream server is selected (with support for load balancing),
+//!    skipping the upstreams failing their [health checks](crate::http::health).
+//! 4. The request is rewritten as necessary for the selected upstream. The path is replaced with
+//!    the path of the upstream uri, except for gRPC requests whose path names the method.
 //! 5. The rewritten request is passed to an inner handler for further processing
 //!
 //! # Usage
@@ -37,7 +39,7 @@
 //!             route::RouteConfig, ConnectionReuseHandler, ContentHandler, RewriteAndRouteHandler,
 //!             UpstreamHandler,
 //!         },
-//!         HttpServerTimeout,
+//!         Http2Settings, HttpServerTimeout, RequestLimitConfig,
 //!     },
 //! };
 //! use service_async::{layer::FactoryLayer, stack::FactoryStack, Param};
@@ -56,6 +58,16 @@
 //!         HttpServerTimeout::default()
 //!     }
 //! }
+//! impl Param<Http2Settings> for DummyConfig {
+//!     fn param(&self) -> Http2Settings {
+//!         Http2Settings::default()
+//!     }
+//! }
+//! impl Param<RequestLimitConfig> for DummyConfig {
+//!     fn param(&self) -> RequestLimitConfig {
+//!         RequestLimitConfig::default()
+//!     }
+//! }
 //!
 //! let config = DummyConfig;
 //! let stacks = FactoryStack::new(config)
@@ -78,6 +90,10 @@
 //! associated upstreams. These configurations can be dynamically updated by recreating
 //! the handler through its factory.
 //!
+//! gRPC calls are routed like other requests on their `/package.Service/Method` path: a route
+//! for `/package.Service/{*method}` serves all the methods of a service, while a route for
+//! `/package.Service/Method` serves a single method.
+//!
 //! # Error Handling
 //!
 //! - Routing errors (no matching route) result in a 404 Not Found response.
@@ -111,32 +127,117 @@ use service_async::{
 };
 
 use crate::{
-    common::selector::{
-        IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Mapping, Select,
-        ServiceRouter,
+    common::{
+        ip_filter::IpFilterConfig,
+        rate_limit::RateLimitConfig,
+        selector::{
+            IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Mapping,
+            Select, ServiceRouter,
+        },
+    },
+    http::{
+        generate_response, grpc,
+        health::{HealthAwareLoadBalancer, HealthCheckConfig},
+        util::HttpErrorResponder,
     },
-    http::{generate_response, util::HttpErrorResponder},
 };
 
 #[derive(Debug)]
 pub struct Router<T>(pub matchit::Router<T>);
 
-impl Router<LoadBalancer<Endpoint>> {
+impl Router<HealthAwareLoadBalancer> {
     pub fn new_from_iter<I, E>(iter: I) -> Result<Self, RoutingFactoryError<E>>
     where
         I: IntoIterator<Item = RouteConfig>,
     {
         let mut router = matchit::Router::new();
         for route in iter {
+            let endpoints = route
+                .upstreams
+                .iter()
+                .map(|upstream| upstream.endpoint.clone())
+                .collect();
+            let lb =
+                LoadBalancer::try_from_upstreams(route.load_balancer, route.upstreams).unwrap();
             router.insert(
                 &route.path,
-                LoadBalancer::try_from_upstreams(route.load_balancer, route.upstreams).unwrap(),
+                HealthAwareLoadBalancer::new(lb, endpoints, route.health_check),
             )?;
         }
         Ok(Self(router))
     }
 }
 
+/// Settings of the routes of a server for the handlers configured per route, found by matching
+/// request paths with the route paths.
+///
+/// Every route is matched once one has settings, so that requests to a route without settings
+/// do not get the settings of a less specific route.
+#[derive(Clone)]
+pub(crate) struct RouteMatcher<T> {
+    router: matchit::Router<usize>,
+    routes: Vec<(String, Option<T>)>,
+}
+
+impl<T> RouteMatcher<T> {
+    /// Match `routes`, given as paths with their settings. `settings` names the settings in the
+    /// warnings about the routes whose path is invalid, which fail the routing handler.
+    pub(crate) fn new(
+        settings: &str,
+        routes: impl IntoIterator<Item = (String, Option<T>)>,
+    ) -> Self {
+        let mut routes: Vec<_> = routes.into_iter().collect();
+        if routes.iter().all(|(_, route)| route.is_none()) {
+            // Skip matching routes when none has settings.
+            routes.clear();
+        }
+        let mut matcher = Self {
+            router: matchit::Router::new(),
+            routes: Vec::with_capacity(routes.len()),
+        };
+        for (path, route) in routes {
+            if let Err(e) = matcher.router.insert(&path, matcher.routes.len()) {
+                tracing::warn!("{settings} of route {path} ignored: {e}");
+                continue;
+            }
+            matcher.routes.push((path, route));
+        }
+        matcher
+    }
+
+    /// Settings of the route matching `path`.
+    pub(crate) fn at(&self, path: &str) -> Option<&T> {
+        let index = *self.router.at(path).ok()?.value;
+        self.routes[index].1.as_ref()
+    }
+
+    /// Settings of the route whose path is `path`.
+    pub(crate) fn route(&self, path: &str) -> Option<&T> {
+        self.routes
+            .iter()
+            .find(|(route, _)| route == path)
+            .and_then(|(_, route)| route.as_ref())
+    }
+
+    /// The same routes with the settings `f` maps theirs to.
+    pub(crate) fn map<U>(&self, mut f: impl FnMut(&str, &T) -> U) -> RouteMatcher<U> {
+        RouteMatcher {
+            router: self.router.clone(),
+            routes: self
+                .routes
+                .iter()
+                .map(|(path, route)| (path.clone(), route.as_ref().map(|route| f(path, route))))
+                .collect(),
+        }
+    }
+}
+
+impl<T: PartialEq> PartialEq for RouteMatcher<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.routes == other.routes
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RouterError<E> {
     #[error("route empty")]
@@ -210,7 +311,7 @@ pub struct RewriteAndRouteHandlerFactory<F> {
 }
 
 pub type RewriteAndRouteHandler<T> = HttpErrorResponder<
-    ServiceRouter<Router<LoadBalancer<Endpoint>>, RewriteHandler<T>, PathExtractor>,
+    ServiceRouter<Router<HealthAwareLoadBalancer>, RewriteHandler<T>, PathExtractor>,
 >;
 
 #[derive(thiserror::Error, Debug)]
@@ -307,6 +408,47 @@ pub struct RouteConfig {
     ///
     /// Multiple upstreams allow for load balancing and failover configurations.
     pub upstreams: Vec<Upstream>,
+
+    /// Active health checks of the upstreams, unhealthy upstreams are skipped.
+    #[serde(default)]
+    pub health_check: Option<HealthCheckConfig>,
+
+    /// Rate limit of the requests of the route, enforced by the
+    /// [`RateLimitHandler`](crate::http::handlers::RateLimitHandler).
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+
+    /// Client IP allow and deny lists of the route, enforced by the
+    /// [`IpFilterHandler`](crate::http::handlers::IpFilterHandler).
+    #[serde(default)]
+    pub ip_filter: Option<IpFilterConfig>,
+
+    /// Body size limit of the requests of the route, overriding the one of the server, enforced
+    /// by the [`HttpCoreService`](crate::http::HttpCoreService).
+    #[serde(default)]
+    pub max_body_size: Option<u64>,
+
+    /// CORS settings of the route, implemented by the
+    /// [`CorsHandler`](crate::http::handlers::CorsHandler).
+    #[serde(default)]
+    pub cors: Option<crate::http::handlers::cors::CorsConfig>,
+
+    /// Scopes and claims the JWT of requests must have, enforced by the
+    /// [`JwtHandler`](crate::http::handlers::JwtHandler) of the server.
+    #[cfg(feature = "jwt")]
+    #[serde(default)]
+    pub jwt: Option<crate::http::handlers::jwt::JwtRouteConfig>,
+
+    /// Opt-out of the [`OpenIdHandler`](crate::http::handlers::OpenIdHandler) of the server.
+    #[cfg(feature = "openid")]
+    #[serde(default)]
+    pub openid: Option<crate::http::handlers::openid::OpenIdRouteConfig>,
+
+    /// HTTP Basic and API key authentication of the route, enforced by the
+    /// [`AuthHandler`](crate::http::handlers::AuthHandler).
+    #[cfg(feature = "auth")]
+    #[serde(default)]
+    pub auth: Option<crate::http::handlers::auth::AuthRouteConfig>,
 }
 
 const fn default_weight() -> u16 {
@@ -328,6 +470,13 @@ pub struct Upstream {
     /// If not specified, it defaults to a value provided by the `default_weight` function.
     #[serde(default = "default_weight")]
     pub weight: u16,
+
+    /// TLS settings used when connecting to an HTTPS endpoint.
+    ///
+    /// Upstreams without it use the default connector, which verifies against the webpki roots.
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    pub tls: Option<crate::tls::UpstreamTlsConfig>,
 }
 
 impl IntoWeightedEndpoint for Upstream {
@@ -401,8 +550,11 @@ fn rewrite_request<B>(request: &mut Request<B>, endpoint: &Endpoint) {
             None => Scheme::HTTP,
         };
 
+        // The path of gRPC requests names the method, it is kept as is.
+        let keep_path = grpc::is_grpc(request.headers());
         let uri = request.uri_mut();
         let path_and_query = match uri.path_and_query() {
+            Some(path_and_query) if keep_path => path_and_query.to_string(),
             Some(path_and_query) => match path_and_query.query() {
                 Some(query) => format!("{}?{}", remote.path(), query),
                 None => String::from(remote.path()),
@@ -447,7 +599,20 @@ mod tests {
             upstreams: Vec::from([Upstream {
                 endpoint: Endpoint::Uri(format!("http://test{n}.endpoint").parse().unwrap()),
                 weight: Default::default(),
+                #[cfg(feature = "tls")]
+                tls: None,
             }]),
+            health_check: None,
+            rate_limit: None,
+            ip_filter: None,
+            max_body_size: None,
+            cors: None,
+            #[cfg(feature = "jwt")]
+            jwt: None,
+            #[cfg(feature = "openid")]
+            openid: None,
+            #[cfg(feature = "auth")]
+            auth: None,
         })
     }
 
@@ -473,4 +638,25 @@ mod tests {
         println!("{:?}", iterate_route);
         assert!(matchit_match_elapsed < (iterate_match_elapsed / 100));
     }
+
+    #[test]
+    fn route_matcher() {
+        let routes = [
+            ("/api/{*p}", Some(1)),
+            ("/api/public", None),
+            ("/{bad", Some(2)),
+        ]
+        .map(|(path, limit)| (path.to_string(), limit));
+        let matcher = RouteMatcher::new("limit", routes.clone());
+        assert_eq!(matcher.at("/api/private"), Some(&1));
+        // Routes without settings do not fall back to less specific routes.
+        assert_eq!(matcher.at("/api/public"), None);
+        assert_eq!(matcher.at("/other"), None);
+        assert_eq!(matcher.route("/{bad"), None);
+
+        let doubled = matcher.map(|_, limit| limit * 2);
+        assert_eq!(doubled.at("/api/private"), Some(&2));
+        assert_eq!(doubled.route("/api/{*p}"), Some(&2));
+        assert!(matcher == RouteMatcher::new("limit", routes));
+    }
 }
@@ -17,8 +17,14 @@
 //! - Connection pooling for efficient resource usage, provided by `HttpConnector`
 //! - Support for both HTTP/1.1 and HTTP/2 protocols
 //! - Configurable timeout settings
-//! - TLS support (enabled with the `tls` feature flag)
+//! - TLS support (enabled with the `tls` feature flag), with per-upstream CA bundle, client
+//!   certificate, SNI, ALPN and minimum version settings
 //! - X-Forwarded-For header management
+//! - Forwarding of the verified client certificate as `X-Client-Cert-*` headers
+//! - gRPC requests to cleartext upstreams always use HTTP/2, see
+//!   [`GrpcHandler`](crate::http::handlers::GrpcHandler) for gRPC deadlines and statuses
+//! - Protocol upgrades such as WebSocket or h2c, and WebSockets over HTTP/2 extended CONNECT (see
+//!   [`upgrade`](crate::http::upgrade))
 //! - Leverages monoio's native IO traits built on top of io_uring for high performance
 //!
 //! # HTTP Connector Usage
@@ -30,6 +36,12 @@
 //! - Optimized for monoio's asynchronous runtime and io_uring
 //! - TLS support for secure HTTPS connections
 //!
+//! # Protocol Upgrades
+//!
+//! Upgrade requests are not sent over pooled connections. Each one opens a dedicated HTTP/1.1
+//! connection to the upstream, which is spliced with the client once the upstream switches
+//! protocols. Responses declining the upgrade are relayed and the dedicated connection is closed.
+//!
 //! # Error Handling
 //!
 //! - Connection errors result in 502 Bad Gateway responses
@@ -46,42 +58,92 @@
 //! # Feature Flags
 //!
 //! - `tls`: Enables TLS support for HTTPS connections to upstream servers
+#[cfg(feature = "tls")]
+use std::collections::HashMap;
 use std::{
     convert::Infallible,
+    fmt::Debug,
+    future::Future,
     net::{SocketAddr, ToSocketAddrs},
     time::Duration,
 };
 
-use bytes::Bytes;
-use http::{header, HeaderMap, HeaderValue, Request, StatusCode};
-use monoio::net::TcpStream;
-use monoio_http::common::{
-    body::{Body, HttpBody},
-    error::HttpError,
+use bytes::{Bytes, BytesMut};
+#[cfg(feature = "tls")]
+use http::uri::Authority;
+use http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode, Uri};
+use monoio::{
+    io::{sink::SinkExt, stream::Stream, AsyncReadRent, AsyncWriteRent, Split, Splitable},
+    net::TcpStream,
+};
+use monoio_http::{
+    common::{
+        body::{Body, FixedBody, HttpBody},
+        error::HttpError,
+    },
+    h1::{
+        codec::{decoder::ClientResponseDecoder, encoder::GenericEncoder},
+        payload::FramedPayload,
+        BorrowFramedRead,
+    },
 };
 #[cfg(feature = "tls")]
-use monoio_transports::connectors::{TlsConnector, TlsStream};
+use monoio_transports::connectors::{TcpTlsAddr, TlsConnector, TlsStream};
 use monoio_transports::{
     connectors::{Connector, TcpConnector},
     http::{HttpConnection, HttpConnector},
 };
 use monolake_core::{
-    context::{PeerAddr, RemoteAddr},
+    context::{PeerAddr, PeerCertificate, RemoteAddr},
     http::ResponseWithContinue,
     listener::AcceptedAddr,
 };
 use service_async::{AsyncMakeService, MakeService, ParamMaybeRef, ParamRef, Service};
 use tracing::{debug, info};
 
-use crate::http::{generate_response, HttpVersion};
+#[cfg(feature = "tls")]
+use super::route::{Endpoint, RouteConfig};
+use crate::http::{
+    generate_response, grpc,
+    upgrade::{self, OnUpgrade, Upgraded},
+    Http2Settings, HttpVersion,
+};
+#[cfg(feature = "tls")]
+use crate::tls::UpstreamTls;
+
+const CLIENT_CERT_SUBJECT: &str = "x-client-cert-subject";
+const CLIENT_CERT_SAN: &str = "x-client-cert-san";
+const CLIENT_CERT_FINGERPRINT: &str = "x-client-cert-fingerprint";
+/// Bodies of responses declining an upgrade larger than this are answered with `502`.
+const MAX_DECLINED_BODY_SIZE: usize = 64 * 1024;
+
+pub(crate) type PooledHttpConnector = HttpConnector<TcpConnector, SocketAddr, TcpStream>;
+#[cfg(feature = "tls")]
+pub(crate) type PooledHttpsConnector =
+    HttpConnector<TlsConnector<TcpConnector>, TcpTlsAddr, TlsStream<TcpStream>>;
+
+/// HTTPS connector dedicated to an upstream with its own TLS settings.
+#[cfg(feature = "tls")]
+struct UpstreamHttpsConnector {
+    connector: PooledHttpsConnector,
+    upgrade_connector: UpgradeTlsConnector,
+    tls: UpstreamTls,
+}
+
+/// TLS connector for upgrade requests, which always negotiate HTTP/1.1.
+#[cfg(feature = "tls")]
+#[derive(Clone)]
+struct UpgradeTlsConnector(TlsConnector<TcpConnector>);
 
-type PooledHttpConnector = HttpConnector<TcpConnector, SocketAddr, TcpStream>;
 #[cfg(feature = "tls")]
-type PooledHttpsConnector = HttpConnector<
-    TlsConnector<TcpConnector>,
-    monoio_transports::connectors::TcpTlsAddr,
-    TlsStream<TcpStream>,
->;
+impl Default for UpgradeTlsConnector {
+    fn default() -> Self {
+        Self(TlsConnector::new_with_tls_default(
+            TcpConnector::default(),
+            Some(vec!["http/1.1"]),
+        ))
+    }
+}
 
 /// Handles proxying of HTTP and HTTPS requests to upstream servers.
 ///
@@ -94,8 +156,15 @@ type PooledHttpsConnector = HttpConnector<
 #[derive(Default)]
 pub struct UpstreamHandler {
     http_connector: PooledHttpConnector,
+    // gRPC requires HTTP/2, cleartext gRPC requests use h2c when the upstream version is not.
+    grpc_connector: Option<PooledHttpConnector>,
     #[cfg(feature = "tls")]
     https_connector: PooledHttpsConnector,
+    #[cfg(feature = "tls")]
+    upgrade_tls_connector: UpgradeTlsConnector,
+    // Keyed by the authority of the upstream uri, which is what requests are rewritten to.
+    #[cfg(feature = "tls")]
+    upstream_https_connectors: HashMap<Authority, UpstreamHttpsConnector>,
     pub http_upstream_timeout: HttpUpstreamTimeout,
 }
 
@@ -107,6 +176,7 @@ impl UpstreamHandler {
     ) -> Self {
         UpstreamHandler {
             http_connector,
+            grpc_connector: None,
             http_upstream_timeout,
         }
     }
@@ -119,27 +189,29 @@ impl UpstreamHandler {
     ) -> Self {
         UpstreamHandler {
             http_connector: connector,
+            grpc_connector: None,
             https_connector: tls_connector,
+            upgrade_tls_connector: UpgradeTlsConnector::default(),
+            upstream_https_connectors: HashMap::new(),
             http_upstream_timeout,
         }
     }
 
-    pub const fn factory(
+    pub fn factory(
         http_upstream_timeout: HttpUpstreamTimeout,
         version: HttpVersion,
     ) -> UpstreamHandlerFactory {
-        UpstreamHandlerFactory {
-            http_upstream_timeout,
-            version,
-        }
+        UpstreamHandlerFactory::new(http_upstream_timeout, version)
     }
 }
 
 impl<CX, B> Service<(Request<B>, CX)> for UpstreamHandler
 where
-    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+    CX: ParamRef<PeerAddr>
+        + ParamMaybeRef<Option<RemoteAddr>>
+        + ParamMaybeRef<Option<PeerCertificate>>,
     // B: Body,
-    B: Body<Data = Bytes, Error = HttpError>,
+    B: Body<Data = Bytes, Error = HttpError> + 'static,
     HttpError: From<B::Error>,
 {
     type Response = ResponseWithContinue<HttpBody>;
@@ -147,6 +219,20 @@ where
 
     async fn call(&self, (mut req, ctx): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
         add_xff_header(req.headers_mut(), &ctx);
+        add_client_cert_headers(req.headers_mut(), &ctx);
+        if upgrade::is_upgrade_request(&req) {
+            return Ok(self.send_upgrade_request(req, None::<B>).await);
+        }
+        if let Some(protocol) = upgrade::extended_connect_protocol(&req) {
+            if !protocol.as_str().eq_ignore_ascii_case(upgrade::WEBSOCKET) {
+                info!("unsupported extended connect protocol: {protocol:?}");
+                return Ok((generate_response(StatusCode::NOT_IMPLEMENTED, false), true));
+            }
+            let (mut parts, body) = req.into_parts();
+            upgrade::websocket_request(&mut parts);
+            let req = Request::from_parts(parts, HttpBody::fixed_body(None));
+            return Ok(self.send_upgrade_request(req, Some(body)).await);
+        }
         #[cfg(feature = "tls")]
         if req.uri().scheme() == Some(&http::uri::Scheme::HTTPS) {
             return self.send_https_request(req).await;
@@ -164,24 +250,15 @@ impl UpstreamHandler {
         B: Body<Data = Bytes, Error = HttpError>,
         HttpError: From<B::Error>,
     {
-        let Some(host) = req.uri().host() else {
-            info!("invalid uri which does not contain host: {:?}", req.uri());
-            return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
-        };
-        let port = req.uri().port_u16().unwrap_or(80);
-        let mut iter = match (host, port).to_socket_addrs() {
-            Ok(iter) => iter,
-            Err(e) => {
-                info!("convert invalid uri: {:?} with error: {:?}", req.uri(), e);
-                return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
-            }
-        };
-        let Some(key) = iter.next() else {
-            info!("unable to resolve host: {host}");
+        let Some(key) = destination(&req) else {
             return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
         };
         debug!("key: {:?}", key);
-        let mut conn = match self.http_connector.connect(key).await {
+        let connector = match &self.grpc_connector {
+            Some(connector) if grpc::is_grpc(req.headers()) => connector,
+            _ => &self.http_connector,
+        };
+        let mut conn = match connector.connect(key).await {
             Ok(conn) => {
                 match &conn {
                     HttpConnection::Http1(_) => {
@@ -217,19 +294,30 @@ impl UpstreamHandler {
         B: Body<Data = Bytes, Error = HttpError>,
         HttpError: From<B::Error>,
     {
-        let key = match req.uri().try_into() {
+        let mut key: TcpTlsAddr = match req.uri().try_into() {
             Ok(key) => key,
             Err(e) => {
                 info!("convert invalid uri: {:?} with error: {:?}", req.uri(), e);
                 return Ok((generate_response(StatusCode::BAD_REQUEST, true), true));
             }
         };
+        let upstream = req
+            .uri()
+            .authority()
+            .and_then(|authority| self.upstream_https_connectors.get(authority));
+        let connector = match upstream {
+            Some(upstream) => {
+                if let Some(sni) = &upstream.tls.sni {
+                    key.sn = sni.clone();
+                }
+                &upstream.connector
+            }
+            None => &self.https_connector,
+        };
         debug!("key: {:?}", key);
         let connect = match self.http_upstream_timeout.connect_timeout {
             Some(connect_timeout) => {
-                match monoio::time::timeout(connect_timeout, self.https_connector.connect(key))
-                    .await
-                {
+                match monoio::time::timeout(connect_timeout, connector.connect(key)).await {
                     Ok(x) => x,
                     Err(_) => {
                         info!("connect upstream timeout");
@@ -237,7 +325,7 @@ impl UpstreamHandler {
                     }
                 }
             }
-            None => self.https_connector.connect(key).await,
+            None => connector.connect(key).await,
         };
 
         let mut conn = match connect {
@@ -257,9 +345,228 @@ impl UpstreamHandler {
     }
 }
 
+impl UpstreamHandler {
+    /// Forward an upgrade request over a dedicated connection. `stream` is the request body of an
+    /// HTTP/2 extended CONNECT, which carries the upgraded stream.
+    async fn send_upgrade_request<B, T>(
+        &self,
+        mut req: Request<B>,
+        stream: Option<T>,
+    ) -> ResponseWithContinue<HttpBody>
+    where
+        B: Body<Data = Bytes, Error = HttpError>,
+        HttpError: From<B::Error>,
+        T: Body<Data = Bytes> + 'static,
+    {
+        *req.version_mut() = http::Version::HTTP_11;
+        if !req.headers().contains_key(header::HOST) {
+            if let Some(host) = req
+                .uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+            {
+                req.headers_mut().insert(header::HOST, host);
+            }
+        }
+        #[cfg(feature = "tls")]
+        if req.uri().scheme() == Some(&http::uri::Scheme::HTTPS) {
+            let mut key: TcpTlsAddr = match req.uri().try_into() {
+                Ok(key) => key,
+                Err(e) => {
+                    info!("convert invalid uri: {:?} with error: {:?}", req.uri(), e);
+                    return (generate_response(StatusCode::BAD_REQUEST, true), true);
+                }
+            };
+            let upstream = req
+                .uri()
+                .authority()
+                .and_then(|authority| self.upstream_https_connectors.get(authority));
+            let connector = match upstream {
+                Some(upstream) => {
+                    if let Some(sni) = &upstream.tls.sni {
+                        key.sn = sni.clone();
+                    }
+                    &upstream.upgrade_connector.0
+                }
+                None => &self.upgrade_tls_connector.0,
+            };
+            return match self.connect(connector.connect(key)).await {
+                Some(io) => self.upgrade(io, req, stream).await,
+                None => (generate_response(StatusCode::BAD_GATEWAY, true), true),
+            };
+        }
+        let Some(key) = destination(&req) else {
+            return (generate_response(StatusCode::BAD_REQUEST, true), true);
+        };
+        match self.connect(TcpStream::connect(key)).await {
+            Some(io) => self.upgrade(io, req, stream).await,
+            None => (generate_response(StatusCode::BAD_GATEWAY, true), true),
+        }
+    }
+
+    async fn connect<C, E: Debug>(&self, connect: impl Future<Output = Result<C, E>>) -> Option<C> {
+        let connect = match self.http_upstream_timeout.connect_timeout {
+            Some(connect_timeout) => match monoio::time::timeout(connect_timeout, connect).await {
+                Ok(x) => x,
+                Err(_) => {
+                    info!("connect upstream timeout");
+                    return None;
+                }
+            },
+            None => connect.await,
+        };
+        connect
+            .map_err(|e| info!("connect upstream error: {:?}", e))
+            .ok()
+    }
+
+    async fn upgrade<IO, B, T>(
+        &self,
+        io: IO,
+        req: Request<B>,
+        stream: Option<T>,
+    ) -> ResponseWithContinue<HttpBody>
+    where
+        IO: Split + AsyncReadRent + AsyncWriteRent + 'static,
+        B: Body<Data = Bytes, Error = HttpError>,
+        HttpError: From<B::Error>,
+        T: Body<Data = Bytes> + 'static,
+    {
+        let (mut reader, mut writer) = io.into_split();
+        if let Err(e) = GenericEncoder::new(&mut writer).send_and_flush(req).await {
+            info!("send upgrade request error: {e}");
+            return (generate_response(StatusCode::BAD_GATEWAY, false), true);
+        }
+
+        let mut decoder = match self.http_upstream_timeout.read_timeout {
+            Some(read_timeout) => {
+                ClientResponseDecoder::new_with_timeout(&mut reader, read_timeout)
+            }
+            None => ClientResponseDecoder::new(&mut reader),
+        };
+        let (mut parts, payload) = match decoder.next().await {
+            Some(Ok(resp)) => resp.into_parts(),
+            Some(Err(e)) => {
+                info!("receive upgrade response error: {e}");
+                return (generate_response(StatusCode::BAD_GATEWAY, false), true);
+            }
+            None => {
+                info!("upstream closed before upgrade response");
+                return (generate_response(StatusCode::BAD_GATEWAY, false), true);
+            }
+        };
+
+        if parts.status != StatusCode::SWITCHING_PROTOCOLS {
+            // The upgrade is declined, relay the response as is.
+            let too_large = || {
+                info!("upgrade response body larger than {MAX_DECLINED_BODY_SIZE} bytes");
+                (generate_response(StatusCode::BAD_GATEWAY, false), true)
+            };
+            let content_length = parts
+                .headers
+                .get(header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok()?.parse::<usize>().ok());
+            if content_length.is_some_and(|len| len > MAX_DECLINED_BODY_SIZE) {
+                return too_large();
+            }
+            let mut payload = FramedPayload::new(&mut decoder, payload);
+            let mut body = BytesMut::new();
+            while let Some(data) = payload.next_data().await {
+                match data {
+                    Ok(data) if body.len() + data.len() > MAX_DECLINED_BODY_SIZE => {
+                        return too_large();
+                    }
+                    Ok(data) => body.extend_from_slice(&data),
+                    Err(e) => {
+                        info!("receive upgrade response error: {e}");
+                        return (generate_response(StatusCode::BAD_GATEWAY, false), true);
+                    }
+                }
+            }
+            // The body is relayed with a fixed length.
+            parts.headers.remove(header::TRANSFER_ENCODING);
+            let body = (!body.is_empty()).then(|| body.freeze());
+            return (
+                Response::from_parts(parts, HttpBody::fixed_body(body)),
+                true,
+            );
+        }
+
+        // Bytes the upstream sent right after switching protocols.
+        let prelude = decoder.framed_mut().read_buffer_mut().split().freeze();
+        drop(decoder);
+        match stream {
+            Some(stream) => {
+                upgrade::websocket_response(&mut parts);
+                let (client, mut upstream) = Upgraded::pair();
+                upstream.feed(prelude);
+                monoio::spawn(async move {
+                    if let Err(e) = upgrade::splice_stream(stream, upstream, reader, writer).await {
+                        debug!("upgraded stream closed with error: {e}");
+                    }
+                });
+                parts.extensions.insert(OnUpgrade::new(client));
+                (
+                    Response::from_parts(parts, HttpBody::fixed_body(None)),
+                    true,
+                )
+            }
+            None => {
+                let (client, mut upstream) = Upgraded::pair();
+                upstream.feed(prelude);
+                monoio::spawn(async move {
+                    if let Err(e) = upstream.splice(reader, writer).await {
+                        debug!("upgraded connection closed with error: {e}");
+                    }
+                });
+                parts.extensions.insert(OnUpgrade::new(client));
+                (
+                    Response::from_parts(parts, HttpBody::fixed_body(None)),
+                    false,
+                )
+            }
+        }
+    }
+}
+
+/// Address of the uri host of a cleartext request, already resolved and checked by a
+/// [`ForwardProxyHandler`](super::ForwardProxyHandler).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ResolvedAddr(pub(crate) SocketAddr);
+
+fn destination<B>(req: &Request<B>) -> Option<SocketAddr> {
+    match req.extensions().get::<ResolvedAddr>() {
+        Some(addr) => Some(addr.0),
+        None => resolve(req.uri()),
+    }
+}
+
+pub(crate) fn resolve(uri: &Uri) -> Option<SocketAddr> {
+    let Some(host) = uri.host() else {
+        info!("invalid uri which does not contain host: {:?}", uri);
+        return None;
+    };
+    let port = uri.port_u16().unwrap_or(80);
+    let mut iter = match (host, port).to_socket_addrs() {
+        Ok(iter) => iter,
+        Err(e) => {
+            info!("convert invalid uri: {:?} with error: {:?}", uri, e);
+            return None;
+        }
+    };
+    let addr = iter.next();
+    if addr.is_none() {
+        info!("unable to resolve host: {host}");
+    }
+    addr
+}
+
 pub struct UpstreamHandlerFactory {
     http_upstream_timeout: HttpUpstreamTimeout,
     version: HttpVersion,
+    h2_settings: Http2Settings,
+    #[cfg(feature = "tls")]
+    upstream_tls: Vec<(Authority, UpstreamTls)>,
 }
 
 impl UpstreamHandlerFactory {
@@ -270,12 +577,69 @@ impl UpstreamHandlerFactory {
         UpstreamHandlerFactory {
             http_upstream_timeout,
             version,
+            h2_settings: Http2Settings::default(),
+            #[cfg(feature = "tls")]
+            upstream_tls: Vec::new(),
         }
     }
+
+    /// Use `h2_settings` for the HTTP/2 connections to upstreams. Pooled connections keep the
+    /// settings they were established with.
+    pub fn with_h2_settings(mut self, h2_settings: Http2Settings) -> Self {
+        self.h2_settings = h2_settings;
+        self
+    }
+
+    /// Use dedicated TLS settings for the given upstream authorities, see
+    /// [`load_upstream_tls`].
+    #[cfg(feature = "tls")]
+    pub fn with_upstream_tls(mut self, upstream_tls: Vec<(Authority, UpstreamTls)>) -> Self {
+        self.upstream_tls = upstream_tls;
+        self
+    }
+}
+
+/// Load the TLS settings of all upstreams in `routes`.
+///
+/// Returns an error if a setting cannot be loaded, is attached to an endpoint which is not an
+/// https uri, or if the same authority is configured with different settings.
+#[cfg(feature = "tls")]
+pub fn load_upstream_tls(routes: &[RouteConfig]) -> anyhow::Result<Vec<(Authority, UpstreamTls)>> {
+    let mut loaded: Vec<(Authority, UpstreamTls)> = Vec::new();
+    for upstream in routes.iter().flat_map(|route| route.upstreams.iter()) {
+        let Some(tls) = &upstream.tls else {
+            continue;
+        };
+        let authority = match &upstream.endpoint {
+            Endpoint::Uri(uri) if uri.scheme() == Some(&http::uri::Scheme::HTTPS) => {
+                uri.authority().cloned()
+            }
+            _ => None,
+        };
+        let Some(authority) = authority else {
+            anyhow::bail!(
+                "upstream tls is only supported for https uri endpoints: {:?}",
+                upstream.endpoint
+            );
+        };
+        match loaded.iter().find(|(a, _)| a == &authority) {
+            Some((_, existing)) if &existing.config == tls => continue,
+            Some(_) => anyhow::bail!("conflicting upstream tls settings for {authority}"),
+            None => loaded.push((authority, tls.load()?)),
+        }
+    }
+    Ok(loaded)
 }
 
 macro_rules! create_connectors {
-    ($self:ident, $http_connector:ident, $https_connector:ident, $old_service:ident) => {
+    (
+        $self:ident,
+        $http_connector:ident,
+        $grpc_connector:ident,
+        $https_connector:ident,
+        $upstream_https_connectors:ident,
+        $old_service:ident
+    ) => {
         let mut $http_connector = match $self.version {
             HttpVersion::Http2 => PooledHttpConnector::build_tcp_http2_only(),
             HttpVersion::Http11 => {
@@ -288,6 +652,16 @@ macro_rules! create_connectors {
             }
         };
         $http_connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
+        $self.h2_settings.apply_client($http_connector.h2_builder());
+        let mut $grpc_connector = match $self.version {
+            HttpVersion::Http2 => None,
+            HttpVersion::Http11 | HttpVersion::Auto => {
+                let mut connector = PooledHttpConnector::build_tcp_http2_only();
+                connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
+                $self.h2_settings.apply_client(connector.h2_builder());
+                Some(connector)
+            }
+        };
 
         #[cfg(feature = "tls")]
         let mut $https_connector = match $self.version {
@@ -306,6 +680,43 @@ macro_rules! create_connectors {
         };
         #[cfg(feature = "tls")]
         $https_connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
+        #[cfg(feature = "tls")]
+        $self
+            .h2_settings
+            .apply_client($https_connector.h2_builder());
+
+        #[cfg(feature = "tls")]
+        let mut $upstream_https_connectors = HashMap::with_capacity($self.upstream_tls.len());
+        #[cfg(feature = "tls")]
+        for (authority, tls) in $self.upstream_tls.iter() {
+            let mut client_config = (*tls.client_config).clone();
+            if tls.config.alpn.is_none() {
+                client_config.alpn_protocols = match $self.version {
+                    HttpVersion::Http2 => vec![b"h2".to_vec()],
+                    HttpVersion::Http11 => vec![b"http/1.1".to_vec()],
+                    HttpVersion::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                };
+            }
+            let mut upgrade_config = client_config.clone();
+            upgrade_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+            let mut connector = PooledHttpsConnector::new(TlsConnector::new(
+                TcpConnector::default(),
+                client_config.into(),
+            ));
+            connector.set_read_timeout($self.http_upstream_timeout.read_timeout);
+            $self.h2_settings.apply_client(connector.h2_builder());
+            $upstream_https_connectors.insert(
+                authority.clone(),
+                UpstreamHttpsConnector {
+                    connector,
+                    upgrade_connector: UpgradeTlsConnector(TlsConnector::new(
+                        TcpConnector::default(),
+                        upgrade_config.into(),
+                    )),
+                    tls: tls.clone(),
+                },
+            );
+        }
 
         // If there is an old service, transfer the pool from the old service to the new one
         // to avoid creating new connections.
@@ -320,6 +731,11 @@ macro_rules! create_connectors {
                     tracing::error!("Failed to transfer pool: {:?}", e);
                 }
             }
+            if let (Some(old), Some(new)) = (&$old_service.grpc_connector, &mut $grpc_connector) {
+                if let Err(e) = PooledHttpConnector::transfer_pool(old, new) {
+                    tracing::error!("Failed to transfer pool: {:?}", e);
+                }
+            }
             #[cfg(feature = "tls")]
             match PooledHttpsConnector::transfer_pool(
                 &$old_service.https_connector,
@@ -330,6 +746,21 @@ macro_rules! create_connectors {
                     tracing::error!("Failed to transfer pool: {:?}", e);
                 }
             }
+            // Connections of an upstream are only reused when its TLS settings are unchanged.
+            #[cfg(feature = "tls")]
+            for (authority, new) in $upstream_https_connectors.iter_mut() {
+                let Some(old) = $old_service.upstream_https_connectors.get(authority) else {
+                    continue;
+                };
+                if old.tls.config != new.tls.config {
+                    continue;
+                }
+                if let Err(e) =
+                    PooledHttpsConnector::transfer_pool(&old.connector, &mut new.connector)
+                {
+                    tracing::error!("Failed to transfer pool: {:?}", e);
+                }
+            }
         }
     };
 }
@@ -338,11 +769,23 @@ impl MakeService for UpstreamHandlerFactory {
     type Service = UpstreamHandler;
     type Error = Infallible;
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
-        create_connectors!(self, http_connector, https_connector, old);
+        create_connectors!(
+            self,
+            http_connector,
+            grpc_connector,
+            https_connector,
+            upstream_https_connectors,
+            old
+        );
         Ok(UpstreamHandler {
             http_connector,
+            grpc_connector,
             #[cfg(feature = "tls")]
             https_connector,
+            #[cfg(feature = "tls")]
+            upgrade_tls_connector: UpgradeTlsConnector::default(),
+            #[cfg(feature = "tls")]
+            upstream_https_connectors,
             http_upstream_timeout: self.http_upstream_timeout,
         })
     }
@@ -356,11 +799,23 @@ impl AsyncMakeService for UpstreamHandlerFactory {
         &self,
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
-        create_connectors!(self, http_connector, https_connector, old);
+        create_connectors!(
+            self,
+            http_connector,
+            grpc_connector,
+            https_connector,
+            upstream_https_connectors,
+            old
+        );
         Ok(UpstreamHandler {
             http_connector,
+            grpc_connector,
             #[cfg(feature = "tls")]
             https_connector,
+            #[cfg(feature = "tls")]
+            upgrade_tls_connector: UpgradeTlsConnector::default(),
+            #[cfg(feature = "tls")]
+            upstream_https_connectors,
             http_upstream_timeout: self.http_upstream_timeout,
         })
     }
@@ -386,7 +841,7 @@ where
         .unwrap_or(&peer_addr.0);
 
     match addr {
-        AcceptedAddr::Tcp(addr) => {
+        AcceptedAddr::Tcp(addr) | AcceptedAddr::Udp(addr) => {
             if let Ok(value) = HeaderValue::from_maybe_shared(Bytes::from(addr.ip().to_string())) {
                 headers.insert(header::FORWARDED, value);
             }
@@ -400,3 +855,235 @@ where
         }
     }
 }
+
+// Forward the verified client certificate to the upstream. Headers with the same names sent by
+// the client are always dropped, on every listener, so they cannot be spoofed.
+fn add_client_cert_headers<CX>(headers: &mut HeaderMap, ctx: &CX)
+where
+    CX: ParamMaybeRef<Option<PeerCertificate>>,
+{
+    headers.remove(CLIENT_CERT_SUBJECT);
+    headers.remove(CLIENT_CERT_SAN);
+    headers.remove(CLIENT_CERT_FINGERPRINT);
+    let Some(Some(cert)) = ParamMaybeRef::<Option<PeerCertificate>>::param_maybe_ref(ctx) else {
+        return;
+    };
+    if let Ok(value) = HeaderValue::from_str(&cert.subject) {
+        headers.insert(CLIENT_CERT_SUBJECT, value);
+    }
+    if !cert.sans.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&cert.sans.join(",")) {
+            headers.insert(CLIENT_CERT_SAN, value);
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&cert.fingerprint) {
+        headers.insert(CLIENT_CERT_FINGERPRINT, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Ctx(PeerAddr, Option<PeerCertificate>);
+
+    fn ctx(cert: Option<PeerCertificate>) -> Ctx {
+        Ctx(
+            PeerAddr(SocketAddr::from(([127, 0, 0, 1], 1234)).into()),
+            cert,
+        )
+    }
+
+    impl ParamRef<PeerAddr> for Ctx {
+        fn param_ref(&self) -> &PeerAddr {
+            &self.0
+        }
+    }
+
+    impl ParamMaybeRef<Option<RemoteAddr>> for Ctx {
+        fn param_maybe_ref(&self) -> Option<&Option<RemoteAddr>> {
+            None
+        }
+    }
+
+    impl ParamMaybeRef<Option<PeerCertificate>> for Ctx {
+        fn param_maybe_ref(&self) -> Option<&Option<PeerCertificate>> {
+            Some(&self.1)
+        }
+    }
+
+    struct PlainCtx;
+
+    impl ParamMaybeRef<Option<PeerCertificate>> for PlainCtx {
+        fn param_maybe_ref(&self) -> Option<&Option<PeerCertificate>> {
+            None
+        }
+    }
+
+    fn spoofed() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CLIENT_CERT_SUBJECT, HeaderValue::from_static("CN=admin"));
+        headers.insert(CLIENT_CERT_SAN, HeaderValue::from_static("DNS:admin"));
+        headers.insert(CLIENT_CERT_FINGERPRINT, HeaderValue::from_static("00"));
+        headers
+    }
+
+    #[test]
+    fn client_cert_headers() {
+        // Listeners without TLS or without a client certificate never forward client values.
+        let mut headers = spoofed();
+        add_client_cert_headers(&mut headers, &PlainCtx);
+        assert!(headers.is_empty());
+        let mut headers = spoofed();
+        add_client_cert_headers(&mut headers, &ctx(None));
+        assert!(headers.is_empty());
+
+        let mut headers = spoofed();
+        let cert = PeerCertificate {
+            subject: "CN=client".to_string(),
+            sans: Vec::new(),
+            fingerprint: "ab".to_string(),
+        };
+        add_client_cert_headers(&mut headers, &ctx(Some(cert)));
+        assert_eq!(headers[CLIENT_CERT_SUBJECT], "CN=client");
+        assert_eq!(headers[CLIENT_CERT_FINGERPRINT], "ab");
+        assert!(!headers.contains_key(CLIENT_CERT_SAN));
+    }
+
+    #[cfg(feature = "tls")]
+    fn routes(upstreams: serde_json::Value) -> Vec<RouteConfig> {
+        vec![serde_json::from_value(serde_json::json!({
+            "path": "/",
+            "upstreams": upstreams,
+        }))
+        .unwrap()]
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn upstream_tls_settings() {
+        use serde_json::json;
+
+        let ca = crate::testing::TestCa::new();
+        let (chain, key) = ca.issue(&["client"]);
+        let dir = tempfile::tempdir().unwrap();
+        let ca_file = crate::testing::write_file(dir.path(), "ca.pem", ca.pem());
+        let cert_file = crate::testing::write_file(dir.path(), "cert.pem", &chain);
+        let key_file = crate::testing::write_file(dir.path(), "key.pem", &key);
+        let empty_file = crate::testing::write_file(dir.path(), "empty.pem", "");
+        let endpoint = |uri: &str| json!({ "type": "uri", "value": uri });
+        let tls =
+            json!({ "ca": ca_file, "cert": cert_file, "key": key_file, "sni": "upstream.test" });
+
+        // Upstreams sharing an authority share its settings, plain upstreams have none.
+        let loaded = load_upstream_tls(&routes(json!([
+            { "endpoint": endpoint("https://a.test"), "tls": tls },
+            { "endpoint": endpoint("https://a.test"), "tls": tls },
+            { "endpoint": endpoint("http://b.test") },
+            { "endpoint": endpoint("https://c.test:8443"), "tls": { "min_version": "tls1.3" } },
+        ])))
+        .unwrap();
+        assert_eq!(loaded.len(), 2);
+        let (authority, a) = &loaded[0];
+        assert_eq!(authority.as_str(), "a.test");
+        assert_eq!(
+            a.sni,
+            Some(rustls_client::pki_types::ServerName::try_from("upstream.test").unwrap())
+        );
+        assert!(a.client_config.client_auth_cert_resolver.has_certs());
+        let (authority, c) = &loaded[1];
+        assert_eq!(authority.as_str(), "c.test:8443");
+        assert_eq!(c.sni, None);
+        assert!(!c.client_config.client_auth_cert_resolver.has_certs());
+
+        for upstreams in [
+            // Settings on a plain endpoint.
+            json!([{ "endpoint": endpoint("http://a.test"), "tls": tls }]),
+            // Conflicting settings for one authority.
+            json!([
+                { "endpoint": endpoint("https://a.test"), "tls": tls },
+                { "endpoint": endpoint("https://a.test/other"), "tls": { "ca": ca_file } },
+            ]),
+            // A client certificate without its key.
+            json!([{ "endpoint": endpoint("https://a.test"), "tls": { "cert": cert_file } }]),
+            json!([{ "endpoint": endpoint("https://a.test"), "tls": { "ca": empty_file } }]),
+            json!([{ "endpoint": endpoint("https://a.test"), "tls": { "ca": "/nonexistent" } }]),
+            json!([{ "endpoint": endpoint("https://a.test"), "tls": { "sni": "not a name" } }]),
+        ] {
+            assert!(
+                load_upstream_tls(&routes(upstreams.clone())).is_err(),
+                "{upstreams}"
+            );
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[monoio::test(timer_enabled = true)]
+    async fn connectors_per_upstream() {
+        use serde_json::json;
+
+        let ca = crate::testing::TestCa::new();
+        let (chain, key) = ca.issue(&["upstream.test"]);
+        let dir = tempfile::tempdir().unwrap();
+        let ca_file = crate::testing::write_file(dir.path(), "ca.pem", ca.pem());
+        let answer = |scheme: &'static str| {
+            move |_| async move { Response::new(scheme.as_bytes().to_vec()) }
+        };
+        let https = crate::testing::stub_https(&chain, &key, answer("https"));
+        let other = crate::testing::stub_https(&chain, &key, answer("https"));
+        let http = crate::testing::stub_http(answer("http"));
+
+        let upstream = format!("https://127.0.0.1:{}", https.port());
+        let routes = routes(json!([{
+            "endpoint": { "type": "uri", "value": upstream },
+            "tls": { "ca": ca_file, "sni": "upstream.test" },
+        }]));
+        let factory = UpstreamHandler::factory(Default::default(), HttpVersion::Http11)
+            .with_upstream_tls(load_upstream_tls(&routes).unwrap());
+        let handler = MakeService::make(&factory).unwrap();
+        let call = |uri: String| {
+            let req = Request::get(uri).body(HttpBody::fixed_body(None)).unwrap();
+            handler.call((req, ctx(None)))
+        };
+
+        // The upstream with its own settings trusts the test CA and verifies the SNI name.
+        let (resp, _) = call(format!("{upstream}/")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(crate::testing::body_bytes(resp.into_body()).await, b"https");
+        // Other HTTPS authorities use the default connector, which does not.
+        let (resp, _) = call(format!("https://{other}/")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        // Plain upstreams use the plain connector.
+        let (resp, _) = call(format!("http://{http}/")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(crate::testing::body_bytes(resp.into_body()).await, b"http");
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn declined_upgrade() {
+        let upstream = crate::testing::stub_http(|req: Request<Bytes>| async move {
+            let size = req.uri().path()[1..].parse().unwrap();
+            Response::new(vec![b'x'; size])
+        });
+        let factory = UpstreamHandler::factory(Default::default(), HttpVersion::Http11);
+        let handler = MakeService::make(&factory).unwrap();
+        let call = |size: usize| {
+            let req = Request::get(format!("http://{upstream}/{size}"))
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "websocket")
+                .body(HttpBody::fixed_body(None))
+                .unwrap();
+            handler.call((req, ctx(None)))
+        };
+
+        // The response declining the upgrade is relayed as is, as long as its body is small.
+        let (resp, _) = call(16).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            crate::testing::body_bytes(resp.into_body()).await,
+            [b'x'; 16]
+        );
+        let (resp, _) = call(MAX_DECLINED_BODY_SIZE + 1).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,387 @@
+//! Active health checks of the upstreams of HTTP routes.
+//!
+//! Routes with a [`HealthCheckConfig`] periodically probe each of their upstreams, and requests
+//! are only routed to the upstreams found healthy.
+//!
+//! # Key Components
+//!
+//! - [`HealthCheckConfig`]: Probe type, interval, timeout and thresholds of a route.
+//! - [`HealthCheckKind`]: The probe sent to upstreams:
+//!   - `http`: A `GET` request to a path, healthy when answered with a 2xx status.
+//!   - `grpc`: A call to `grpc.health.v1.Health/Check` of the gRPC health checking protocol over
+//!     HTTP/2, healthy when the service is `SERVING`.
+//! - [`HealthAwareLoadBalancer`]: The load balancer of a route, which skips unhealthy upstreams.
+//!
+//! # Behavior
+//!
+//! - Upstreams start healthy. They are marked unhealthy after `unhealthy_threshold` consecutive
+//!   failed probes and healthy again after `healthy_threshold` consecutive successful probes.
+//! - When the load balancer selects an unhealthy upstream, the next healthy upstream of the route
+//!   is used instead. When no upstream is healthy, the selection is kept: failing open gives
+//!   requests a chance rather than rejecting all of them.
+//! - Only uri endpoints are probed. Probes use the default TLS settings for https endpoints.
+//! - Each worker probes the upstreams of its routes. The probing task ends when the routes are
+//!   dropped, e.g. replaced by a configuration reload.
+use std::{
+    cell::Cell,
+    convert::Infallible,
+    num::NonZeroU64,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::{header, Method, Request, Response, Uri};
+use monoio_http::common::body::{FixedBody, HttpBody};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    client::{ClientError, HttpClient},
+    grpc::{self, ProtoField},
+    handlers::route::Endpoint,
+};
+use crate::common::selector::{LoadBalancer, Select};
+
+const GRPC_HEALTH_CHECK_PATH: &str = "/grpc.health.v1.Health/Check";
+// HealthCheckResponse.ServingStatus.SERVING
+const GRPC_SERVING: u64 = 1;
+
+/// Active health check settings of a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    #[serde(flatten)]
+    pub kind: HealthCheckKind,
+    /// Seconds between two probes of an upstream.
+    #[serde(default = "default_interval")]
+    pub interval_sec: NonZeroU64,
+    /// Seconds after which a probe fails.
+    #[serde(default = "default_timeout")]
+    pub timeout_sec: NonZeroU64,
+    /// Consecutive failed probes marking an upstream unhealthy.
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    /// Consecutive successful probes marking an upstream healthy again.
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+}
+
+/// Probe sent to the upstreams of a route.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// `GET` the path, 2xx statuses are healthy.
+    Http {
+        #[serde(default = "default_path")]
+        path: String,
+    },
+    /// gRPC health checking protocol. An empty service checks the overall health of the server.
+    Grpc {
+        #[serde(default)]
+        service: String,
+    },
+}
+
+const fn default_interval() -> NonZeroU64 {
+    NonZeroU64::new(10).unwrap()
+}
+
+const fn default_timeout() -> NonZeroU64 {
+    NonZeroU64::new(2).unwrap()
+}
+
+const fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+const fn default_healthy_threshold() -> u32 {
+    2
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+/// Load balancer of a route skipping the upstreams failing their health checks.
+///
+/// See the [module level documentation](crate::http::health) for details.
+#[derive(Debug)]
+pub struct HealthAwareLoadBalancer {
+    lb: LoadBalancer<Endpoint>,
+    health: Option<Rc<[UpstreamHealth]>>,
+}
+
+#[derive(Debug)]
+struct UpstreamHealth {
+    endpoint: Endpoint,
+    healthy: Cell<bool>,
+    // Consecutive probe results contradicting the current state
+    streak: Cell<u32>,
+}
+
+impl HealthAwareLoadBalancer {
+    /// Wrap `lb`, probing `endpoints` in the background when `config` is set.
+    ///
+    /// Must be called within a monoio runtime when `config` is set.
+    pub fn new(
+        lb: LoadBalancer<Endpoint>,
+        endpoints: Vec<Endpoint>,
+        config: Option<HealthCheckConfig>,
+    ) -> Self {
+        let Some(config) = config else {
+            return Self { lb, health: None };
+        };
+        let health: Rc<[UpstreamHealth]> = endpoints
+            .into_iter()
+            .map(|endpoint| UpstreamHealth {
+                endpoint,
+                healthy: Cell::new(true),
+                streak: Cell::new(0),
+            })
+            .collect();
+        monoio::spawn(check_loop(Rc::downgrade(&health), config));
+        Self {
+            lb,
+            health: Some(health),
+        }
+    }
+}
+
+impl Select<str> for HealthAwareLoadBalancer {
+    type Output<'a>
+        = &'a Endpoint
+    where
+        Self: 'a;
+    type Error = Infallible;
+
+    fn select(&self, key: &str) -> Result<Self::Output<'_>, Self::Error> {
+        let selected = self.lb.select(key)?;
+        let Some(health) = &self.health else {
+            return Ok(selected);
+        };
+        let Some(idx) = health.iter().position(|h| &h.endpoint == selected) else {
+            return Ok(selected);
+        };
+        if health[idx].healthy.get() {
+            return Ok(selected);
+        }
+        let next_healthy = health[idx + 1..]
+            .iter()
+            .chain(&health[..idx])
+            .find(|h| h.healthy.get());
+        Ok(next_healthy.map_or(selected, |h| &h.endpoint))
+    }
+}
+
+impl UpstreamHealth {
+    fn record(&self, success: bool, config: &HealthCheckConfig) {
+        if success == self.healthy.get() {
+            self.streak.set(0);
+            return;
+        }
+        let streak = self.streak.get() + 1;
+        let threshold = match success {
+            true => config.healthy_threshold,
+            false => config.unhealthy_threshold,
+        };
+        if streak < threshold {
+            self.streak.set(streak);
+            return;
+        }
+        self.streak.set(0);
+        self.healthy.set(success);
+        match success {
+            true => info!("upstream {:?} is healthy", self.endpoint),
+            false => warn!("upstream {:?} is unhealthy", self.endpoint),
+        }
+    }
+}
+
+async fn check_loop(health: Weak<[UpstreamHealth]>, config: HealthCheckConfig) {
+    let prober = Prober::new(&config);
+    let interval = Duration::from_secs(config.interval_sec.get());
+    // The routes may be dropped while probing, don't wait for another interval then.
+    while health.strong_count() > 0 {
+        monoio::time::sleep(interval).await;
+        let Some(health) = health.upgrade() else {
+            return;
+        };
+        for upstream in health.iter() {
+            let Endpoint::Uri(uri) = &upstream.endpoint else {
+                continue;
+            };
+            let success = prober.probe(uri, &config.kind).await;
+            upstream.record(success, &config);
+        }
+    }
+}
+
+struct Prober {
+    client: HttpClient,
+}
+
+impl Prober {
+    fn new(config: &HealthCheckConfig) -> Self {
+        let timeout = Duration::from_secs(config.timeout_sec.get());
+        let client = match config.kind {
+            HealthCheckKind::Http { .. } => HttpClient::new(timeout),
+            HealthCheckKind::Grpc { .. } => HttpClient::http2(timeout),
+        };
+        Self { client }
+    }
+
+    async fn probe(&self, upstream: &Uri, kind: &HealthCheckKind) -> bool {
+        let request = match kind {
+            HealthCheckKind::Http { path } => probe_request(upstream, path, Method::GET, None),
+            HealthCheckKind::Grpc { service } => {
+                // HealthCheckRequest { string service = 1; }
+                let mut message = Vec::with_capacity(service.len() + 2);
+                if !service.is_empty() {
+                    grpc::encode_bytes_field(1, service.as_bytes(), &mut message);
+                }
+                probe_request(
+                    upstream,
+                    GRPC_HEALTH_CHECK_PATH,
+                    Method::POST,
+                    Some(grpc::encode_message(&message)),
+                )
+            }
+        };
+        let Some(request) = request else {
+            return false;
+        };
+        Self::check_response(upstream, self.client.send(request).await, kind)
+    }
+
+    fn check_response(
+        upstream: &Uri,
+        response: Result<Response<Bytes>, ClientError>,
+        kind: &HealthCheckKind,
+    ) -> bool {
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                info!("health check of {upstream} failed: {e:?}");
+                return false;
+            }
+        };
+        match kind {
+            HealthCheckKind::Http { .. } => response.status().is_success(),
+            HealthCheckKind::Grpc { .. } => {
+                if !response.status().is_success() || !grpc::is_grpc(response.headers()) {
+                    return false;
+                }
+                // Trailers-Only responses carry a grpc-status in their headers.
+                if response
+                    .headers()
+                    .get(grpc::GRPC_STATUS)
+                    .is_some_and(|status| status != "0")
+                {
+                    return false;
+                }
+                // HealthCheckResponse { ServingStatus status = 1; }
+                grpc::decode_message(response.body())
+                    .and_then(grpc::decode_fields)
+                    .is_some_and(|fields| fields.contains(&(1, ProtoField::Varint(GRPC_SERVING))))
+            }
+        }
+    }
+}
+
+fn probe_request(
+    upstream: &Uri,
+    path: &str,
+    method: Method,
+    body: Option<Bytes>,
+) -> Option<Request<HttpBody>> {
+    let authority = upstream.authority()?;
+    let uri = Uri::builder()
+        .scheme(
+            upstream
+                .scheme()
+                .cloned()
+                .unwrap_or(http::uri::Scheme::HTTP),
+        )
+        .authority(authority.clone())
+        .path_and_query(path)
+        .build()
+        .ok()?;
+    let mut request = Request::builder().method(method).uri(uri);
+    if let Some(body) = &body {
+        request = request
+            .header(header::CONTENT_TYPE, grpc::GRPC_CONTENT_TYPE)
+            .header(header::TE, "trailers")
+            .header(header::CONTENT_LENGTH, body.len());
+    }
+    request.body(HttpBody::fixed_body(body)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::selector::{IdentitySelector, RoundRobinSelector};
+
+    fn config() -> HealthCheckConfig {
+        HealthCheckConfig {
+            kind: HealthCheckKind::Grpc {
+                service: String::new(),
+            },
+            interval_sec: default_interval(),
+            timeout_sec: default_timeout(),
+            unhealthy_threshold: 2,
+            healthy_threshold: default_healthy_threshold(),
+        }
+    }
+
+    #[test]
+    fn unhealthy_upstreams_are_skipped() {
+        let endpoints: Vec<Endpoint> = (0..3)
+            .map(|i| Endpoint::Uri(format!("http://10.0.0.{i}:50051").parse().unwrap()))
+            .collect();
+        let lb = HealthAwareLoadBalancer {
+            lb: LoadBalancer::RoundRobin(RoundRobinSelector::new(endpoints.clone()).unwrap()),
+            health: Some(
+                endpoints
+                    .iter()
+                    .map(|endpoint| UpstreamHealth {
+                        endpoint: endpoint.clone(),
+                        healthy: Cell::new(true),
+                        streak: Cell::new(0),
+                    })
+                    .collect(),
+            ),
+        };
+        let health = lb.health.as_ref().unwrap();
+        let config = config();
+        health[1].record(false, &config);
+        assert!(health[1].healthy.get());
+        health[1].record(false, &config);
+        assert!(!health[1].healthy.get());
+
+        let selected: Vec<_> = (0..3).map(|_| lb.select("/").unwrap().clone()).collect();
+        assert_eq!(selected, [0, 2, 2].map(|i| endpoints[i].clone()));
+
+        // Fail open when no upstream is healthy.
+        for h in health.iter() {
+            h.healthy.set(false);
+        }
+        assert_eq!(lb.select("/").unwrap(), &endpoints[0]);
+
+        let single = HealthAwareLoadBalancer::new(
+            LoadBalancer::Identity(IdentitySelector(endpoints[0].clone())),
+            vec![endpoints[0].clone()],
+            None,
+        );
+        assert_eq!(single.select("/").unwrap(), &endpoints[0]);
+    }
+
+    #[test]
+    fn zero_durations_rejected() {
+        let config: HealthCheckConfig = serde_json::from_str(r#"{"type": "http"}"#).unwrap();
+        assert_eq!(config.interval_sec, default_interval());
+        for field in ["interval_sec", "timeout_sec"] {
+            let config = format!(r#"{{"type": "http", "{field}": 0}}"#);
+            assert!(serde_json::from_str::<HealthCheckConfig>(&config).is_err());
+        }
+    }
+}
@@ -0,0 +1,578 @@
+//! Limits of the size of HTTP requests.
+//!
+//! [`HttpCoreService`](crate::http::HttpCoreService) and its HTTP/3 counterpart enforce the
+//! [`RequestLimits`] of their server while reading requests, before they reach the handler chain,
+//! so that oversized requests are neither buffered nor forwarded:
+//!
+//! - `431 Request Header Fields Too Large` when the request line and headers exceed
+//!   `max_header_size` bytes, or there are more than `max_header_count` headers.
+//! - `414 URI Too Long` when the request target exceeds `max_uri_length` bytes.
+//! - `413 Content Too Large` when the body exceeds `max_body_size` bytes. Routes may set their own
+//!   `max_body_size`, overriding the one of the server.
+//!
+//! HTTP/1.1 connections are closed after these responses, as the rest of the request is not read.
+//!
+//! # Key Components
+//!
+//! - [`RequestLimits`]: Limits of the requests of a server.
+//! - [`RequestLimitConfig`]: Limits of a server along with the body limits of its routes.
+//! - [`LimitedBody`]: Body of the requests of `HttpCoreService`, counting the bytes of HTTP/2
+//!   bodies.
+//!
+//! # Features
+//!
+//! - HTTP/1.1 heads are checked as they are read: the limits apply to the bytes buffered so far,
+//!   before the head is complete
+//! - Bodies whose `Content-Length` exceeds the limit are refused before the handler chain is
+//!   called, chunked, HTTP/2 and HTTP/3 bodies are counted as the handler chain reads them and fail
+//!   once they exceed the limit, in which case the response of the handler chain is replaced
+//! - The trailer section of chunked bodies is bounded by the header limits, see
+//!   [`h1`](crate::http::h1)
+//! - HTTP/2 header lists are limited through the `SETTINGS_MAX_HEADER_LIST_SIZE` of the connection,
+//!   for which the HTTP/2 implementation answers `431` on its own, and HTTP/3 field sections
+//!   through `SETTINGS_MAX_FIELD_SECTION_SIZE` likewise
+use std::{cell::Cell, io, rc::Rc, sync::Arc, time::Duration};
+
+use bytes::{Bytes, BytesMut};
+use http::{HeaderMap, StatusCode, Uri};
+use monoio::io::{stream::Stream, AsyncReadRent};
+use monoio_codec::{Decoded, Decoder, FramedRead};
+use monoio_http::{
+    common::{
+        body::{Body, FixedBody, HttpBody, StreamHint},
+        error::HttpError,
+        request::Request,
+    },
+    h1::{
+        codec::decoder::{
+            ChannelWrapper, DecodeError, FillPayload, FixedBodyDecoder, GenericHeadDecoder,
+            NextDecoder, RequestHeadDecoder,
+        },
+        payload::PayloadError,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::http::{
+    h1::{ChunkedDecoder, RequestTrailers},
+    handlers::route::{RouteConfig, RouteMatcher},
+};
+
+const CRLF: &[u8] = b"\r\n";
+const HEAD_END: &[u8] = b"\r\n\r\n";
+// " HTTP/1.1" following the request target.
+const VERSION_LEN: usize = 9;
+
+/// Limits of the requests of a server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestLimits {
+    /// Bytes of the request target.
+    #[serde(default)]
+    pub max_uri_length: Option<usize>,
+    /// Bytes of the request line and headers of HTTP/1.1 requests, and of the header list of
+    /// HTTP/2 requests.
+    #[serde(default = "default_max_header_size")]
+    pub max_header_size: usize,
+    /// Number of headers. The HTTP/1.1 codec does not support more than 96 headers.
+    #[serde(default = "default_max_header_count")]
+    pub max_header_count: usize,
+    /// Bytes of the body.
+    #[serde(default)]
+    pub max_body_size: Option<u64>,
+}
+
+fn default_max_header_size() -> usize {
+    64 * 1024
+}
+
+fn default_max_header_count() -> usize {
+    96
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_uri_length: None,
+            max_header_size: default_max_header_size(),
+            max_header_count: default_max_header_count(),
+            max_body_size: None,
+        }
+    }
+}
+
+/// Limits of the requests of a server, and body limits of its routes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestLimitConfig {
+    pub limits: RequestLimits,
+    /// Path and body limit of the routes, in the order of the routes.
+    pub route_body_sizes: Vec<(String, Option<u64>)>,
+}
+
+impl RequestLimitConfig {
+    pub fn new(limits: RequestLimits, routes: &[RouteConfig]) -> Self {
+        Self {
+            limits,
+            route_body_sizes: routes
+                .iter()
+                .map(|route| (route.path.clone(), route.max_body_size))
+                .collect(),
+        }
+    }
+}
+
+/// A request limit exceeded by a request.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LimitExceeded {
+    #[error("request target too long")]
+    UriTooLong,
+    #[error("request headers too large")]
+    HeaderTooLarge,
+    #[error("too many request headers")]
+    TooManyHeaders,
+    #[error("request body too large")]
+    BodyTooLarge,
+}
+
+impl LimitExceeded {
+    pub(crate) fn status(self) -> StatusCode {
+        match self {
+            LimitExceeded::UriTooLong => StatusCode::URI_TOO_LONG,
+            LimitExceeded::HeaderTooLarge | LimitExceeded::TooManyHeaders => {
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            LimitExceeded::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        }
+    }
+}
+
+/// Request limits of a server, with the routes resolving body limits.
+pub(crate) struct RequestLimiter {
+    limits: RequestLimits,
+    routes: RouteMatcher<u64>,
+}
+
+impl RequestLimiter {
+    pub(crate) fn new(config: &RequestLimitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            limits: config.limits,
+            routes: RouteMatcher::new("body limit", config.route_body_sizes.iter().cloned()),
+        })
+    }
+
+    pub(crate) fn limits(&self) -> &RequestLimits {
+        &self.limits
+    }
+
+    /// Body limit of the requests to `path`.
+    pub(crate) fn body_limit(&self, path: &str) -> Option<u64> {
+        self.routes.at(path).copied().or(self.limits.max_body_size)
+    }
+
+    /// Check the `:path` and headers of an HTTP/2 request against the URI and header count
+    /// limits.
+    pub(crate) fn check_head(&self, uri: &Uri, headers: &HeaderMap) -> Result<(), LimitExceeded> {
+        let uri_length = uri.path_and_query().map_or(0, |path| path.as_str().len());
+        if self
+            .limits
+            .max_uri_length
+            .is_some_and(|max| uri_length > max)
+        {
+            return Err(LimitExceeded::UriTooLong);
+        }
+        if headers.len() > self.limits.max_header_count {
+            return Err(LimitExceeded::TooManyHeaders);
+        }
+        Ok(())
+    }
+
+    /// Check the declared length of a body against its limit.
+    pub(crate) fn check_content_length(
+        headers: &HeaderMap,
+        limit: Option<u64>,
+    ) -> Result<(), LimitExceeded> {
+        let Some(limit) = limit else {
+            return Ok(());
+        };
+        let length = headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok());
+        match length {
+            Some(length) if length > limit => Err(LimitExceeded::BodyTooLarge),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Error of [`LimitedRequestDecoder`].
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum RequestDecodeError {
+    #[error("{0}")]
+    Limit(LimitExceeded),
+    #[error("{0}")]
+    Http(#[from] HttpError),
+}
+
+impl From<io::Error> for RequestDecodeError {
+    fn from(e: io::Error) -> Self {
+        Self::Http(e.into())
+    }
+}
+
+type HeadDecoder = GenericHeadDecoder<Request, RequestHeadDecoder, ChannelWrapper>;
+type BodyDecoder = NextDecoder<FixedBodyDecoder, ChunkedDecoder, Bytes>;
+
+/// Decoder of HTTP/1.1 request heads failing once the buffered head exceeds the limits.
+struct LimitedHeadDecoder {
+    inner: HeadDecoder,
+    limits: RequestLimits,
+}
+
+impl LimitedHeadDecoder {
+    fn check(&self, src: &[u8]) -> Result<(), LimitExceeded> {
+        let head = &src[..src.len().min(self.limits.max_header_size + HEAD_END.len())];
+        let head_end = memchr::memmem::find(head, HEAD_END);
+        if let Some(max) = self.limits.max_uri_length {
+            let line = match memchr::memmem::find(head, CRLF) {
+                Some(end) => &head[..end],
+                None => head,
+            };
+            if let Some(start) = memchr::memchr(b' ', line) {
+                let target = &line[start + 1..];
+                let length = match memchr::memrchr(b' ', target) {
+                    Some(end) if line.len() < head.len() => end,
+                    // The line is incomplete, the version may follow.
+                    _ => target.len().saturating_sub(VERSION_LEN),
+                };
+                if length > max {
+                    return Err(LimitExceeded::UriTooLong);
+                }
+            }
+        }
+        let Some(end) = head_end else {
+            if src.len() > self.limits.max_header_size {
+                return Err(LimitExceeded::HeaderTooLarge);
+            }
+            return Ok(());
+        };
+        if end > self.limits.max_header_size {
+            return Err(LimitExceeded::HeaderTooLarge);
+        }
+        // Lines of the head, without the request line.
+        let lines = memchr::memmem::find_iter(&head[..end], CRLF).count();
+        if lines > self.limits.max_header_count {
+            return Err(LimitExceeded::TooManyHeaders);
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for LimitedHeadDecoder {
+    type Item = (Request, BodyDecoder);
+    type Error = RequestDecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Decoded<Self::Item>, Self::Error> {
+        self.check(src).map_err(RequestDecodeError::Limit)?;
+        let (mut request, next_decoder) = match self.inner.decode(src)? {
+            Decoded::Some(decoded) => decoded,
+            Decoded::Insufficient => return Ok(Decoded::Insufficient),
+            Decoded::InsufficientAtLeast(len) => return Ok(Decoded::InsufficientAtLeast(len)),
+        };
+        let next_decoder = match next_decoder {
+            NextDecoder::None => NextDecoder::None,
+            NextDecoder::Fixed(decoder, sender) => NextDecoder::Fixed(decoder, sender),
+            // Chunked bodies are read with their trailers, which the codec does not support.
+            NextDecoder::Streamed(_, sender) => {
+                let trailers = RequestTrailers::default();
+                request.extensions_mut().insert(trailers.clone());
+                let decoder = ChunkedDecoder::new(
+                    self.limits.max_header_size,
+                    self.limits.max_header_count,
+                    trailers,
+                );
+                NextDecoder::Streamed(decoder, sender)
+            }
+        };
+        Ok(Decoded::Some((request, next_decoder)))
+    }
+}
+
+/// HTTP/1.1 request decoder enforcing [`RequestLimits`].
+///
+/// It reads requests like the `RequestDecoder` of `monoio_http`, and fails with
+/// [`RequestDecodeError::Limit`] when a head exceeds the limits or a body exceeds the limit set
+/// with [`set_body_limit`](Self::set_body_limit).
+pub(crate) struct LimitedRequestDecoder<IO> {
+    framed: FramedRead<IO, LimitedHeadDecoder>,
+    next_decoder: BodyDecoder,
+    timeout: Option<Duration>,
+    body_limit: Option<u64>,
+}
+
+impl<IO> LimitedRequestDecoder<IO> {
+    pub(crate) fn new(io: IO, limits: RequestLimits) -> Self {
+        Self {
+            framed: FramedRead::new(
+                io,
+                LimitedHeadDecoder {
+                    inner: Default::default(),
+                    limits,
+                },
+            ),
+            next_decoder: NextDecoder::None,
+            timeout: None,
+            body_limit: None,
+        }
+    }
+
+    pub(crate) fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Limit the body of the last decoded request.
+    pub(crate) fn set_body_limit(&mut self, limit: Option<u64>) {
+        self.body_limit = limit;
+    }
+}
+
+impl<IO: AsyncReadRent> FillPayload for LimitedRequestDecoder<IO> {
+    type Error = RequestDecodeError;
+
+    async fn fill_payload(&mut self) -> Result<(), Self::Error> {
+        loop {
+            match &mut self.next_decoder {
+                NextDecoder::None => return Ok(()),
+                NextDecoder::Fixed(..) => {
+                    let NextDecoder::Fixed(mut decoder, sender) =
+                        std::mem::take(&mut self.next_decoder)
+                    else {
+                        unreachable!()
+                    };
+                    // The content length was checked against the limit with the head.
+                    match self.framed.next_with(&mut decoder).await {
+                        None => {
+                            sender.feed(Err(PayloadError::UnexpectedEof.into()));
+                            return Err(HttpError::from(DecodeError::UnexpectedEof).into());
+                        }
+                        Some(Ok(item)) => sender.feed(Ok(item)),
+                        Some(Err(e)) => {
+                            sender.feed(Err(PayloadError::Decode.into()));
+                            return Err(HttpError::from(e).into());
+                        }
+                    }
+                }
+                NextDecoder::Streamed(decoder, sender) => {
+                    match self.framed.next_with(decoder).await {
+                        None => {
+                            sender.feed_error(PayloadError::UnexpectedEof.into());
+                            return Err(HttpError::from(DecodeError::UnexpectedEof).into());
+                        }
+                        Some(Ok(Some(item))) => {
+                            if let Some(limit) = &mut self.body_limit {
+                                match limit.checked_sub(item.len() as u64) {
+                                    Some(left) => *limit = left,
+                                    None => {
+                                        sender.feed_error(PayloadError::Decode.into());
+                                        self.next_decoder = NextDecoder::None;
+                                        return Err(RequestDecodeError::Limit(
+                                            LimitExceeded::BodyTooLarge,
+                                        ));
+                                    }
+                                }
+                            }
+                            sender.feed_data(Some(item));
+                        }
+                        Some(Ok(None)) => {
+                            sender.feed_data(None);
+                            self.next_decoder = NextDecoder::None;
+                        }
+                        Some(Err(e)) => {
+                            sender.feed_error(PayloadError::Decode.into());
+                            self.next_decoder = NextDecoder::None;
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<IO: AsyncReadRent> Stream for LimitedRequestDecoder<IO> {
+    type Item = Result<Request, RequestDecodeError>;
+
+    async fn next(&mut self) -> Option<Self::Item> {
+        if !matches!(self.next_decoder, NextDecoder::None) {
+            if let Err(e) = self.fill_payload().await {
+                return Some(Err(e));
+            }
+        }
+        if let Some(duration) = self.timeout {
+            match monoio::time::timeout(duration, self.framed.peek_data()).await {
+                Err(_) => return Some(Err(HttpError::from(DecodeError::TimedOut).into())),
+                Ok(Err(e)) => return Some(Err(e.into())),
+                Ok(Ok(_)) => {}
+            }
+        }
+        match self.framed.next().await? {
+            Ok((item, next_decoder)) => {
+                self.next_decoder = next_decoder;
+                self.body_limit = None;
+                Some(Ok(item))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Bytes a request body may still carry before it exceeds its limit.
+pub(crate) struct BodyLimit {
+    left: u64,
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl BodyLimit {
+    /// Limit a body to `limit` bytes. The flag returned is raised once the body exceeds it.
+    pub(crate) fn new(limit: u64) -> (Self, Rc<Cell<bool>>) {
+        let exceeded = Rc::new(Cell::new(false));
+        let limit = Self {
+            left: limit,
+            exceeded: exceeded.clone(),
+        };
+        (limit, exceeded)
+    }
+
+    /// Count `len` more bytes of the body, failing it once it exceeds the limit.
+    pub(crate) fn count(&mut self, len: usize) -> Result<(), HttpError> {
+        match self.left.checked_sub(len as u64) {
+            Some(left) if !self.exceeded.get() => {
+                self.left = left;
+                Ok(())
+            }
+            _ => {
+                self.exceeded.set(true);
+                Err(PayloadError::Decode.into())
+            }
+        }
+    }
+}
+
+/// Body of the requests of [`HttpCoreService`](crate::http::HttpCoreService).
+///
+/// HTTP/1.1 bodies are held to their limit while decoded. HTTP/2 bodies are counted here as
+/// handlers read them, so that the flow control of the stream still holds the client back, and
+/// fail once they exceed their limit.
+pub struct LimitedBody {
+    body: HttpBody,
+    limit: Option<BodyLimit>,
+}
+
+impl LimitedBody {
+    pub(crate) fn new(body: HttpBody, limit: Option<BodyLimit>) -> Self {
+        Self { body, limit }
+    }
+}
+
+impl From<HttpBody> for LimitedBody {
+    fn from(body: HttpBody) -> Self {
+        Self::new(body, None)
+    }
+}
+
+impl Body for LimitedBody {
+    type Data = Bytes;
+    type Error = HttpError;
+
+    async fn next_data(&mut self) -> Option<Result<Bytes, HttpError>> {
+        let data = self.body.next_data().await;
+        if let (Some(Ok(data)), Some(limit)) = (&data, &mut self.limit) {
+            if let Err(e) = limit.count(data.len()) {
+                return Some(Err(e));
+            }
+        }
+        data
+    }
+
+    fn stream_hint(&self) -> StreamHint {
+        self.body.stream_hint()
+    }
+}
+
+impl FixedBody for LimitedBody {
+    fn fixed_body(data: Option<Bytes>) -> Self {
+        HttpBody::fixed_body(data).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use monoio_http::h1::payload::{stream_payload_pair, Payload};
+
+    use super::*;
+
+    fn check(limits: RequestLimits, src: &str) -> Result<(), LimitExceeded> {
+        let decoder = LimitedHeadDecoder {
+            inner: Default::default(),
+            limits,
+        };
+        decoder.check(src.as_bytes())
+    }
+
+    #[test]
+    fn head_limits() {
+        let limits = RequestLimits {
+            max_uri_length: Some(8),
+            max_header_size: 64,
+            max_header_count: 2,
+            max_body_size: None,
+        };
+        assert_eq!(
+            check(limits, "GET /abc HTTP/1.1\r\nhost: a\r\n\r\n"),
+            Ok(())
+        );
+        assert_eq!(check(limits, "GET /abc HTTP/1."), Ok(()));
+        assert_eq!(
+            check(limits, "GET /abcdefghi HTTP/1.1\r\n"),
+            Err(LimitExceeded::UriTooLong)
+        );
+        // The request line is still incomplete.
+        assert_eq!(
+            check(limits, "GET /abcdefghijklmnopq"),
+            Err(LimitExceeded::UriTooLong)
+        );
+        assert_eq!(
+            check(limits, "GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\n\r\n"),
+            Err(LimitExceeded::TooManyHeaders)
+        );
+        let long = format!("GET / HTTP/1.1\r\nhost: {}\r\n", "a".repeat(64));
+        assert_eq!(check(limits, &long), Err(LimitExceeded::HeaderTooLarge));
+    }
+
+    #[monoio::test]
+    async fn body_limit() {
+        let body = |chunks: &[&'static str]| {
+            let (payload, mut sender) = stream_payload_pair();
+            for chunk in chunks {
+                sender.feed_data(Some(Bytes::from_static(chunk.as_bytes())));
+            }
+            sender.feed_data(None);
+            HttpBody::from(Payload::from(payload))
+        };
+
+        let (limit, exceeded) = BodyLimit::new(6);
+        let mut limited = LimitedBody::new(body(&["abc", "def"]), Some(limit));
+        while let Some(data) = limited.next_data().await {
+            data.unwrap();
+        }
+        assert!(!exceeded.get());
+
+        // Bytes are counted as they are read, and the body fails once over the limit.
+        let (limit, exceeded) = BodyLimit::new(5);
+        let mut limited = LimitedBody::new(body(&["abc", "def", "g"]), Some(limit));
+        assert_eq!(limited.next_data().await.unwrap().unwrap(), "abc");
+        assert!(!exceeded.get());
+        assert!(limited.next_data().await.unwrap().is_err());
+        assert!(exceeded.get());
+        assert!(limited.next_data().await.unwrap().is_err());
+    }
+}
@@ -12,6 +12,14 @@
 //! - [`handlers`]: Provides various HTTP request handlers for different aspects of request
 //!   processing.
 //! - [`detect`]: Implements HTTP version detection functionality.
+//! - `h3`: Serves HTTP/3 over QUIC connections with `Http3CoreService` (requires the `http3`
+//!   feature).
+//! - [`grpc`]: gRPC protocol helpers, used to report failures of gRPC calls with gRPC statuses.
+//! - [`h1`]: Trailers of HTTP/1.1 chunked requests and responses.
+//! - [`flood`]: Protection of HTTP/2 connections against rapid reset and other frame floods.
+//! - [`health`]: Active `http` and `grpc` health checks of the upstreams of routes.
+//! - [`limits`]: Limits of the URI, headers and body of requests.
+//! - [`upgrade`]: Splices upgraded connections such as WebSockets with their upstream.
 //!
 //! ## Structs and Types
 //!
@@ -41,14 +49,33 @@
 //!
 //! - The modular design allows for easy extension and customization of HTTP handling behavior
 //! - Custom handlers can be implemented and integrated into the `HttpCoreService`
+use std::time::Duration;
+
 use http::HeaderValue;
+use monoio_http::h2;
 use serde::{Deserialize, Serialize};
 
-pub use self::core::{HttpCoreService, HttpServerTimeout};
+#[cfg(feature = "http3")]
+pub use self::h3::{Http3Body, Http3CoreService, QuicStream};
+pub use self::{
+    core::{HttpCoreService, HttpServerTimeout},
+    flood::{Http2FloodLimits, Http2FloodStats},
+    h1::RequestTrailers,
+    limits::{LimitedBody, RequestLimitConfig, RequestLimits},
+};
 pub mod handlers;
 
+pub(crate) mod client;
 pub mod core;
 pub mod detect;
+pub mod flood;
+pub mod grpc;
+pub mod h1;
+#[cfg(feature = "http3")]
+pub mod h3;
+pub mod health;
+pub mod limits;
+pub mod upgrade;
 pub mod util;
 
 pub(crate) const CLOSE: &str = "close";
@@ -67,3 +94,187 @@ pub enum HttpVersion {
     #[default]
     Auto,
 }
+
+/// HTTP/2 settings of a server, for its client connections or its upstream connections.
+///
+/// Unset values keep the defaults of the HTTP/2 implementation, except for the stream window and
+/// the concurrent streams of client connections which default to 1MB and 1000. Upstream connections
+/// only use the window, frame and header list sizes: their concurrent streams are limited by the
+/// upstream, and they are not pinged nor closed when idle, nor subject to the `flood` limits.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Http2Settings {
+    /// Flow control window of each stream, i.e. the data the peer may send before it is read.
+    pub initial_stream_window_size: Option<u32>,
+    /// Flow control window shared by the streams of a connection.
+    pub initial_connection_window_size: Option<u32>,
+    /// Streams a client may open concurrently on a connection.
+    pub max_concurrent_streams: Option<u32>,
+    /// Largest frame payload accepted from the peer.
+    pub max_frame_size: Option<u32>,
+    /// Largest header list accepted from the peer, in the uncompressed size of its fields.
+    pub max_header_list_size: Option<u32>,
+    /// Send a PING after this time, and close the connection when it is not acknowledged
+    /// within `keepalive_timeout_sec`.
+    pub keepalive_interval_sec: Option<u64>,
+    /// Defaults to 20 seconds.
+    pub keepalive_timeout_sec: Option<u64>,
+    /// Send a GOAWAY when no stream has been open for this time.
+    pub idle_timeout_sec: Option<u64>,
+    /// Limits protecting client connections from floods of streams and frames.
+    #[serde(default)]
+    pub flood: Http2FloodLimits,
+}
+
+/// Invalid value of [`Http2Settings`].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidHttp2Settings {
+    #[error("max_frame_size must be between 16384 and 16777215, got {0}")]
+    MaxFrameSize(u32),
+    #[error("{0} must not exceed 2147483647")]
+    WindowSize(&'static str),
+    #[error("{0} must not be 0")]
+    Zero(&'static str),
+}
+
+impl Http2Settings {
+    const DEFAULT_STREAM_WINDOW_SIZE: u32 = 1_000_000;
+    const DEFAULT_MAX_CONCURRENT_STREAMS: u32 = 1000;
+    const DEFAULT_KEEPALIVE_TIMEOUT_SEC: u64 = 20;
+    const MIN_FRAME_SIZE: u32 = 16_384;
+    const MAX_FRAME_SIZE: u32 = 16_777_215;
+    const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+    /// Check the settings against the bounds of the protocol. The HTTP/2 implementation panics on
+    /// a frame size out of them, and a zero interval or timeout would ping or close connections
+    /// right away.
+    pub fn validate(&self) -> Result<(), InvalidHttp2Settings> {
+        if let Some(size) = self.max_frame_size {
+            if !(Self::MIN_FRAME_SIZE..=Self::MAX_FRAME_SIZE).contains(&size) {
+                return Err(InvalidHttp2Settings::MaxFrameSize(size));
+            }
+        }
+        for (name, size) in [
+            (
+                "initial_stream_window_size",
+                self.initial_stream_window_size,
+            ),
+            (
+                "initial_connection_window_size",
+                self.initial_connection_window_size,
+            ),
+        ] {
+            if size.is_some_and(|size| size > Self::MAX_WINDOW_SIZE) {
+                return Err(InvalidHttp2Settings::WindowSize(name));
+            }
+        }
+        for (name, sec) in [
+            ("keepalive_interval_sec", self.keepalive_interval_sec),
+            ("keepalive_timeout_sec", self.keepalive_timeout_sec),
+            ("idle_timeout_sec", self.idle_timeout_sec),
+        ] {
+            if sec == Some(0) {
+                return Err(InvalidHttp2Settings::Zero(name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval_sec.map(Duration::from_secs)
+    }
+
+    pub fn keepalive_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.keepalive_timeout_sec
+                .unwrap_or(Self::DEFAULT_KEEPALIVE_TIMEOUT_SEC),
+        )
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout_sec.map(Duration::from_secs)
+    }
+
+    pub fn max_pending_streams(&self) -> usize {
+        self.flood.max_pending_streams.unwrap_or(
+            2 * self
+                .max_concurrent_streams
+                .unwrap_or(Self::DEFAULT_MAX_CONCURRENT_STREAMS),
+        ) as usize
+    }
+
+    pub(crate) fn apply_server(&self, builder: &mut h2::server::Builder) {
+        builder
+            .initial_window_size(
+                self.initial_stream_window_size
+                    .unwrap_or(Self::DEFAULT_STREAM_WINDOW_SIZE),
+            )
+            .max_concurrent_streams(
+                self.max_concurrent_streams
+                    .unwrap_or(Self::DEFAULT_MAX_CONCURRENT_STREAMS),
+            );
+        if let Some(size) = self.initial_connection_window_size {
+            builder.initial_connection_window_size(size);
+        }
+        if let Some(size) = self.max_frame_size {
+            builder.max_frame_size(size);
+        }
+        if let Some(size) = self.max_header_list_size {
+            builder.max_header_list_size(size);
+        }
+    }
+
+    pub(crate) fn apply_client(&self, builder: &mut h2::client::Builder) {
+        if let Some(size) = self.initial_stream_window_size {
+            builder.initial_window_size(size);
+        }
+        if let Some(size) = self.initial_connection_window_size {
+            builder.initial_connection_window_size(size);
+        }
+        if let Some(size) = self.max_frame_size {
+            builder.max_frame_size(size);
+        }
+        if let Some(size) = self.max_header_list_size {
+            builder.max_header_list_size(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http2_settings_bounds() {
+        assert_eq!(Http2Settings::default().validate(), Ok(()));
+        let settings = |f: fn(&mut Http2Settings)| {
+            let mut settings = Http2Settings::default();
+            f(&mut settings);
+            settings.validate()
+        };
+        assert_eq!(settings(|s| s.max_frame_size = Some(16_384)), Ok(()));
+        assert_eq!(settings(|s| s.max_frame_size = Some(16_777_215)), Ok(()));
+        assert_eq!(
+            settings(|s| s.max_frame_size = Some(16_383)),
+            Err(InvalidHttp2Settings::MaxFrameSize(16_383))
+        );
+        assert_eq!(
+            settings(|s| s.max_frame_size = Some(16_777_216)),
+            Err(InvalidHttp2Settings::MaxFrameSize(16_777_216))
+        );
+        assert_eq!(
+            settings(|s| s.initial_connection_window_size = Some(1 << 31)),
+            Err(InvalidHttp2Settings::WindowSize(
+                "initial_connection_window_size"
+            ))
+        );
+        assert_eq!(
+            settings(|s| s.keepalive_interval_sec = Some(0)),
+            Err(InvalidHttp2Settings::Zero("keepalive_interval_sec"))
+        );
+        assert_eq!(
+            settings(|s| s.idle_timeout_sec = Some(0)),
+            Err(InvalidHttp2Settings::Zero("idle_timeout_sec"))
+        );
+        assert_eq!(settings(|s| s.idle_timeout_sec = Some(1)), Ok(()));
+    }
+}
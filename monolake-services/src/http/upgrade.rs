@@ -0,0 +1,301 @@
+//! Protocol upgrades of proxied HTTP exchanges.
+//!
+//! Three kinds of exchanges turn into a bidirectional byte stream once the upstream accepts them:
+//!
+//! - HTTP/1.1 requests carrying `Connection: Upgrade`, e.g. WebSocket or h2c. The upstream answers
+//!   with `101 Switching Protocols` and both connections are spliced from then on.
+//! - HTTP/2 extended CONNECT requests (RFC 8441) bootstrapping a WebSocket. They are forwarded as
+//!   HTTP/1.1 WebSocket upgrades, and the stream data of the HTTP/2 request and response carries
+//!   the WebSocket frames.
+//! - CONNECT requests of a forward proxy, see
+//!   [`ForwardProxyHandler`](crate::http::handlers::ForwardProxyHandler). The tunnel is answered
+//!   with `200` and spliced with the HTTP/1.1 connection or the HTTP/2 stream the same way.
+//!
+//! The upstream connection of an upgraded exchange is owned by the handler that opened it while
+//! the client connection is owned by [`HttpCoreService`](crate::http::HttpCoreService). Both ends
+//! are linked by a pair of [`Upgraded`] channels: the handler attaches its peer end to the `101`
+//! response as an [`OnUpgrade`] extension, and the core service splices it with the client
+//! connection after the response has been written. Over HTTP/2 the handler writes the request
+//! stream to the upstream itself and the core service sends what the peer end delivers on the
+//! response stream, as the client grants flow control capacity. The channels are bounded, so a
+//! slow side holds back the other one.
+use std::{
+    io,
+    pin::pin,
+    sync::{Arc, Mutex},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use futures::{
+    channel::mpsc,
+    future::{select, Either},
+    SinkExt, StreamExt,
+};
+use http::{
+    header, request, response, HeaderMap, HeaderValue, Method, Request, StatusCode, Version,
+};
+use monoio::io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt};
+use monoio_http::{
+    common::body::Body,
+    h2::{ext::Protocol, SendStream},
+};
+
+use super::util;
+
+/// Extended CONNECT protocol of WebSockets over HTTP/2.
+pub const WEBSOCKET: &str = "websocket";
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+const TUNNEL_CAPACITY: usize = 16;
+
+/// One end of an upgraded connection.
+///
+/// Bytes read from the connection spliced with an end are delivered to the other end and the
+/// other way around. Closing the connection on one side shuts down the writing half of the other.
+pub struct Upgraded {
+    tx: mpsc::Sender<Bytes>,
+    rx: mpsc::Receiver<Bytes>,
+}
+
+impl Upgraded {
+    /// Create both ends of an upgraded connection.
+    pub fn pair() -> (Upgraded, Upgraded) {
+        let (a_tx, b_rx) = mpsc::channel(TUNNEL_CAPACITY);
+        let (b_tx, a_rx) = mpsc::channel(TUNNEL_CAPACITY);
+        (
+            Upgraded { tx: a_tx, rx: a_rx },
+            Upgraded { tx: b_tx, rx: b_rx },
+        )
+    }
+
+    /// Queue bytes already read from the connection, e.g. data following the response head.
+    pub(crate) fn feed(&mut self, data: Bytes) {
+        if !data.is_empty() {
+            let _ = self.tx.try_send(data);
+        }
+    }
+
+    /// Splice the connection split into `reader` and `writer` with the other end until both
+    /// directions are closed.
+    pub async fn splice<R, W>(self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: AsyncReadRent,
+        W: AsyncWriteRent,
+    {
+        let Upgraded { tx, mut rx } = self;
+        let inbound = read_into(reader, tx);
+        let outbound = async move {
+            let mut writer = writer;
+            while let Some(data) = rx.next().await {
+                writer.write_all(data).await.0?;
+            }
+            writer.shutdown().await
+        };
+        let (inbound, outbound) = monoio::join!(inbound, outbound);
+        inbound.and(outbound)
+    }
+
+    /// Send the data delivered to this end on an HTTP/2 stream, then end the stream once the other
+    /// end is closed. Data is only pulled as the peer grants capacity, so a slow client holds back
+    /// the upstream instead of piling data up in the stream buffer.
+    pub(crate) async fn send_h2(self, mut stream: SendStream<Bytes>) {
+        let Upgraded { mut rx, .. } = self;
+        while let Some(data) = rx.next().await {
+            if !util::h2_send_data(&mut stream, data).await {
+                // The stream was reset, dropping `rx` stops the upstream side.
+                return;
+            }
+        }
+        let _ = stream.send_data(Bytes::new(), true);
+    }
+}
+
+/// Pending upgrade attached to a `101 Switching Protocols` response, or to the `200` response of a
+/// CONNECT tunnel, by the handler which owns the upstream connection.
+#[derive(Clone)]
+pub struct OnUpgrade(Arc<Mutex<Option<Upgraded>>>);
+
+impl OnUpgrade {
+    pub fn new(upgraded: Upgraded) -> Self {
+        Self(Arc::new(Mutex::new(Some(upgraded))))
+    }
+
+    /// Take the end to splice with the client connection.
+    pub fn take(&self) -> Option<Upgraded> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+/// Whether the request asks to switch the HTTP/1.1 connection to another protocol.
+pub fn is_upgrade_request<B>(request: &Request<B>) -> bool {
+    request.version() == Version::HTTP_11
+        && request.headers().contains_key(header::UPGRADE)
+        && connection_has_upgrade(request.headers())
+}
+
+/// The protocol of an HTTP/2 extended CONNECT request.
+pub fn extended_connect_protocol<B>(request: &Request<B>) -> Option<&Protocol> {
+    if request.method() != Method::CONNECT || request.version() != Version::HTTP_2 {
+        return None;
+    }
+    request.extensions().get::<Protocol>()
+}
+
+fn connection_has_upgrade(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Encode the head of a `101 Switching Protocols` response or of a successful CONNECT response.
+/// Unlike other responses they carry neither a content length nor a body.
+pub(crate) fn encode_head(head: &response::Parts) -> Vec<u8> {
+    let mut encoded = format!(
+        "HTTP/1.1 {} {}\r\n",
+        head.status.as_str(),
+        head.status.canonical_reason().unwrap_or_default()
+    )
+    .into_bytes();
+    for (name, value) in head
+        .headers
+        .iter()
+        .filter(|(name, _)| *name != header::CONTENT_LENGTH && *name != header::TRANSFER_ENCODING)
+    {
+        encoded.extend_from_slice(name.as_ref());
+        encoded.extend_from_slice(b": ");
+        encoded.extend_from_slice(value.as_bytes());
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded.extend_from_slice(b"\r\n");
+    encoded
+}
+
+/// Turn the head of a WebSocket extended CONNECT request into an HTTP/1.1 WebSocket upgrade.
+pub(crate) fn websocket_request(parts: &mut request::Parts) {
+    parts.method = Method::GET;
+    parts.version = Version::HTTP_11;
+    let key = STANDARD.encode(rand::random::<[u8; 16]>());
+    let headers = &mut parts.headers;
+    headers.insert(header::UPGRADE, HeaderValue::from_static(WEBSOCKET));
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    if let Ok(key) = HeaderValue::from_str(&key) {
+        headers.insert(header::SEC_WEBSOCKET_KEY, key);
+    }
+}
+
+/// Turn the `101` response to a WebSocket upgrade into the `200` response to the extended CONNECT
+/// request it was made for.
+pub(crate) fn websocket_response(parts: &mut response::Parts) {
+    parts.status = StatusCode::OK;
+    parts.version = Version::HTTP_2;
+    parts.headers.remove(header::CONNECTION);
+    parts.headers.remove(header::UPGRADE);
+    parts.headers.remove(header::SEC_WEBSOCKET_ACCEPT);
+}
+
+/// Splice an upstream connection with the stream of an HTTP/2 extended CONNECT exchange: the
+/// request body is written to the upstream and the upstream data is delivered to `upstream`, whose
+/// other end is attached to the response and sent by the core service as the client grants flow
+/// control capacity.
+///
+/// A reset of the client stream fails the request body, which ends the whole splice.
+pub(crate) async fn splice_stream<B, R, W>(
+    body: B,
+    upstream: Upgraded,
+    reader: R,
+    mut writer: W,
+) -> io::Result<()>
+where
+    B: Body<Data = Bytes>,
+    R: AsyncReadRent,
+    W: AsyncWriteRent,
+{
+    let Upgraded { tx, .. } = upstream;
+    let inbound = pin!(async move {
+        let mut body = body;
+        while let Some(data) = body.next_data().await {
+            match data {
+                Ok(data) => writer.write_all(data).await.0?,
+                Err(_) => return Err(io::ErrorKind::ConnectionReset.into()),
+            };
+        }
+        writer.shutdown().await
+    });
+    let outbound = pin!(read_into(reader, tx));
+    match select(inbound, outbound).await {
+        Either::Left((Err(e), _)) => Err(e),
+        Either::Left((Ok(()), outbound)) => outbound.await,
+        Either::Right((outbound, inbound)) => inbound.await.and(outbound),
+    }
+}
+
+async fn read_into<R: AsyncReadRent>(mut reader: R, mut tx: mpsc::Sender<Bytes>) -> io::Result<()> {
+    let mut buf = BytesMut::with_capacity(READ_BUFFER_SIZE);
+    loop {
+        let (read, b) = reader.read(buf).await;
+        buf = b;
+        if read? == 0 {
+            return Ok(());
+        }
+        if tx.send(buf.split().freeze()).await.is_err() {
+            // The other end is gone, nothing left to deliver to.
+            return Ok(());
+        }
+        buf.reserve(READ_BUFFER_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use monoio::{
+        io::{AsyncReadRentExt, Splitable},
+        net::{TcpListener, TcpStream},
+    };
+    use monoio_http::h1::payload::{stream_payload_pair, Payload};
+
+    use super::*;
+
+    #[monoio::test(timer_enabled = true)]
+    async fn stream_splice() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut upstream, _) = listener.accept().await.unwrap();
+        let (reader, writer) = proxy.into_split();
+        let (payload, mut request) = stream_payload_pair::<Bytes, io::Error>();
+        let (mut client, end) = Upgraded::pair();
+        let splice = monoio::spawn(splice_stream(Payload::Stream(payload), end, reader, writer));
+
+        request.feed_data(Some(Bytes::from_static(b"ping")));
+        let (read, buf) = upstream.read_exact(vec![0; 4]).await;
+        read.unwrap();
+        assert_eq!(buf, b"ping");
+
+        // Upstream data is read only as fast as the client end takes it.
+        let (mut upstream_reader, mut upstream_writer) = upstream.into_split();
+        monoio::spawn(async move {
+            let _ = upstream_writer.write_all(vec![0; 4 * 1024 * 1024]).await;
+        });
+        monoio::time::sleep(Duration::from_millis(200)).await;
+        let mut queued = 0;
+        while let Ok(Some(data)) = client.rx.try_next() {
+            queued += data.len();
+        }
+        assert!(queued > 0);
+        assert!(queued <= (TUNNEL_CAPACITY + 1) * READ_BUFFER_SIZE);
+
+        // A reset of the client stream ends the splice, even with an idle upstream.
+        request.feed_error(io::ErrorKind::ConnectionReset.into());
+        let result = monoio::time::timeout(Duration::from_secs(1), splice).await;
+        assert!(result.unwrap().is_err());
+        let (read, _) = upstream_reader.read(Vec::with_capacity(16)).await;
+        assert_eq!(read.unwrap(), 0);
+    }
+}
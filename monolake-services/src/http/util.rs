@@ -1,7 +1,14 @@
-use std::{future::Future, task::Poll};
+use std::{
+    future::{poll_fn, Future},
+    task::Poll,
+};
 
-use http::{HeaderValue, Request, Response, StatusCode};
-use monoio_http::common::body::FixedBody;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderValue, Request, Response, StatusCode};
+use monoio_http::{
+    common::body::{FixedBody, HttpBody},
+    h2::SendStream,
+};
 use monolake_core::http::{HttpError, HttpHandler, ResponseWithContinue};
 use service_async::Service;
 
@@ -80,6 +87,11 @@ impl<FMAIN, FACC, T> AccompanyPair<FMAIN, FACC, T> {
         }
     }
 
+    /// Output of the accompanying future, when it completed before the main one.
+    pub(crate) fn accompany_output(&self) -> Option<&T> {
+        self.accompany_slot.as_ref()
+    }
+
     pub(crate) fn into_accompany(self) -> Accompany<FACC, T> {
         Accompany {
             accompany: self.accompany,
@@ -88,6 +100,38 @@ impl<FMAIN, FACC, T> AccompanyPair<FMAIN, FACC, T> {
     }
 }
 
+/// Send `data` on an HTTP/2 stream as the peer grants flow control capacity, rather than
+/// buffering it in the stream. Returns `false` once the stream is reset or closed.
+pub(crate) async fn h2_send_data(stream: &mut SendStream<Bytes>, mut data: Bytes) -> bool {
+    while !data.is_empty() {
+        stream.reserve_capacity(data.len());
+        let capacity = match stream.capacity() {
+            0 => match poll_fn(|cx| stream.poll_capacity(cx)).await {
+                Some(Ok(_)) => continue,
+                _ => return false,
+            },
+            capacity => capacity,
+        };
+        let chunk = data.split_to(capacity.min(data.len()));
+        if stream.send_data(chunk, false).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Read the trailers ending a body whose data has been read.
+///
+/// Only the bodies received over HTTP/2 carry trailers: the trailers of HTTP/1.1 chunked requests
+/// are found in their [`RequestTrailers`](crate::http::RequestTrailers) extension, and the bodies
+/// built by handlers have none.
+pub async fn trailers(body: &mut HttpBody) -> Option<HeaderMap> {
+    match body {
+        HttpBody::H2(stream) => stream.trailers().await.ok().flatten(),
+        _ => None,
+    }
+}
+
 pub(crate) fn generate_response<B: FixedBody>(status_code: StatusCode, close: bool) -> Response<B> {
     let mut resp = Response::builder();
     resp = resp.status(status_code);
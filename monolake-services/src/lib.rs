@@ -65,6 +65,14 @@
 //! - [`TimeoutService`](common::TimeoutService) Adds configurable timeout functionality to any
 //!   inner service. It ensures that long-running operations don't block the server indefinitely.
 //!
+//! ### Layer 4 Services
+//!
+//! - [`TcpProxyService`](tcp::proxy): Forwards raw TCP connections to load balanced upstreams.
+//! - [`SniRouter`](tcp::passthrough): Routes TLS connections by the server name of their
+//!   ClientHello without terminating them.
+//! - [`UdpProxyService`](udp::proxy): Relays the datagrams of UDP client sessions to load balanced
+//!   upstreams.
+//!
 //! ### TLS Service
 //!
 //! - [`UnifiedTlsService`](crate::tls): Provides a unified interface for different TLS
@@ -155,6 +163,7 @@ pub mod common;
 pub mod http;
 pub mod tcp;
 pub mod thrift;
+pub mod udp;
 
 #[cfg(feature = "proxy-protocol")]
 pub mod proxy_protocol;
@@ -164,3 +173,6 @@ pub mod tls;
 
 #[cfg(feature = "hyper")]
 pub mod hyper;
+
+#[cfg(test)]
+mod testing;
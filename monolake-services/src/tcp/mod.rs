@@ -1,5 +1,13 @@
 //! Tcp specific Services(Under progress)
+//!
+//! - [`echo`]: Echo the bytes read from a connection.
+//! - [`proxy`]: Layer 4 proxy forwarding connections to upstream servers with [`TcpProxyService`].
+//! - [`passthrough`]: TLS passthrough routing connections by server name with [`SniRouter`].
 pub mod echo;
+pub mod passthrough;
 pub mod proxy;
 
+pub use passthrough::SniRouter;
+pub use proxy::TcpProxyService;
+
 pub type Accept<Stream, Ctx> = (Stream, Ctx);
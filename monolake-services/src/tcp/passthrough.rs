@@ -0,0 +1,431 @@
+//! TLS passthrough routing connections by the server name of the ClientHello.
+//!
+//! This module routes TLS connections to upstream servers without terminating them. The
+//! ClientHello is read with [`ClientHelloDetector`], its server name (SNI) and ALPN protocols
+//! select a route, and the connection is relayed to the upstreams of the route by a
+//! [`TcpProxyService`]: the bytes read for detection are replayed to the upstream and the rest is
+//! spliced.
+//!
+//! # Key Components
+//!
+//! - [`ClientHelloDetector`]: A [`Detect`] implementation reading the ClientHello.
+//! - [`SniRouter`]: The service selecting the route of a connection.
+//! - [`TlsPassthroughFactory`]: Factory for creating the detecting and routing service.
+//!
+//! # Features
+//!
+//! - Server name patterns, either exact names ignoring case or `*.example.com` for all subdomains
+//! - Optional ALPN conditions, e.g. to send `h2` clients to dedicated upstreams
+//! - Routes without server names match any ClientHello, including ones without SNI
+//! - ClientHellos spanning several TLS records
+//! - Configurable ClientHello read timeout
+//!
+//! # Usage
+//!
+//! `TlsPassthroughFactory` replaces the TLS layer of a stack, connections reaching it must not be
+//! decrypted:
+//!
+//! ```ignore
+//! use service_async::stack::FactoryStack;
+//!
+//! use crate::tcp::passthrough::SniRouter;
+//!
+//! let routes = vec![SniRouteConfig { /* ... */ }];
+//! let stack = FactoryStack::new(config)
+//!     .replace(SniRouter::factory(routes, TcpProxyTimeout::default()))
+//!     // ... other layers ...
+//!     ;
+//!
+//! let service = stack.make_async().await.unwrap();
+//! // Use the service to handle accepted connections
+//! ```
+use std::{io, rc::Rc};
+
+use monoio::{
+    buf::IoBufMut,
+    io::{AsyncReadRent, AsyncReadRentExt, AsyncWriteRent, Split},
+};
+use monolake_core::context::PeerAddr;
+use serde::{Deserialize, Serialize};
+use service_async::{AsyncMakeService, MakeService, ParamRef, Service};
+use tracing::debug;
+
+use super::proxy::{
+    TcpProxyError, TcpProxyService, TcpProxyStats, TcpProxyStream, TcpProxyTimeout, TcpRouteConfig,
+};
+use crate::common::{
+    detect::{Detect, DetectService},
+    selector::{LoadBalanceError, LoadBalancer},
+};
+
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_ALPN: u16 = 16;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+// Records may carry at most 2^14 bytes of plaintext
+const MAX_RECORD_LEN: usize = 1 << 14;
+const MAX_CLIENT_HELLO_LEN: usize = 1 << 16;
+
+/// Server name and ALPN protocols offered by a TLS client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientHello {
+    /// The host name of the SNI extension, in lowercase.
+    pub server_name: Option<String>,
+    /// The protocols of the ALPN extension, in client preference order.
+    pub alpn: Vec<Vec<u8>>,
+}
+
+/// Detect the ClientHello at the start of a TLS connection.
+///
+/// The detected value is `None` when the connection does not start with a ClientHello. The
+/// stream is returned along with the bytes read from it, to be replayed to the upstream.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientHelloDetector {
+    pub timeout: Option<std::time::Duration>,
+}
+
+impl<IO> Detect<IO> for ClientHelloDetector
+where
+    IO: AsyncReadRent,
+{
+    type DetOut = Option<ClientHello>;
+    type IOOut = (IO, Vec<u8>);
+
+    async fn detect(&self, mut io: IO) -> io::Result<(Self::DetOut, Self::IOOut)> {
+        let mut buf = Vec::new();
+        let read = read_client_hello(&mut io, &mut buf);
+        let hello = match self.timeout {
+            Some(timeout) => monoio::time::timeout(timeout, read)
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+            None => read.await?,
+        };
+        Ok((hello, (io, buf)))
+    }
+}
+
+/// Read the TLS records carrying the ClientHello into `buf`.
+async fn read_client_hello<IO: AsyncReadRent>(
+    io: &mut IO,
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<ClientHello>> {
+    let mut handshake = Vec::new();
+    loop {
+        read_more(io, buf, RECORD_HEADER_LEN).await?;
+        let header = &buf[buf.len() - RECORD_HEADER_LEN..];
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if header[0] != CONTENT_TYPE_HANDSHAKE || len == 0 || len > MAX_RECORD_LEN {
+            return Ok(None);
+        }
+        read_more(io, buf, len).await?;
+        handshake.extend_from_slice(&buf[buf.len() - len..]);
+        if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+            return Ok(None);
+        }
+        if handshake.len() < 4 {
+            continue;
+        }
+        let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if hello_len > MAX_CLIENT_HELLO_LEN {
+            return Ok(None);
+        }
+        if handshake.len() >= 4 + hello_len {
+            return Ok(parse_client_hello(&handshake[4..4 + hello_len]));
+        }
+    }
+}
+
+async fn read_more<IO: AsyncReadRent>(io: &mut IO, buf: &mut Vec<u8>, n: usize) -> io::Result<()> {
+    let len = buf.len();
+    buf.reserve(n);
+    let (result, slice) = io
+        .read_exact(std::mem::take(buf).slice_mut(len..len + n))
+        .await;
+    *buf = slice.into_inner();
+    result.map(|_| ())
+}
+
+/// Reader of the big endian, length prefixed fields of TLS messages.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.take(len).map(Reader)
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.take(len).map(Reader)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn parse_client_hello(body: &[u8]) -> Option<ClientHello> {
+    let mut hello = ClientHello::default();
+    let mut r = Reader(body);
+    // legacy version and random
+    r.take(2 + 32)?;
+    // session id, cipher suites and compression methods
+    r.vec8()?;
+    r.vec16()?;
+    r.vec8()?;
+    if r.is_empty() {
+        return Some(hello);
+    }
+    let mut extensions = r.vec16()?;
+    while !extensions.is_empty() {
+        let extension = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        match extension {
+            EXTENSION_SERVER_NAME => {
+                let mut names = data.vec16()?;
+                while !names.is_empty() {
+                    let name_type = names.u8()?;
+                    let name = names.vec16()?;
+                    if name_type == NAME_TYPE_HOST_NAME {
+                        let name = std::str::from_utf8(name.0).ok()?;
+                        hello.server_name = Some(name.to_ascii_lowercase());
+                    }
+                }
+            }
+            EXTENSION_ALPN => {
+                let mut protocols = data.vec16()?;
+                while !protocols.is_empty() {
+                    hello.alpn.push(protocols.vec8()?.0.to_vec());
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsPassthroughError {
+    #[error("connection does not start with a tls client hello")]
+    NotTls,
+    #[error("no route for server name {0:?}")]
+    NoRoute(Option<String>),
+    #[error("proxy error: {0}")]
+    Proxy(#[from] TcpProxyError),
+}
+
+/// Route of TLS connections, see [`SniRouter`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SniRouteConfig {
+    /// Server names of the route, exact names or `*.example.com` for all subdomains. The route
+    /// matches all connections when empty.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+
+    /// The client must offer one of these ALPN protocols when not empty.
+    #[serde(default)]
+    pub alpn: Vec<String>,
+
+    #[serde(flatten)]
+    pub route: TcpRouteConfig,
+}
+
+struct SniRoute {
+    server_names: Vec<String>,
+    alpn: Vec<Vec<u8>>,
+    proxy: TcpProxyService,
+}
+
+impl SniRoute {
+    fn matches(&self, hello: &ClientHello) -> bool {
+        let name_matches = match &hello.server_name {
+            Some(name) => self
+                .server_names
+                .iter()
+                .any(|pattern| server_name_matches(pattern, name)),
+            None => false,
+        };
+        (self.server_names.is_empty() || name_matches)
+            && (self.alpn.is_empty() || hello.alpn.iter().any(|p| self.alpn.contains(p)))
+    }
+}
+
+fn server_name_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => name.len().checked_sub(domain.len() + 1).is_some_and(|dot| {
+            name.as_bytes()[dot] == b'.' && name[dot + 1..].eq_ignore_ascii_case(domain)
+        }),
+        None => pattern.eq_ignore_ascii_case(name),
+    }
+}
+
+/// Relay TLS connections to the upstreams of the first route matching their ClientHello.
+///
+/// For implementation details and example usage, see the
+/// [module level documentation](crate::tcp::passthrough).
+pub struct SniRouter {
+    routes: Vec<SniRoute>,
+    stats: Rc<TcpProxyStats>,
+}
+
+impl SniRouter {
+    pub const fn factory(
+        routes: Vec<SniRouteConfig>,
+        timeout: TcpProxyTimeout,
+    ) -> TlsPassthroughFactory {
+        TlsPassthroughFactory { routes, timeout }
+    }
+
+    /// Counters of the connections relayed by all routes.
+    pub fn stats(&self) -> &TcpProxyStats {
+        &self.stats
+    }
+}
+
+impl<S, CX> Service<(Option<ClientHello>, (S, Vec<u8>), CX)> for SniRouter
+where
+    S: TcpProxyStream + Split + AsyncReadRent + AsyncWriteRent,
+    CX: ParamRef<PeerAddr>,
+{
+    type Response = ();
+    type Error = TlsPassthroughError;
+
+    async fn call(
+        &self,
+        (hello, (stream, prefix), ctx): (Option<ClientHello>, (S, Vec<u8>), CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let hello = hello.ok_or(TlsPassthroughError::NotTls)?;
+        let Some(route) = self.routes.iter().find(|route| route.matches(&hello)) else {
+            return Err(TlsPassthroughError::NoRoute(hello.server_name));
+        };
+        debug!("tls passthrough {:?}", hello.server_name);
+        route
+            .proxy
+            .relay(stream, prefix, &ParamRef::<PeerAddr>::param_ref(&ctx).0)
+            .await
+            .map_err(Into::into)
+    }
+}
+
+/// Factory for creating the service detecting the ClientHello and routing connections.
+///
+/// Counters of the previous service are carried over when the service is rebuilt.
+pub struct TlsPassthroughFactory {
+    routes: Vec<SniRouteConfig>,
+    timeout: TcpProxyTimeout,
+}
+
+impl TlsPassthroughFactory {
+    fn make(
+        &self,
+        old: Option<&DetectService<ClientHelloDetector, SniRouter>>,
+    ) -> Result<DetectService<ClientHelloDetector, SniRouter>, LoadBalanceError> {
+        let stats = old.map(|o| o.inner.stats.clone()).unwrap_or_default();
+        let routes = self
+            .routes
+            .iter()
+            .map(|config| {
+                let endpoints = LoadBalancer::try_from_upstreams(
+                    config.route.load_balancer,
+                    config.route.upstreams.clone(),
+                )?;
+                Ok(SniRoute {
+                    server_names: config.server_names.clone(),
+                    alpn: config.alpn.iter().map(|p| p.as_bytes().to_vec()).collect(),
+                    proxy: TcpProxyService::new(endpoints, self.timeout).with_stats(stats.clone()),
+                })
+            })
+            .collect::<Result<_, LoadBalanceError>>()?;
+        Ok(DetectService {
+            detector: ClientHelloDetector {
+                timeout: self.timeout.client_hello_timeout,
+            },
+            inner: SniRouter { routes, stats },
+        })
+    }
+}
+
+impl MakeService for TlsPassthroughFactory {
+    type Service = DetectService<ClientHelloDetector, SniRouter>;
+    type Error = LoadBalanceError;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.make(old)
+    }
+}
+
+impl AsyncMakeService for TlsPassthroughFactory {
+    type Service = DetectService<ClientHelloDetector, SniRouter>;
+    type Error = LoadBalanceError;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.make(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_hello(server_name: &str, alpn: &[&str]) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        let name = server_name.as_bytes();
+        extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(name.len() as u16 + 5).to_be_bytes());
+        extensions.extend_from_slice(&(name.len() as u16 + 3).to_be_bytes());
+        extensions.push(NAME_TYPE_HOST_NAME);
+        extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(name);
+        let protocols: Vec<u8> = alpn
+            .iter()
+            .flat_map(|p| std::iter::once(p.len() as u8).chain(p.bytes()))
+            .collect();
+        extensions.extend_from_slice(&EXTENSION_ALPN.to_be_bytes());
+        extensions.extend_from_slice(&(protocols.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(protocols.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&protocols);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        body
+    }
+
+    #[test]
+    fn client_hello_routes() {
+        let hello = parse_client_hello(&client_hello("API.example.com", &["h2", "http/1.1"]));
+        let hello = hello.unwrap();
+        assert_eq!(hello.server_name.as_deref(), Some("api.example.com"));
+        assert_eq!(hello.alpn, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert!(parse_client_hello(&[3, 3, 0]).is_none());
+
+        assert!(server_name_matches("*.example.com", "api.example.com"));
+        assert!(!server_name_matches("*.example.com", "example.com"));
+        assert!(!server_name_matches("*.example.com", "badexample.com"));
+        assert!(server_name_matches("Example.com", "example.com"));
+    }
+}
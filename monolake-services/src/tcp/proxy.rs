@@ -1 +1,733 @@
-//! TODO
+//! Layer 4 proxy forwarding raw TCP and Unix streams to upstream servers.
+//!
+//! This module provides a proxy service for non-HTTP protocols such as databases or message
+//! brokers. Each accepted connection is forwarded to an upstream selected by a
+//! [`LoadBalancer`], and bytes are relayed in both directions until both sides are closed.
+//!
+//! # Key Components
+//!
+//! - [`TcpProxyService`]: The service relaying accepted connections to upstream servers.
+//! - [`TcpProxyServiceFactory`]: Factory for creating `TcpProxyService` instances.
+//! - [`TcpProxyStream`]: Accepted streams which may be plain sockets.
+//! - [`TcpProxyStats`]: Connection and byte counters of a service.
+//!
+//! # Features
+//!
+//! - Upstream selection with the configured load balancing strategy, failing over to the other
+//!   upstreams in configuration order when connecting to the selected one fails
+//! - Support for both TCP and Unix socket connections to upstream servers
+//! - Zero-copy forwarding with `splice(2)` when both connections are plain sockets on Linux,
+//!   buffered forwarding otherwise, e.g. after TLS termination
+//! - Half-closed connections: the end of one direction is propagated as a write shutdown
+//! - Configurable connect and idle timeouts, connections idle for 10 minutes are closed by default
+//!
+//! # Usage
+//!
+//! `TcpProxyService` is the innermost service of a layer 4 stack:
+//!
+//! ```ignore
+//! use service_async::stack::FactoryStack;
+//!
+//! use crate::tcp::proxy::TcpProxyService;
+//!
+//! let config = TcpRouteConfig { /* ... */ };
+//! let stack = FactoryStack::new(config.clone())
+//!     .replace(TcpProxyService::factory(config, TcpProxyTimeout::default()))
+//!     // ... tls and other layers ...
+//!     ;
+//!
+//! let service = stack.make_async().await.unwrap();
+//! // Use the service to handle accepted connections
+//! ```
+//!
+//! # Performance Considerations
+//!
+//! - Spliced bytes move between the sockets through a pipe without being copied to user space
+//! - Counters are kept per worker and carried over when the service is rebuilt
+use std::{cell::Cell, io, rc::Rc, time::Duration};
+
+#[cfg(target_os = "linux")]
+use monoio::{
+    io::{
+        as_fd::{AsReadFd, AsWriteFd},
+        splice::{SpliceDestination, SpliceSource},
+    },
+    net::unix::new_pipe,
+};
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, Split, Splitable},
+    time::Instant,
+};
+use monoio_transports::connectors::{
+    Connector, UnifiedL4Addr, UnifiedL4Connector, UnifiedL4Stream,
+};
+use monolake_core::{
+    context::PeerAddr,
+    listener::{AcceptedAddr, AcceptedStream},
+};
+use serde::{Deserialize, Serialize};
+use service_async::{AsyncMakeService, MakeService, ParamRef, Service};
+use tracing::{debug, info};
+
+use crate::common::selector::{
+    IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Select,
+};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+#[cfg(target_os = "linux")]
+const SPLICE_SIZE: u32 = 64 * 1024;
+
+/// Layer 4 proxy relaying accepted connections to upstream servers.
+///
+/// For implementation details and example usage, see the
+/// [module level documentation](crate::tcp::proxy).
+pub struct TcpProxyService {
+    connector: UnifiedL4Connector,
+    endpoints: LoadBalancer<Endpoint>,
+    timeout: TcpProxyTimeout,
+    stats: Rc<TcpProxyStats>,
+}
+
+/// Timeouts of the layer 4 proxy.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpProxyTimeout {
+    /// Timeout of connecting to the upstream.
+    pub connect_timeout: Option<Duration>,
+    /// Close both connections when no byte is relayed in either direction for this duration.
+    pub idle_timeout: Option<Duration>,
+    /// Timeout of reading the ClientHello of TLS passthrough connections.
+    pub client_hello_timeout: Option<Duration>,
+}
+
+impl Default for TcpProxyTimeout {
+    fn default() -> Self {
+        Self {
+            connect_timeout: None,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            client_hello_timeout: None,
+        }
+    }
+}
+
+/// Connection and byte counters of a [`TcpProxyService`].
+///
+/// Counters are kept per worker thread.
+#[derive(Debug, Default)]
+pub struct TcpProxyStats {
+    connections: Cell<u64>,
+    active: Cell<u64>,
+    sent: Cell<u64>,
+    received: Cell<u64>,
+}
+
+impl TcpProxyStats {
+    /// Number of connections relayed to an upstream.
+    pub fn connections(&self) -> u64 {
+        self.connections.get()
+    }
+
+    /// Number of connections currently relayed.
+    pub fn active(&self) -> u64 {
+        self.active.get()
+    }
+
+    /// Bytes sent from clients to upstreams.
+    pub fn sent(&self) -> u64 {
+        self.sent.get()
+    }
+
+    /// Bytes received from upstreams and sent to clients.
+    pub fn received(&self) -> u64 {
+        self.received.get()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TcpProxyError {
+    #[error("connect upstream {0:?} error: {1}")]
+    Connect(Endpoint, io::Error),
+    #[error("connect upstream {0:?} timeout")]
+    ConnectTimeout(Endpoint),
+    #[error("relay error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Accepted streams which may be plain sockets.
+///
+/// Plain TCP and Unix connections are spliced with the upstream connection, other streams such as
+/// TLS ones are relayed through a buffer.
+pub trait TcpProxyStream: Sized {
+    /// Return the socket of a plain connection, or the stream itself otherwise.
+    fn into_socket(self) -> Result<UnifiedL4Stream, Self>;
+}
+
+impl TcpProxyStream for AcceptedStream {
+    #[inline]
+    fn into_socket(self) -> Result<UnifiedL4Stream, Self> {
+        match self {
+            AcceptedStream::Tcp(stream) => Ok(UnifiedL4Stream::Tcp(stream)),
+            AcceptedStream::Unix(stream) => Ok(UnifiedL4Stream::Unix(stream)),
+            #[allow(unreachable_patterns)]
+            stream => Err(stream),
+        }
+    }
+}
+
+impl TcpProxyStream for monoio::net::TcpStream {
+    #[inline]
+    fn into_socket(self) -> Result<UnifiedL4Stream, Self> {
+        Ok(UnifiedL4Stream::Tcp(self))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S> TcpProxyStream for crate::tls::RustlsStream<S> {
+    #[inline]
+    fn into_socket(self) -> Result<UnifiedL4Stream, Self> {
+        Err(self)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S> TcpProxyStream for monoio_native_tls::TlsStream<S> {
+    #[inline]
+    fn into_socket(self) -> Result<UnifiedL4Stream, Self> {
+        Err(self)
+    }
+}
+
+#[cfg(feature = "proxy-protocol")]
+impl<S, P> TcpProxyStream for monoio::io::PrefixedReadIo<S, P> {
+    // The header may be followed by client bytes already read into the prefix.
+    #[inline]
+    fn into_socket(self) -> Result<UnifiedL4Stream, Self> {
+        Err(self)
+    }
+}
+
+impl TcpProxyService {
+    pub fn new(endpoints: LoadBalancer<Endpoint>, timeout: TcpProxyTimeout) -> Self {
+        TcpProxyService {
+            connector: UnifiedL4Connector::default(),
+            endpoints,
+            timeout,
+            stats: Default::default(),
+        }
+    }
+
+    pub const fn factory(
+        config: TcpRouteConfig,
+        timeout: TcpProxyTimeout,
+    ) -> TcpProxyServiceFactory {
+        TcpProxyServiceFactory { config, timeout }
+    }
+
+    /// Counters of the connections relayed by this service.
+    pub fn stats(&self) -> &TcpProxyStats {
+        &self.stats
+    }
+
+    /// Share the counters of another service.
+    pub(crate) fn with_stats(mut self, stats: Rc<TcpProxyStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    async fn connect(&self, endpoint: &Endpoint) -> Result<UnifiedL4Stream, TcpProxyError> {
+        let key = match endpoint {
+            Endpoint::Socket(addr) => UnifiedL4Addr::Tcp(*addr),
+            Endpoint::Unix(path) => UnifiedL4Addr::Unix(path.clone()),
+        };
+        let connect = self.connector.connect(key);
+        let result = match self.timeout.connect_timeout {
+            Some(timeout) => match monoio::time::timeout(timeout, connect).await {
+                Ok(result) => result,
+                Err(_) => return Err(TcpProxyError::ConnectTimeout(endpoint.clone())),
+            },
+            None => connect.await,
+        };
+        result.map_err(|e| TcpProxyError::Connect(endpoint.clone(), e))
+    }
+
+    /// Connect to the selected upstream, or else to the first other upstream accepting the
+    /// connection, returning the error of the last attempt when none does.
+    async fn connect_any(&self) -> Result<(&Endpoint, UnifiedL4Stream), TcpProxyError> {
+        let Ok(selected) = self.endpoints.select(&());
+        let others = self
+            .endpoints
+            .endpoints()
+            .iter()
+            .filter(|endpoint| *endpoint != selected);
+        let mut endpoint = selected;
+        let mut result = self.connect(selected).await;
+        for other in others {
+            let Err(e) = &result else {
+                break;
+            };
+            info!("tcp proxy failing over to {other:?} after: {e}");
+            endpoint = other;
+            result = self.connect(other).await;
+        }
+        result.map(|upstream| (endpoint, upstream))
+    }
+}
+
+impl<S, CX> Service<(S, CX)> for TcpProxyService
+where
+    S: TcpProxyStream + Split + AsyncReadRent + AsyncWriteRent,
+    CX: ParamRef<PeerAddr>,
+{
+    type Response = ();
+    type Error = TcpProxyError;
+
+    async fn call(&self, (stream, ctx): (S, CX)) -> Result<Self::Response, Self::Error> {
+        self.relay(stream, Vec::new(), &ParamRef::<PeerAddr>::param_ref(&ctx).0)
+            .await
+    }
+}
+
+impl TcpProxyService {
+    /// Relay `stream` to an upstream, after sending the `prefix` already read from it.
+    pub async fn relay<S>(
+        &self,
+        stream: S,
+        prefix: Vec<u8>,
+        peer: &AcceptedAddr,
+    ) -> Result<(), TcpProxyError>
+    where
+        S: TcpProxyStream + Split + AsyncReadRent + AsyncWriteRent,
+    {
+        let (endpoint, upstream) = self.connect_any().await?;
+        debug!("tcp proxy {peer:?} connected to {endpoint:?}");
+
+        let stats = &self.stats;
+        stats.connections.set(stats.connections.get() + 1);
+        let transfer = Transfer::new(stats);
+        let result = transfer
+            .relay(stream, prefix, upstream, self.timeout.idle_timeout)
+            .await;
+
+        info!(
+            "tcp proxy {peer:?} to {endpoint:?} closed, sent {} bytes, received {} bytes",
+            transfer.sent.get(),
+            transfer.received.get()
+        );
+        result.map_err(Into::into)
+    }
+}
+
+/// Bytes relayed over one proxied connection, counted as active until dropped, so that relays
+/// cancelled by a shutdown are not counted forever.
+struct Transfer<'a> {
+    stats: &'a TcpProxyStats,
+    sent: Cell<u64>,
+    received: Cell<u64>,
+    last_activity: Cell<Instant>,
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        self.stats.active.set(self.stats.active.get() - 1);
+    }
+}
+
+impl<'a> Transfer<'a> {
+    fn new(stats: &'a TcpProxyStats) -> Self {
+        stats.active.set(stats.active.get() + 1);
+        Self {
+            stats,
+            sent: Cell::new(0),
+            received: Cell::new(0),
+            last_activity: Cell::new(Instant::now()),
+        }
+    }
+
+    fn record(&self, upstream_bound: bool, n: u64) {
+        let (conn, total) = match upstream_bound {
+            true => (&self.sent, &self.stats.sent),
+            false => (&self.received, &self.stats.received),
+        };
+        conn.set(conn.get() + n);
+        total.set(total.get() + n);
+        self.last_activity.set(Instant::now());
+    }
+
+    async fn relay<S>(
+        &self,
+        stream: S,
+        prefix: Vec<u8>,
+        mut upstream: UnifiedL4Stream,
+        idle_timeout: Option<Duration>,
+    ) -> io::Result<()>
+    where
+        S: TcpProxyStream + Split + AsyncReadRent + AsyncWriteRent,
+    {
+        let relay = async {
+            if !prefix.is_empty() {
+                let n = prefix.len();
+                upstream.write_all(prefix).await.0?;
+                self.record(true, n as u64);
+            }
+            #[cfg(target_os = "linux")]
+            let stream = match stream.into_socket() {
+                Ok(socket) => {
+                    return match (socket, upstream) {
+                        (UnifiedL4Stream::Tcp(c), UnifiedL4Stream::Tcp(u)) => {
+                            self.splice(c, u).await
+                        }
+                        (UnifiedL4Stream::Tcp(c), UnifiedL4Stream::Unix(u)) => {
+                            self.splice(c, u).await
+                        }
+                        (UnifiedL4Stream::Unix(c), UnifiedL4Stream::Tcp(u)) => {
+                            self.splice(c, u).await
+                        }
+                        (UnifiedL4Stream::Unix(c), UnifiedL4Stream::Unix(u)) => {
+                            self.splice(c, u).await
+                        }
+                    };
+                }
+                Err(stream) => stream,
+            };
+            match upstream {
+                UnifiedL4Stream::Tcp(u) => self.copy(stream, u).await,
+                UnifiedL4Stream::Unix(u) => self.copy(stream, u).await,
+            }
+        };
+
+        let Some(idle_timeout) = idle_timeout else {
+            return relay.await;
+        };
+        monoio::select! {
+            result = relay => result,
+            _ = self.idle(idle_timeout) => {
+                debug!("tcp proxy connection idle for {idle_timeout:?}, closing");
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolve once no byte has been relayed for `timeout`.
+    async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = self.last_activity.get() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            monoio::time::sleep_until(deadline).await;
+        }
+    }
+
+    async fn copy<C, U>(&self, client: C, upstream: U) -> io::Result<()>
+    where
+        C: Split + AsyncReadRent + AsyncWriteRent,
+        U: Split + AsyncReadRent + AsyncWriteRent,
+    {
+        let (client_read, client_write) = client.into_split();
+        let (upstream_read, upstream_write) = upstream.into_split();
+        let (sent, received) = monoio::join!(
+            self.copy_one(client_read, upstream_write, true),
+            self.copy_one(upstream_read, client_write, false)
+        );
+        sent.and(received)
+    }
+
+    async fn copy_one<R, W>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        upstream_bound: bool,
+    ) -> io::Result<()>
+    where
+        R: AsyncReadRent,
+        W: AsyncWriteRent,
+    {
+        let mut buffer = Vec::with_capacity(BUFFER_SIZE);
+        loop {
+            let (result, buf) = reader.read(buffer).await;
+            let n = result?;
+            if n == 0 {
+                break;
+            }
+            let (result, buf) = writer.write_all(buf).await;
+            result?;
+            self.record(upstream_bound, n as u64);
+            buffer = buf;
+            buffer.clear();
+        }
+        shutdown(writer).await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn splice<C, U>(&self, client: C, upstream: U) -> io::Result<()>
+    where
+        C: Splitable,
+        U: Splitable,
+        C::OwnedRead: AsReadFd,
+        C::OwnedWrite: AsWriteFd + AsyncWriteRent,
+        U::OwnedRead: AsReadFd,
+        U::OwnedWrite: AsWriteFd + AsyncWriteRent,
+    {
+        let (client_read, client_write) = client.into_split();
+        let (upstream_read, upstream_write) = upstream.into_split();
+        let (sent, received) = monoio::join!(
+            self.splice_one(client_read, upstream_write, true),
+            self.splice_one(upstream_read, client_write, false)
+        );
+        sent.and(received)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn splice_one<R, W>(
+        &self,
+        mut reader: R,
+        mut writer: W,
+        upstream_bound: bool,
+    ) -> io::Result<()>
+    where
+        R: AsReadFd,
+        W: AsWriteFd + AsyncWriteRent,
+    {
+        let (mut pipe_read, mut pipe_write) = new_pipe()?;
+        loop {
+            let n = reader.splice_to_pipe(&mut pipe_write, SPLICE_SIZE).await?;
+            if n == 0 {
+                break;
+            }
+            let mut pending = n;
+            while pending > 0 {
+                let written = writer.splice_from_pipe(&mut pipe_read, pending).await?;
+                if written == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                pending -= written;
+            }
+            self.record(upstream_bound, n as u64);
+        }
+        shutdown(writer).await
+    }
+}
+
+/// Propagate the end of one direction, the peer may have closed the connection already.
+async fn shutdown<W: AsyncWriteRent>(mut writer: W) -> io::Result<()> {
+    match writer.shutdown().await {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        result => result,
+    }
+}
+
+/// Factory for creating `TcpProxyService` instances.
+///
+/// Counters of the previous service are carried over when the service is rebuilt.
+pub struct TcpProxyServiceFactory {
+    config: TcpRouteConfig,
+    timeout: TcpProxyTimeout,
+}
+
+impl TcpProxyServiceFactory {
+    fn make(&self, old: Option<&TcpProxyService>) -> Result<TcpProxyService, LoadBalanceError> {
+        let endpoints = LoadBalancer::try_from_upstreams(
+            self.config.load_balancer,
+            self.config.upstreams.clone(),
+        )?;
+        let svc = TcpProxyService::new(endpoints, self.timeout);
+        Ok(match old {
+            Some(old) => svc.with_stats(old.stats.clone()),
+            None => svc,
+        })
+    }
+}
+
+impl MakeService for TcpProxyServiceFactory {
+    type Service = TcpProxyService;
+    type Error = LoadBalanceError;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.make(old)
+    }
+}
+
+impl AsyncMakeService for TcpProxyServiceFactory {
+    type Service = TcpProxyService;
+    type Error = LoadBalanceError;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.make(old)
+    }
+}
+
+/// Upstream servers of the layer 4 proxy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TcpRouteConfig {
+    #[serde(default)]
+    pub load_balancer: LoadBalanceStrategy,
+
+    /// A list of upstream servers connections are forwarded to.
+    pub upstreams: Vec<Upstream>,
+}
+
+const fn default_weight() -> u16 {
+    1
+}
+
+/// Configuration for an upstream server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upstream {
+    /// The endpoint of the upstream server.
+    pub endpoint: Endpoint,
+
+    /// The weight of this upstream for load balancing purposes.
+    #[serde(default = "default_weight")]
+    pub weight: u16,
+}
+
+impl IntoWeightedEndpoint for Upstream {
+    type Endpoint = Endpoint;
+
+    #[inline]
+    fn into_weighted_endpoint(self) -> (Self::Endpoint, u16) {
+        (self.endpoint, self.weight)
+    }
+}
+
+/// Address of an upstream server.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Endpoint {
+    /// A socket address endpoint.
+    Socket(std::net::SocketAddr),
+
+    /// A Unix domain socket endpoint.
+    Unix(std::path::PathBuf),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use monoio::{
+        io::AsyncReadRentExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    fn proxy(upstreams: &[SocketAddr], timeout: TcpProxyTimeout) -> TcpProxyService {
+        let upstreams = upstreams.iter().map(|addr| Upstream {
+            endpoint: Endpoint::Socket(*addr),
+            weight: 1,
+        });
+        let endpoints =
+            LoadBalancer::try_from_upstreams(LoadBalanceStrategy::RoundRobin, upstreams).unwrap();
+        TcpProxyService::new(endpoints, timeout)
+    }
+
+    /// Address nothing listens on.
+    fn closed() -> SocketAddr {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Serve one connection, echoing it when `echo` or else never answering.
+    fn upstream(echo: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        monoio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::with_capacity(BUFFER_SIZE);
+            loop {
+                let (result, read) = conn.read(buf).await;
+                match result {
+                    Ok(n) if n > 0 && echo => {
+                        let (result, written) = conn.write_all(read).await;
+                        result.unwrap();
+                        buf = written;
+                        buf.clear();
+                    }
+                    Ok(n) if n > 0 => buf = read,
+                    _ => break,
+                }
+            }
+        });
+        addr
+    }
+
+    /// A client connection and its accepted end.
+    async fn accepted() -> (TcpStream, TcpStream, AcceptedAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        (client, stream, AcceptedAddr::from(peer))
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn failover() {
+        let live = upstream(true);
+        let service = proxy(&[closed(), live], TcpProxyTimeout::default());
+        let (mut client, stream, peer) = accepted().await;
+        let exchange = async {
+            client.write_all(b"ping".to_vec()).await.0.unwrap();
+            let (result, echoed) = client.read_exact(vec![0; 4]).await;
+            result.unwrap();
+            client.shutdown().await.unwrap();
+            echoed
+        };
+        let (relayed, echoed) = monoio::join!(service.relay(stream, Vec::new(), &peer), exchange);
+        relayed.unwrap();
+        assert_eq!(echoed, b"ping");
+        assert_eq!(service.stats().connections(), 1);
+        assert_eq!(service.stats().sent(), 4);
+
+        // Every upstream is attempted before failing.
+        let last = closed();
+        let service = proxy(&[closed(), last], TcpProxyTimeout::default());
+        let (_client, stream, peer) = accepted().await;
+        match service.relay(stream, Vec::new(), &peer).await {
+            Err(TcpProxyError::Connect(endpoint, _)) => {
+                assert_eq!(endpoint, Endpoint::Socket(last))
+            }
+            result => panic!("unexpected result {result:?}"),
+        }
+        assert_eq!(service.stats().connections(), 0);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn idle_timeout() {
+        let timeout = TcpProxyTimeout {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let service = proxy(&[upstream(false)], timeout);
+        let (mut client, stream, peer) = accepted().await;
+        client.write_all(b"ping".to_vec()).await.0.unwrap();
+        let relay = service.relay(stream, Vec::new(), &peer);
+        monoio::time::timeout(Duration::from_secs(5), relay)
+            .await
+            .expect("idle connection not closed")
+            .unwrap();
+        assert_eq!(service.stats().active(), 0);
+        // The client connection is closed.
+        let (result, _) = client.read(vec![0; 4]).await;
+        assert_eq!(result.unwrap(), 0);
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn cancelled_relay() {
+        let service = proxy(&[upstream(false)], TcpProxyTimeout::default());
+        let (_client, stream, peer) = accepted().await;
+        let relay = service.relay(stream, Vec::new(), &peer);
+        // Dropped mid-relay, as on a worker shutdown.
+        assert!(monoio::time::timeout(Duration::from_millis(100), relay)
+            .await
+            .is_err());
+        assert_eq!(service.stats().connections(), 1);
+        assert_eq!(service.stats().active(), 0);
+    }
+}
@@ -0,0 +1,312 @@
+//! Helpers shared by the tests of the crate: throwaway certificates and files, and loopback stub
+//! servers standing in for upstreams and external services.
+// Not every helper is used with every feature set.
+#![allow(dead_code)]
+use std::{
+    cell::RefCell, convert::Infallible, fmt::Debug, future::Future, net::SocketAddr, path::Path,
+    rc::Rc,
+};
+
+use bytes::Bytes;
+use certain_map::{Attach, Fork};
+use http::{Request, Response};
+use monoio::{
+    io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt},
+    net::TcpListener,
+};
+use monoio_http::common::body::{Body, FixedBody, HttpBody};
+use monolake_core::{
+    context::{PeerAddr, PeerCertificate, RemoteAddr},
+    http::ResponseWithContinue,
+    listener::AcceptedAddr,
+};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use service_async::{ParamMaybeRef, ParamRef, Service};
+
+/// A self-signed CA issuing certificates for the names used by a test.
+pub(crate) struct TestCa {
+    cert: Certificate,
+    pem: String,
+}
+
+impl TestCa {
+    pub(crate) fn new() -> Self {
+        let mut params = CertificateParams::new(Vec::new());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let cert = Certificate::from_params(params).unwrap();
+        let pem = cert.serialize_pem().unwrap();
+        Self { cert, pem }
+    }
+
+    /// PEM encoded certificate of the CA.
+    pub(crate) fn pem(&self) -> &str {
+        &self.pem
+    }
+
+    /// Issue a certificate for `names`, returning its PEM encoded chain and PKCS#8 key.
+    pub(crate) fn issue(&self, names: &[&str]) -> (String, String) {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        self.issue_params(CertificateParams::new(names))
+    }
+
+    /// Issue a certificate with `params`, returning its PEM encoded chain and PKCS#8 key.
+    pub(crate) fn issue_params(&self, params: CertificateParams) -> (String, String) {
+        let cert = Certificate::from_params(params).unwrap();
+        (
+            cert.serialize_pem_with_signer(&self.cert).unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+}
+
+/// Write `contents` to `name` in `dir` and return the path of the file.
+pub(crate) fn write_file(dir: &Path, name: &str, contents: impl AsRef<[u8]>) -> String {
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+/// Collect the body of a response, which the tests keep small.
+pub(crate) async fn body_bytes(mut body: HttpBody) -> Vec<u8> {
+    let mut buf = Vec::new();
+    while let Some(data) = body.next_data().await {
+        buf.extend_from_slice(&data.unwrap());
+    }
+    buf
+}
+
+/// Context of the requests of handler tests, from a client without certificate.
+#[derive(Clone)]
+pub(crate) struct TestCtx {
+    peer: PeerAddr,
+    remote: Option<RemoteAddr>,
+    cert: Option<PeerCertificate>,
+}
+
+impl TestCtx {
+    pub(crate) fn new(peer: &str) -> Self {
+        Self {
+            peer: PeerAddr(AcceptedAddr::from(peer.parse::<SocketAddr>().unwrap())),
+            remote: None,
+            cert: None,
+        }
+    }
+}
+
+impl ParamRef<PeerAddr> for TestCtx {
+    fn param_ref(&self) -> &PeerAddr {
+        &self.peer
+    }
+}
+
+impl ParamMaybeRef<Option<RemoteAddr>> for TestCtx {
+    fn param_maybe_ref(&self) -> Option<&Option<RemoteAddr>> {
+        Some(&self.remote)
+    }
+}
+
+impl ParamMaybeRef<Option<PeerCertificate>> for TestCtx {
+    fn param_maybe_ref(&self) -> Option<&Option<PeerCertificate>> {
+        Some(&self.cert)
+    }
+}
+
+/// Lets core services give every request of a connection a copy of its context.
+impl Fork for TestCtx {
+    type Store = TestCtx;
+    type State = Forked;
+
+    fn fork(&self) -> (TestCtx, Forked) {
+        (self.clone(), Forked)
+    }
+}
+
+pub(crate) struct Forked;
+
+impl Attach<TestCtx> for Forked {
+    type Hdr<'a> = TestCtx;
+
+    unsafe fn attach(self, store: &mut TestCtx) -> TestCtx {
+        store.clone()
+    }
+}
+
+/// Innermost handler of handler tests, answering `200 OK` and keeping the requests it got with
+/// their bodies.
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Rc<RefCell<Vec<Request<Vec<u8>>>>>);
+
+impl Recorder {
+    /// Take the requests handled so far.
+    pub(crate) fn take(&self) -> Vec<Request<Vec<u8>>> {
+        std::mem::take(&mut self.0.borrow_mut())
+    }
+}
+
+impl<CX, B> Service<(Request<B>, CX)> for Recorder
+where
+    B: Body<Data = Bytes>,
+    B::Error: Debug,
+{
+    type Response = ResponseWithContinue<HttpBody>;
+    type Error = Infallible;
+
+    async fn call(&self, (request, _): (Request<B>, CX)) -> Result<Self::Response, Self::Error> {
+        let (parts, mut body) = request.into_parts();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.next_data().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        self.0.borrow_mut().push(Request::from_parts(parts, data));
+        Ok((Response::new(HttpBody::fixed_body(None)), true))
+    }
+}
+
+/// Serve HTTP/1.1 on a loopback port with `handler`, until the runtime of the test stops.
+///
+/// Requests are answered one at a time per connection. Bodies are read with their
+/// `content-length`, or with chunked encoding, and the response always carries a
+/// `content-length`.
+pub(crate) fn stub_http<F, Fut>(handler: F) -> SocketAddr
+where
+    F: Fn(Request<Bytes>) -> Fut + 'static,
+    Fut: Future<Output = Response<Vec<u8>>> + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Rc::new(handler);
+    monoio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            monoio::spawn(serve_http(stream, handler.clone()));
+        }
+    });
+    addr
+}
+
+/// Same as [`stub_http`], behind TLS with the given PEM encoded chain and key.
+#[cfg(feature = "tls")]
+pub(crate) fn stub_https<F, Fut>(chain: &str, key: &str, handler: F) -> SocketAddr
+where
+    F: Fn(Request<Bytes>) -> Fut + 'static,
+    Fut: Future<Output = Response<Vec<u8>>> + 'static,
+{
+    let pem =
+        crate::tls::PemServerConfig::from((chain.as_bytes().to_vec(), key.as_bytes().to_vec()));
+    let crate::tls::TlsConfig::Rustls(config) =
+        crate::tls::TlsConfig::try_from(crate::tls::TlsConfig::Rustls(pem)).unwrap()
+    else {
+        unreachable!()
+    };
+    let acceptor = monoio_rustls::TlsAcceptor::from(std::sync::Arc::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Rc::new(handler);
+    monoio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (acceptor, handler) = (acceptor.clone(), handler.clone());
+            monoio::spawn(async move {
+                if let Ok(stream) = acceptor.accept(stream).await {
+                    serve_http(stream, handler).await;
+                }
+            });
+        }
+    });
+    addr
+}
+
+async fn serve_http<S, F, Fut>(mut stream: S, handler: Rc<F>)
+where
+    S: AsyncReadRent + AsyncWriteRent,
+    F: Fn(Request<Bytes>) -> Fut,
+    Fut: Future<Output = Response<Vec<u8>>>,
+{
+    let mut buf = Vec::new();
+    loop {
+        let Some(req) = read_request(&mut stream, &mut buf).await else {
+            return;
+        };
+        let resp = handler(req).await;
+        let mut out = format!("HTTP/1.1 {}\r\n", resp.status()).into_bytes();
+        for (name, value) in resp.headers() {
+            out.extend_from_slice(name.as_str().as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(format!("content-length: {}\r\n\r\n", resp.body().len()).as_bytes());
+        out.extend_from_slice(resp.body());
+        if stream.write_all(out).await.0.is_err() {
+            return;
+        }
+    }
+}
+
+async fn read_request<S: AsyncReadRent>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> Option<Request<Bytes>> {
+    let (head_len, mut req) = loop {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        if let httparse::Status::Complete(len) = parsed.parse(buf).ok()? {
+            let mut req = Request::builder().method(parsed.method?).uri(parsed.path?);
+            for header in parsed.headers.iter() {
+                req = req.header(header.name, header.value);
+            }
+            break (len, req.body(()).ok()?);
+        }
+        fill(stream, buf).await?;
+    };
+    buf.drain(..head_len);
+    let chunked = req
+        .headers()
+        .get(http::header::TRANSFER_ENCODING)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"chunked"));
+    let body = if chunked {
+        let mut body = Vec::new();
+        loop {
+            let line = loop {
+                if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+                    break pos;
+                }
+                fill(stream, buf).await?;
+            };
+            let size = std::str::from_utf8(&buf[..line]).ok()?;
+            let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+            while buf.len() < line + 2 + size + 2 {
+                fill(stream, buf).await?;
+            }
+            body.extend_from_slice(&buf[line + 2..line + 2 + size]);
+            buf.drain(..line + 2 + size + 2);
+            if size == 0 {
+                break body;
+            }
+        }
+    } else {
+        let len = req
+            .headers()
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .unwrap_or(0);
+        while buf.len() < len {
+            fill(stream, buf).await?;
+        }
+        buf.drain(..len).collect()
+    };
+    req.headers_mut().remove(http::header::TRANSFER_ENCODING);
+    Some(req.map(|_| Bytes::from(body)))
+}
+
+async fn fill<S: AsyncReadRent>(stream: &mut S, buf: &mut Vec<u8>) -> Option<()> {
+    let (res, chunk) = stream.read(Vec::with_capacity(4096)).await;
+    match res {
+        Ok(n) if n > 0 => {
+            buf.extend_from_slice(&chunk[..n]);
+            Some(())
+        }
+        _ => None,
+    }
+}
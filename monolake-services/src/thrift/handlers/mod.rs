@@ -1,3 +1,5 @@
 //! Thrift specific handlers
 pub mod proxy;
 pub use proxy::ProxyHandler;
+pub mod rate_limit;
+pub use rate_limit::RateLimitHandler;
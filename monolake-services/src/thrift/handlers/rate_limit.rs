@@ -0,0 +1,312 @@
+//! Local rate limiting of Thrift requests.
+//!
+//! [`RateLimitHandler`] checks every request against the rate limit of its server, see
+//! [`rate_limit`](crate::common::rate_limit) for the budgets and their keys. Header keys are
+//! looked up in the string headers of the TTHeader.
+//!
+//! Requests exceeding the limit are not forwarded: they are answered with a
+//! `TApplicationException` carrying the name and sequence id of the request, encoded with the
+//! protocol of the request. Requests whose message header can not be read with the binary or
+//! compact protocols fail, which closes their connection.
+//!
+//! # Usage
+//!
+//! ```ignore
+//! let stacks = FactoryStack::new(config)
+//!     .replace(TProxyHandler::factory(proxy_config))
+//!     .push(RateLimitHandler::layer())
+//!     .push(TtheaderCoreService::layer());
+//! ```
+use std::io;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use monoio_thrift::codec::ttheader::{ProtocolId, TTHeader, TTHeaderPayload};
+use monolake_core::{
+    context::{PeerAddr, RemoteAddr},
+    thrift::{ThriftBody, ThriftHandler, ThriftRequest, ThriftResponse},
+};
+use service_async::{
+    layer::{layer_fn, FactoryLayer},
+    AsyncMakeService, MakeService, Param, ParamMaybeRef, ParamRef, Service,
+};
+use tracing::debug;
+
+use crate::common::rate_limit::{BucketKey, RateLimit, RateLimitConfig, RateLimitKey, RateLimiter};
+
+const MESSAGE_EXCEPTION: u8 = 3;
+const BINARY_VERSION_1: u32 = 0x8001_0000;
+const COMPACT_PROTOCOL_ID: u8 = 0x82;
+
+/// Handler enforcing the rate limit of a Thrift server.
+///
+/// See the [module level documentation](crate::thrift::handlers::rate_limit) for details.
+#[derive(Clone)]
+pub struct RateLimitHandler<H> {
+    inner: H,
+    limiter: Option<RateLimiter>,
+}
+
+impl<H, CX> Service<(ThriftRequest<ThriftBody>, CX)> for RateLimitHandler<H>
+where
+    H: ThriftHandler<CX>,
+    H::Error: From<io::Error>,
+    CX: ParamRef<PeerAddr> + ParamMaybeRef<Option<RemoteAddr>>,
+{
+    type Response = ThriftResponse<ThriftBody>;
+    type Error = H::Error;
+
+    async fn call(
+        &self,
+        (request, ctx): (ThriftRequest<ThriftBody>, CX),
+    ) -> Result<Self::Response, Self::Error> {
+        let Some(limiter) = &self.limiter else {
+            return self.inner.handle(request, ctx).await;
+        };
+        let key = match &limiter.config().key {
+            RateLimitKey::Route => Some(BucketKey::All),
+            RateLimitKey::ClientIp => Some(BucketKey::client(&ctx)),
+            RateLimitKey::Header(name) => request
+                .ttheader
+                .str_headers
+                .get(name.as_str())
+                .map(|value| BucketKey::Value(value.as_bytes().into())),
+        };
+        let decision = match key {
+            Some(key) => limiter.check(&key),
+            None => return self.inner.handle(request, ctx).await,
+        };
+        match decision.retry_after {
+            None => self.inner.handle(request, ctx).await,
+            Some(retry_after) => {
+                debug!("thrift request rate limited");
+                let message = format!(
+                    "rate limited, retry after {}ms",
+                    retry_after.as_millis().max(1)
+                );
+                Ok(exception_response(&request, &message)?)
+            }
+        }
+    }
+}
+
+/// Build the `TApplicationException` reply of a request.
+fn exception_response(
+    request: &ThriftRequest<ThriftBody>,
+    message: &str,
+) -> io::Result<ThriftResponse<ThriftBody>> {
+    let payload = request.payload.as_deref().unwrap_or_default();
+    let protocol = request.ttheader.protocol_id;
+    let payload = match protocol {
+        ProtocolId::Binary => {
+            let (name, seq_id) = read_binary_header(payload)?;
+            write_binary_exception(name, seq_id, message)
+        }
+        ProtocolId::Compact | ProtocolId::CompactV2 => {
+            let (version, name, seq_id) = read_compact_header(payload)?;
+            write_compact_exception(version, name, seq_id, message)
+        }
+        ProtocolId::Protobuf => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "rate limited protobuf request",
+            ))
+        }
+    };
+    Ok(TTHeaderPayload {
+        ttheader: TTHeader {
+            seq_id: request.ttheader.seq_id,
+            flags: request.ttheader.flags,
+            protocol_id: protocol,
+            ..Default::default()
+        },
+        payload: Some(payload),
+    })
+}
+
+fn invalid_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid thrift message header")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid_header());
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn varint(&mut self) -> io::Result<u32> {
+        let mut value = 0u32;
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid_header())
+    }
+}
+
+/// Read the name and sequence id of a binary protocol message, strict or not.
+fn read_binary_header(payload: &[u8]) -> io::Result<(&[u8], i32)> {
+    let mut reader = Reader(payload);
+    let first = reader.i32()?;
+    if first < 0 {
+        if first as u32 & 0xffff_0000 != BINARY_VERSION_1 {
+            return Err(invalid_header());
+        }
+        let len = reader.i32()?;
+        let name = reader.take(usize::try_from(len).map_err(|_| invalid_header())?)?;
+        Ok((name, reader.i32()?))
+    } else {
+        let name = reader.take(first as usize)?;
+        reader.u8()?;
+        Ok((name, reader.i32()?))
+    }
+}
+
+/// Read the version, name and sequence id of a compact protocol message.
+fn read_compact_header(payload: &[u8]) -> io::Result<(u8, &[u8], i32)> {
+    let mut reader = Reader(payload);
+    if reader.u8()? != COMPACT_PROTOCOL_ID {
+        return Err(invalid_header());
+    }
+    let version = reader.u8()? & 0x1f;
+    let seq_id = reader.varint()? as i32;
+    let len = reader.varint()?;
+    let name = reader.take(len as usize)?;
+    Ok((version, name, seq_id))
+}
+
+fn write_binary_exception(name: &[u8], seq_id: i32, message: &str) -> Bytes {
+    let mut buf = BytesMut::with_capacity(32 + name.len() + message.len());
+    buf.put_u32(BINARY_VERSION_1 | MESSAGE_EXCEPTION as u32);
+    buf.put_i32(name.len() as i32);
+    buf.put_slice(name);
+    buf.put_i32(seq_id);
+    // Field 1: message, a string.
+    buf.put_u8(11);
+    buf.put_i16(1);
+    buf.put_i32(message.len() as i32);
+    buf.put_slice(message.as_bytes());
+    // Field 2: type, an i32, UNKNOWN.
+    buf.put_u8(8);
+    buf.put_i16(2);
+    buf.put_i32(0);
+    buf.put_u8(0);
+    buf.freeze()
+}
+
+fn write_compact_exception(version: u8, name: &[u8], seq_id: i32, message: &str) -> Bytes {
+    fn put_varint(buf: &mut BytesMut, mut value: u32) {
+        while value >= 0x80 {
+            buf.put_u8(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.put_u8(value as u8);
+    }
+
+    let mut buf = BytesMut::with_capacity(24 + name.len() + message.len());
+    buf.put_u8(COMPACT_PROTOCOL_ID);
+    buf.put_u8(version | MESSAGE_EXCEPTION << 5);
+    put_varint(&mut buf, seq_id as u32);
+    put_varint(&mut buf, name.len() as u32);
+    buf.put_slice(name);
+    // Field 1: message, a binary field with a delta of 1.
+    buf.put_u8(0x18);
+    put_varint(&mut buf, message.len() as u32);
+    buf.put_slice(message.as_bytes());
+    // Field 2: type, an i32 field with a delta of 1, zigzag encoded UNKNOWN.
+    buf.put_u8(0x15);
+    put_varint(&mut buf, 0);
+    buf.put_u8(0);
+    buf.freeze()
+}
+
+/// Factory for [`RateLimitHandler`], holding the limit shared by the workers of a server.
+pub struct RateLimitHandlerFactory<F> {
+    inner: F,
+    limit: Option<RateLimit>,
+}
+
+impl<F> RateLimitHandlerFactory<F> {
+    fn make_limiter<H>(&self, old: Option<&RateLimitHandler<H>>) -> Option<RateLimiter> {
+        self.limit
+            .as_ref()
+            .map(|limit| limit.limiter(old.and_then(|o| o.limiter.as_ref())))
+    }
+}
+
+impl<F: MakeService> MakeService for RateLimitHandlerFactory<F> {
+    type Service = RateLimitHandler<F::Service>;
+    type Error = F::Error;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        Ok(RateLimitHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
+            limiter: self.make_limiter(old),
+        })
+    }
+}
+
+impl<F: AsyncMakeService> AsyncMakeService for RateLimitHandlerFactory<F> {
+    type Service = RateLimitHandler<F::Service>;
+    type Error = F::Error;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        Ok(RateLimitHandler {
+            inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
+            limiter: self.make_limiter(old),
+        })
+    }
+}
+
+impl<F> RateLimitHandler<F> {
+    pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = RateLimitHandlerFactory<F>>
+    where
+        C: Param<Option<RateLimitConfig>>,
+    {
+        layer_fn(|c: &C, inner| RateLimitHandlerFactory {
+            inner,
+            limit: c.param().map(RateLimit::new),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exception_keeps_message_header() {
+        let mut request = BytesMut::new();
+        request.put_u32(BINARY_VERSION_1 | 1);
+        request.put_i32(4);
+        request.put_slice(b"ping");
+        request.put_i32(7);
+        let reply = write_binary_exception(b"ping", 7, "rate limited");
+        assert_eq!(read_binary_header(&request).unwrap(), (&b"ping"[..], 7));
+        assert_eq!(read_binary_header(&reply).unwrap(), (&b"ping"[..], 7));
+        assert_eq!(reply[3], MESSAGE_EXCEPTION);
+
+        let reply = write_compact_exception(1, b"ping", 300, "rate limited");
+        assert_eq!(read_compact_header(&reply).unwrap(), (1, &b"ping"[..], 300));
+        assert_eq!(reply[1] >> 5, MESSAGE_EXCEPTION);
+    }
+}
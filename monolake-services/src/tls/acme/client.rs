@@ -0,0 +1,370 @@
+//! Minimal ACME (RFC 8555) client used by the renewal task.
+use std::{io::Cursor, path::Path, time::Duration};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http::{header, Request, Response};
+use monoio_http::common::body::{FixedBody, HttpBody};
+use rcgen::{
+    Certificate, CertificateParams, CustomExtension, DistinguishedName, KeyPair,
+    PKCS_ECDSA_P256_SHA256,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls_client::{
+    pki_types::{CertificateDer, Der, TrustAnchor},
+    ClientConfig, RootCertStore,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use super::{challenges, AcmeChallengeType, AcmeConfig};
+use crate::http::client::HttpClient;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+const ACCOUNT_KEY_FILE: &str = "account.key";
+
+/// PEM encoded certificate chain and private key of an issued certificate.
+pub(super) struct Issued {
+    pub(super) chain: String,
+    pub(super) key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+fn response_header<'a>(response: &'a Response<Bytes>, name: &str) -> Option<&'a str> {
+    response.headers().get(name)?.to_str().ok()
+}
+
+fn json<T: for<'de> Deserialize<'de>>(response: &Response<Bytes>) -> anyhow::Result<T> {
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+/// TLS settings for the ACME server: the webpki roots, plus `ca` when given.
+pub(super) fn tls_config(ca: Option<&[u8]>) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| TrustAnchor {
+        subject: Der::from_slice(ta.subject),
+        subject_public_key_info: Der::from_slice(ta.spki),
+        name_constraints: ta.name_constraints.map(Der::from_slice),
+    }));
+    if let Some(ca) = ca {
+        for cert in rustls_pemfile::certs(&mut Cursor::new(ca))? {
+            roots.add(CertificateDer::from(cert))?;
+        }
+    }
+    Ok(ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Run a complete order for `config.domains` and return the issued certificate.
+pub(super) async fn issue(config: &AcmeConfig) -> anyhow::Result<Issued> {
+    let http = HttpClient::with_tls(IO_TIMEOUT, tls_config(config.ca.as_deref())?);
+    let directory: Directory = json(&http.send(get(&config.directory)?).await?)?;
+    let mut account = Account::load_or_create(&config.storage, &http, directory.new_nonce)?;
+    account
+        .register(&directory.new_account, &config.contact)
+        .await?;
+
+    let identifiers = config
+        .domains
+        .iter()
+        .map(|domain| json!({ "type": "dns", "value": domain }))
+        .collect::<Vec<_>>();
+    let response = account
+        .post(
+            &directory.new_order,
+            Some(&json!({ "identifiers": identifiers })),
+        )
+        .await?;
+    let order_url = response_header(&response, "location")
+        .ok_or_else(|| anyhow::anyhow!("acme order without location"))?
+        .to_string();
+    let order: Order = json(&response)?;
+
+    for authorization in order.authorizations.iter() {
+        account.authorize(authorization, config.challenge).await?;
+    }
+    account
+        .poll(&order_url, |order: &Order| match order.status.as_str() {
+            "ready" | "processing" | "valid" => Ok(true),
+            "pending" => Ok(false),
+            status => anyhow::bail!("acme order is {status}"),
+        })
+        .await?;
+
+    let key = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+    let key_pem = key.serialize_pem();
+    let mut params = CertificateParams::new(config.domains.clone());
+    params.distinguished_name = DistinguishedName::new();
+    params.key_pair = Some(key);
+    let csr = Certificate::from_params(params)?.serialize_request_der()?;
+    account
+        .post(
+            &order.finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )
+        .await?;
+
+    let order: Order = account
+        .poll(&order_url, |order: &Order| match order.status.as_str() {
+            "valid" => Ok(true),
+            "invalid" => anyhow::bail!("acme order became invalid"),
+            _ => Ok(false),
+        })
+        .await?;
+    let certificate = order
+        .certificate
+        .ok_or_else(|| anyhow::anyhow!("acme order without certificate"))?;
+    let chain = String::from_utf8(account.post(&certificate, None).await?.into_body().to_vec())?;
+    Ok(Issued {
+        chain,
+        key: key_pem,
+    })
+}
+
+struct Account<'a> {
+    http: &'a HttpClient,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+    new_nonce: String,
+}
+
+impl<'a> Account<'a> {
+    fn load_or_create(
+        storage: &Path,
+        http: &'a HttpClient,
+        new_nonce: String,
+    ) -> anyhow::Result<Self> {
+        let path = storage.join(ACCOUNT_KEY_FILE);
+        let key = match std::fs::read_to_string(&path) {
+            Ok(pem) => KeyPair::from_pem(&pem)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = KeyPair::generate(&PKCS_ECDSA_P256_SHA256)?;
+                std::fs::create_dir_all(storage)?;
+                super::write_private(&path, key.serialize_pem().as_bytes())?;
+                key
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let rng = SystemRandom::new();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key.serialize_der(), &rng)
+                .map_err(|e| anyhow::anyhow!("invalid acme account key: {e}"))?;
+
+        // Uncompressed point: 0x04 || x || y
+        let point = key.public_key().as_ref();
+        let (x, y) = point[1..].split_at(32);
+        let (x, y) = (URL_SAFE_NO_PAD.encode(x), URL_SAFE_NO_PAD.encode(y));
+        // RFC 7638: members in lexicographic order, no whitespace.
+        let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint = URL_SAFE_NO_PAD.encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            thumbprint.as_bytes(),
+        ));
+        Ok(Self {
+            http,
+            key,
+            rng,
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            thumbprint,
+            kid: None,
+            nonce: None,
+            new_nonce,
+        })
+    }
+
+    async fn register(&mut self, url: &str, contact: &[String]) -> anyhow::Result<()> {
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": contact });
+        let response = self.post(url, Some(&payload)).await?;
+        self.kid = Some(
+            response_header(&response, "location")
+                .ok_or_else(|| anyhow::anyhow!("acme account without location"))?
+                .to_string(),
+        );
+        Ok(())
+    }
+
+    async fn authorize(&mut self, url: &str, kind: AcmeChallengeType) -> anyhow::Result<()> {
+        let authorization: Authorization = json(&self.post(url, None).await?)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let name = match kind {
+            AcmeChallengeType::Http01 => "http-01",
+            AcmeChallengeType::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == name)
+            .ok_or_else(|| anyhow::anyhow!("acme server offers no {name} challenge"))?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint);
+        let domain = authorization.identifier.value;
+
+        let _guard = match kind {
+            AcmeChallengeType::Http01 => {
+                challenges().set_http01(&challenge.token, key_authorization)
+            }
+            AcmeChallengeType::TlsAlpn01 => {
+                challenges().set_tls_alpn01(&domain, tls_alpn01_cert(&domain, &key_authorization)?)
+            }
+        };
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        self.poll(url, |authorization: &Authorization| {
+            match authorization.status.as_str() {
+                "valid" => Ok(true),
+                "pending" | "processing" => Ok(false),
+                status => anyhow::bail!("acme authorization for {domain} is {status}"),
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn poll<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        url: &str,
+        done: impl Fn(&T) -> anyhow::Result<bool>,
+    ) -> anyhow::Result<T> {
+        for _ in 0..POLL_ATTEMPTS {
+            let value: T = json(&self.post(url, None).await?)?;
+            if done(&value)? {
+                return Ok(value);
+            }
+            monoio::time::sleep(POLL_INTERVAL).await;
+        }
+        anyhow::bail!("timed out waiting for acme server")
+    }
+
+    /// Signed POST, or POST-as-GET when `payload` is `None`. A rejected nonce is retried once.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> anyhow::Result<Response<Bytes>> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                // GET rather than HEAD, which every server supports as well (RFC 8555 7.2).
+                None => response_header(
+                    &self.http.send(get(&self.new_nonce)?).await?,
+                    "replay-nonce",
+                )
+                .ok_or_else(|| anyhow::anyhow!("acme server sent no nonce"))?
+                .to_string(),
+            };
+            let body = self.sign(url, nonce, payload)?;
+            let request = Request::post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .header(header::USER_AGENT, "monolake")
+                .body(HttpBody::fixed_body(Some(body.into())))?;
+            let response = self.http.send(request).await?;
+            self.nonce = response_header(&response, "replay-nonce").map(str::to_string);
+            if !(response.status().is_client_error() || response.status().is_server_error()) {
+                return Ok(response);
+            }
+            let problem: Value = json(&response).unwrap_or_default();
+            if !retried && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+            anyhow::bail!(
+                "acme request to {url} failed with status {}: {problem}",
+                response.status()
+            );
+        }
+    }
+
+    fn sign(&self, url: &str, nonce: String, payload: Option<&Value>) -> anyhow::Result<Vec<u8>> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(payload.to_string()),
+            None => String::new(),
+        };
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|_| anyhow::anyhow!("failed to sign acme request"))?;
+        let body = json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature),
+        });
+        Ok(serde_json::to_vec(&body)?)
+    }
+}
+
+fn get(url: &str) -> anyhow::Result<Request<HttpBody>> {
+    Ok(Request::get(url)
+        .header(header::USER_AGENT, "monolake")
+        .body(HttpBody::fixed_body(None))?)
+}
+
+/// Self signed certificate answering a TLS-ALPN-01 challenge (RFC 8737).
+fn tls_alpn01_cert(
+    domain: &str,
+    key_authorization: &str,
+) -> anyhow::Result<rustls::sign::CertifiedKey> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+    let mut params = CertificateParams::new(vec![domain.to_string()]);
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+    let cert = Certificate::from_params(params)?;
+    let key = rustls::PrivateKey(cert.serialize_private_key_der());
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| anyhow::anyhow!("unsupported challenge key"))?;
+    Ok(rustls::sign::CertifiedKey::new(
+        vec![rustls::Certificate(cert.serialize_der()?)],
+        key,
+    ))
+}
@@ -0,0 +1,501 @@
+//! Automatic certificate provisioning through ACME (RFC 8555).
+//!
+//! A listener configured with [`AcmeConfig`] serves its certificate through a resolver that is
+//! shared by all workers, and by the listeners of later config loads with the same domains. A
+//! single task on the TLS background thread loads the certificate stored on disk, orders a new one
+//! when it is missing or about to expire, and swaps it into the resolver, so running
+//! [`UnifiedTlsService`](super::UnifiedTlsService)s pick it up without a config reload.
+//!
+//! Challenges are answered from a process wide store:
+//! - HTTP-01 by [`AcmeChallengeHandler`](crate::http::handlers::AcmeChallengeHandler) on the plain
+//!   HTTP listeners.
+//! - TLS-ALPN-01 by the rustls acceptor of the listener itself, which presents the challenge
+//!   certificate to clients negotiating the `acme-tls/1` protocol.
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock, PoisonError, RwLock, Weak},
+    time::{Duration, Instant, SystemTime},
+};
+
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+use super::AcmeChallengeType;
+
+mod client;
+
+/// ALPN protocol identifying TLS-ALPN-01 validation handshakes.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+const DEFAULT_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const MIN_RETRY: Duration = Duration::from_secs(60);
+/// How often the renewal task checks whether its resolver was dropped or reconfigured.
+const TICK: Duration = Duration::from_secs(1);
+
+/// ACME settings of a rustls listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcmeConfig {
+    /// Directory url of the ACME server.
+    pub directory: String,
+    /// Domains of the certificate; the first one names the files in `storage`.
+    pub domains: Vec<String>,
+    /// Account contact urls, e.g. `mailto:admin@example.com`.
+    pub contact: Vec<String>,
+    /// Directory holding the account key and the issued certificates.
+    pub storage: PathBuf,
+    pub challenge: AcmeChallengeType,
+    /// PEM encoded CA bundle trusted for the ACME server in addition to the webpki roots, e.g.
+    /// the root of a Pebble test server.
+    pub ca: Option<Vec<u8>>,
+    /// Renew the certificate once it expires within this duration.
+    pub renew_before: Duration,
+}
+
+impl AcmeConfig {
+    pub fn new(directory: String, domains: Vec<String>, storage: PathBuf) -> Self {
+        Self {
+            directory,
+            domains,
+            contact: Vec::new(),
+            storage,
+            challenge: AcmeChallengeType::default(),
+            ca: None,
+            renew_before: DEFAULT_RENEW_BEFORE,
+        }
+    }
+
+    fn cert_path(&self) -> PathBuf {
+        self.storage.join(format!("{}.crt", self.domains[0]))
+    }
+
+    fn key_path(&self) -> PathBuf {
+        self.storage.join(format!("{}.key", self.domains[0]))
+    }
+}
+
+/// Pending challenge responses, shared by all listeners and workers.
+#[derive(Default)]
+pub struct AcmeChallenges {
+    http01: RwLock<HashMap<String, String>>,
+    tls_alpn01: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+/// Process wide challenge store.
+pub fn challenges() -> &'static AcmeChallenges {
+    static CHALLENGES: OnceLock<AcmeChallenges> = OnceLock::new();
+    CHALLENGES.get_or_init(AcmeChallenges::default)
+}
+
+impl AcmeChallenges {
+    /// Key authorization to serve for an HTTP-01 `token`.
+    pub fn http01(&self, token: &str) -> Option<String> {
+        self.http01.read().ok()?.get(token).cloned()
+    }
+
+    fn tls_alpn01(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.tls_alpn01.read().ok()?.get(domain).cloned()
+    }
+
+    fn set_http01(&self, token: &str, key_authorization: String) -> ChallengeGuard {
+        if let Ok(mut http01) = self.http01.write() {
+            http01.insert(token.to_string(), key_authorization);
+        }
+        ChallengeGuard::Http01(token.to_string())
+    }
+
+    fn set_tls_alpn01(&self, domain: &str, cert: CertifiedKey) -> ChallengeGuard {
+        if let Ok(mut tls_alpn01) = self.tls_alpn01.write() {
+            tls_alpn01.insert(domain.to_string(), Arc::new(cert));
+        }
+        ChallengeGuard::TlsAlpn01(domain.to_string())
+    }
+}
+
+/// Removes a challenge response once the authorization is done.
+enum ChallengeGuard {
+    Http01(String),
+    TlsAlpn01(String),
+}
+
+impl Drop for ChallengeGuard {
+    fn drop(&mut self) {
+        match self {
+            ChallengeGuard::Http01(token) => {
+                if let Ok(mut http01) = challenges().http01.write() {
+                    http01.remove(token);
+                }
+            }
+            ChallengeGuard::TlsAlpn01(domain) => {
+                if let Ok(mut tls_alpn01) = challenges().tls_alpn01.write() {
+                    tls_alpn01.remove(domain);
+                }
+            }
+        }
+    }
+}
+
+/// Certificate resolver of an ACME managed listener.
+///
+/// Resolvers are shared by the listeners with the same set of domains, across config reloads, so
+/// a single renewal task orders their certificate.
+pub(crate) struct AcmeResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    config: RwLock<AcmeConfig>,
+}
+
+impl ResolvesServerCert for AcmeResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let validation = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if validation {
+            return challenges().tls_alpn01(client_hello.server_name()?);
+        }
+        self.current.read().ok()?.clone()
+    }
+}
+
+/// Live resolvers, at most one per set of domains.
+static RESOLVERS: Mutex<Vec<Weak<AcmeResolver>>> = Mutex::new(Vec::new());
+
+impl AcmeResolver {
+    /// Resolver for `config.domains`.
+    ///
+    /// A live resolver for the same domains is reused and switched to `config`. Otherwise the
+    /// stored certificate is loaded and a renewal task is started on the
+    /// [`background`](super::background) thread, which ends once the resolver is dropped.
+    pub(crate) fn new(config: AcmeConfig) -> anyhow::Result<Arc<Self>> {
+        if config.domains.is_empty() {
+            anyhow::bail!("acme requires at least one domain");
+        }
+        client::tls_config(config.ca.as_deref())?;
+        let stored = match load(&config) {
+            Ok((key, _)) => Some(Arc::new(key)),
+            Err(e) => {
+                tracing::info!("no usable acme certificate for {:?}: {e}", config.domains);
+                None
+            }
+        };
+
+        let mut resolvers = RESOLVERS.lock().unwrap_or_else(PoisonError::into_inner);
+        resolvers.retain(|resolver| resolver.strong_count() > 0);
+        let shared = resolvers
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|resolver| same_domains(&resolver.config().domains, &config.domains));
+        if let Some(resolver) = shared {
+            if stored.is_some() {
+                *resolver
+                    .current
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = stored;
+            }
+            *resolver
+                .config
+                .write()
+                .unwrap_or_else(PoisonError::into_inner) = config;
+            return Ok(resolver);
+        }
+
+        let resolver = Arc::new(Self {
+            current: RwLock::new(stored),
+            config: RwLock::new(config),
+        });
+        resolvers.push(Arc::downgrade(&resolver));
+        let weak = Arc::downgrade(&resolver);
+        super::background::spawn(move || renew_loop(weak));
+        Ok(resolver)
+    }
+
+    fn config(&self) -> AcmeConfig {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set(&self, key: CertifiedKey) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(key));
+    }
+}
+
+fn same_domains(a: &[String], b: &[String]) -> bool {
+    let set = |domains: &[String]| {
+        domains
+            .iter()
+            .map(|domain| domain.to_ascii_lowercase())
+            .collect::<BTreeSet<_>>()
+    };
+    set(a) == set(b)
+}
+
+/// Time until the certificate expiring at `not_after` has to be renewed, `None` once due.
+fn renew_in(not_after: SystemTime, renew_before: Duration) -> Option<Duration> {
+    not_after
+        .checked_sub(renew_before)?
+        .duration_since(SystemTime::now())
+        .ok()
+}
+
+/// Renew the certificate of `resolver` until it is dropped.
+///
+/// The schedule is recomputed whenever a reload switches the resolver to another config.
+async fn renew_loop(resolver: Weak<AcmeResolver>) {
+    let mut retry = MIN_RETRY;
+    let mut scheduled: Option<(AcmeConfig, Instant)> = None;
+    loop {
+        let Some(config) = resolver.upgrade().map(|resolver| resolver.config()) else {
+            return;
+        };
+        match &scheduled {
+            Some((current, due)) if *current == config && *due > Instant::now() => {
+                monoio::time::sleep(TICK).await;
+                continue;
+            }
+            Some((current, _)) if *current != config => retry = MIN_RETRY,
+            _ => {}
+        }
+
+        let stored = load(&config).ok().map(|(_, not_after)| not_after);
+        let wait = match stored.and_then(|not_after| renew_in(not_after, config.renew_before)) {
+            Some(wait) => wait.min(CHECK_INTERVAL),
+            // Missing, unreadable or expiring certificate.
+            None => match order(&config).await {
+                Ok((key, not_after)) => {
+                    tracing::info!("acme certificate issued for {:?}", config.domains);
+                    match resolver.upgrade() {
+                        Some(resolver) => resolver.set(key),
+                        None => return,
+                    }
+                    retry = MIN_RETRY;
+                    renew_in(not_after, config.renew_before).unwrap_or_else(|| {
+                        tracing::warn!(
+                            "acme certificate for {:?} expires within renew_before",
+                            config.domains
+                        );
+                        CHECK_INTERVAL
+                    })
+                }
+                Err(e) => {
+                    tracing::warn!("acme order for {:?} failed: {e:?}", config.domains);
+                    let wait = retry;
+                    retry = (retry * 2).min(CHECK_INTERVAL);
+                    wait
+                }
+            },
+        };
+        scheduled = Some((config, Instant::now() + wait.min(CHECK_INTERVAL)));
+    }
+}
+
+async fn order(config: &AcmeConfig) -> anyhow::Result<(CertifiedKey, SystemTime)> {
+    let issued = client::issue(config).await?;
+    std::fs::create_dir_all(&config.storage)?;
+    write_private(&config.key_path(), issued.key.as_bytes())?;
+    std::fs::write(config.cert_path(), issued.chain.as_bytes())?;
+    load(config)
+}
+
+/// Load the stored certificate together with its expiry.
+fn load(config: &AcmeConfig) -> anyhow::Result<(CertifiedKey, SystemTime)> {
+    let chain = std::fs::read(config.cert_path())?;
+    let key = std::fs::read(config.key_path())?;
+    let chain = rustls_pemfile::certs(&mut chain.as_slice())?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_slice())?
+        .pop()
+        .map(rustls::PrivateKey)
+        .ok_or_else(|| anyhow::anyhow!("empty acme key file"))?;
+    let leaf = chain
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("empty acme cert file"))?;
+    let (_, leaf) = X509Certificate::from_der(&leaf.0)?;
+    let not_after = SystemTime::UNIX_EPOCH
+        + Duration::from_secs(leaf.validity().not_after.timestamp().try_into()?);
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| anyhow::anyhow!("unsupported acme key"))?;
+    Ok((CertifiedKey::new(chain, key), not_after))
+}
+
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, os::unix::fs::PermissionsExt, rc::Rc};
+
+    use http::{Request, Response};
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{stub_http, TestCa};
+
+    #[derive(Default)]
+    struct Stub {
+        orders: usize,
+        key_authorization: Option<String>,
+        finalized: bool,
+    }
+
+    /// ACME server validating every challenge and issuing `chain` for every order.
+    fn stub_acme(chain: String, state: Rc<RefCell<Stub>>) -> std::net::SocketAddr {
+        stub_http(move |request: Request<bytes::Bytes>| {
+            let (chain, state) = (chain.clone(), state.clone());
+            async move {
+                let base = format!("http://{}", request.headers()["host"].to_str().unwrap());
+                let mut state = state.borrow_mut();
+                let order = |state: &Stub| {
+                    json!({
+                        "status": if state.finalized { "valid" } else { "ready" },
+                        "authorizations": [format!("{base}/authz/1")],
+                        "finalize": format!("{base}/finalize/1"),
+                        "certificate": format!("{base}/cert/1"),
+                    })
+                };
+                let (location, body) = match request.uri().path() {
+                    "/directory" => (
+                        None,
+                        json!({
+                            "newNonce": format!("{base}/nonce"),
+                            "newAccount": format!("{base}/account"),
+                            "newOrder": format!("{base}/order"),
+                        })
+                        .to_string(),
+                    ),
+                    "/nonce" => (None, String::new()),
+                    "/account" => (Some(format!("{base}/account/1")), "{}".to_string()),
+                    "/order" => {
+                        state.orders += 1;
+                        state.finalized = false;
+                        let mut order = order(&state);
+                        order["status"] = json!("pending");
+                        (Some(format!("{base}/order/1")), order.to_string())
+                    }
+                    "/authz/1" => {
+                        let status = match state.key_authorization {
+                            Some(_) => "valid",
+                            None => "pending",
+                        };
+                        let authorization = json!({
+                            "status": status,
+                            "identifier": { "value": "example.test" },
+                            "challenges": [{
+                                "type": "http-01",
+                                "url": format!("{base}/challenge/1"),
+                                "token": "acme-test-token",
+                            }],
+                        });
+                        (None, authorization.to_string())
+                    }
+                    "/challenge/1" => {
+                        state.key_authorization = challenges().http01("acme-test-token");
+                        (None, "{}".to_string())
+                    }
+                    "/finalize/1" => {
+                        state.finalized = true;
+                        (None, order(&state).to_string())
+                    }
+                    "/order/1" => (None, order(&state).to_string()),
+                    "/cert/1" => (None, chain),
+                    _ => {
+                        return Response::builder()
+                            .status(404)
+                            .header("replay-nonce", "nonce")
+                            .body(Vec::new())
+                            .unwrap()
+                    }
+                };
+                let mut response = Response::builder().header("replay-nonce", "nonce");
+                if let Some(location) = location {
+                    response = response.status(201).header("location", location);
+                }
+                response.body(body.into_bytes()).unwrap()
+            }
+        })
+    }
+
+    async fn issued(resolver: &AcmeResolver) -> Option<Arc<CertifiedKey>> {
+        for _ in 0..200 {
+            if let Some(key) = resolver.current.read().unwrap().clone() {
+                return Some(key);
+            }
+            monoio::time::sleep(Duration::from_millis(50)).await;
+        }
+        None
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn shared_renewal() {
+        let (chain, _) = TestCa::new().issue(&["example.test"]);
+        let state = Rc::new(RefCell::new(Stub::default()));
+        let addr = stub_acme(chain.clone(), state.clone());
+        let storage = tempfile::tempdir().unwrap();
+        let mut config = AcmeConfig::new(
+            format!("http://{addr}/directory"),
+            vec!["example.test".to_string()],
+            storage.path().to_path_buf(),
+        );
+
+        // A reload with the same domains shares the resolver and its single renewal task.
+        let first = AcmeResolver::new(config.clone()).unwrap();
+        config.contact = vec!["mailto:admin@example.test".to_string()];
+        let second = AcmeResolver::new(config.clone()).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(first.config(), config);
+
+        let key = issued(&first).await.expect("certificate not issued");
+        let der = rustls_pemfile::certs(&mut chain.as_bytes()).unwrap();
+        assert_eq!(key.cert[0].0, der[0]);
+        assert_eq!(state.borrow().orders, 1);
+        let key_authorization = state.borrow().key_authorization.clone().unwrap();
+        assert!(key_authorization.starts_with("acme-test-token."));
+        assert_eq!(challenges().http01("acme-test-token"), None);
+        assert_eq!(std::fs::read_to_string(config.cert_path()).unwrap(), chain);
+        let mode = std::fs::metadata(config.key_path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Once dropped, a new resolver starts from the stored certificate without ordering.
+        drop((first, second));
+        let third = AcmeResolver::new(config).unwrap();
+        assert!(third.current.read().unwrap().is_some());
+        monoio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(state.borrow().orders, 1);
+    }
+
+    #[test]
+    fn domain_sets() {
+        let domains = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(same_domains(
+            &domains(&["a.test", "b.test"]),
+            &domains(&["B.test", "a.test"])
+        ));
+        assert!(!same_domains(
+            &domains(&["a.test"]),
+            &domains(&["a.test", "b.test"])
+        ));
+    }
+}
@@ -0,0 +1,42 @@
+//! Thread running the background tasks of TLS listeners.
+//!
+//! Certificate resolvers are built once per config load and shared by all workers, so the tasks
+//! keeping them up to date, OCSP response refreshes and ACME renewals, can not run on a worker.
+//! They are all driven by a single thread, started with the first task, whose runtime runs them
+//! concurrently.
+use std::{future::Future, pin::Pin, sync::OnceLock};
+
+use futures::{channel::mpsc, StreamExt};
+
+type Task = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// Run the future built by `task` on the background thread.
+///
+/// Tasks run until they complete, they are expected to end once the resolver they serve is
+/// dropped.
+pub(crate) fn spawn<F, Fut>(task: F)
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    static TASKS: OnceLock<mpsc::UnboundedSender<Task>> = OnceLock::new();
+    let tasks = TASKS.get_or_init(|| {
+        let (tx, mut rx) = mpsc::unbounded::<Task>();
+        std::thread::Builder::new()
+            .name("tls-background".to_string())
+            .spawn(move || {
+                let mut runtime = monoio::RuntimeBuilder::<monoio::LegacyDriver>::new()
+                    .enable_timer()
+                    .build()
+                    .expect("unable to build the tls background runtime");
+                runtime.block_on(async move {
+                    while let Some(task) = rx.next().await {
+                        monoio::spawn(task());
+                    }
+                });
+            })
+            .expect("unable to start the tls background thread");
+        tx
+    });
+    let _ = tasks.unbounded_send(Box::new(move || Box::pin(task())));
+}
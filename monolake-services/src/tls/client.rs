@@ -0,0 +1,174 @@
+use std::{io::Cursor, sync::Arc};
+
+use rustls_client::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{
+        CertificateDer, Der, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, TrustAnchor, UnixTime,
+    },
+    version::{TLS12, TLS13},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme, SupportedProtocolVersion,
+};
+use serde::{Deserialize, Serialize};
+
+/// TLS protocol versions which can be selected in configuration.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "tls1.2")]
+    Tls12,
+    #[serde(rename = "tls1.3")]
+    Tls13,
+}
+
+/// TLS settings for connections to an HTTPS upstream.
+///
+/// Certificates and keys are given as paths to PEM files and loaded by
+/// [`UpstreamTlsConfig::load`].
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct UpstreamTlsConfig {
+    /// CA bundle used to verify the upstream instead of the bundled webpki roots.
+    pub ca: Option<String>,
+    /// Client certificate chain presented to the upstream (mutual TLS).
+    pub cert: Option<String>,
+    /// PKCS#8 private key of `cert`.
+    pub key: Option<String>,
+    /// Server name sent in SNI and verified against the upstream certificate, instead of the
+    /// host of the upstream uri.
+    pub sni: Option<String>,
+    /// Accept any upstream certificate. Only meant for lab environments.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// ALPN protocols to advertise. Defaults to the protocols implied by the upstream HTTP
+    /// version.
+    pub alpn: Option<Vec<String>>,
+    /// Minimum TLS version to negotiate.
+    #[serde(default)]
+    pub min_version: TlsVersion,
+}
+
+/// Loaded form of [`UpstreamTlsConfig`].
+#[derive(Debug, Clone)]
+pub struct UpstreamTls {
+    pub(crate) config: UpstreamTlsConfig,
+    pub(crate) client_config: Arc<ClientConfig>,
+    pub(crate) sni: Option<ServerName<'static>>,
+}
+
+impl UpstreamTlsConfig {
+    pub fn load(&self) -> anyhow::Result<UpstreamTls> {
+        let versions: &[&'static SupportedProtocolVersion] = match self.min_version {
+            TlsVersion::Tls12 => &[&TLS13, &TLS12],
+            TlsVersion::Tls13 => &[&TLS13],
+        };
+        let builder = ClientConfig::builder_with_protocol_versions(versions);
+        let builder = if self.insecure_skip_verify {
+            let provider = builder.crypto_provider().clone();
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoServerVerification(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            match &self.ca {
+                Some(ca) => {
+                    let ca = monolake_core::util::file_read_sync(ca)?;
+                    for cert in rustls_pemfile::certs(&mut Cursor::new(&ca))? {
+                        roots.add(CertificateDer::from(cert))?;
+                    }
+                    if roots.is_empty() {
+                        anyhow::bail!("empty upstream ca file");
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| TrustAnchor {
+                    subject: Der::from_slice(ta.subject),
+                    subject_public_key_info: Der::from_slice(ta.spki),
+                    name_constraints: ta.name_constraints.map(Der::from_slice),
+                })),
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let mut client_config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let cert = monolake_core::util::file_read_sync(cert)?;
+                let key = monolake_core::util::file_read_sync(key)?;
+                let chain = rustls_pemfile::certs(&mut Cursor::new(&cert))?
+                    .into_iter()
+                    .map(CertificateDer::from)
+                    .collect::<Vec<_>>();
+                if chain.is_empty() {
+                    anyhow::bail!("empty upstream client cert file");
+                }
+                let key = rustls_pemfile::pkcs8_private_keys(&mut Cursor::new(&key))?
+                    .pop()
+                    .map(|key| PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key)))
+                    .ok_or_else(|| anyhow::anyhow!("empty upstream client key file"))?;
+                builder.with_client_auth_cert(chain, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => anyhow::bail!("upstream client certificate requires both cert and key"),
+        };
+        if let Some(alpn) = &self.alpn {
+            client_config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        }
+
+        let sni = match &self.sni {
+            Some(sni) => Some(ServerName::try_from(sni.clone())?),
+            None => None,
+        };
+        Ok(UpstreamTls {
+            config: self.clone(),
+            client_config: Arc::new(client_config),
+            sni,
+        })
+    }
+}
+
+/// Skips certificate chain and name validation, while still checking handshake signatures.
+#[derive(Debug)]
+struct NoServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls_client::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls_client::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls_client::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -10,6 +10,15 @@
 //!   implementations.
 //! - [`UnifiedTlsFactory`]: Factory for creating `UnifiedTlsService` instances.
 //! - [`TlsConfig`]: Configuration enum for specifying TLS settings.
+//! - [`ClientAuthConfig`]: Client certificate verification (mutual TLS) settings.
+//! - [`SessionResumptionConfig`]: Session cache and ticket key rotation settings.
+//! - [`OcspConfig`]: OCSP stapling settings.
+//! - [`TlsPolicy`]: Protocol versions, cipher suites, key exchange groups and ALPN of a listener.
+//! - [`UpstreamTlsConfig`]: TLS settings for connections to HTTPS upstreams.
+//! - [`AcmeConfig`]: Automatic certificate provisioning through ACME (available with the "acme"
+//!   feature).
+//! - [`PemServerConfig::quic_server_config`]: The TLS 1.3 configuration of QUIC listeners, built
+//!   from the certificate of a TCP listener (available with the "http3" feature).
 //!
 //! # Features
 //!
@@ -17,7 +26,12 @@
 //! - Option for non-TLS (passthrough) connections
 //! - Integration with `service_async` for easy composition in service stacks
 //! - Unified error handling across different TLS implementations
-//! - ALPN support for protocol negotiation (e.g., HTTP/2)
+//! - Configurable ALPN for protocol negotiation (e.g., HTTP/2), protocol versions, cipher suites
+//!   and key exchange groups, validated against the selected stack at config load
+//! - Mutual TLS with required or optional client certificates and CRL checking (Rustls only); the
+//!   verified certificate is exposed to downstream services as `Option<PeerCertificate>`
+//! - Session resumption through a shared session cache and rotating ticket keys, and OCSP stapling
+//!   from a file or the certificate's responder (Rustls only)
 //!
 //! # Usage
 //!
@@ -43,19 +57,40 @@
 use std::io::Cursor;
 
 use monolake_core::AnyError;
-use native_tls::Identity;
+use serde::{Deserialize, Serialize};
 use service_async::{
     layer::{layer_fn, FactoryLayer},
     AsyncMakeService, MakeService, Param, Service,
 };
 
-pub use self::{nativetls::NativeTlsService, rustls::RustlsService};
+#[cfg(feature = "acme")]
+pub use self::acme::{AcmeConfig, ACME_TLS_ALPN};
+#[cfg(feature = "http3")]
+pub use self::quic::H3_ALPN;
+pub use self::{
+    client::{TlsVersion, UpstreamTls, UpstreamTlsConfig},
+    nativetls::{NativeTlsConfig, NativeTlsService},
+    ocsp::{OcspConfig, OcspSource},
+    policy::TlsPolicy,
+    rustls::{RustlsService, RustlsStream},
+    session::SessionResumptionConfig,
+};
 use self::{nativetls::NativeTlsServiceFactory, rustls::RustlsServiceFactory};
 use crate::tcp::Accept;
 
+#[cfg(feature = "acme")]
+pub(crate) mod acme;
+mod background;
+mod client;
 mod nativetls;
+mod ocsp;
+mod policy;
+#[cfg(feature = "http3")]
+mod quic;
 mod rustls;
+mod session;
 
+/// ALPN protocols offered by listeners without an explicit [`TlsPolicy::alpn`].
 pub const APLN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
 
 /// Unified TLS service supporting multiple TLS implementations.
@@ -230,7 +265,7 @@ where
 /// This enum allows for flexible configuration of TLS services,
 /// supporting both Rustls and Native TLS implementations, as well as a non-TLS option.
 #[derive(Clone)]
-pub enum TlsConfig<A = ::rustls::ServerConfig, B = ::native_tls::Identity> {
+pub enum TlsConfig<A = ::rustls::ServerConfig, B = NativeTlsConfig> {
     Rustls(A),
     Native(B),
     None,
@@ -251,7 +286,7 @@ impl<F> UnifiedTlsFactory<F> {
     where
         C: Param<TlsConfig<A, B>>,
         A: Param<::rustls::ServerConfig>,
-        B: Param<Identity>,
+        B: Param<NativeTlsConfig>,
     {
         layer_fn(|c: &C, inner| match &c.param() {
             TlsConfig::Rustls(i) => Self::Rustls(RustlsServiceFactory::layer().layer(i, inner)),
@@ -261,14 +296,168 @@ impl<F> UnifiedTlsFactory<F> {
     }
 }
 
+/// Challenge type used by ACME to prove control over the domains of a certificate.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AcmeChallengeType {
+    /// Served over plain HTTP on port 80 under `/.well-known/acme-challenge/`.
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// Served by the TLS listener itself on port 443.
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// Whether a client certificate must be presented during the handshake.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuthMode {
+    /// Reject clients that do not present a valid certificate.
+    #[default]
+    Required,
+    /// Accept anonymous clients, but verify the certificate when one is presented.
+    Optional,
+}
+
+/// Client certificate verification settings for mutual TLS.
+#[derive(Debug, Clone, Default)]
+pub struct ClientAuthConfig {
+    /// PEM encoded CA bundle trusted to issue client certificates.
+    pub ca: Vec<u8>,
+    pub mode: ClientAuthMode,
+    /// Certificate revocation lists, each either PEM or DER encoded.
+    pub crls: Vec<Vec<u8>>,
+}
+
+impl ClientAuthConfig {
+    fn apply(
+        &self,
+        builder: ::rustls::ConfigBuilder<::rustls::ServerConfig, ::rustls::WantsVerifier>,
+    ) -> anyhow::Result<
+        ::rustls::ConfigBuilder<::rustls::ServerConfig, ::rustls::server::WantsServerCert>,
+    > {
+        use ::rustls::server::{
+            AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient,
+            UnparsedCertRevocationList,
+        };
+
+        let mut roots = ::rustls::RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut Cursor::new(&self.ca))? {
+            roots.add(&::rustls::Certificate(cert))?;
+        }
+        if roots.is_empty() {
+            anyhow::bail!("empty client ca file");
+        }
+
+        let mut crls = Vec::new();
+        for crl in self.crls.iter() {
+            let pem = rustls_pemfile::crls(&mut Cursor::new(crl))?;
+            if pem.is_empty() {
+                crls.push(UnparsedCertRevocationList(crl.clone()));
+            } else {
+                crls.extend(pem.into_iter().map(UnparsedCertRevocationList));
+            }
+        }
+
+        let verifier = match self.mode {
+            ClientAuthMode::Required => AllowAnyAuthenticatedClient::new(roots)
+                .with_crls(crls)
+                .map_err(|e| anyhow::anyhow!("invalid crl: {e:?}"))?
+                .boxed(),
+            ClientAuthMode::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                .with_crls(crls)
+                .map_err(|e| anyhow::anyhow!("invalid crl: {e:?}"))?
+                .boxed(),
+        };
+        Ok(builder.with_client_cert_verifier(verifier))
+    }
+}
+
+/// PEM encoded server identity together with the optional settings used to build a
+/// [`TlsConfig`].
+#[derive(Debug, Clone, Default)]
+pub struct PemServerConfig {
+    pub chain: Vec<u8>,
+    pub key: Vec<u8>,
+    pub client_auth: Option<ClientAuthConfig>,
+    /// Session resumption settings, rustls defaults are used when unset.
+    pub session: Option<SessionResumptionConfig>,
+    pub ocsp: Option<OcspConfig>,
+    pub policy: TlsPolicy,
+    /// Obtain and renew the certificate through ACME instead of using `chain` and `key`, which
+    /// must be left empty.
+    #[cfg(feature = "acme")]
+    pub acme: Option<AcmeConfig>,
+}
+
+impl From<(Vec<u8>, Vec<u8>)> for PemServerConfig {
+    fn from((chain, key): (Vec<u8>, Vec<u8>)) -> Self {
+        Self {
+            chain,
+            key,
+            client_auth: None,
+            session: None,
+            ocsp: None,
+            policy: TlsPolicy::default(),
+            #[cfg(feature = "acme")]
+            acme: None,
+        }
+    }
+}
+
 impl TryFrom<TlsConfig<(Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>)>> for TlsConfig {
     type Error = anyhow::Error;
 
     fn try_from(
         value: TlsConfig<(Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>)>,
     ) -> Result<Self, Self::Error> {
+        let value: TlsConfig<PemServerConfig, PemServerConfig> = match value {
+            TlsConfig::Rustls(pem) => TlsConfig::Rustls(pem.into()),
+            TlsConfig::Native(pem) => TlsConfig::Native(pem.into()),
+            TlsConfig::None => TlsConfig::None,
+        };
+        value.try_into()
+    }
+}
+
+impl TryFrom<TlsConfig<PemServerConfig, PemServerConfig>> for TlsConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(value: TlsConfig<PemServerConfig, PemServerConfig>) -> Result<Self, Self::Error> {
         match value {
-            TlsConfig::Rustls((chain, key)) => {
+            TlsConfig::Rustls(PemServerConfig {
+                chain,
+                key,
+                client_auth,
+                session,
+                ocsp,
+                policy,
+                #[cfg(feature = "acme")]
+                acme,
+            }) => {
+                let builder = policy.rustls_builder()?;
+                let builder = match client_auth {
+                    Some(client_auth) => client_auth.apply(builder)?,
+                    None => builder.with_no_client_auth(),
+                };
+                #[cfg(feature = "acme")]
+                if let Some(acme) = acme {
+                    if !chain.is_empty() || !key.is_empty() || ocsp.is_some() {
+                        anyhow::bail!(
+                            "acme managed certificates cannot be combined with chain, key or ocsp"
+                        );
+                    }
+                    let tls_alpn01 = acme.challenge == AcmeChallengeType::TlsAlpn01;
+                    let mut scfg = builder.with_cert_resolver(acme::AcmeResolver::new(acme)?);
+                    if let Some(session) = session {
+                        session.apply(&mut scfg)?;
+                    }
+                    scfg.alpn_protocols = policy.alpn_protocols();
+                    if tls_alpn01 {
+                        scfg.alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+                    }
+                    return Ok(TlsConfig::Rustls(scfg));
+                }
                 let chain = rustls_pemfile::certs(&mut Cursor::new(&chain))?
                     .into_iter()
                     .map(::rustls::Certificate)
@@ -280,16 +469,49 @@ impl TryFrom<TlsConfig<(Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>)>> for TlsConfig {
                     .pop()
                     .map(::rustls::PrivateKey)
                     .ok_or_else(|| anyhow::anyhow!("empty key file"))?;
-                let mut scfg = ::rustls::ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(chain, key)?;
-                scfg.alpn_protocols = APLN_PROTOCOLS.map(|proto| proto.to_vec()).to_vec();
+                let mut scfg = match ocsp {
+                    Some(ocsp) => {
+                        let signing_key = ::rustls::sign::any_supported_type(&key)
+                            .map_err(|_| anyhow::anyhow!("unsupported private key type"))?;
+                        let certified = ::rustls::sign::CertifiedKey::new(chain, signing_key);
+                        builder.with_cert_resolver(ocsp::StaplingResolver::new(certified, ocsp)?)
+                    }
+                    None => builder.with_single_cert(chain, key)?,
+                };
+                if let Some(session) = session {
+                    session.apply(&mut scfg)?;
+                }
+                scfg.alpn_protocols = policy.alpn_protocols();
                 Ok(TlsConfig::Rustls(scfg))
             }
-            TlsConfig::Native((chain, key)) => Ok(TlsConfig::Native(
-                native_tls::Identity::from_pkcs8(&chain, &key)?,
-            )),
+            TlsConfig::Native(PemServerConfig {
+                chain,
+                key,
+                client_auth,
+                session,
+                ocsp,
+                policy,
+                #[cfg(feature = "acme")]
+                acme,
+            }) => {
+                #[cfg(feature = "acme")]
+                if acme.is_some() {
+                    anyhow::bail!("acme is not supported by native-tls");
+                }
+                if client_auth.is_some() {
+                    anyhow::bail!("client certificate verification is not supported by native-tls");
+                }
+                if session.is_some() || ocsp.is_some() {
+                    anyhow::bail!(
+                        "session resumption and ocsp stapling settings are not supported by \
+                         native-tls"
+                    );
+                }
+                let mut config =
+                    NativeTlsConfig::from(native_tls::Identity::from_pkcs8(&chain, &key)?);
+                policy.apply_native(&mut config)?;
+                Ok(TlsConfig::Native(config))
+            }
             TlsConfig::None => Ok(TlsConfig::None),
         }
     }
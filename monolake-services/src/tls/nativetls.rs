@@ -3,7 +3,7 @@ use std::fmt::Display;
 use monoio::io::{AsyncReadRent, AsyncWriteRent};
 use monoio_native_tls::{TlsAcceptor, TlsStream};
 use monolake_core::AnyError;
-use native_tls::Identity;
+use native_tls::{Identity, Protocol};
 use service_async::{
     layer::{layer_fn, FactoryLayer},
     AsyncMakeService, MakeService, Param, Service,
@@ -34,18 +34,46 @@ where
     }
 }
 
+/// Identity and accepted protocol range of a native-tls listener.
+#[derive(Clone)]
+pub struct NativeTlsConfig {
+    pub identity: Identity,
+    pub min_version: Option<Protocol>,
+    pub max_version: Option<Protocol>,
+}
+
+impl From<Identity> for NativeTlsConfig {
+    fn from(identity: Identity) -> Self {
+        Self {
+            identity,
+            min_version: None,
+            max_version: None,
+        }
+    }
+}
+
+impl NativeTlsConfig {
+    fn acceptor(&self) -> Result<TlsAcceptor, AnyError> {
+        let acceptor = native_tls::TlsAcceptor::builder(self.identity.clone())
+            .min_protocol_version(self.min_version)
+            .max_protocol_version(self.max_version)
+            .build()?;
+        Ok(TlsAcceptor::from(acceptor))
+    }
+}
+
 pub struct NativeTlsServiceFactory<F> {
-    identity: Identity,
+    config: NativeTlsConfig,
     inner: F,
 }
 
 impl<F> NativeTlsServiceFactory<F> {
     pub fn layer<C>() -> impl FactoryLayer<C, F, Factory = Self>
     where
-        C: Param<Identity>,
+        C: Param<NativeTlsConfig>,
     {
         layer_fn(|c: &C, inner| NativeTlsServiceFactory {
-            identity: c.param(),
+            config: c.param(),
             inner,
         })
     }
@@ -60,8 +88,7 @@ where
     type Error = AnyError;
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
-        let builder = native_tls::TlsAcceptor::builder(self.identity.clone());
-        let acceptor = TlsAcceptor::from(builder.build().map_err(AnyError::from)?);
+        let acceptor = self.config.acceptor()?;
         Ok(NativeTlsService {
             acceptor,
             inner: self
@@ -84,8 +111,7 @@ where
         &self,
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
-        let builder = native_tls::TlsAcceptor::builder(self.identity.clone());
-        let acceptor = TlsAcceptor::from(builder.build().map_err(AnyError::from)?);
+        let acceptor = self.config.acceptor()?;
         Ok(NativeTlsService {
             acceptor,
             inner: self
@@ -0,0 +1,454 @@
+use std::{
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::{Duration, Instant},
+};
+
+use http::{header, Request, StatusCode};
+use monoio_http::common::body::{FixedBody, HttpBody};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate,
+};
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName,
+    oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP, prelude::FromDer,
+};
+
+use super::background;
+use crate::http::client::HttpClient;
+
+const DEFAULT_RESPONDER_REFRESH: Duration = Duration::from_secs(60 * 60);
+const RESPONDER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the refresher looks for due responses and dropped resolvers.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Where the stapled OCSP response comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OcspSource {
+    /// DER encoded OCSP response file, e.g. maintained by `openssl ocsp`.
+    File(String),
+    /// Query the responder named in the authority information access extension of the
+    /// certificate. The issuer certificate must be part of the chain.
+    Responder,
+}
+
+/// OCSP stapling settings of a rustls listener.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OcspConfig {
+    pub source: OcspSource,
+    /// How often the response is reloaded. Files are loaded once when unset, responders are
+    /// queried hourly.
+    pub refresh_interval: Option<Duration>,
+}
+
+/// Certificate with its latest OCSP response, shared by the resolvers serving it.
+struct Staple {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Staple {
+    fn update(&self, response: Vec<u8>) {
+        if let Ok(mut key) = self.key.write() {
+            let mut next = CertifiedKey::clone(&key);
+            next.ocsp = Some(response);
+            *key = Arc::new(next);
+        }
+    }
+}
+
+/// Certificate resolver serving a single certificate whose stapled OCSP response is replaced
+/// in the background.
+pub(crate) struct StaplingResolver {
+    staple: Arc<Staple>,
+}
+
+impl ResolvesServerCert for StaplingResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.staple.key.read().ok().map(|key| key.clone())
+    }
+}
+
+/// Staples refreshed in the background, keyed by certificate chain and settings.
+struct Refreshed {
+    chain: Vec<Certificate>,
+    config: OcspConfig,
+    interval: Duration,
+    staple: Weak<Staple>,
+    due: Instant,
+}
+
+struct Refresher {
+    entries: Vec<Refreshed>,
+    running: bool,
+}
+
+static REFRESHER: Mutex<Refresher> = Mutex::new(Refresher {
+    entries: Vec::new(),
+    running: false,
+});
+
+impl StaplingResolver {
+    /// Load the initial response and schedule its refreshes.
+    ///
+    /// Resolvers of the same certificate and settings, e.g. built by successive config reloads,
+    /// share their response and its refreshes. Responders are first queried in the background:
+    /// the certificate is served without a staple until then. Refreshes stop once all the
+    /// resolvers of a certificate are dropped.
+    pub(crate) fn new(key: CertifiedKey, config: OcspConfig) -> anyhow::Result<Arc<Self>> {
+        if config
+            .refresh_interval
+            .is_some_and(|interval| interval.is_zero())
+        {
+            anyhow::bail!("ocsp refresh interval must be positive");
+        }
+        let response = match &config.source {
+            OcspSource::File(path) => Some(load_file(path)?),
+            OcspSource::Responder => {
+                // Fail early on certificates we can never build a request for.
+                build_request(&key.cert)?;
+                None
+            }
+        };
+        let interval = match config.source {
+            OcspSource::File(_) => config.refresh_interval,
+            OcspSource::Responder => {
+                Some(config.refresh_interval.unwrap_or(DEFAULT_RESPONDER_REFRESH))
+            }
+        };
+        let Some(interval) = interval else {
+            let mut key = key;
+            key.ocsp = response;
+            return Ok(Arc::new(Self {
+                staple: Arc::new(Staple {
+                    key: RwLock::new(Arc::new(key)),
+                }),
+            }));
+        };
+
+        let mut refresher = REFRESHER
+            .lock()
+            .map_err(|_| anyhow::anyhow!("ocsp refresher poisoned"))?;
+        let shared = refresher
+            .entries
+            .iter()
+            .filter(|entry| entry.chain == key.cert && entry.config == config)
+            .find_map(|entry| entry.staple.upgrade());
+        if let Some(staple) = shared {
+            if let Some(response) = response {
+                staple.update(response);
+            }
+            return Ok(Arc::new(Self { staple }));
+        }
+
+        let chain = key.cert.clone();
+        let mut key = key;
+        // Files were just loaded, responders are queried right away.
+        let due = match response {
+            Some(_) => Instant::now() + interval,
+            None => Instant::now(),
+        };
+        key.ocsp = response;
+        let staple = Arc::new(Staple {
+            key: RwLock::new(Arc::new(key)),
+        });
+        refresher.entries.push(Refreshed {
+            chain,
+            config,
+            interval,
+            staple: Arc::downgrade(&staple),
+            due,
+        });
+        if !refresher.running {
+            refresher.running = true;
+            background::spawn(refresh_loop);
+        }
+        Ok(Arc::new(Self { staple }))
+    }
+}
+
+/// Refresh the due responses until no resolver is left.
+async fn refresh_loop() {
+    let client = HttpClient::new(RESPONDER_TIMEOUT);
+    loop {
+        let due = {
+            let Ok(mut refresher) = REFRESHER.lock() else {
+                return;
+            };
+            refresher
+                .entries
+                .retain(|entry| entry.staple.strong_count() > 0);
+            if refresher.entries.is_empty() {
+                refresher.running = false;
+                return;
+            }
+            let now = Instant::now();
+            refresher
+                .entries
+                .iter_mut()
+                .filter(|entry| entry.due <= now)
+                .map(|entry| {
+                    entry.due = now + entry.interval;
+                    (
+                        entry.staple.clone(),
+                        entry.chain.clone(),
+                        entry.config.source.clone(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        for (staple, chain, source) in due {
+            let response = match &source {
+                OcspSource::File(path) => load_file(path),
+                OcspSource::Responder => fetch(&client, &chain).await,
+            };
+            match (response, staple.upgrade()) {
+                (Ok(response), Some(staple)) => {
+                    tracing::debug!("ocsp response refreshed");
+                    staple.update(response);
+                }
+                // Keep serving the previous response, it is usually valid for several days.
+                (Err(e), Some(_)) => tracing::warn!("ocsp response refresh failed: {e:?}"),
+                (_, None) => {}
+            }
+        }
+        monoio::time::sleep(TICK).await;
+    }
+}
+
+fn load_file(path: &str) -> anyhow::Result<Vec<u8>> {
+    let response = monolake_core::util::file_read_sync(path)?;
+    check_response(&response)?;
+    Ok(response)
+}
+
+async fn fetch(client: &HttpClient, chain: &[Certificate]) -> anyhow::Result<Vec<u8>> {
+    let (url, body) = build_request(chain)?;
+    let request = Request::post(url)
+        .header(header::CONTENT_TYPE, "application/ocsp-request")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(HttpBody::fixed_body(Some(body.into())))?;
+    let response = client.send(request).await?;
+    if response.status() != StatusCode::OK {
+        anyhow::bail!("ocsp responder replied with status {}", response.status());
+    }
+    let response = response.into_body().to_vec();
+    check_response(&response)?;
+    Ok(response)
+}
+
+/// Build an OCSP request (RFC 6960) for the end-entity certificate of `chain` and return it
+/// together with the responder url.
+fn build_request(chain: &[Certificate]) -> anyhow::Result<(http::Uri, Vec<u8>)> {
+    let (leaf, issuer) = match chain {
+        [leaf, issuer, ..] => (leaf, issuer),
+        _ => anyhow::bail!("ocsp stapling requires the issuer certificate in the chain"),
+    };
+    let (_, leaf) = X509Certificate::from_der(&leaf.0)?;
+    let (_, issuer) = X509Certificate::from_der(&issuer.0)?;
+
+    let url = leaf
+        .extensions()
+        .iter()
+        .find_map(|ext| match ext.parsed_extension() {
+            x509_parser::extensions::ParsedExtension::AuthorityInfoAccess(aia) => {
+                aia.iter().find_map(|desc| match &desc.access_location {
+                    GeneralName::URI(uri)
+                        if desc.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP =>
+                    {
+                        Some(uri.to_string())
+                    }
+                    _ => None,
+                })
+            }
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("certificate does not name an ocsp responder"))?;
+    let url: http::Uri = url.parse()?;
+    if url.scheme_str() != Some("http") {
+        anyhow::bail!("unsupported ocsp responder url {url}");
+    }
+
+    let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, issuer.subject().as_raw());
+    let key_hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        &issuer.public_key().subject_public_key.data,
+    );
+    // AlgorithmIdentifier of sha1 with NULL parameters.
+    const SHA1_ALGORITHM: &[u8] = &[
+        0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00,
+    ];
+    let cert_id = der_tlv(
+        0x30,
+        &[
+            SHA1_ALGORITHM,
+            &der_tlv(0x04, name_hash.as_ref()),
+            &der_tlv(0x04, key_hash.as_ref()),
+            &der_tlv(0x02, leaf.raw_serial()),
+        ]
+        .concat(),
+    );
+    // OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+    let request = [0x30; 3]
+        .iter()
+        .fold(der_tlv(0x30, &cert_id), |inner, tag| der_tlv(*tag, &inner));
+    Ok((url, request))
+}
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// Make sure `response` is an OCSPResponse with a `successful` status. The response itself is
+/// verified by the clients.
+fn check_response(response: &[u8]) -> anyhow::Result<()> {
+    let (_, any) = x509_parser::der_parser::parse_der(response)
+        .map_err(|e| anyhow::anyhow!("invalid ocsp response: {e}"))?;
+    let status = any
+        .as_sequence()
+        .ok()
+        .and_then(|items| items.first())
+        .and_then(|status| status.as_u32().ok());
+    match status {
+        Some(0) => Ok(()),
+        Some(status) => anyhow::bail!("ocsp responder returned status {status}"),
+        None => anyhow::bail!("invalid ocsp response"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use bytes::Bytes;
+    use rcgen::{CertificateParams, CustomExtension};
+
+    use super::*;
+    use crate::testing::{stub_http, write_file, TestCa};
+
+    /// A `successful` OCSPResponse, without the response bytes the server does not check.
+    fn ocsp_response() -> Vec<u8> {
+        der_tlv(0x30, &der_tlv(0x0a, &[0]))
+    }
+
+    fn certified(chain: &str, key: &str) -> CertifiedKey {
+        let chain = rustls_pemfile::certs(&mut chain.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        let key = rustls_pemfile::pkcs8_private_keys(&mut key.as_bytes())
+            .unwrap()
+            .pop()
+            .unwrap();
+        let key = rustls::sign::any_supported_type(&rustls::PrivateKey(key)).unwrap();
+        CertifiedKey::new(chain, key)
+    }
+
+    fn stapled(resolver: &StaplingResolver) -> Option<Vec<u8>> {
+        resolver.staple.key.read().unwrap().ocsp.clone()
+    }
+
+    async fn wait_for(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            monoio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("condition not met in time");
+    }
+
+    #[test]
+    fn file_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = TestCa::new();
+        let (chain, key) = ca.issue(&["file.ocsp.test"]);
+        let key = certified(&chain, &key);
+        let path = write_file(dir.path(), "staple.der", ocsp_response());
+        let config = |refresh_interval| OcspConfig {
+            source: OcspSource::File(path.clone()),
+            refresh_interval,
+        };
+
+        let resolver = StaplingResolver::new(key.clone(), config(None)).unwrap();
+        assert_eq!(stapled(&resolver), Some(ocsp_response()));
+        assert!(StaplingResolver::new(key.clone(), config(Some(Duration::ZERO))).is_err());
+        let missing = OcspConfig {
+            source: OcspSource::File(dir.path().join("missing").to_str().unwrap().to_string()),
+            refresh_interval: None,
+        };
+        assert!(StaplingResolver::new(key, missing).is_err());
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn responder_source() {
+        let queries = Rc::new(Cell::new(0));
+        let counted = queries.clone();
+        let addr = stub_http(move |request: Request<Bytes>| {
+            let counted = counted.clone();
+            async move {
+                assert_eq!(
+                    request.headers()[header::CONTENT_TYPE],
+                    "application/ocsp-request"
+                );
+                counted.set(counted.get() + 1);
+                http::Response::new(ocsp_response())
+            }
+        });
+        // AuthorityInfoAccess { AccessDescription { id-ad-ocsp, uniformResourceIdentifier } }
+        const ID_AD_OCSP: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+        let uri = format!("http://{addr}/");
+        let aia = der_tlv(
+            0x30,
+            &der_tlv(0x30, &[ID_AD_OCSP, &der_tlv(0x86, uri.as_bytes())].concat()),
+        );
+        let mut params = CertificateParams::new(vec!["responder.ocsp.test".to_string()]);
+        params
+            .custom_extensions
+            .push(CustomExtension::from_oid_content(
+                &[1, 3, 6, 1, 5, 5, 7, 1, 1],
+                aia,
+            ));
+        let ca = TestCa::new();
+        let (chain, key) = ca.issue_params(params);
+        let key = certified(&format!("{chain}{}", ca.pem()), &key);
+        let config = OcspConfig {
+            source: OcspSource::Responder,
+            refresh_interval: Some(Duration::from_secs(1)),
+        };
+
+        // The responder is queried in the background, not while building the resolver.
+        let first = StaplingResolver::new(key.clone(), config.clone()).unwrap();
+        wait_for(|| stapled(&first).is_some()).await;
+        assert_eq!(stapled(&first), Some(ocsp_response()));
+
+        // Reloads share the response and its refreshes.
+        let second = StaplingResolver::new(key, config).unwrap();
+        assert!(Arc::ptr_eq(&first.staple, &second.staple));
+        let seen = queries.get();
+        wait_for(|| queries.get() > seen).await;
+        let seen = queries.get();
+        monoio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(queries.get() <= seen + 2);
+
+        // Refreshes stop with the last resolver.
+        drop((first, second));
+        monoio::time::sleep(Duration::from_millis(1500)).await;
+        let seen = queries.get();
+        monoio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(queries.get(), seen);
+    }
+}
@@ -0,0 +1,220 @@
+use rustls::{
+    version::{TLS12, TLS13},
+    ConfigBuilder, ServerConfig, SupportedProtocolVersion, WantsVerifier, ALL_CIPHER_SUITES,
+    ALL_KX_GROUPS,
+};
+
+use super::{nativetls::NativeTlsConfig, TlsVersion, APLN_PROTOCOLS};
+
+/// Protocol policy of a TLS listener. Unset fields keep the defaults of the TLS stack.
+///
+/// A policy is validated when the listener config is built, so settings the selected stack
+/// cannot enforce are rejected at config load instead of failing handshakes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsPolicy {
+    /// Lowest accepted version, rustls listeners accept TLS 1.2 and 1.3 by default.
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    /// Cipher suite allow-list by IANA name, e.g. `TLS13_AES_128_GCM_SHA256` or
+    /// `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    pub cipher_suites: Option<Vec<String>>,
+    /// Key exchange groups, e.g. `X25519`, `secp256r1` or `secp384r1`.
+    pub kx_groups: Option<Vec<String>>,
+    /// ALPN protocols in order of preference, defaults to [`APLN_PROTOCOLS`].
+    pub alpn: Option<Vec<String>>,
+}
+
+impl TlsPolicy {
+    fn versions(&self) -> anyhow::Result<Vec<&'static SupportedProtocolVersion>> {
+        let min = self.min_version.unwrap_or(TlsVersion::Tls12);
+        let max = self.max_version.unwrap_or(TlsVersion::Tls13);
+        if min > max {
+            anyhow::bail!("tls min_version {min:?} is above max_version {max:?}");
+        }
+        Ok([(TlsVersion::Tls13, &TLS13), (TlsVersion::Tls12, &TLS12)]
+            .into_iter()
+            .filter(|(version, _)| (min..=max).contains(version))
+            .map(|(_, supported)| supported)
+            .collect())
+    }
+
+    pub(crate) fn rustls_builder(
+        &self,
+    ) -> anyhow::Result<ConfigBuilder<ServerConfig, WantsVerifier>> {
+        let suites = match &self.cipher_suites {
+            Some(names) => lookup(names, "cipher suite", ALL_CIPHER_SUITES, |suite| {
+                format!("{:?}", suite.suite())
+            })?,
+            None => ALL_CIPHER_SUITES.to_vec(),
+        };
+        let kx_groups = match &self.kx_groups {
+            Some(names) => lookup(names, "key exchange group", &ALL_KX_GROUPS, |group| {
+                format!("{:?}", group.name)
+            })?,
+            None => ALL_KX_GROUPS.to_vec(),
+        };
+        let versions = self.versions()?;
+        ServerConfig::builder()
+            .with_cipher_suites(&suites)
+            .with_kx_groups(&kx_groups)
+            .with_protocol_versions(&versions)
+            .map_err(|e| anyhow::anyhow!("invalid tls policy: {e}"))
+    }
+
+    pub(crate) fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match &self.alpn {
+            Some(alpn) => alpn.iter().map(|proto| proto.as_bytes().to_vec()).collect(),
+            None => APLN_PROTOCOLS.map(|proto| proto.to_vec()).to_vec(),
+        }
+    }
+
+    pub(crate) fn apply_native(&self, config: &mut NativeTlsConfig) -> anyhow::Result<()> {
+        if self.cipher_suites.is_some() || self.kx_groups.is_some() || self.alpn.is_some() {
+            anyhow::bail!(
+                "cipher suites, key exchange groups and alpn are not configurable with native-tls"
+            );
+        }
+        self.versions()?;
+        // native-tls has no way to express TLS 1.3, so only a range starting at TLS 1.2 and
+        // optionally capped at TLS 1.2 can be enforced.
+        config.min_version = match self.min_version {
+            Some(TlsVersion::Tls12) => Some(native_tls::Protocol::Tlsv12),
+            Some(TlsVersion::Tls13) => {
+                anyhow::bail!("native-tls cannot enforce tls1.3 as minimum version")
+            }
+            None => None,
+        };
+        config.max_version = match self.max_version {
+            Some(TlsVersion::Tls12) => Some(native_tls::Protocol::Tlsv12),
+            Some(TlsVersion::Tls13) | None => None,
+        };
+        Ok(())
+    }
+}
+
+fn lookup<T: Copy>(
+    names: &[String],
+    kind: &str,
+    supported: &[T],
+    name_of: impl Fn(&T) -> String,
+) -> anyhow::Result<Vec<T>> {
+    if names.is_empty() {
+        anyhow::bail!("empty {kind} list");
+    }
+    names
+        .iter()
+        .map(|name| {
+            supported
+                .iter()
+                .find(|item| name_of(item).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| anyhow::anyhow!("unsupported {kind} {name}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestCa;
+
+    fn policy(min_version: Option<TlsVersion>, max_version: Option<TlsVersion>) -> TlsPolicy {
+        TlsPolicy {
+            min_version,
+            max_version,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn version_range() {
+        let versions = |policy: TlsPolicy| {
+            policy
+                .versions()
+                .map(|versions| versions.iter().map(|v| v.version).collect::<Vec<_>>())
+        };
+        let (tls12, tls13) = (Some(TlsVersion::Tls12), Some(TlsVersion::Tls13));
+        // Both versions are accepted unless restricted.
+        assert_eq!(
+            versions(policy(None, None)).unwrap(),
+            [TLS13.version, TLS12.version]
+        );
+        assert_eq!(
+            versions(policy(tls12, None)).unwrap(),
+            [TLS13.version, TLS12.version]
+        );
+        assert_eq!(versions(policy(None, tls12)).unwrap(), [TLS12.version]);
+        assert_eq!(versions(policy(tls13, tls13)).unwrap(), [TLS13.version]);
+        assert!(versions(policy(tls13, tls12)).is_err());
+    }
+
+    #[test]
+    fn rustls_settings() {
+        let named = |cipher_suites: &[&str], kx_groups: &[&str]| TlsPolicy {
+            min_version: Some(TlsVersion::Tls12),
+            cipher_suites: Some(cipher_suites.iter().map(|s| s.to_string()).collect()),
+            kx_groups: Some(kx_groups.iter().map(|s| s.to_string()).collect()),
+            ..Default::default()
+        };
+        assert!(named(
+            &[
+                "TLS13_AES_128_GCM_SHA256",
+                "tls_ecdhe_rsa_with_aes_128_gcm_sha256"
+            ],
+            &["X25519"]
+        )
+        .rustls_builder()
+        .is_ok());
+        assert!(named(&["TLS_RSA_WITH_RC4_128_SHA"], &["X25519"])
+            .rustls_builder()
+            .is_err());
+        assert!(named(&["TLS13_AES_128_GCM_SHA256"], &["X448"])
+            .rustls_builder()
+            .is_err());
+        assert!(named(&[], &["X25519"]).rustls_builder().is_err());
+        // Only TLS 1.2 suites for a TLS 1.3 listener.
+        let mut policy = named(&["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"], &["X25519"]);
+        policy.min_version = Some(TlsVersion::Tls13);
+        assert!(policy.rustls_builder().is_err());
+
+        assert_eq!(
+            TlsPolicy::default().alpn_protocols(),
+            [b"h2".to_vec(), b"http/1.1".to_vec()]
+        );
+        let policy = TlsPolicy {
+            alpn: Some(vec!["http/1.1".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(policy.alpn_protocols(), [b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn native_settings() {
+        let (chain, key) = TestCa::new().issue(&["native.test"]);
+        let identity = native_tls::Identity::from_pkcs8(chain.as_bytes(), key.as_bytes()).unwrap();
+        let mut config = NativeTlsConfig::from(identity);
+
+        policy(Some(TlsVersion::Tls12), Some(TlsVersion::Tls12))
+            .apply_native(&mut config)
+            .unwrap();
+        assert!(matches!(
+            config.min_version,
+            Some(native_tls::Protocol::Tlsv12)
+        ));
+        assert!(matches!(
+            config.max_version,
+            Some(native_tls::Protocol::Tlsv12)
+        ));
+        policy(None, None).apply_native(&mut config).unwrap();
+        assert!(config.min_version.is_none() && config.max_version.is_none());
+
+        assert!(policy(Some(TlsVersion::Tls13), None)
+            .apply_native(&mut config)
+            .is_err());
+        let alpn = TlsPolicy {
+            alpn: Some(vec!["h2".to_string()]),
+            ..Default::default()
+        };
+        assert!(alpn.apply_native(&mut config).is_err());
+    }
+}
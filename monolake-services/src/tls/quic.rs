@@ -0,0 +1,103 @@
+use std::{io::Cursor, sync::Arc};
+
+use quinn_proto::crypto::rustls::QuicServerConfig;
+use rustls_client::{
+    crypto::aws_lc_rs,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    version::TLS13,
+    ServerConfig,
+};
+
+use super::{PemServerConfig, TlsVersion};
+
+/// ALPN protocol of HTTP/3, the only one offered by QUIC listeners.
+pub const H3_ALPN: &[u8] = b"h3";
+
+impl PemServerConfig {
+    /// Build the configuration of a QUIC listener serving HTTP/3 with the certificate of this
+    /// config, so that a QUIC listener can share it with the TCP listener of the same server.
+    ///
+    /// QUIC always runs TLS 1.3 and negotiates `h3`, hence the version range and ALPN of the
+    /// policy are not used. Settings which are only implemented for TCP listeners are rejected.
+    pub fn quic_server_config(&self) -> anyhow::Result<Arc<quinn_proto::ServerConfig>> {
+        #[cfg(feature = "acme")]
+        if self.acme.is_some() {
+            anyhow::bail!("acme managed certificates are not supported on quic listeners");
+        }
+        if self.client_auth.is_some() || self.session.is_some() || self.ocsp.is_some() {
+            anyhow::bail!(
+                "client_auth, session and ocsp settings are not supported on quic listeners"
+            );
+        }
+        if self.policy.cipher_suites.is_some() || self.policy.kx_groups.is_some() {
+            anyhow::bail!("cipher_suites and kx_groups are not supported on quic listeners");
+        }
+        if self.policy.max_version == Some(TlsVersion::Tls12) {
+            anyhow::bail!("quic listeners require tls1.3");
+        }
+
+        let chain = rustls_pemfile::certs(&mut Cursor::new(&self.chain))?
+            .into_iter()
+            .map(CertificateDer::from)
+            .collect::<Vec<_>>();
+        if chain.is_empty() {
+            anyhow::bail!("empty cert file");
+        }
+        let key = rustls_pemfile::pkcs8_private_keys(&mut Cursor::new(&self.key))?
+            .pop()
+            .map(|key| PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key)))
+            .ok_or_else(|| anyhow::anyhow!("empty key file"))?;
+
+        let mut tls = ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_protocol_versions(&[&TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)?;
+        tls.alpn_protocols = vec![H3_ALPN.to_vec()];
+        let crypto = QuicServerConfig::try_from(tls)?;
+        Ok(Arc::new(quinn_proto::ServerConfig::with_crypto(Arc::new(
+            crypto,
+        ))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::TestCa, tls::ClientAuthMode};
+
+    #[test]
+    fn server_config() {
+        let ca = TestCa::new();
+        let (chain, key) = ca.issue(&["localhost"]);
+        let pem = PemServerConfig::from((chain.into_bytes(), key.into_bytes()));
+        assert!(pem.quic_server_config().is_ok());
+        // TLS 1.2 as the lower bound is fine, QUIC negotiates TLS 1.3 anyway.
+        let mut config = pem.clone();
+        config.policy.min_version = Some(TlsVersion::Tls12);
+        assert!(config.quic_server_config().is_ok());
+
+        let mut rejected = Vec::new();
+        let mut config = pem.clone();
+        config.policy.max_version = Some(TlsVersion::Tls12);
+        rejected.push(config);
+        let mut config = pem.clone();
+        config.policy.cipher_suites = Some(vec!["TLS13_AES_128_GCM_SHA256".to_string()]);
+        rejected.push(config);
+        let mut config = pem.clone();
+        config.client_auth = Some(crate::tls::ClientAuthConfig {
+            ca: ca.pem().as_bytes().to_vec(),
+            mode: ClientAuthMode::Required,
+            crls: Vec::new(),
+        });
+        rejected.push(config);
+        let mut config = pem.clone();
+        config.chain = Vec::new();
+        rejected.push(config);
+        let mut config = pem;
+        config.key = Vec::new();
+        rejected.push(config);
+        for config in rejected {
+            assert!(config.quic_server_config().is_err());
+        }
+    }
+}
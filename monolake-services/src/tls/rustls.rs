@@ -1,35 +1,149 @@
-use std::{fmt::Display, sync::Arc};
+use std::{fmt::Display, io::Cursor, net::IpAddr, sync::Arc};
 
-use monoio::io::{AsyncReadRent, AsyncWriteRent};
-use monoio_rustls::{ServerTlsStream, TlsAcceptor};
-use monolake_core::AnyError;
-use rustls::ServerConfig;
+use certain_map::ParamSet;
+use monoio::io::{AsyncReadRent, AsyncWriteRent, AsyncWriteRentExt, PrefixedReadIo};
+use monoio_rustls::ServerTlsStream;
+use monolake_core::{context::PeerCertificate, AnyError};
+use rustls::{ServerConfig, ServerConnection};
 use service_async::{
     layer::{layer_fn, FactoryLayer},
     AsyncMakeService, MakeService, Param, Service,
 };
+use x509_parser::{extensions::GeneralName, prelude::FromDer};
 
 use crate::tcp::Accept;
 
-type RustlsAccept<Stream, SocketAddr> = (ServerTlsStream<Stream>, SocketAddr);
+const HANDSHAKE_READ_SIZE: usize = 16 * 1024;
 
+/// Stream produced by [`RustlsService`]. Ciphertext which was read during the handshake but not
+/// consumed by it is replayed in front of the raw stream.
+pub type RustlsStream<S> = ServerTlsStream<PrefixedReadIo<S, Cursor<Vec<u8>>>>;
+
+type RustlsAccept<Stream, CX> = (RustlsStream<Stream>, CX);
+
+/// Service terminating TLS with rustls.
+///
+/// When the server config carries a client certificate verifier, the verified end-entity
+/// certificate is recorded in the context as `Option<PeerCertificate>` so that handlers can
+/// authorize on it or forward it upstream.
 pub struct RustlsService<T> {
-    acceptor: TlsAcceptor,
+    config: Arc<ServerConfig>,
     inner: T,
 }
 
 impl<T, S, CX> Service<Accept<S, CX>> for RustlsService<T>
 where
-    T: Service<RustlsAccept<S, CX>>,
+    T: Service<RustlsAccept<S, CX::Transformed>>,
     T::Error: Into<AnyError> + Display,
     S: AsyncReadRent + AsyncWriteRent,
+    CX: ParamSet<Option<PeerCertificate>>,
 {
     type Response = T::Response;
     type Error = AnyError;
 
     async fn call(&self, (stream, cx): Accept<S, CX>) -> Result<Self::Response, Self::Error> {
-        let stream = self.acceptor.accept(stream).await?;
-        self.inner.call((stream, cx)).await.map_err(Into::into)
+        let session = ServerConnection::new(self.config.clone())?;
+        let (stream, session, remaining) = handshake(stream, session).await?;
+        // TLS-ALPN-01 validation connections carry no application data.
+        #[cfg(feature = "acme")]
+        if session.alpn_protocol() == Some(super::ACME_TLS_ALPN) {
+            return Err(anyhow::anyhow!("acme-tls/1 validation handshake completed"));
+        }
+        let peer_cert = session
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(|cert| parse_peer_certificate(&cert.0));
+        let stream =
+            ServerTlsStream::new(PrefixedReadIo::new(stream, Cursor::new(remaining)), session);
+        self.inner
+            .call((stream, cx.param_set(peer_cert)))
+            .await
+            .map_err(Into::into)
+    }
+}
+
+/// Drive the server side handshake to completion.
+///
+/// Returns the raw stream, the established session and the bytes read from the peer that the
+/// session has not consumed yet.
+async fn handshake<S>(
+    mut stream: S,
+    mut session: ServerConnection,
+) -> std::io::Result<(S, ServerConnection, Vec<u8>)>
+where
+    S: AsyncReadRent + AsyncWriteRent,
+{
+    let mut buf = Vec::with_capacity(HANDSHAKE_READ_SIZE);
+    let mut pos = 0;
+    loop {
+        flush_tls(&mut stream, &mut session).await?;
+        if !session.is_handshaking() {
+            break;
+        }
+        if pos == buf.len() {
+            buf.clear();
+            pos = 0;
+            let (res, read) = stream.read(buf).await;
+            buf = read;
+            if res? == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "tls handshake eof",
+                ));
+            }
+        }
+        pos += session.read_tls(&mut &buf[pos..])?;
+        if let Err(e) = session.process_new_packets() {
+            // Best effort to deliver the alert before giving up.
+            let _ = flush_tls(&mut stream, &mut session).await;
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e));
+        }
+    }
+    buf.drain(..pos);
+    Ok((stream, session, buf))
+}
+
+async fn flush_tls<S: AsyncWriteRent>(
+    stream: &mut S,
+    session: &mut ServerConnection,
+) -> std::io::Result<()> {
+    while session.wants_write() {
+        let mut out = Vec::new();
+        session.write_tls(&mut out)?;
+        stream.write_all(out).await.0?;
+    }
+    Ok(())
+}
+
+fn parse_peer_certificate(der: &[u8]) -> Option<PeerCertificate> {
+    let (_, cert) = x509_parser::certificate::X509Certificate::from_der(der).ok()?;
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(ext)) => ext
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(format!("DNS:{dns}")),
+                GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+                GeneralName::RFC822Name(email) => Some(format!("email:{email}")),
+                GeneralName::IPAddress(ip) => parse_ip(ip).map(|ip| format!("IP:{ip}")),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some(PeerCertificate {
+        subject: cert.subject().to_string(),
+        sans,
+        fingerprint: monolake_core::util::hash::sha256(der),
+    })
+}
+
+fn parse_ip(raw: &[u8]) -> Option<IpAddr> {
+    match raw.len() {
+        4 => <[u8; 4]>::try_from(raw).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(raw).ok().map(IpAddr::from),
+        _ => None,
     }
 }
 
@@ -55,9 +169,8 @@ impl<F: MakeService> MakeService for RustlsServiceFactory<F> {
     type Error = F::Error;
 
     fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
-        let acceptor = TlsAcceptor::from(self.config.clone());
         Ok(RustlsService {
-            acceptor,
+            config: self.config.clone(),
             inner: self.inner.make_via_ref(old.map(|o| &o.inner))?,
         })
     }
@@ -71,9 +184,8 @@ impl<F: AsyncMakeService> AsyncMakeService for RustlsServiceFactory<F> {
         &self,
         old: Option<&Self::Service>,
     ) -> Result<Self::Service, Self::Error> {
-        let acceptor = TlsAcceptor::from(self.config.clone());
         Ok(RustlsService {
-            acceptor,
+            config: self.config.clone(),
             inner: self.inner.make_via_ref(old.map(|o| &o.inner)).await?,
         })
     }
@@ -0,0 +1,182 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use rustls::server::{NoServerSessionStorage, ProducesTickets, ServerSessionMemoryCache};
+
+const DEFAULT_CACHE_SIZE: usize = 256;
+const DEFAULT_TICKET_KEY_ROTATION: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// Session resumption settings of a rustls listener.
+///
+/// The session cache and the ticket keys are created once per loaded config and shared by all
+/// workers, so a session established on one worker can be resumed on any other.
+#[derive(Debug, Clone)]
+pub struct SessionResumptionConfig {
+    /// Number of sessions kept in the server side session cache, 0 disables the cache.
+    pub cache_size: usize,
+    /// Issue stateless session tickets.
+    pub tickets: bool,
+    /// Interval after which a new ticket key is generated. Tickets encrypted with the previous
+    /// key are still accepted for one more interval.
+    pub ticket_key_rotation: Duration,
+}
+
+impl Default for SessionResumptionConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: DEFAULT_CACHE_SIZE,
+            tickets: false,
+            ticket_key_rotation: DEFAULT_TICKET_KEY_ROTATION,
+        }
+    }
+}
+
+impl SessionResumptionConfig {
+    pub(crate) fn apply(&self, config: &mut rustls::ServerConfig) -> anyhow::Result<()> {
+        config.session_storage = match self.cache_size {
+            0 => Arc::new(NoServerSessionStorage {}),
+            size => ServerSessionMemoryCache::new(size),
+        };
+        if self.tickets {
+            config.ticketer = Arc::new(RotatingTicketer::new(self.ticket_key_rotation)?);
+        }
+        Ok(())
+    }
+}
+
+/// Ticket encrypter which replaces its key every `rotation` and keeps the previous key around
+/// for decryption, so tickets stay valid for at least one rotation interval.
+struct RotatingTicketer {
+    rotation: Duration,
+    rng: SystemRandom,
+    keys: Mutex<TicketKeys>,
+}
+
+struct TicketKeys {
+    current: LessSafeKey,
+    previous: Option<LessSafeKey>,
+    rotate_at: Instant,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration) -> anyhow::Result<Self> {
+        if rotation.is_zero() {
+            anyhow::bail!("ticket key rotation interval must not be zero");
+        }
+        let rng = SystemRandom::new();
+        let keys = TicketKeys {
+            current: generate_key(&rng)?,
+            previous: None,
+            rotate_at: Instant::now() + rotation,
+        };
+        Ok(Self {
+            rotation,
+            rng,
+            keys: Mutex::new(keys),
+        })
+    }
+
+    fn with_keys<R>(&self, f: impl FnOnce(&TicketKeys) -> R) -> Option<R> {
+        let mut keys = self.keys.lock().ok()?;
+        let now = Instant::now();
+        if now >= keys.rotate_at {
+            let next = generate_key(&self.rng).ok()?;
+            let expired = now >= keys.rotate_at + self.rotation;
+            let current = std::mem::replace(&mut keys.current, next);
+            // After a long idle period the current key is older than a full interval as well.
+            keys.previous = (!expired).then_some(current);
+            keys.rotate_at = now + self.rotation;
+        }
+        Some(f(&keys))
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut out = Vec::with_capacity(NONCE_LEN + plain.len() + CHACHA20_POLY1305.tag_len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(plain);
+        self.with_keys(|keys| {
+            let tag = keys
+                .current
+                .seal_in_place_separate_tag(
+                    Nonce::assume_unique_for_key(nonce),
+                    Aad::empty(),
+                    &mut out[NONCE_LEN..],
+                )
+                .ok()?;
+            out.extend_from_slice(tag.as_ref());
+            Some(out)
+        })?
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < NONCE_LEN + CHACHA20_POLY1305.tag_len() {
+            return None;
+        }
+        let (nonce, sealed) = cipher.split_at(NONCE_LEN);
+        self.with_keys(|keys| {
+            std::iter::once(&keys.current)
+                .chain(keys.previous.as_ref())
+                .find_map(|key| {
+                    let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+                    let mut buf = sealed.to_vec();
+                    let plain = key.open_in_place(nonce, Aad::empty(), &mut buf).ok()?;
+                    Some(plain.to_vec())
+                })
+        })?
+    }
+}
+
+fn generate_key(rng: &SystemRandom) -> anyhow::Result<LessSafeKey> {
+    let mut key = [0; 32];
+    rng.fill(&mut key)
+        .map_err(|_| anyhow::anyhow!("failed to generate ticket key"))?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &key)
+        .map_err(|_| anyhow::anyhow!("invalid ticket key"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticket_survives_one_rotation() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(60)).unwrap();
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session");
+
+        let rotate = |keys: &mut TicketKeys| keys.rotate_at = Instant::now();
+        rotate(&mut ticketer.keys.lock().unwrap());
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session");
+
+        rotate(&mut ticketer.keys.lock().unwrap());
+        assert!(ticketer.decrypt(&ticket).is_none());
+    }
+
+    #[test]
+    fn reject_tampered_ticket() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(60)).unwrap();
+        let mut ticket = ticketer.encrypt(b"session").unwrap();
+        *ticket.last_mut().unwrap() ^= 1;
+        assert!(ticketer.decrypt(&ticket).is_none());
+        assert!(ticketer.decrypt(&ticket[..4]).is_none());
+    }
+}
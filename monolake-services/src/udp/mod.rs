@@ -0,0 +1,7 @@
+//! Udp specific Services
+//!
+//! - [`proxy`]: Proxy relaying the datagrams of client sessions to upstream servers with
+//!   [`UdpProxyService`].
+pub mod proxy;
+
+pub use proxy::UdpProxyService;
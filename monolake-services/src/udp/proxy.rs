@@ -0,0 +1,410 @@
+//! UDP proxy relaying the datagrams of client sessions to upstream servers.
+//!
+//! This module provides a proxy service for datagram protocols such as DNS or syslog. Sessions
+//! are tracked per client address by the UDP listener of `monolake_core`. Each session is
+//! relayed to an upstream selected by a [`LoadBalancer`] through a dedicated connected socket, so
+//! that the replies of the upstream can be sent back to the client, and expires after some idle
+//! time.
+//!
+//! # Key Components
+//!
+//! - [`UdpProxyService`]: The service relaying sessions to upstream servers.
+//! - [`UdpProxyServiceFactory`]: Factory for creating `UdpProxyService` instances.
+//! - [`UdpProxyStats`]: Session and byte counters of a service.
+//!
+//! # Features
+//!
+//! - Upstream selection with the configured load balancing strategy, once per session
+//! - Idle session expiry, a later datagram of the client opens a new session
+//! - Sessions end when the upstream is unreachable, so that the next one may pick another upstream
+//!
+//! # Usage
+//!
+//! `UdpProxyService` is the innermost service of a UDP stack:
+//!
+//! ```ignore
+//! use service_async::stack::FactoryStack;
+//!
+//! use crate::udp::proxy::UdpProxyService;
+//!
+//! let config = UdpRouteConfig { /* ... */ };
+//! let stack = FactoryStack::new(config.clone())
+//!     .replace(UdpProxyService::factory(config, UdpProxyTimeout::default()))
+//!     // ... other layers ...
+//!     ;
+//!
+//! let service = stack.make_async().await.unwrap();
+//! // Use the service to handle the sessions of a UDP listener
+//! ```
+use std::{
+    cell::Cell,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    rc::Rc,
+    time::Duration,
+};
+
+use monoio::{net::udp::UdpSocket, time::Instant};
+use monolake_core::{listener::AcceptedStream, udp::UdpSession};
+use serde::{Deserialize, Serialize};
+use service_async::{AsyncMakeService, MakeService, Service};
+use tracing::{debug, info};
+
+use crate::common::selector::{
+    IntoWeightedEndpoint, LoadBalanceError, LoadBalanceStrategy, LoadBalancer, Select,
+};
+
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// UDP proxy relaying client sessions to upstream servers.
+///
+/// For implementation details and example usage, see the
+/// [module level documentation](crate::udp::proxy).
+pub struct UdpProxyService {
+    endpoints: LoadBalancer<Endpoint>,
+    timeout: UdpProxyTimeout,
+    stats: Rc<UdpProxyStats>,
+}
+
+/// Timeouts of the UDP proxy.
+#[derive(Debug, Clone, Copy)]
+pub struct UdpProxyTimeout {
+    /// Expire sessions when no datagram is relayed in either direction for this duration.
+    pub idle_timeout: Duration,
+}
+
+impl Default for UdpProxyTimeout {
+    fn default() -> Self {
+        Self {
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+/// Session and byte counters of a [`UdpProxyService`].
+///
+/// Counters are kept per worker thread.
+#[derive(Debug, Default)]
+pub struct UdpProxyStats {
+    sessions: Cell<u64>,
+    active: Cell<u64>,
+    sent: Cell<u64>,
+    received: Cell<u64>,
+}
+
+impl UdpProxyStats {
+    /// Number of sessions relayed to an upstream.
+    pub fn sessions(&self) -> u64 {
+        self.sessions.get()
+    }
+
+    /// Number of sessions currently relayed.
+    pub fn active(&self) -> u64 {
+        self.active.get()
+    }
+
+    /// Bytes sent from clients to upstreams.
+    pub fn sent(&self) -> u64 {
+        self.sent.get()
+    }
+
+    /// Bytes received from upstreams and sent to clients.
+    pub fn received(&self) -> u64 {
+        self.received.get()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UdpProxyError {
+    #[error("udp proxy serves udp sessions only")]
+    NotUdp,
+    #[error("upstream {0} socket error: {1}")]
+    Upstream(SocketAddr, io::Error),
+    #[error("client socket error: {0}")]
+    Client(io::Error),
+}
+
+/// Accepted streams which may be UDP sessions.
+pub trait UdpStream {
+    fn into_udp(self) -> Option<UdpSession>;
+}
+
+impl UdpStream for AcceptedStream {
+    #[inline]
+    fn into_udp(self) -> Option<UdpSession> {
+        match self {
+            AcceptedStream::Udp(session) => Some(session),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S> UdpStream for crate::tls::RustlsStream<S> {
+    #[inline]
+    fn into_udp(self) -> Option<UdpSession> {
+        None
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S> UdpStream for monoio_native_tls::TlsStream<S> {
+    #[inline]
+    fn into_udp(self) -> Option<UdpSession> {
+        None
+    }
+}
+
+impl UdpProxyService {
+    pub fn new(endpoints: LoadBalancer<Endpoint>, timeout: UdpProxyTimeout) -> Self {
+        UdpProxyService {
+            endpoints,
+            timeout,
+            stats: Default::default(),
+        }
+    }
+
+    pub const fn factory(
+        config: UdpRouteConfig,
+        timeout: UdpProxyTimeout,
+    ) -> UdpProxyServiceFactory {
+        UdpProxyServiceFactory { config, timeout }
+    }
+
+    /// Counters of the sessions relayed by this service.
+    pub fn stats(&self) -> &UdpProxyStats {
+        &self.stats
+    }
+}
+
+impl<S, CX> Service<(S, CX)> for UdpProxyService
+where
+    S: UdpStream,
+{
+    type Response = ();
+    type Error = UdpProxyError;
+
+    async fn call(&self, (stream, _ctx): (S, CX)) -> Result<Self::Response, Self::Error> {
+        let mut session = stream.into_udp().ok_or(UdpProxyError::NotUdp)?;
+        let Ok(&Endpoint::Socket(addr)) = self.endpoints.select(&());
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let upstream = UdpSocket::bind(local).map_err(|e| UdpProxyError::Upstream(addr, e))?;
+        upstream
+            .connect(addr)
+            .await
+            .map_err(|e| UdpProxyError::Upstream(addr, e))?;
+        let peer = session.peer_addr();
+        debug!("udp proxy session of {peer} relayed to {addr}");
+
+        let stats = &self.stats;
+        stats.sessions.set(stats.sessions.get() + 1);
+        stats.active.set(stats.active.get() + 1);
+        let sent = Cell::new(0);
+        let received = Cell::new(0);
+        let last_activity = Cell::new(Instant::now());
+        let record = |counter: &Cell<u64>, total: &Cell<u64>, n: usize| {
+            counter.set(counter.get() + n as u64);
+            total.set(total.get() + n as u64);
+            last_activity.set(Instant::now());
+        };
+
+        let sender = session.sender();
+        let to_upstream = async {
+            while let Some(datagram) = session.recv().await {
+                let n = datagram.len();
+                upstream
+                    .send(datagram)
+                    .await
+                    .0
+                    .map_err(|e| UdpProxyError::Upstream(addr, e))?;
+                record(&sent, &stats.sent, n);
+            }
+            Ok(())
+        };
+        let to_client = async {
+            let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+            loop {
+                let (result, b) = upstream.recv(buf).await;
+                let n = result.map_err(|e| UdpProxyError::Upstream(addr, e))?;
+                let (result, b) = sender.send(b).await;
+                result.map_err(UdpProxyError::Client)?;
+                record(&received, &stats.received, n);
+                buf = b;
+            }
+        };
+        let idle = async {
+            loop {
+                let deadline = last_activity.get() + self.timeout.idle_timeout;
+                if Instant::now() >= deadline {
+                    return;
+                }
+                monoio::time::sleep_until(deadline).await;
+            }
+        };
+        let result = monoio::select! {
+            result = to_upstream => result,
+            result = to_client => result,
+            _ = idle => Ok(()),
+        };
+        stats.active.set(stats.active.get() - 1);
+
+        info!(
+            "udp proxy session of {peer} to {addr} ended, sent {} bytes, received {} bytes",
+            sent.get(),
+            received.get()
+        );
+        result
+    }
+}
+
+/// Factory for creating `UdpProxyService` instances.
+///
+/// Counters of the previous service are carried over when the service is rebuilt.
+pub struct UdpProxyServiceFactory {
+    config: UdpRouteConfig,
+    timeout: UdpProxyTimeout,
+}
+
+impl UdpProxyServiceFactory {
+    fn make(&self, old: Option<&UdpProxyService>) -> Result<UdpProxyService, LoadBalanceError> {
+        let endpoints = LoadBalancer::try_from_upstreams(
+            self.config.load_balancer,
+            self.config.upstreams.clone(),
+        )?;
+        let mut svc = UdpProxyService::new(endpoints, self.timeout);
+        if let Some(old) = old {
+            svc.stats = old.stats.clone();
+        }
+        Ok(svc)
+    }
+}
+
+impl MakeService for UdpProxyServiceFactory {
+    type Service = UdpProxyService;
+    type Error = LoadBalanceError;
+
+    fn make_via_ref(&self, old: Option<&Self::Service>) -> Result<Self::Service, Self::Error> {
+        self.make(old)
+    }
+}
+
+impl AsyncMakeService for UdpProxyServiceFactory {
+    type Service = UdpProxyService;
+    type Error = LoadBalanceError;
+
+    async fn make_via_ref(
+        &self,
+        old: Option<&Self::Service>,
+    ) -> Result<Self::Service, Self::Error> {
+        self.make(old)
+    }
+}
+
+/// Upstream servers of the UDP proxy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UdpRouteConfig {
+    #[serde(default)]
+    pub load_balancer: LoadBalanceStrategy,
+
+    /// A list of upstream servers sessions are relayed to.
+    pub upstreams: Vec<Upstream>,
+}
+
+const fn default_weight() -> u16 {
+    1
+}
+
+/// Configuration for an upstream server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Upstream {
+    /// The endpoint of the upstream server.
+    pub endpoint: Endpoint,
+
+    /// The weight of this upstream for load balancing purposes.
+    #[serde(default = "default_weight")]
+    pub weight: u16,
+}
+
+impl IntoWeightedEndpoint for Upstream {
+    type Endpoint = Endpoint;
+
+    #[inline]
+    fn into_weighted_endpoint(self) -> (Self::Endpoint, u16) {
+        (self.endpoint, self.weight)
+    }
+}
+
+/// Address of an upstream server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Endpoint {
+    /// A socket address endpoint.
+    Socket(SocketAddr),
+}
+
+#[cfg(test)]
+mod tests {
+    use monolake_core::udp::UdpListener;
+
+    use super::*;
+
+    /// Echo the datagrams sent to the returned address.
+    fn echo() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        monoio::spawn(async move {
+            let mut buf = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+            loop {
+                let (result, b) = socket.recv_from(buf).await;
+                let Ok((_, peer)) = result else {
+                    return;
+                };
+                let (result, b) = socket.send_to(b, peer).await;
+                result.unwrap();
+                buf = b;
+                buf.clear();
+            }
+        });
+        addr
+    }
+
+    #[monoio::test(timer_enabled = true)]
+    async fn relay_and_expiry() {
+        let upstreams = vec![Upstream {
+            endpoint: Endpoint::Socket(echo()),
+            weight: 1,
+        }];
+        let endpoints =
+            LoadBalancer::try_from_upstreams(LoadBalanceStrategy::First, upstreams).unwrap();
+        let timeout = UdpProxyTimeout {
+            idle_timeout: Duration::from_millis(200),
+        };
+        let service = UdpProxyService::new(endpoints, timeout);
+
+        let mut listener = UdpListener::bind("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+        let listening = listener.local_addr().unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&b"ping"[..], listening).await.0.unwrap();
+        let session = listener.accept().await.unwrap();
+
+        let exchange = async {
+            let (result, reply) = client.recv_from(vec![0; 16]).await;
+            let (n, from) = result.unwrap();
+            // Replies come from the address the client sent to.
+            assert_eq!(from, listening);
+            reply[..n].to_vec()
+        };
+        let (served, reply) =
+            monoio::join!(service.call((AcceptedStream::Udp(session), ())), exchange);
+        // The session expired once idle.
+        served.unwrap();
+        assert_eq!(reply, b"ping");
+        let stats = service.stats();
+        assert_eq!((stats.sessions(), stats.active()), (1, 0));
+        assert_eq!((stats.sent(), stats.received()), (4, 4));
+    }
+}
@@ -2,11 +2,17 @@ use certain_map::Param;
 #[cfg(feature = "openid")]
 use monolake_services::http::handlers::openid::OpenIdConfig;
 use monolake_services::{
+    common::{ip_filter::IpFilterConfig, rate_limit::RateLimitConfig},
     http::{
-        handlers::{route::RouteConfig as HttpRouteConfig, upstream::HttpUpstreamTimeout},
-        HttpServerTimeout, HttpVersion,
+        handlers::{
+            forward_proxy::ForwardProxyConfig, ip_filter::RequestIpFilterConfig,
+            route::RouteConfig as HttpRouteConfig, upstream::HttpUpstreamTimeout,
+        },
+        Http2Settings, HttpServerTimeout, HttpVersion, RequestLimitConfig,
     },
+    tcp::proxy::{TcpProxyTimeout, TcpRouteConfig},
     thrift::{ttheader::ThriftServerTimeout, RouteConfig as ThriftRouteConfig},
+    udp::proxy::{UdpProxyTimeout, UdpRouteConfig},
 };
 
 use super::ServerConfig;
@@ -15,10 +21,57 @@ impl Param<HttpServerTimeout> for ServerConfig {
     #[inline]
     fn param(&self) -> HttpServerTimeout {
         match &self.protocol {
-            super::ServerProtocolConfig::Http { server_timeout, .. } => *server_timeout,
+            super::ServerProtocolConfig::Http { server_timeout, .. }
+            | super::ServerProtocolConfig::ForwardProxy { server_timeout, .. } => *server_timeout,
             super::ServerProtocolConfig::Thrift { .. } => {
                 panic!("extract http server timeout from thrift config")
             }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract http server timeout from l4 config")
+            }
+        }
+    }
+}
+
+impl Param<Http2Settings> for ServerConfig {
+    #[inline]
+    fn param(&self) -> Http2Settings {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http { http2, .. } => *http2,
+            super::ServerProtocolConfig::ForwardProxy { .. } => Http2Settings::default(),
+            super::ServerProtocolConfig::Thrift { .. } => {
+                panic!("extract http2 settings from thrift config")
+            }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract http2 settings from l4 config")
+            }
+        }
+    }
+}
+
+impl Param<RequestLimitConfig> for ServerConfig {
+    #[inline]
+    fn param(&self) -> RequestLimitConfig {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http { request_limits, .. } => request_limits.clone(),
+            super::ServerProtocolConfig::ForwardProxy { request_limits, .. } => {
+                RequestLimitConfig {
+                    limits: *request_limits,
+                    route_body_sizes: Vec::new(),
+                }
+            }
+            super::ServerProtocolConfig::Thrift { .. } => {
+                panic!("extract request limits from thrift config")
+            }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract request limits from l4 config")
+            }
         }
     }
 }
@@ -29,10 +82,18 @@ impl Param<HttpUpstreamTimeout> for ServerConfig {
         match &self.protocol {
             super::ServerProtocolConfig::Http {
                 upstream_timeout, ..
+            }
+            | super::ServerProtocolConfig::ForwardProxy {
+                upstream_timeout, ..
             } => *upstream_timeout,
             super::ServerProtocolConfig::Thrift { .. } => {
                 panic!("extract http upstream timeout from thrift config")
             }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract http upstream timeout from l4 config")
+            }
         }
     }
 }
@@ -42,9 +103,15 @@ impl Param<ThriftServerTimeout> for ServerConfig {
     fn param(&self) -> ThriftServerTimeout {
         match &self.protocol {
             super::ServerProtocolConfig::Thrift { server_timeout, .. } => *server_timeout,
-            super::ServerProtocolConfig::Http { .. } => {
+            super::ServerProtocolConfig::Http { .. }
+            | super::ServerProtocolConfig::ForwardProxy { .. } => {
                 panic!("extract thrift server timeout from http config")
             }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract thrift server timeout from l4 config")
+            }
         }
     }
 }
@@ -64,6 +131,57 @@ impl Param<Vec<HttpRouteConfig>> for ServerConfig {
             super::ServerProtocolConfig::Thrift { .. } => {
                 panic!("extract http routes from thrift config")
             }
+            super::ServerProtocolConfig::ForwardProxy { .. } => {
+                panic!("extract http routes from forward proxy config")
+            }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract http routes from l4 config")
+            }
+        }
+    }
+}
+
+impl Param<Option<RateLimitConfig>> for ServerConfig {
+    #[inline]
+    fn param(&self) -> Option<RateLimitConfig> {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http { rate_limit, .. }
+            | super::ServerProtocolConfig::Thrift { rate_limit, .. } => rate_limit.clone(),
+            super::ServerProtocolConfig::ForwardProxy { .. } => None,
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract rate limit from l4 config")
+            }
+        }
+    }
+}
+
+impl Param<Option<IpFilterConfig>> for ServerConfig {
+    #[inline]
+    fn param(&self) -> Option<IpFilterConfig> {
+        self.ip_filter.clone()
+    }
+}
+
+impl Param<RequestIpFilterConfig> for ServerConfig {
+    #[inline]
+    fn param(&self) -> RequestIpFilterConfig {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http {
+                request_ip_filter, ..
+            } => request_ip_filter.clone(),
+            super::ServerProtocolConfig::ForwardProxy { .. } => RequestIpFilterConfig::default(),
+            super::ServerProtocolConfig::Thrift { .. } => {
+                panic!("extract request ip filter from thrift config")
+            }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract request ip filter from l4 config")
+            }
         }
     }
 }
@@ -73,9 +191,34 @@ impl Param<ThriftRouteConfig> for ServerConfig {
     fn param(&self) -> ThriftRouteConfig {
         match &self.protocol {
             super::ServerProtocolConfig::Thrift { route, .. } => route.clone(),
-            super::ServerProtocolConfig::Http { .. } => {
+            super::ServerProtocolConfig::Http { .. }
+            | super::ServerProtocolConfig::ForwardProxy { .. } => {
                 panic!("extract thrift routes from http config")
             }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract thrift routes from l4 config")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Param<Vec<(http::uri::Authority, monolake_services::tls::UpstreamTls)>> for ServerConfig {
+    #[inline]
+    fn param(&self) -> Vec<(http::uri::Authority, monolake_services::tls::UpstreamTls)> {
+        match &self.protocol {
+            super::ServerProtocolConfig::Http { upstream_tls, .. } => upstream_tls.clone(),
+            super::ServerProtocolConfig::ForwardProxy { .. } => Vec::new(),
+            super::ServerProtocolConfig::Thrift { .. } => {
+                panic!("extract upstream tls from thrift config")
+            }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract upstream tls from l4 config")
+            }
         }
     }
 }
@@ -87,6 +230,59 @@ impl Param<monolake_services::tls::TlsConfig> for ServerConfig {
     }
 }
 
+impl Param<ForwardProxyConfig> for ServerConfig {
+    #[inline]
+    fn param(&self) -> ForwardProxyConfig {
+        match &self.protocol {
+            super::ServerProtocolConfig::ForwardProxy { forward_proxy, .. } => {
+                forward_proxy.clone()
+            }
+            _ => panic!("extract forward proxy config from non forward proxy config"),
+        }
+    }
+}
+
+impl Param<TcpRouteConfig> for ServerConfig {
+    #[inline]
+    fn param(&self) -> TcpRouteConfig {
+        match &self.protocol {
+            super::ServerProtocolConfig::Tcp { route, .. } => route.clone(),
+            _ => panic!("extract tcp routes from non tcp config"),
+        }
+    }
+}
+
+impl Param<TcpProxyTimeout> for ServerConfig {
+    #[inline]
+    fn param(&self) -> TcpProxyTimeout {
+        match &self.protocol {
+            super::ServerProtocolConfig::Tcp { timeout, .. }
+            | super::ServerProtocolConfig::TlsPassthrough { timeout, .. } => *timeout,
+            _ => panic!("extract tcp timeout from non tcp config"),
+        }
+    }
+}
+
+impl Param<UdpRouteConfig> for ServerConfig {
+    #[inline]
+    fn param(&self) -> UdpRouteConfig {
+        match &self.protocol {
+            super::ServerProtocolConfig::Udp { route, .. } => route.clone(),
+            _ => panic!("extract udp routes from non udp config"),
+        }
+    }
+}
+
+impl Param<UdpProxyTimeout> for ServerConfig {
+    #[inline]
+    fn param(&self) -> UdpProxyTimeout {
+        match &self.protocol {
+            super::ServerProtocolConfig::Udp { timeout, .. } => *timeout,
+            _ => panic!("extract udp timeout from non udp config"),
+        }
+    }
+}
+
 impl Param<HttpVersion> for ServerConfig {
     #[inline]
     fn param(&self) -> HttpVersion {
@@ -94,10 +290,19 @@ impl Param<HttpVersion> for ServerConfig {
             super::ServerProtocolConfig::Http {
                 upstream_http_version,
                 ..
+            }
+            | super::ServerProtocolConfig::ForwardProxy {
+                upstream_http_version,
+                ..
             } => *upstream_http_version,
             super::ServerProtocolConfig::Thrift { .. } => {
                 panic!("extract http version from thrift config")
             }
+            super::ServerProtocolConfig::Tcp { .. }
+            | super::ServerProtocolConfig::TlsPassthrough { .. }
+            | super::ServerProtocolConfig::Udp { .. } => {
+                panic!("extract http version from l4 config")
+            }
         }
     }
 }
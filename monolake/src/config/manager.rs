@@ -20,7 +20,7 @@ type ServiceConfigMap = HashMap<String, ServiceConfig<ListenerConfig, ServerConf
 pub struct StaticFileConfigManager<F, LF, FP, LFP>
 where
     FP: Fn(ServerConfig) -> F,
-    LFP: Fn(ListenerConfig) -> LF,
+    LFP: Fn(ListenerConfig, &ServerConfig) -> LF,
 {
     online_config_content: RefCell<Vec<u8>>,
     online_services: RefCell<ServiceConfigMap>,
@@ -37,7 +37,7 @@ where
     LFP: 'static,
     F: AsyncMakeService,
     FP: Fn(ServerConfig) -> F,
-    LFP: Fn(ListenerConfig) -> LF,
+    LFP: Fn(ListenerConfig, &ServerConfig) -> LF,
 {
     pub fn new(
         worker_manager: WorkerManager<F, LF>,
@@ -161,12 +161,15 @@ where
                 Patch::Insert {
                     key,
                     listener_config,
-                    ..
+                    server_config,
                 } => {
                     self.worker_manager
                         .dispatch_service_command(ServiceCommand::Commit(
                             Arc::new(key.to_string()),
-                            (self.listener_factory_provider)(listener_config.clone()),
+                            (self.listener_factory_provider)(
+                                listener_config.clone(),
+                                server_config,
+                            ),
                         ))
                         .await
                         .err()?;
@@ -225,7 +228,9 @@ enum Patch {
     },
     Update {
         key: String,
-        server_config: ServerConfig, // ListenerConfig dynamic update not supported yet
+        // ListenerConfig dynamic update not supported yet, which includes the certificate of quic
+        // listeners
+        server_config: ServerConfig,
     },
     Delete {
         key: String,
@@ -1,15 +1,36 @@
+#[cfg(feature = "http3")]
+use std::sync::Arc;
 use std::{collections::HashMap, path::Path, time::Duration};
 
+use http::HeaderValue;
 use monolake_core::{
     config::{RuntimeConfig, ServiceConfig},
     listener::ListenerBuilder,
+    orchestrator::ConnectionLimitConfig,
+    udp::DEFAULT_MAX_SESSIONS,
 };
 use monolake_services::{
+    common::{
+        ip_filter::{IpFilterConfig, IpNet},
+        rate_limit::RateLimitConfig,
+    },
     http::{
-        handlers::{route::RouteConfig as HttpRouteConfig, upstream::HttpUpstreamTimeout},
-        HttpServerTimeout, HttpVersion,
+        handlers::{
+            ext_authz::ExtAuthzConfig,
+            forward_proxy::{ForwardProxyConfig, ProxyAuth},
+            global_rate_limit::GlobalRateLimitConfig,
+            ip_filter::RequestIpFilterConfig,
+            route::RouteConfig as HttpRouteConfig,
+            upstream::HttpUpstreamTimeout,
+        },
+        Http2Settings, HttpServerTimeout, HttpVersion, RequestLimitConfig, RequestLimits,
+    },
+    tcp::{
+        passthrough::SniRouteConfig,
+        proxy::{TcpProxyTimeout, TcpRouteConfig},
     },
     thrift::{ttheader::ThriftServerTimeout, RouteConfig as ThriftRouteConfig},
+    udp::proxy::{UdpProxyTimeout, UdpRouteConfig},
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
@@ -29,6 +50,10 @@ pub enum ProxyType {
     #[default]
     Http,
     Thrift,
+    ForwardProxy,
+    Tcp,
+    TlsPassthrough,
+    Udp,
 }
 
 #[derive(Debug, Clone)]
@@ -39,6 +64,13 @@ pub struct ServerConfig {
     pub tls: monolake_services::tls::TlsConfig,
     #[cfg(feature = "openid")]
     pub auth_config: Option<AuthConfig>,
+    // Crypto config of quic listeners, built from the tls config of the server
+    #[cfg(feature = "http3")]
+    pub quic: Option<Arc<monolake_core::quic::ServerConfig>>,
+    // Allow and deny lists of the peer addresses of connections
+    pub ip_filter: Option<IpFilterConfig>,
+    // Limits of the concurrent connections of the listener, applied when it is created
+    pub connection_limit: Option<ConnectionLimitConfig>,
     pub protocol: ServerProtocolConfig,
 }
 
@@ -46,11 +78,20 @@ pub struct ServerConfig {
 pub struct ServerUserConfig {
     pub name: String,
     pub tls: Option<TlsUserConfig>,
+    // Filter of connections by peer address, checked before PROXY protocol headers and TLS
+    #[serde(default)]
+    pub ip_filter: Option<IpFilterConfig>,
+    // Limits of the concurrent connections of the listener, across workers, per worker and per
+    // client IP. Changes apply when the listener is created
+    #[serde(default)]
+    pub connection_limit: Option<ConnectionLimitConfig>,
 
     #[serde(flatten)]
     pub protocol_config: ServerProtocolUserConfig,
 }
 
+// Built once per server, the size of the variants does not matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone)]
 pub enum ServerProtocolConfig {
     Http {
@@ -58,11 +99,45 @@ pub enum ServerProtocolConfig {
         server_timeout: HttpServerTimeout,
         upstream_timeout: HttpUpstreamTimeout,
         upstream_http_version: HttpVersion,
+        http2: Http2Settings,
+        upstream_http2: Http2Settings,
         opt_handlers: HttpOptHandlers,
+        #[cfg(feature = "tls")]
+        upstream_tls: Vec<(http::uri::Authority, monolake_services::tls::UpstreamTls)>,
+        // Alt-Svc header advertising the quic listener of the server
+        alt_svc: Option<HeaderValue>,
+        rate_limit: Option<RateLimitConfig>,
+        global_rate_limit: Option<GlobalRateLimitConfig>,
+        ext_authz: Option<ExtAuthzConfig>,
+        request_ip_filter: RequestIpFilterConfig,
+        request_limits: RequestLimitConfig,
+        #[cfg(feature = "jwt")]
+        jwt: Option<monolake_services::http::handlers::jwt::JwtConfig>,
     },
     Thrift {
         route: ThriftRouteConfig,
         server_timeout: ThriftServerTimeout,
+        rate_limit: Option<RateLimitConfig>,
+    },
+    ForwardProxy {
+        server_timeout: HttpServerTimeout,
+        upstream_timeout: HttpUpstreamTimeout,
+        upstream_http_version: HttpVersion,
+        forward_proxy: ForwardProxyConfig,
+        request_limits: RequestLimits,
+    },
+    Tcp {
+        route: TcpRouteConfig,
+        timeout: TcpProxyTimeout,
+    },
+    TlsPassthrough {
+        routes: Vec<SniRouteConfig>,
+        timeout: TcpProxyTimeout,
+    },
+    Udp {
+        route: UdpRouteConfig,
+        timeout: UdpProxyTimeout,
+        max_sessions: usize,
     },
 }
 
@@ -71,6 +146,10 @@ pub enum ServerProtocolConfig {
 pub enum ServerProtocolUserConfig {
     Http(ServerHttpUserConfig),
     Thrift(ServerThriftUserConfig),
+    ForwardProxy(ServerForwardProxyUserConfig),
+    Tcp(ServerTcpUserConfig),
+    TlsPassthrough(ServerTlsPassthroughUserConfig),
+    Udp(ServerUdpUserConfig),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -80,8 +159,85 @@ pub struct ServerHttpUserConfig {
     pub timeout: HttpTimeout,
     #[serde(default)]
     pub upstream_http_version: HttpVersion,
+    // HTTP/2 settings of client connections
+    #[serde(default)]
+    pub http2: Http2Settings,
+    // HTTP/2 settings of upstream connections
+    #[serde(default)]
+    pub upstream_http2: Http2Settings,
     #[serde(default)]
     pub http_opt_handlers: HttpOptHandlers,
+    // Rate limit of all the requests of the server, routes may have their own.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    // Quotas shared by a fleet of proxies, checked with an external rate limit service.
+    #[serde(default)]
+    pub global_rate_limit: Option<GlobalRateLimitConfig>,
+    // Authorization of requests delegated to an external policy service.
+    #[serde(default)]
+    pub ext_authz: Option<ExtAuthzConfig>,
+    // Filter of requests by client address, routes may have their own.
+    #[serde(default)]
+    pub request_ip_filter: Option<IpFilterConfig>,
+    // Proxies whose X-Forwarded-For headers are trusted to tell client addresses
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    // Limits of the URI, headers and body of requests, routes may have their own body limit.
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+    // Bearer token authentication of requests, routes may require scopes and claims.
+    #[cfg(feature = "jwt")]
+    #[serde(default)]
+    pub jwt: Option<monolake_services::http::handlers::jwt::JwtConfig>,
+    // OpenID Connect login of users, routes may opt out.
+    #[cfg(feature = "openid")]
+    #[serde(default)]
+    pub openid: Option<monolake_services::http::handlers::openid::OpenIdConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerForwardProxyUserConfig {
+    #[serde(default)]
+    pub timeout: HttpTimeout,
+    #[serde(default)]
+    pub upstream_http_version: HttpVersion,
+    // Destination hosts, exact names or `*.example.com` for all subdomains. All hosts are allowed
+    // when allow_hosts is empty, and deny lists take precedence.
+    #[serde(default)]
+    pub allow_hosts: Vec<String>,
+    #[serde(default)]
+    pub deny_hosts: Vec<String>,
+    // Destination ports, all ports are allowed when allow_ports is empty.
+    #[serde(default)]
+    pub allow_ports: Vec<u16>,
+    #[serde(default)]
+    pub deny_ports: Vec<u16>,
+    // Allow destinations resolving to loopback, private, link-local and other non-public
+    // addresses, which are denied by default.
+    #[serde(default)]
+    pub allow_private: bool,
+    // Require Proxy-Authorization basic credentials.
+    pub auth: Option<ProxyAuthUserConfig>,
+    // Limits of the URI, headers and body of requests.
+    #[serde(default)]
+    pub request_limits: RequestLimits,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyAuthUserConfig {
+    #[serde(default = "default_proxy_realm")]
+    pub realm: String,
+    pub users: Vec<ProxyUserConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyUserConfig {
+    pub username: String,
+    pub password: String,
+}
+
+fn default_proxy_realm() -> String {
+    "monolake".to_string()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,14 +245,114 @@ pub struct ServerThriftUserConfig {
     pub route: ThriftRouteConfig,
     #[serde(default)]
     pub timeout: ThriftTimeout,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerTcpUserConfig {
+    pub route: TcpRouteConfig,
+    #[serde(default)]
+    pub timeout: TcpTimeout,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerTlsPassthroughUserConfig {
+    // Connections are relayed to the first route matching the server name and ALPN of their
+    // ClientHello, without terminating TLS.
+    pub routes: Vec<SniRouteConfig>,
+    #[serde(default)]
+    pub timeout: TcpTimeout,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerUdpUserConfig {
+    // Sessions of client addresses are relayed to an upstream selected when they start.
+    pub route: UdpRouteConfig,
+    #[serde(default)]
+    pub timeout: UdpTimeout,
+    // Sessions tracked by each worker, datagrams of new clients are dropped beyond. 10000 by
+    // default, changes apply when the listener is created.
+    pub max_sessions: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TlsUserConfig {
-    pub key: String,
-    pub chain: String,
+    // PEM encoded key and certificate chain, required unless acme is configured
+    pub key: Option<String>,
+    pub chain: Option<String>,
     #[serde(default)]
     pub stack: TlsStack,
+    // Verify client certificates (mutual TLS). Only supported by the rustls stack.
+    pub client_auth: Option<ClientAuthUserConfig>,
+    // Session cache and tickets. Only supported by the rustls stack.
+    pub session: Option<TlsSessionUserConfig>,
+    // OCSP stapling. Only supported by the rustls stack.
+    pub ocsp: Option<OcspUserConfig>,
+    // Accepted protocol range. native_tls can only enforce tls1.2 as bounds.
+    #[cfg(feature = "tls")]
+    pub min_version: Option<monolake_services::tls::TlsVersion>,
+    #[cfg(feature = "tls")]
+    pub max_version: Option<monolake_services::tls::TlsVersion>,
+    // Cipher suite allow-list by IANA name. Only supported by the rustls stack.
+    pub cipher_suites: Option<Vec<String>>,
+    // Key exchange groups, e.g. "X25519". Only supported by the rustls stack.
+    pub kx_groups: Option<Vec<String>>,
+    // ALPN protocols, defaults to ["h2", "http/1.1"]. Only supported by the rustls stack.
+    pub alpn: Option<Vec<String>>,
+    // Obtain and renew the certificate through ACME. Requires the acme feature and the rustls
+    // stack.
+    pub acme: Option<AcmeUserConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcmeUserConfig {
+    // Directory url of the ACME server
+    pub directory: String,
+    pub domains: Vec<String>,
+    // Account contacts, e.g. "mailto:admin@example.com"
+    #[serde(default)]
+    pub contact: Vec<String>,
+    // Directory where the account key and certificates are stored
+    pub storage: String,
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    pub challenge: monolake_services::tls::AcmeChallengeType,
+    // Extra PEM encoded CA trusted for the ACME server, e.g. the Pebble root
+    pub ca: Option<String>,
+    // Renew the certificate when it expires within this duration. Defaults to 30 days
+    pub renew_before_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientAuthUserConfig {
+    // PEM encoded CA bundle used to verify client certificates
+    pub ca: String,
+    #[cfg(feature = "tls")]
+    #[serde(default)]
+    pub mode: monolake_services::tls::ClientAuthMode,
+    // PEM or DER encoded certificate revocation lists
+    #[serde(default)]
+    pub crls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsSessionUserConfig {
+    // Number of sessions kept in the session cache, 0 disables the cache. Defaults to 256
+    pub cache_size: Option<usize>,
+    // Issue stateless session tickets, with keys shared by all workers
+    #[serde(default)]
+    pub tickets: bool,
+    // Interval after which a new ticket key is generated. Defaults to 6 hours
+    pub ticket_key_rotation_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OcspUserConfig {
+    // DER encoded OCSP response. The responder named in the certificate is queried when unset
+    pub file: Option<String>,
+    // Reload the file or query the responder periodically. Responders default to 1 hour
+    pub refresh_interval_sec: Option<u64>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
@@ -153,6 +409,50 @@ impl From<ThriftTimeout> for ThriftServerTimeout {
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct TcpTimeout {
+    // Connect timeout
+    // Like Nginx 'proxy_connect_timeout'
+    upstream_connect_timeout_sec: Option<u64>,
+    // Close the connection when no byte is relayed in either direction, 600 seconds by default.
+    // Like Nginx stream 'proxy_timeout'
+    idle_timeout_sec: Option<u64>,
+    // Read the ClientHello of tls passthrough connections.
+    // Like Nginx stream 'preread_timeout'
+    client_hello_timeout_sec: Option<u64>,
+}
+
+impl From<TcpTimeout> for TcpProxyTimeout {
+    fn from(t: TcpTimeout) -> Self {
+        TcpProxyTimeout {
+            connect_timeout: t.upstream_connect_timeout_sec.map(Duration::from_secs),
+            idle_timeout: t
+                .idle_timeout_sec
+                .map(Duration::from_secs)
+                .or(TcpProxyTimeout::default().idle_timeout),
+            client_hello_timeout: t.client_hello_timeout_sec.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct UdpTimeout {
+    // Expire sessions when no datagram is relayed in either direction, 60 seconds by default.
+    // Like Nginx stream 'proxy_timeout'
+    idle_timeout_sec: Option<u64>,
+}
+
+impl From<UdpTimeout> for UdpProxyTimeout {
+    fn from(t: UdpTimeout) -> Self {
+        match t.idle_timeout_sec {
+            Some(sec) => UdpProxyTimeout {
+                idle_timeout: Duration::from_secs(sec),
+            },
+            None => UdpProxyTimeout::default(),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum TlsStack {
@@ -176,15 +476,40 @@ pub struct AuthConfig(pub monolake_services::http::handlers::openid::OpenIdConfi
 pub enum ListenerConfig {
     Socket(std::net::SocketAddr),
     Unix(std::path::PathBuf),
+    // UDP address serving HTTP/3. Uses the tls config of the server, or of the server with the
+    // same name on a socket listener, which then advertises it through Alt-Svc. Requires the
+    // http3 feature.
+    Quic(std::net::SocketAddr),
+    // UDP address serving the sessions of udp servers.
+    Udp(std::net::SocketAddr),
 }
 
-impl TryFrom<ListenerConfig> for ListenerBuilder {
-    type Error = std::io::Error;
-
-    fn try_from(value: ListenerConfig) -> Result<Self, Self::Error> {
-        match value {
+impl ListenerConfig {
+    /// Build the listener, quic listeners take their crypto config from the server.
+    pub fn into_builder(self, server: &ServerConfig) -> std::io::Result<ListenerBuilder> {
+        match self {
             ListenerConfig::Socket(addr) => ListenerBuilder::bind_tcp(addr, Default::default()),
             ListenerConfig::Unix(addr) => ListenerBuilder::bind_unix(addr),
+            ListenerConfig::Udp(addr) => {
+                let max_sessions = match server.protocol {
+                    ServerProtocolConfig::Udp { max_sessions, .. } => max_sessions,
+                    _ => DEFAULT_MAX_SESSIONS,
+                };
+                ListenerBuilder::bind_udp(addr, max_sessions)
+            }
+            #[cfg(feature = "http3")]
+            ListenerConfig::Quic(addr) => match &server.quic {
+                Some(quic) => ListenerBuilder::bind_quic(addr, quic.clone()),
+                None => Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "quic listener without quic config",
+                )),
+            },
+            #[cfg(not(feature = "http3"))]
+            ListenerConfig::Quic(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "quic listeners require the http3 feature",
+            )),
         }
     }
 }
@@ -237,44 +562,209 @@ impl Config {
 pub fn build_server_config(
     servers: HashMap<String, ServiceConfig<ListenerConfig, ServerUserConfig>>,
 ) -> anyhow::Result<HashMap<String, ServiceConfig<ListenerConfig, ServerConfig>>> {
+    // Servers with a quic listener share the certificate of, and are advertised by, the servers
+    // with the same name on socket listeners.
+    let mut quic_ports = HashMap::new();
+    #[cfg(feature = "http3")]
+    let mut shared_tls = HashMap::new();
+    for ServiceConfig { listener, server } in servers.values() {
+        match (listener, &server.tls) {
+            (ListenerConfig::Quic(addr), _) => {
+                quic_ports.insert(server.name.clone(), addr.port());
+            }
+            #[cfg(feature = "http3")]
+            (_, Some(tls)) => {
+                shared_tls.insert(server.name.clone(), tls.clone());
+            }
+            _ => {}
+        }
+    }
+
     let mut servers_new = HashMap::with_capacity(servers.len());
     for (key, server) in servers.into_iter() {
         let ServiceConfig { listener, server } = server;
+        let is_quic = matches!(listener, ListenerConfig::Quic(_));
+        #[cfg(not(feature = "http3"))]
+        if is_quic {
+            anyhow::bail!("quic listeners require monolake to be built with the http3 feature");
+        }
+        if is_quic && !matches!(server.protocol_config, ServerProtocolUserConfig::Http(_)) {
+            anyhow::bail!("quic listeners only serve http");
+        }
+        let is_udp = matches!(server.protocol_config, ServerProtocolUserConfig::Udp(_));
+        if is_udp != matches!(listener, ListenerConfig::Udp(_)) {
+            anyhow::bail!(
+                "server {} must use a udp listener if and only if it is a udp proxy",
+                server.name
+            );
+        }
+        if is_udp && server.tls.is_some() {
+            anyhow::bail!("udp server {} does not support tls", server.name);
+        }
+        if server.tls.is_some()
+            && matches!(
+                server.protocol_config,
+                ServerProtocolUserConfig::TlsPassthrough(_)
+            )
+        {
+            anyhow::bail!(
+                "tls passthrough server {} must not terminate tls",
+                server.name
+            );
+        }
+        #[cfg(feature = "http3")]
+        let (tls_user_config, quic) = if is_quic {
+            let inner = server
+                .tls
+                .clone()
+                .or_else(|| shared_tls.get(&server.name).cloned())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "quic listener of server {} requires tls, on the server or on a socket \
+                         listener of the same name",
+                        server.name
+                    )
+                })?;
+            (None, Some(build_pem_config(inner)?.quic_server_config()?))
+        } else {
+            (server.tls, None)
+        };
+        #[cfg(all(feature = "tls", not(feature = "http3")))]
+        let tls_user_config = server.tls;
         #[cfg(feature = "tls")]
-        let tls = match server.tls {
+        let tls = match tls_user_config {
             Some(inner) => {
-                let chain = monolake_core::util::file_read_sync(&inner.chain)?;
-                let key = monolake_core::util::file_read_sync(&inner.key)?;
-                match inner.stack {
+                let stack = inner.stack;
+                let pem = build_pem_config(inner)?;
+                match stack {
                     TlsStack::Rustls => {
-                        monolake_services::tls::TlsConfig::Rustls((chain, key)).try_into()?
+                        monolake_services::tls::TlsConfig::Rustls(pem).try_into()?
                     }
                     TlsStack::NativeTls => {
-                        monolake_services::tls::TlsConfig::Native((chain, key)).try_into()?
+                        monolake_services::tls::TlsConfig::Native(pem).try_into()?
                     }
                 }
             }
             None => monolake_services::tls::TlsConfig::None,
         };
 
+        #[cfg(feature = "openid")]
+        let mut auth_config = None;
         let protocol = match server.protocol_config {
             ServerProtocolUserConfig::Http(http) => {
+                #[cfg(feature = "openid")]
+                if let Some(openid) = http.openid {
+                    // The cookie key is derived from the secret, which must be long enough.
+                    anyhow::ensure!(
+                        openid.cookie_secret.len() >= 32,
+                        "cookie_secret of the openid config of server {} is shorter than 32 bytes",
+                        server.name
+                    );
+                    auth_config = Some(AuthConfig(openid));
+                }
+                for (name, settings) in [
+                    ("http2", &http.http2),
+                    ("upstream_http2", &http.upstream_http2),
+                ] {
+                    if let Err(e) = settings.validate() {
+                        anyhow::bail!("{name} settings of server {}: {e}", server.name);
+                    }
+                }
+                #[cfg(feature = "jwt")]
+                if let Some(Err(e)) = http.jwt.as_ref().map(|jwt| jwt.validate()) {
+                    anyhow::bail!("jwt settings of server {}: {e}", server.name);
+                }
+                for route in &http.routes {
+                    if let Some(Err(e)) = route.cors.as_ref().map(|cors| cors.validate()) {
+                        anyhow::bail!(
+                            "cors settings of route {} of server {}: {e}",
+                            route.path,
+                            server.name
+                        );
+                    }
+                }
                 let routes = http.routes;
                 let server_timeout = http.timeout.into();
                 let upstream_timeout = http.timeout.into();
                 let upstream_http_version = http.upstream_http_version;
                 let opt_handlers = http.http_opt_handlers;
+                let alt_svc = match quic_ports.get(&server.name) {
+                    Some(port) if !is_quic => {
+                        Some(HeaderValue::from_str(&format!("h3=\":{port}\"; ma=86400"))?)
+                    }
+                    _ => None,
+                };
+                #[cfg(feature = "tls")]
+                let upstream_tls =
+                    monolake_services::http::handlers::upstream::load_upstream_tls(&routes)?;
+                let request_limits = RequestLimitConfig::new(http.request_limits, &routes);
                 ServerProtocolConfig::Http {
                     routes,
                     server_timeout,
                     upstream_timeout,
                     upstream_http_version,
+                    http2: http.http2,
+                    upstream_http2: http.upstream_http2,
                     opt_handlers,
+                    #[cfg(feature = "tls")]
+                    upstream_tls,
+                    alt_svc,
+                    rate_limit: http.rate_limit,
+                    global_rate_limit: http.global_rate_limit,
+                    ext_authz: http.ext_authz,
+                    request_ip_filter: RequestIpFilterConfig {
+                        filter: http.request_ip_filter,
+                        trusted_proxies: http.trusted_proxies,
+                    },
+                    request_limits,
+                    #[cfg(feature = "jwt")]
+                    jwt: http.jwt,
                 }
             }
             ServerProtocolUserConfig::Thrift(thrift) => ServerProtocolConfig::Thrift {
                 route: thrift.route,
                 server_timeout: thrift.timeout.into(),
+                rate_limit: thrift.rate_limit,
+            },
+            ServerProtocolUserConfig::ForwardProxy(proxy) => {
+                let auth = match proxy.auth {
+                    Some(auth) => Some(ProxyAuth::basic(
+                        &auth.realm,
+                        auth.users
+                            .into_iter()
+                            .map(|user| (user.username, user.password)),
+                    )?),
+                    None => None,
+                };
+                ServerProtocolConfig::ForwardProxy {
+                    server_timeout: proxy.timeout.into(),
+                    upstream_timeout: proxy.timeout.into(),
+                    upstream_http_version: proxy.upstream_http_version,
+                    forward_proxy: ForwardProxyConfig {
+                        allow_hosts: proxy.allow_hosts,
+                        deny_hosts: proxy.deny_hosts,
+                        allow_ports: proxy.allow_ports,
+                        deny_ports: proxy.deny_ports,
+                        allow_private: proxy.allow_private,
+                        auth,
+                    },
+                    request_limits: proxy.request_limits,
+                }
+            }
+            ServerProtocolUserConfig::Tcp(tcp) => ServerProtocolConfig::Tcp {
+                route: tcp.route,
+                timeout: tcp.timeout.into(),
+            },
+            ServerProtocolUserConfig::TlsPassthrough(passthrough) => {
+                ServerProtocolConfig::TlsPassthrough {
+                    routes: passthrough.routes,
+                    timeout: passthrough.timeout.into(),
+                }
+            }
+            ServerProtocolUserConfig::Udp(udp) => ServerProtocolConfig::Udp {
+                route: udp.route,
+                timeout: udp.timeout.into(),
+                max_sessions: udp.max_sessions.unwrap_or(DEFAULT_MAX_SESSIONS),
             },
         };
 
@@ -285,7 +775,11 @@ pub fn build_server_config(
                 #[cfg(feature = "tls")]
                 tls,
                 #[cfg(feature = "openid")]
-                auth_config: None,
+                auth_config,
+                #[cfg(feature = "http3")]
+                quic,
+                ip_filter: server.ip_filter,
+                connection_limit: server.connection_limit,
                 protocol,
             },
         };
@@ -294,6 +788,91 @@ pub fn build_server_config(
     Ok(servers_new)
 }
 
+#[cfg(feature = "tls")]
+fn build_pem_config(
+    inner: TlsUserConfig,
+) -> anyhow::Result<monolake_services::tls::PemServerConfig> {
+    let (chain, key) = match (&inner.chain, &inner.key, &inner.acme) {
+        (Some(chain), Some(key), _) => (
+            monolake_core::util::file_read_sync(chain)?,
+            monolake_core::util::file_read_sync(key)?,
+        ),
+        (None, None, Some(_)) => (Vec::new(), Vec::new()),
+        _ => anyhow::bail!("tls requires both chain and key unless acme is configured"),
+    };
+    #[cfg(feature = "acme")]
+    let acme = match inner.acme {
+        Some(acme) => {
+            let mut config = monolake_services::tls::AcmeConfig::new(
+                acme.directory,
+                acme.domains,
+                acme.storage.into(),
+            );
+            config.contact = acme.contact;
+            config.challenge = acme.challenge;
+            if let Some(ca) = acme.ca {
+                config.ca = Some(monolake_core::util::file_read_sync(ca)?);
+            }
+            if let Some(renew_before) = acme.renew_before_sec {
+                config.renew_before = Duration::from_secs(renew_before);
+            }
+            Some(config)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "acme"))]
+    if inner.acme.is_some() {
+        anyhow::bail!("acme requires monolake to be built with the acme feature");
+    }
+    let client_auth = match inner.client_auth {
+        Some(client_auth) => Some(monolake_services::tls::ClientAuthConfig {
+            ca: monolake_core::util::file_read_sync(&client_auth.ca)?,
+            mode: client_auth.mode,
+            crls: client_auth
+                .crls
+                .iter()
+                .map(monolake_core::util::file_read_sync)
+                .collect::<Result<_, _>>()?,
+        }),
+        None => None,
+    };
+    let session = inner.session.map(|session| {
+        let default = monolake_services::tls::SessionResumptionConfig::default();
+        monolake_services::tls::SessionResumptionConfig {
+            cache_size: session.cache_size.unwrap_or(default.cache_size),
+            tickets: session.tickets,
+            ticket_key_rotation: session
+                .ticket_key_rotation_sec
+                .map(Duration::from_secs)
+                .unwrap_or(default.ticket_key_rotation),
+        }
+    });
+    let ocsp = inner.ocsp.map(|ocsp| monolake_services::tls::OcspConfig {
+        source: match ocsp.file {
+            Some(file) => monolake_services::tls::OcspSource::File(file),
+            None => monolake_services::tls::OcspSource::Responder,
+        },
+        refresh_interval: ocsp.refresh_interval_sec.map(Duration::from_secs),
+    });
+    let policy = monolake_services::tls::TlsPolicy {
+        min_version: inner.min_version,
+        max_version: inner.max_version,
+        cipher_suites: inner.cipher_suites,
+        kx_groups: inner.kx_groups,
+        alpn: inner.alpn,
+    };
+    Ok(monolake_services::tls::PemServerConfig {
+        chain,
+        key,
+        client_auth,
+        session,
+        ocsp,
+        policy,
+        #[cfg(feature = "acme")]
+        acme,
+    })
+}
+
 pub fn parse_from_slice<T: DeserializeOwned>(content: &[u8]) -> anyhow::Result<T> {
     // read first non-space u8
     let is_json = match content
@@ -308,3 +887,64 @@ pub fn parse_from_slice<T: DeserializeOwned>(content: &[u8]) -> anyhow::Result<T
         false => toml::from_str::<T>(&String::from_utf8_lossy(content)).map_err(Into::into),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_server(settings: &str) -> String {
+        format!(
+            r#"
+            [servers.demo]
+            name = "demo"
+            proxy_type = "http"
+            listener = {{ type = "socket", value = "127.0.0.1:8080" }}
+            routes = [{{ path = "/", upstreams = [{{ endpoint = {{ type = "uri", value = "http://127.0.0.1:9080" }} }}] }}]
+            {settings}
+            "#
+        )
+    }
+
+    #[test]
+    fn http2_settings() {
+        let parse = |http2: &str| Config::parse_service_config(http_server(http2).as_bytes());
+        parse("http2 = { max_frame_size = 16384, idle_timeout_sec = 60 }").unwrap();
+        let e = parse("http2 = { max_frame_size = 1024 }").unwrap_err();
+        assert!(e.to_string().contains("max_frame_size"), "{e}");
+        let e = parse("upstream_http2 = { max_frame_size = 16777216 }").unwrap_err();
+        assert!(e.to_string().contains("upstream_http2"), "{e}");
+        let e = parse("http2 = { keepalive_interval_sec = 0 }").unwrap_err();
+        assert!(e.to_string().contains("keepalive_interval_sec"), "{e}");
+        let e = parse("http2 = { idle_timeout_sec = 0 }").unwrap_err();
+        assert!(e.to_string().contains("idle_timeout_sec"), "{e}");
+    }
+
+    #[cfg(feature = "jwt")]
+    #[test]
+    fn jwt_settings() {
+        let parse = |jwt: &str| Config::parse_service_config(http_server(jwt).as_bytes());
+        parse(r#"jwt = { jwks = { type = "uri", value = "https://idp/jwks.json" } }"#).unwrap();
+        let e = parse(r#"jwt = { jwks = { type = "uri", value = "http://idp/jwks.json" } }"#)
+            .unwrap_err();
+        assert!(e.to_string().contains("allow_http_jwks"), "{e}");
+        parse(
+            r#"jwt = { jwks = { type = "uri", value = "http://idp/jwks.json" }, allow_http_jwks = true }"#,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn cors_settings() {
+        let parse = |cors: &str| {
+            // The cors settings go in the route of the server.
+            let route = r#""http://127.0.0.1:9080" } }]"#;
+            let server = http_server("").replace(route, &format!("{route}, cors = {cors}"));
+            Config::parse_service_config(server.as_bytes())
+        };
+        parse(r#"{ allow_origins = ["*"] }"#).unwrap();
+        parse(r#"{ allow_origins = ["https://app.example.com"], allow_credentials = true }"#)
+            .unwrap();
+        let e = parse(r#"{ allow_origins = ["*"], allow_credentials = true }"#).unwrap_err();
+        assert!(e.to_string().contains("allow_credentials"), "{e}");
+    }
+}
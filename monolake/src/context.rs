@@ -1,4 +1,4 @@
-use monolake_core::context::{PeerAddr, RemoteAddr};
+use monolake_core::context::{PeerAddr, PeerCertificate, RemoteAddr};
 
 // This struct should be a app-defined struct.
 // Framework should not bind it.
@@ -10,6 +10,8 @@ certain_map::certain_map! {
         peer_addr: PeerAddr,
         // Set by ProxyProtocolService
         remote_addr: Option<RemoteAddr>,
+        // Set by RustlsService
+        peer_cert: Option<PeerCertificate>,
     }
 }
 
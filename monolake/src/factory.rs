@@ -4,23 +4,36 @@ use std::fmt::Debug;
 use certain_map::Param;
 use monoio::net::TcpStream;
 use monolake_core::listener::{AcceptedAddr, AcceptedStream};
+#[cfg(feature = "acme")]
+use monolake_services::http::handlers::AcmeChallengeHandler;
+#[cfg(feature = "auth")]
+use monolake_services::http::handlers::AuthHandler;
+#[cfg(feature = "jwt")]
+use monolake_services::http::handlers::JwtHandler;
 #[cfg(feature = "openid")]
 use monolake_services::http::handlers::OpenIdHandler;
+#[cfg(feature = "http3")]
+use monolake_services::http::Http3CoreService;
 #[cfg(feature = "proxy-protocol")]
 use monolake_services::proxy_protocol::ProxyProtocolServiceFactory;
 use monolake_services::{
-    common::ContextService,
+    common::{ip_filter::IpFilterService, ContextService},
     http::{
         core::HttpCoreService,
         detect::H2Detect,
         handlers::{
-            upstream::HttpUpstreamTimeout, ConnectionReuseHandler, ContentHandler,
-            RewriteAndRouteHandler, UpstreamHandler,
+            upstream::HttpUpstreamTimeout, AltSvcHandler, ConnectionReuseHandler, ContentHandler,
+            CorsHandler, ExtAuthzHandler, ForwardProxyHandler, GlobalRateLimitHandler, GrpcHandler,
+            IpFilterHandler, RateLimitHandler, RewriteAndRouteHandler, UpstreamHandler,
         },
         HttpVersion,
     },
-    tcp::Accept,
-    thrift::{handlers::ProxyHandler as TProxyHandler, ttheader::TtheaderCoreService},
+    tcp::{Accept, SniRouter, TcpProxyService},
+    thrift::{
+        handlers::{ProxyHandler as TProxyHandler, RateLimitHandler as TRateLimitHandler},
+        ttheader::TtheaderCoreService,
+    },
+    udp::UdpProxyService,
 };
 use service_async::{stack::FactoryStack, ArcMakeService, Service};
 
@@ -29,6 +42,21 @@ use crate::{
     context::{Context, FullContext},
 };
 
+/// Put the services shared by every server in front of the connection stack of a server, checking
+/// that it serves `$stream`, and box the result.
+macro_rules! boxed_stack {
+    ($stacks:expr, $stream:ty) => {
+        $stacks
+            .check_make_svc::<($stream, FullContext)>()
+            .push(IpFilterService::layer())
+            .push(ContextService::<Context, _>::layer())
+            .check_make_svc::<($stream, AcceptedAddr)>()
+            .into_boxed_service()
+            .into_arc_factory()
+            .into_inner()
+    };
+}
+
 /// Create a new factory for l7 proxy.
 // Here we use a fixed generic type `Accept<AcceptedStream, AcceptedAddr>`
 // for simplification and make return impl work.
@@ -40,19 +68,83 @@ pub fn l7_factory(
     impl Debug,
 > {
     match &config.protocol {
-        crate::config::ServerProtocolConfig::Http { opt_handlers, .. } => {
+        crate::config::ServerProtocolConfig::Http {
+            opt_handlers,
+            alt_svc,
+            upstream_http2,
+            global_rate_limit,
+            ext_authz,
+            #[cfg(feature = "jwt")]
+            jwt,
+            ..
+        } => {
             let version: HttpVersion = config.param();
             let http_upstream_timeout: HttpUpstreamTimeout = config.param();
             let enable_content_handler = opt_handlers.content_handler;
+            let upstream_factory = UpstreamHandler::factory(http_upstream_timeout, version)
+                .with_h2_settings(*upstream_http2);
+            #[cfg(feature = "tls")]
+            let upstream_factory = upstream_factory.with_upstream_tls(config.param());
             let stacks = FactoryStack::new(config.clone())
-                .replace(UpstreamHandler::factory(http_upstream_timeout, version))
+                .replace(upstream_factory)
                 .push(ContentHandler::opt_layer(enable_content_handler))
-                .push(RewriteAndRouteHandler::layer());
+                .push(RewriteAndRouteHandler::layer())
+                .push(ExtAuthzHandler::opt_layer(ext_authz.clone()));
+
+            #[cfg(feature = "jwt")]
+            let stacks = stacks.push(JwtHandler::opt_layer(jwt.clone()));
+
+            #[cfg(feature = "auth")]
+            let stacks = stacks.push(AuthHandler::layer());
 
+            // Logins call the provider, so they are rate limited like any other request.
             #[cfg(feature = "openid")]
             let stacks = stacks.push(OpenIdHandler::layer());
 
             let stacks = stacks
+                .push(GlobalRateLimitHandler::opt_layer(global_rate_limit.clone()))
+                .push(RateLimitHandler::layer())
+                .push(GrpcHandler::layer());
+
+            let stacks = stacks.push(CorsHandler::layer());
+
+            #[cfg(feature = "acme")]
+            let stacks = stacks.push(AcmeChallengeHandler::layer());
+
+            // Denied clients are rejected before any other request handler works for them.
+            let stacks = stacks.push(IpFilterHandler::layer());
+
+            #[cfg(feature = "http3")]
+            if config.quic.is_some() {
+                // TLS is part of QUIC and handled by the listener, the tls config of quic servers
+                // is None so the tls layer only passes connections through.
+                let stacks = stacks
+                    .push(ConnectionReuseHandler::layer())
+                    .push(Http3CoreService::layer())
+                    .push(monolake_services::tls::UnifiedTlsFactory::layer());
+                return boxed_stack!(stacks, AcceptedStream);
+            }
+
+            let stacks = stacks
+                .push(AltSvcHandler::opt_layer(alt_svc.clone()))
+                .push(ConnectionReuseHandler::layer())
+                .push(HttpCoreService::layer())
+                .push(H2Detect::layer());
+
+            #[cfg(feature = "tls")]
+            let stacks = stacks.push(monolake_services::tls::UnifiedTlsFactory::layer());
+
+            #[cfg(feature = "proxy-protocol")]
+            let stacks = stacks.push(ProxyProtocolServiceFactory::layer());
+
+            boxed_stack!(stacks, TcpStream)
+        }
+        crate::config::ServerProtocolConfig::ForwardProxy { .. } => {
+            let version: HttpVersion = config.param();
+            let http_upstream_timeout: HttpUpstreamTimeout = config.param();
+            let stacks = FactoryStack::new(config.clone())
+                .replace(UpstreamHandler::factory(http_upstream_timeout, version))
+                .push(ForwardProxyHandler::layer())
                 .push(ConnectionReuseHandler::layer())
                 .push(HttpCoreService::layer())
                 .push(H2Detect::layer());
@@ -63,30 +155,56 @@ pub fn l7_factory(
             #[cfg(feature = "proxy-protocol")]
             let stacks = stacks.push(ProxyProtocolServiceFactory::layer());
 
-            stacks
-                .check_make_svc::<(TcpStream, FullContext)>()
-                .push(ContextService::<Context, _>::layer())
-                .check_make_svc::<(TcpStream, AcceptedAddr)>()
-                .into_boxed_service()
-                .into_arc_factory()
-                .into_inner()
+            boxed_stack!(stacks, TcpStream)
         }
         crate::config::ServerProtocolConfig::Thrift { .. } => {
             let proxy_config = config.param();
             let stacks = FactoryStack::new(config)
                 .replace(TProxyHandler::factory(proxy_config))
+                .push(TRateLimitHandler::layer())
                 .push(TtheaderCoreService::layer());
 
             #[cfg(feature = "tls")]
             let stacks = stacks.push(monolake_services::tls::UnifiedTlsFactory::layer());
 
-            stacks
-                .check_make_svc::<(TcpStream, FullContext)>()
-                .push(ContextService::<Context, _>::layer())
-                .check_make_svc::<(TcpStream, AcceptedAddr)>()
-                .into_boxed_service()
-                .into_arc_factory()
-                .into_inner()
+            boxed_stack!(stacks, TcpStream)
+        }
+        crate::config::ServerProtocolConfig::TlsPassthrough { routes, timeout } => {
+            // The tls config of passthrough servers is None so the tls layer only passes
+            // connections through.
+            let stacks = FactoryStack::new(config.clone())
+                .replace(SniRouter::factory(routes.clone(), *timeout));
+
+            #[cfg(feature = "tls")]
+            let stacks = stacks.push(monolake_services::tls::UnifiedTlsFactory::layer());
+
+            #[cfg(feature = "proxy-protocol")]
+            let stacks = stacks.push(ProxyProtocolServiceFactory::layer());
+
+            boxed_stack!(stacks, TcpStream)
+        }
+        crate::config::ServerProtocolConfig::Tcp { route, timeout } => {
+            let stacks = FactoryStack::new(config.clone())
+                .replace(TcpProxyService::factory(route.clone(), *timeout));
+
+            #[cfg(feature = "tls")]
+            let stacks = stacks.push(monolake_services::tls::UnifiedTlsFactory::layer());
+
+            #[cfg(feature = "proxy-protocol")]
+            let stacks = stacks.push(ProxyProtocolServiceFactory::layer());
+
+            boxed_stack!(stacks, TcpStream)
+        }
+        crate::config::ServerProtocolConfig::Udp { route, timeout, .. } => {
+            // Udp sessions carry no PROXY protocol header and the tls config of udp servers is
+            // None so the tls layer only passes sessions through.
+            let stacks = FactoryStack::new(config.clone())
+                .replace(UdpProxyService::factory(route.clone(), *timeout));
+
+            #[cfg(feature = "tls")]
+            let stacks = stacks.push(monolake_services::tls::UnifiedTlsFactory::layer());
+
+            boxed_stack!(stacks, AcceptedStream)
         }
     }
 }
@@ -1,11 +1,13 @@
+// The futures of the layered service stacks are deeply nested types.
+#![recursion_limit = "256"]
+
 use std::{path::Path, sync::Arc};
 
 use anyhow::Result;
 use clap::Parser;
 use monolake_core::{
     config::{RuntimeConfig, RuntimeType},
-    listener::ListenerBuilder,
-    orchestrator::WorkerManager,
+    orchestrator::{LimitedListenerBuilder, WorkerManager},
 };
 use service_async::AsyncMakeServiceWrapper;
 use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, EnvFilter};
@@ -86,10 +88,13 @@ async fn run(runtime_config: RuntimeConfig, service_config_path: impl AsRef<Path
     // Create config manager
     let config_manager = StaticFileConfigManager::new(
         manager,
-        |config| {
-            AsyncMakeServiceWrapper(Arc::new(
-                ListenerBuilder::try_from(config).expect("build listener failed"),
-            ))
+        |listener, server| {
+            AsyncMakeServiceWrapper(Arc::new(LimitedListenerBuilder::new(
+                listener
+                    .into_builder(server)
+                    .expect("build listener failed"),
+                server.connection_limit,
+            )))
         },
         |config| AsyncMakeServiceWrapper(l7_factory(config)),
     );